@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use crate::atomic;
+use crate::error::AppError;
+
+/// 文書ごとのアクセス数を読み込む (ファイルが無い初期状態は空)
+/// 形式は 文書index,アクセス数 (ヘッダー無し)
+pub fn read_counts(csv_file_path: &str) -> Result<HashMap<usize, u64>, AppError> {
+    let bytes: Vec<u8> = match std::fs::read(csv_file_path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false) // ヘッダーが無い事を明示的に設定
+        .from_reader(bytes.as_slice());
+
+    let mut counts: HashMap<usize, u64> = HashMap::new();
+    for (index, result) in rdr.records().enumerate() {
+        let record = result?;
+        let parse = |col: usize| record.get(col).and_then(|s| s.trim().parse::<u64>().ok());
+        match (parse(0), parse(1)) {
+            (Some(doc_id), Some(count)) => {
+                counts.insert(doc_id as usize, count);
+            },
+            _ => {
+                let value: String = record.iter().collect::<Vec<&str>>().join(",");
+                return Err(AppError::ParseFloat { row: index + 1, col: 1, value });
+            },
+        }
+    }
+    Ok(counts)
+}
+
+/// 文書index順に書き出す
+pub fn write_counts(csv_file_out_path: &str, counts: &HashMap<usize, u64>) -> Result<(), AppError> {
+    let mut entries: Vec<(&usize, &u64)> = counts.iter().collect();
+    entries.sort();
+    atomic::write_csv(csv_file_out_path, &csv::WriterBuilder::new(), |wtr| {
+        for (doc_id, count) in entries {
+            wtr.write_record([doc_id.to_string(), count.to_string()])?;
+        }
+        Ok(())
+    })
+}
+
+/// 文書のアクセス数を1増やす
+pub fn increment(csv_file_path: &str, doc_id: usize) -> Result<(), AppError> {
+    let mut counts: HashMap<usize, u64> = read_counts(csv_file_path)?;
+    *counts.entry(doc_id).or_insert(0) += 1;
+    write_counts(csv_file_path, &counts)
+}
+
+/// アクセス数の多い順に n 件の文書index (同数は文書順)
+/// アクセス記録が n 件に満たない場合は、記録の無い文書を先頭から補う
+pub fn top_ids(counts: &HashMap<usize, u64>, n_docs: usize, n: usize) -> Vec<usize> {
+    let mut ids: Vec<usize> = (0..n_docs).collect();
+    ids.sort_by_key(|id| std::cmp::Reverse(counts.get(id).copied().unwrap_or(0)));
+    ids.truncate(n);
+    ids
+}
+
+/// アクセス数を人気度の prior としてスコアを調整し、大きい順に並べ直す (同スコアは元の順)
+/// score * (1 + alpha * アクセス数 / 最大アクセス数)、記録の無い文書は人気度0で元のスコアのまま
+pub fn apply_popularity(ans_vec: Vec<(usize, f64)>, counts: &HashMap<usize, u64>, alpha: f64) -> Vec<(usize, f64)> {
+    let max_count: u64 = counts.values().copied().max().unwrap_or(0);
+    if max_count == 0 {
+        return ans_vec;
+    }
+    let mut score_vec: Vec<(usize, f64)> = ans_vec.into_iter()
+        .map(|(id, score)| {
+            let popularity: f64 = counts.get(&id).copied().unwrap_or(0) as f64 / max_count as f64;
+            (id, score * (1.0 + alpha * popularity))
+        })
+        .collect();
+    score_vec.sort_by(|a, b| b.1.total_cmp(&a.1));
+    score_vec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increment_test1() {
+        let path = std::env::temp_dir().join("nango_access_increment_test1.csv");
+        let path: &str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        // ファイルが無い初期状態はカウント無し
+        assert!(read_counts(path).unwrap().is_empty());
+        increment(path, 3).unwrap();
+        increment(path, 0).unwrap();
+        increment(path, 3).unwrap();
+        assert_eq!(read_counts(path).unwrap(), HashMap::from([(0, 1), (3, 2)]));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "0,1\n3,2\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn apply_popularity_test1() {
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.62), (1, 0.6), (2, 0.3)];
+        let counts: HashMap<usize, u64> = HashMap::from([(1, 10), (2, 5)]);
+        // 同程度のスコアなら人気の文書1が上になる
+        assert_eq!(apply_popularity(ans_vec.to_owned(), &counts, 0.1), vec![(1, 0.6 * 1.1), (0, 0.62), (2, 0.3 * 1.05)]);
+        // α=0・記録無しでは元のスコアのまま
+        assert_eq!(apply_popularity(ans_vec.to_owned(), &counts, 0.0), ans_vec);
+        assert_eq!(apply_popularity(ans_vec.to_owned(), &HashMap::new(), 0.1), ans_vec);
+        // NaN のスコアがあっても並べ替えでパニックしない
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.0), (1, 0.5)];
+        assert_eq!(apply_popularity(ans_vec, &counts, f64::INFINITY).len(), 2);
+    }
+
+    #[test]
+    fn top_ids_test1() {
+        let counts: HashMap<usize, u64> = HashMap::from([(2, 5), (4, 1), (1, 5), (9, 10)]);
+        // 同数は文書順、学習データに無い文書indexは除外
+        assert_eq!(top_ids(&counts, 5, 3), vec![1, 2, 4]);
+        // 記録が足りない分は先頭から補う
+        assert_eq!(top_ids(&counts, 5, 5), vec![1, 2, 4, 0, 3]);
+        assert_eq!(top_ids(&HashMap::new(), 5, 3), vec![0, 1, 2]);
+    }
+}
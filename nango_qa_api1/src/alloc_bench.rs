@@ -0,0 +1,38 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// メモリピーク計測用のアロケータ
+/// テストバイナリ全体のアロケータを差し替えるため、feature alloc_bench を有効にしたベンチ用のビルドでのみ使う
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+/// 計測中に別の計測がピークを書き換えないようにする
+static MEASURE: Mutex<()> = Mutex::new(());
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let now: usize = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(now, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// f の実行中に増えたメモリ使用量のピーク (bytes)
+/// 他のテストのアロケーションも数えるため、--test-threads=1 で実行すること
+pub fn peak<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let _guard = MEASURE.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    let base: usize = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    let res: T = f();
+    (res, PEAK.load(Ordering::Relaxed) - base)
+}
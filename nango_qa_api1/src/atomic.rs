@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::error::AppError;
+
+/// CSV書き込み時のバッファ (csv::Writer 自体のバッファより大きく取り、書き込み回数を減らす)
+const CSV_BUF_CAPACITY: usize = 1 << 20;
+
+/// 一時ファイル (<path>.tmp) に書き切ってから rename で差し替える
+/// 書き込み途中で失敗しても既存ファイルは壊れず、一時ファイルは削除する
+pub fn write_with<F>(path: &str, write: F) -> Result<(), AppError>
+where
+    F: FnOnce(&str) -> Result<(), AppError>,
+{
+    let tmp_path: String = format!("{}.tmp", path);
+    if let Err(err) = write(&tmp_path) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    std::fs::rename(&tmp_path, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp_path);
+    })?;
+    Ok(())
+}
+
+/// CSVをバッファ付きで一時ファイルに書き、flushしてから差し替える
+/// ドロップ時のflushは失敗を握りつぶすため、明示的にflushしてエラーを返す
+pub fn write_csv<F>(path: &str, builder: &csv::WriterBuilder, write: F) -> Result<(), AppError>
+where
+    F: FnOnce(&mut csv::Writer<BufWriter<File>>) -> Result<(), AppError>,
+{
+    write_with(path, |tmp_path| {
+        let mut wtr = builder.from_writer(BufWriter::with_capacity(CSV_BUF_CAPACITY, File::create(tmp_path)?));
+        write(&mut wtr)?;
+        wtr.into_inner().map_err(|err| err.into_error())?.into_inner().map_err(|err| err.into_error())?;
+        Ok(())
+    })
+}
+
+/// バイト列をアトミックに書き出す
+pub fn write(path: &str, contents: &[u8]) -> Result<(), AppError> {
+    write_with(path, |tmp_path| Ok(std::fs::write(tmp_path, contents)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_test1() {
+        let path = std::env::temp_dir().join("nango_atomic_write_test1.csv");
+        let path_str = path.to_str().unwrap();
+        write(path_str, b"new").unwrap();
+        assert_eq!(std::fs::read(path_str).unwrap(), b"new");
+        assert!(!std::path::Path::new(&format!("{}.tmp", path_str)).exists());
+    }
+
+    #[test]
+    fn write_with_fail_test1() {
+        let path = std::env::temp_dir().join("nango_atomic_write_fail_test1.csv");
+        let path_str = path.to_str().unwrap();
+        std::fs::write(path_str, "\"id\",\"犬\"\n\"0\",\"0.5\"\n").unwrap();
+
+        // 途中まで書いたところで失敗させる
+        let res = write_with(path_str, |tmp_path| {
+            let mut wtr = csv::Writer::from_path(tmp_path)?;
+            wtr.write_record(["id"])?;
+            wtr.flush()?;
+            Err(std::io::Error::other("disk full").into())
+        });
+        assert!(res.is_err());
+        // 既存ファイルはそのまま、一時ファイルは残らない
+        assert_eq!(std::fs::read_to_string(path_str).unwrap(), "\"id\",\"犬\"\n\"0\",\"0.5\"\n");
+        assert!(!std::path::Path::new(&format!("{}.tmp", path_str)).exists());
+    }
+}
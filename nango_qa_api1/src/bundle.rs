@@ -0,0 +1,210 @@
+use serde::{Deserialize, Serialize};
+
+use crate::atomic;
+use crate::error::AppError;
+use crate::nlp::calibration::ScoreStats;
+use crate::nlp::tf_idf::TfIdf;
+use crate::pipeline::Pipeline;
+use crate::ModelJson;
+
+const FORMAT_VERSION: u32 = 1;
+/// zstd のフレームの先頭 (圧縮したバンドルの判別用)
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const DEFAULT_MAX_DECODED_BYTES: usize = 512 * 1024 * 1024;
+
+fn default_filters() -> Vec<String> {
+    Pipeline::default().filter_names().to_vec()
+}
+
+/// モデル・単語リスト・前処理の構成をまとめた1ファイルのモデル (JSON)
+/// 別ファイルだと再学習の途中で食い違うため、予測時はこちらを優先して読む
+/// 環境変数 MODEL_COMPRESS=true で zstd 圧縮して保存する (保存先・世代にも圧縮したまま置く、読み込み時は先頭で判別)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    format_version: u32,
+    pipeline: Vec<String>,
+    #[serde(default = "default_filters")] // フィルタを記録する前のバンドルは既定のフィルタ
+    filters: Vec<String>,
+    model: ModelJson,
+    word_list: Vec<Vec<String>>,
+    /// 学習データCSVが無い環境 (データセット横断検索) でも候補のQAを返せるよう保存 (記録する前のバンドルは空)
+    #[serde(default)]
+    questions: Vec<String>,
+    #[serde(default)]
+    answers: Vec<String>,
+    /// predict のスコアのキャリブレーション用の統計 (データセットごとに異なるためモデルと一緒に保存する)
+    #[serde(default)]
+    score_stats: Option<ScoreStats>,
+}
+
+impl Bundle {
+    /// バンドルの内容を解析する (バージョン・文書数の検証は load_bundle と同じ)
+    /// 圧縮したバンドルは展開後の上限 (環境変数 MODEL_MAX_DECODED_BYTES) を超えるとエラー
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, AppError> {
+        let max_decoded: usize = crate::config::env_or("MODEL_MAX_DECODED_BYTES", DEFAULT_MAX_DECODED_BYTES);
+        let bundle: Bundle = serde_json::from_slice(&decode(bytes, max_decoded)?).map_err(std::io::Error::from)?;
+        if bundle.format_version != FORMAT_VERSION {
+            let message: String = format!("unsupported bundle version: {}", bundle.format_version);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into());
+        }
+        if bundle.model.tf_idf.len() != bundle.word_list.len() {
+            let message: String = format!("bundle doc count mismatch (model: {}, word_list: {})", bundle.model.tf_idf.len(), bundle.word_list.len());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into());
+        }
+        if !bundle.questions.is_empty() && (bundle.questions.len() != bundle.word_list.len() || bundle.answers.len() != bundle.word_list.len()) {
+            let message: String = format!("bundle qa count mismatch (questions: {}, answers: {}, word_list: {})", bundle.questions.len(), bundle.answers.len(), bundle.word_list.len());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into());
+        }
+        Ok(bundle)
+    }
+
+    pub fn doc_count(&self) -> usize {
+        self.word_list.len()
+    }
+
+    /// 保存した学習データの質問・回答 (記録する前のバンドルはNone)
+    pub fn qa(&self) -> Option<(&[String], &[String])> {
+        (!self.questions.is_empty()).then_some((&self.questions, &self.answers))
+    }
+
+    /// 学習時の自己検索スコアの分布 (記録する前のバンドル・文書が2件未満の場合はNone)
+    pub fn score_stats(&self) -> Option<&ScoreStats> {
+        self.score_stats.as_ref()
+    }
+
+    /// 学習済みモデル・単語リスト・前処理パイプラインに分解
+    pub fn into_parts(self) -> Result<(TfIdf, Vec<Vec<String>>, Pipeline), AppError> {
+        let pipeline: Pipeline = Pipeline::from_names(&self.pipeline)?.with_filters(&self.filters)?;
+        Ok((self.model.into_tf_idf(), self.word_list, pipeline))
+    }
+}
+
+/// questions・answers は学習データの質問・回答 (無い場合は空)
+pub fn save_bundle(path: &str, tf_idf: &TfIdf, docs: &[Vec<String>], pipeline: &Pipeline, questions: &[String], answers: &[String], score_stats: Option<&ScoreStats>) -> Result<(), AppError> {
+    let bundle = Bundle {
+        format_version: FORMAT_VERSION,
+        pipeline: pipeline.names().into_iter().map(|name| name.to_string()).collect(),
+        filters: pipeline.filter_names().to_vec(),
+        model: ModelJson::new(tf_idf),
+        word_list: docs.to_vec(),
+        questions: questions.to_vec(),
+        answers: answers.to_vec(),
+        score_stats: score_stats.cloned(),
+    };
+    atomic::write(path, &encode(&bundle, crate::config::env_or("MODEL_COMPRESS", false))?)
+}
+
+fn encode(bundle: &Bundle, compress: bool) -> Result<Vec<u8>, AppError> {
+    let json: Vec<u8> = serde_json::to_vec(bundle).map_err(std::io::Error::from)?;
+    if !compress {
+        return Ok(json);
+    }
+    let compressed: Vec<u8> = zstd::bulk::compress(&json, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    println!("model compressed: {} -> {} bytes ({:.1}%)", json.len(), compressed.len(), compressed.len() as f64 / json.len() as f64 * 100.0);
+    Ok(compressed)
+}
+
+/// 圧縮したバンドルを展開する (圧縮していないバンドルはそのまま)
+fn decode(bytes: &[u8], max_decoded: usize) -> Result<std::borrow::Cow<'_, [u8]>, AppError> {
+    if !bytes.starts_with(&ZSTD_MAGIC) {
+        return Ok(std::borrow::Cow::Borrowed(bytes));
+    }
+    Ok(std::borrow::Cow::Owned(zstd::bulk::decompress(bytes, max_decoded)?))
+}
+
+/// バンドルを読み込む (未知のバージョン・モデルと単語リストの文書数の不一致はエラー)
+pub fn load_bundle(path: &str) -> Result<Bundle, AppError> {
+    Bundle::from_slice(&std::fs::read(path)?)
+}
+
+/// 旧形式 (モデル・単語リスト・モデルメタの別ファイル) からバンドルを作る
+/// モデルメタが無い場合は環境変数の前処理設定を記録する (学習データの質問・回答は記録しない)
+pub fn migrate(model_path: &str, word_list_path: &str, meta_path: &str, bundle_path: &str) -> Result<(), AppError> {
+    let tf_idf: TfIdf = crate::read_model(model_path)?;
+    let docs: Vec<Vec<String>> = crate::read_word_list_csv(word_list_path)?;
+    let pipeline: Pipeline = Pipeline::read_meta(meta_path)?.unwrap_or_else(Pipeline::from_env);
+    save_bundle(bundle_path, &tf_idf, &docs, &pipeline, &[], &[], None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn docs() -> Vec<Vec<String>> {
+        vec![
+            ["料金", "は", "いくら"].iter().map(|s| s.to_string()).collect(),
+            ["楽器", "は", "演奏", "でき", "ます", "か"].iter().map(|s| s.to_string()).collect(),
+        ]
+    }
+
+    #[test]
+    fn bundle_roundtrip_test1() {
+        let path = std::env::temp_dir().join("nango_bundle_roundtrip_test1.json");
+        let path: &str = path.to_str().unwrap();
+        let docs: Vec<Vec<String>> = docs();
+        let tf_idf: TfIdf = TfIdf::get_tf_idf(&docs).unwrap();
+        let questions: Vec<String> = vec!["料金はいくら".to_string(), "楽器は演奏できますか".to_string()];
+        let answers: Vec<String> = vec!["1500円です".to_string(), "できません".to_string()];
+        let stats: ScoreStats = ScoreStats::from_scores(vec![0.2, 0.5]).unwrap();
+        save_bundle(path, &tf_idf, &docs, &Pipeline::from_names(&["lemmatize"]).unwrap().with_filters(&["lowercase"]).unwrap(), &questions, &answers, Some(&stats)).unwrap();
+
+        let bundle: Bundle = load_bundle(path).unwrap();
+        assert_eq!(bundle.qa(), Some((questions.as_slice(), answers.as_slice())));
+        assert_eq!(bundle.score_stats(), Some(&stats));
+        let (loaded_tf_idf, loaded_docs, pipeline) = bundle.into_parts().unwrap();
+        assert_eq!(loaded_tf_idf.word_vec, tf_idf.word_vec);
+        assert_eq!(loaded_tf_idf.tf_idf_vec, tf_idf.tf_idf_vec);
+        assert_eq!(loaded_docs, docs);
+        assert_eq!(pipeline.names(), vec!["lemmatize"]);
+        assert_eq!(pipeline.filter_names(), ["lowercase"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bundle_compress_test1() {
+        let docs: Vec<Vec<String>> = docs();
+        let questions: Vec<String> = vec!["料金はいくら".to_string(); 2];
+        let bundle = Bundle {
+            format_version: FORMAT_VERSION,
+            pipeline: vec![],
+            filters: vec![],
+            model: ModelJson::new(&TfIdf::get_tf_idf(&docs).unwrap()),
+            word_list: docs.to_owned(),
+            questions: questions.to_owned(),
+            answers: questions.to_owned(),
+            score_stats: None,
+        };
+        let json: Vec<u8> = encode(&bundle, false).unwrap();
+        let compressed: Vec<u8> = encode(&bundle, true).unwrap();
+        assert!(compressed.starts_with(&ZSTD_MAGIC));
+        // 圧縮の有無にかかわらず同じ内容が読める
+        assert_eq!(Bundle::from_slice(&compressed).unwrap().word_list, docs);
+        assert_eq!(decode(&compressed, json.len()).unwrap().as_ref(), json.as_slice());
+        assert_eq!(decode(&json, 0).unwrap().as_ref(), json.as_slice());
+        // 展開後の上限を超える圧縮データはエラー
+        assert!(decode(&compressed, json.len() - 1).is_err());
+    }
+
+    #[test]
+    fn bundle_migrate_test1() {
+        let dir = std::env::temp_dir();
+        let model_path = dir.join("nango_bundle_migrate_test1_model.csv");
+        let word_list_path = dir.join("nango_bundle_migrate_test1_word_list.csv");
+        let bundle_path = dir.join("nango_bundle_migrate_test1.json");
+        let (model_path, word_list_path, bundle_path) = (model_path.to_str().unwrap(), word_list_path.to_str().unwrap(), bundle_path.to_str().unwrap());
+        let docs: Vec<Vec<String>> = docs();
+        let tf_idf: TfIdf = TfIdf::get_tf_idf(&docs).unwrap();
+        crate::out_model(model_path, &tf_idf).unwrap();
+        crate::out_csv_word(word_list_path, &docs).unwrap();
+
+        migrate(model_path, word_list_path, "not_found_meta.json", bundle_path).unwrap();
+        assert_eq!(load_bundle(bundle_path).unwrap().qa(), None);
+        let (loaded_tf_idf, loaded_docs, _) = load_bundle(bundle_path).unwrap().into_parts().unwrap();
+        assert_eq!(loaded_tf_idf.word_vec, crate::read_model(model_path).unwrap().word_vec);
+        assert_eq!(loaded_tf_idf.tf_idf_vec, crate::read_model(model_path).unwrap().tf_idf_vec);
+        assert_eq!(loaded_docs, docs);
+        for path in [model_path, word_list_path, bundle_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
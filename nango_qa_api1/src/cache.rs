@@ -0,0 +1,102 @@
+use lru::LruCache;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+
+use crate::config;
+use crate::error::AppError;
+
+const DEFAULT_CAPACITY: usize = 128;
+
+static PREDICT_CACHE: OnceLock<Mutex<PredictCache>> = OnceLock::new();
+
+/// 予測結果のキャッシュ (テナントIDごと)
+/// キーにモデルのハッシュを含めるため、再学習後は自然にミスとなる
+pub struct PredictCache {
+    cache: LruCache<(String, u64, String), Vec<(usize, f64)>>,
+}
+
+impl PredictCache {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self { cache: LruCache::new(capacity) }
+    }
+
+    pub fn get(&mut self, tenant_id: &str, model_hash: u64, query: &str) -> Option<Vec<(usize, f64)>> {
+        self.cache.get(&(tenant_id.to_string(), model_hash, query.to_string())).cloned()
+    }
+
+    pub fn put(&mut self, tenant_id: &str, model_hash: u64, query: String, ans_vec: Vec<(usize, f64)>) {
+        self.cache.put((tenant_id.to_string(), model_hash, query), ans_vec);
+    }
+}
+
+/// ウォームインスタンス内で共有するキャッシュ
+/// 容量は環境変数 PREDICT_CACHE_CAPACITY で指定
+pub fn global() -> &'static Mutex<PredictCache> {
+    PREDICT_CACHE.get_or_init(|| {
+        let capacity: usize = config::env_or("PREDICT_CACHE_CAPACITY", DEFAULT_CAPACITY);
+        Mutex::new(PredictCache::new(capacity))
+    })
+}
+
+/// キャッシュのロックを取る (パニックで毒化していても使い続ける)
+pub fn lock() -> MutexGuard<'static, PredictCache> {
+    global().lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// モデルファイルの内容からハッシュ値を算出
+pub fn model_hash(path: &str) -> Result<u64, AppError> {
+    Ok(hash_bytes(&std::fs::read(path)?))
+}
+
+/// 読み込み済みのモデルファイルの内容のハッシュ値 (model_hash と同じ値)
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// キャッシュキー用にクエリを正規化 (前後の空白除去・連続空白の統一)
+pub fn normalize_query(que_sentence: &str) -> String {
+    que_sentence.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn predict_cache_test1() {
+        let mut cache = PredictCache::new(2);
+        // 初回はミス
+        assert_eq!(cache.get("a", 1, "料金は？"), None);
+
+        cache.put("a", 1, "料金は？".to_string(), vec![(0, 0.9), (3, 0.4)]);
+        // 同一クエリ・同一モデルでヒット
+        assert_eq!(cache.get("a", 1, "料金は？"), Some(vec![(0, 0.9), (3, 0.4)]));
+        // モデルが変わるとミス
+        assert_eq!(cache.get("a", 2, "料金は？"), None);
+        // 同じモデルでもテナントが異なるとミス
+        assert_eq!(cache.get("b", 1, "料金は？"), None);
+    }
+
+    #[test]
+    fn predict_cache_test2() {
+        let mut cache = PredictCache::new(2);
+        cache.put("t", 1, "a".to_string(), vec![(0, 0.1)]);
+        cache.put("t", 1, "b".to_string(), vec![(1, 0.2)]);
+        cache.get("t", 1, "a");
+        cache.put("t", 1, "c".to_string(), vec![(2, 0.3)]);
+        // 容量超過で最も使われていない "b" が追い出されるか確認
+        assert_eq!(cache.get("t", 1, "b"), None);
+        assert!(cache.get("t", 1, "a").is_some());
+        assert!(cache.get("t", 1, "c").is_some());
+    }
+
+    #[test]
+    fn normalize_query_test1() {
+        assert_eq!(normalize_query("  料金は  いくら？ "), "料金は いくら？");
+    }
+}
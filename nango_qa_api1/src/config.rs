@@ -0,0 +1,8 @@
+use std::str::FromStr;
+
+/// 環境変数から設定値を取得 (未設定・不正値の場合はデフォルト値)
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key).ok()
+        .and_then(|s| s.trim().parse::<T>().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use crate::nlp::calibration::ScoreStats;
+use crate::nlp::tf_idf::TfIdf;
+use crate::pipeline::Pipeline;
+use crate::schema::CsvSchema;
+use crate::QaData;
+
+static QA_ENGINES: OnceLock<Mutex<HashMap<String, QaEngine>>> = OnceLock::new();
+
+/// 学習結果をウォームインスタンス内で保持する
+/// ファイルに書き出さない中間生成物もここから参照できる
+#[derive(Debug, Default)]
+pub struct QaEngine {
+    word_list: Option<Vec<Vec<String>>>,
+    model: Option<(QaData, TfIdf)>,
+    pipeline: Option<Pipeline>,
+    schema: Option<CsvSchema>,
+    loaded: Option<Arc<LoadedModel>>,
+    fetched: Option<String>,
+}
+
+/// 予測用にバンドルから読み込んだ単語リスト・学習済みモデル・キャリブレーション用の統計
+/// 検索用の行列もモデルと一緒に保持するため、再学習・追加までは読み直さない
+/// hash は読み込んだバンドルの内容のハッシュ値 (予測結果のキャッシュのキー)
+#[derive(Debug)]
+pub struct LoadedModel {
+    pub docs: Vec<Vec<String>>,
+    pub tfidf: TfIdf,
+    pub score_stats: Option<ScoreStats>,
+    pub hash: u64,
+}
+
+impl QaEngine {
+    pub fn set_word_list(&mut self, docs: Vec<Vec<String>>) {
+        self.word_list = Some(docs);
+        self.loaded = None;
+    }
+
+    pub fn word_list(&self) -> Option<&Vec<Vec<String>>> {
+        self.word_list.as_ref()
+    }
+
+    /// 差分追加の元になるQAデータと学習済みモデルを保持
+    pub fn set_model(&mut self, qa_data: QaData, tf_idf: TfIdf) {
+        self.model = Some((qa_data, tf_idf));
+        self.loaded = None;
+    }
+
+    pub fn model(&self) -> Option<&(QaData, TfIdf)> {
+        self.model.as_ref()
+    }
+
+    /// 学習時の前処理パイプラインを保持
+    pub fn set_pipeline(&mut self, pipeline: Pipeline) {
+        self.pipeline = Some(pipeline);
+    }
+
+    pub fn pipeline(&self) -> Option<&Pipeline> {
+        self.pipeline.as_ref()
+    }
+
+    /// 学習時の学習データCSVの列構成を保持
+    pub fn set_schema(&mut self, schema: CsvSchema) {
+        self.schema = Some(schema);
+    }
+
+    pub fn schema(&self) -> Option<&CsvSchema> {
+        self.schema.as_ref()
+    }
+
+    /// 予測用に読み込んだモデルを保持 (学習結果を更新すると破棄する)
+    pub fn set_loaded(&mut self, model: Arc<LoadedModel>) {
+        self.loaded = Some(model);
+    }
+
+    pub fn loaded(&self) -> Option<Arc<LoadedModel>> {
+        self.loaded.clone()
+    }
+
+    /// 保存先から取得・保存先へ書き込んだバンドルの etag を保持 (同じ etag の間は取得し直さない)
+    pub fn set_fetched(&mut self, etag: Option<String>) {
+        self.fetched = etag;
+    }
+
+    pub fn fetched(&self) -> Option<&str> {
+        self.fetched.as_deref()
+    }
+
+    /// 全再学習せずにQAを1件追加し、追加した文書のindexを返す
+    /// 学習時と同じパイプラインでトークン化する (未設定なら環境変数の設定)
+    pub fn add_document(&mut self, question: String, answer: String) -> usize {
+        let doc: Vec<String> = self.pipeline.get_or_insert_with(Pipeline::from_env).run(question.to_owned());
+        self.add_tokens(question, answer, doc)
+    }
+
+    fn add_tokens(&mut self, question: String, answer: String, doc: Vec<String>) -> usize {
+        let docs: &mut Vec<Vec<String>> = self.word_list.get_or_insert_with(Vec::new);
+        let (qa_data, tf_idf) = self.model.get_or_insert_with(|| {
+            (QaData::default(), TfIdf::new(vec![], vec![]))
+        });
+        tf_idf.add_document(docs, &doc);
+        docs.push(doc);
+        qa_data.que_vec.push(question);
+        qa_data.ans_vec.push(answer);
+        qa_data.cat_vec.push("".to_string());
+        qa_data.answers.push(Default::default());
+        self.loaded = None;
+        docs.len() - 1
+    }
+}
+
+/// テナントIDごとの学習結果
+pub fn global() -> &'static Mutex<HashMap<String, QaEngine>> {
+    QA_ENGINES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// テナントの保持中の学習結果のロック (ロック中は他のテナントの学習結果も待たせる)
+pub struct EngineGuard {
+    engines: MutexGuard<'static, HashMap<String, QaEngine>>,
+    tenant_id: String,
+}
+
+impl Deref for EngineGuard {
+    type Target = QaEngine;
+
+    fn deref(&self) -> &QaEngine {
+        &self.engines[&self.tenant_id]
+    }
+}
+
+impl DerefMut for EngineGuard {
+    fn deref_mut(&mut self) -> &mut QaEngine {
+        self.engines.get_mut(&self.tenant_id).unwrap()
+    }
+}
+
+/// テナントの保持中の学習結果のロックを取る (未保持なら空の学習結果を作る)
+/// 更新中にパニックしたロック (毒化) は途中の状態の可能性があるため、学習結果を破棄してから使い続ける
+pub fn lock(tenant_id: &str) -> EngineGuard {
+    let mut engines = lock_or_reset(global());
+    engines.entry(tenant_id.to_string()).or_default();
+    EngineGuard { engines, tenant_id: tenant_id.to_string() }
+}
+
+fn lock_or_reset<T: Default>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        let mut engine = poisoned.into_inner();
+        *engine = T::default();
+        mutex.clear_poison();
+        engine
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_list_test1() {
+        let mut engine = QaEngine::default();
+        assert!(engine.word_list().is_none());
+
+        let docs: Vec<Vec<String>> = vec![vec!["料金".to_string(), "は".to_string()]];
+        engine.set_word_list(docs.to_owned());
+        assert_eq!(engine.word_list(), Some(&docs));
+    }
+
+    #[test]
+    fn lock_test1() {
+        // テナントごとに別の学習結果を保持する
+        lock("engine_lock_test1_a").set_word_list(vec![vec!["料金".to_string()]]);
+        assert!(lock("engine_lock_test1_b").word_list().is_none());
+        assert!(lock("engine_lock_test1_a").word_list().is_some());
+    }
+
+    #[test]
+    fn lock_or_reset_test1() {
+        let mutex: Mutex<QaEngine> = Mutex::new(QaEngine::default());
+        lock_or_reset(&mutex).set_word_list(vec![vec!["料金".to_string()]]);
+        // ロック中のパニックで毒化しても、学習結果を破棄して使い続ける
+        let _ = std::panic::catch_unwind(|| {
+            let _engine = lock_or_reset(&mutex);
+            panic!("更新中のパニック");
+        });
+        assert!(mutex.is_poisoned());
+        assert!(lock_or_reset(&mutex).word_list().is_none());
+        assert!(!mutex.is_poisoned());
+    }
+
+    #[test]
+    fn add_tokens_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            ["料金", "は", "？"].iter().map(|s| s.to_string()).collect(),
+            ["駐車場", "は", "？"].iter().map(|s| s.to_string()).collect(),
+        ];
+        let mut engine = QaEngine::default();
+        engine.add_tokens("料金は？".to_string(), "1500円です。".to_string(), docs[0].to_owned());
+        let doc_id = engine.add_tokens("駐車場は？".to_string(), "ありません。".to_string(), docs[1].to_owned());
+
+        // 差分追加の結果がフル学習と一致するか確認
+        assert_eq!(doc_id, 1);
+        assert_eq!(engine.word_list(), Some(&docs));
+        let (qa_data, tf_idf) = engine.model().unwrap();
+        assert_eq!(qa_data.ans_vec, vec!["1500円です。", "ありません。"]);
+        let exp = TfIdf::get_tf_idf(&docs).unwrap();
+        assert_eq!(tf_idf.word_vec, exp.word_vec);
+        for index in 0..docs.len() {
+            let diff: f64 = tf_idf.dense_row(index).iter().zip(exp.dense_row(index)).map(|(a, b)| crate::nlp::tf_idf::to_f64((a - b).abs())).sum();
+            assert!(diff < 1e-6);
+        }
+    }
+
+    #[test]
+    fn loaded_test1() {
+        let docs: Vec<Vec<String>> = vec![["料金", "は", "？"].iter().map(|s| s.to_string()).collect()];
+        let mut engine = QaEngine::default();
+        engine.set_loaded(Arc::new(LoadedModel { docs: docs.to_owned(), tfidf: TfIdf::get_tf_idf(&docs).unwrap(), score_stats: None, hash: 0 }));
+        assert!(engine.loaded().is_some());
+        // 追加した場合は読み込んだモデルを破棄する
+        engine.add_tokens("駐車場は？".to_string(), "ありません。".to_string(), vec!["駐車場".to_string()]);
+        assert!(engine.loaded().is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use std::fmt;
+
+use crate::nlp::tf_idf::TfIdfError;
+
+/// アプリケーション共通のエラー
+#[derive(Debug)]
+pub enum AppError {
+    Io(std::io::Error),
+    Csv(csv::Error),
+    /// モデルCSVの数値変換失敗 (行・列は1始まり)
+    ParseFloat { row: usize, col: usize, value: String },
+    UnknownCategory(String),
+    /// 保存先に無いデータセット
+    UnknownDataset(String),
+    /// テナントの学習済みモデルが無い (テナントID)
+    ModelNotFound(String),
+    /// 学習データCSVの列不足 (行は1始まり)
+    CsvColumns { row: usize, expected: usize, actual: usize },
+    /// 指定した列位置が学習データCSVの列数を超えている (列番号は0始まり)
+    ColumnOutOfRange { expected: usize, actual: usize },
+    TfIdf(TfIdfError),
+    /// 日本語以外の質問 (日本語の文字の割合)
+    UnsupportedLanguage(f64),
+    /// 学習データ・単語リスト・モデルの文書数の不一致 (再学習が必要)
+    DocCountMismatch { qa_data: usize, word_list: usize, model: usize },
+    /// 上限時間内にトークナイズが終わらなかった (上限はミリ秒)
+    Timeout { limit_ms: u64 },
+    /// 文の解析に失敗した (空文字列など)
+    Tokenize(String),
+    /// 上限時間内にモデルファイルのロックを取れなかった (上限はミリ秒)
+    LockTimeout { limit_ms: u64 },
+    /// ロールバックできる以前のモデルの世代が無い
+    NoPreviousVersion,
+    /// 保存していない (保持する世代数を超えて削除された) モデルの世代
+    UnknownVersion(u64),
+    /// 学習データが上限を超えている (name は docs, vocab, memory_bytes)
+    TooLarge { name: &'static str, actual: u64, limit: u64 },
+}
+
+impl AppError {
+    /// レスポンスに返すステータスコード
+    pub fn code(&self) -> u16 {
+        match self {
+            AppError::UnknownCategory(_) | AppError::UnknownDataset(_) | AppError::ModelNotFound(_) | AppError::ColumnOutOfRange { .. } | AppError::UnsupportedLanguage(_) | AppError::TooLarge { .. } | AppError::NoPreviousVersion | AppError::UnknownVersion(_) => 400,
+            AppError::LockTimeout { .. } => 503,
+            _ => 500,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Io(err) => write!(f, "io error: {}", err),
+            AppError::Csv(err) => write!(f, "csv error: {}", err),
+            AppError::ParseFloat { row, col, value } => {
+                write!(f, "数値に変換できません (行: {}, 列: {}, 値: {:?})", row, col, value)
+            },
+            AppError::UnknownCategory(category) => write!(f, "未知のカテゴリです: {}", category),
+            AppError::UnknownDataset(dataset) => write!(f, "未知のデータセットです: {}", dataset),
+            AppError::ModelNotFound(tenant) => write!(f, "学習済みモデルがありません。学習してください (テナント: {})", tenant),
+            AppError::CsvColumns { row, expected, actual } => {
+                write!(f, "列数が不足しています (行: {}, 必要列数: {}, 実際: {})", row, expected, actual)
+            },
+            AppError::ColumnOutOfRange { expected, actual } => {
+                write!(f, "column_map の列位置が学習データの列数を超えています (必要列数: {}, 実際: {})", expected, actual)
+            },
+            AppError::TfIdf(err) => write!(f, "tf-idf error: {}", err),
+            AppError::UnsupportedLanguage(ratio) => {
+                write!(f, "unsupported_language: 日本語の質問のみ対応しています (日本語の割合: {:.2})", ratio)
+            },
+            AppError::DocCountMismatch { qa_data, word_list, model } => {
+                write!(f, "文書数が一致しません。再学習してください (学習データ: {}, 単語リスト: {}, モデル: {})", qa_data, word_list, model)
+            },
+            AppError::Timeout { limit_ms } => write!(f, "トークナイズがタイムアウトしました (上限: {}ms)", limit_ms),
+            AppError::Tokenize(message) => write!(f, "トークナイズに失敗しました: {}", message),
+            AppError::LockTimeout { limit_ms } => write!(f, "モデルの更新中です。時間をおいて再度お試しください (待ち時間の上限: {}ms)", limit_ms),
+            AppError::NoPreviousVersion => write!(f, "戻せる以前のモデルがありません"),
+            AppError::UnknownVersion(version) => write!(f, "保存されていないモデルの世代です: {}", version),
+            AppError::TooLarge { name, actual, limit } => {
+                write!(f, "学習データが大きすぎます ({}: {}, 上限: {})", name, actual, limit)
+            },
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// pkey 検証のエラー
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    /// pkey が無い・空
+    MissingKey,
+    /// pkey が一致しない
+    InvalidKey,
+    /// 有効な pkey だが、そのモードの実行権限が無い
+    Forbidden(String),
+}
+
+impl AuthError {
+    pub fn code(&self) -> u16 {
+        match self {
+            AuthError::MissingKey | AuthError::InvalidKey => 401,
+            AuthError::Forbidden(_) => 403,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingKey | AuthError::InvalidKey => write!(f, "Not executable"),
+            AuthError::Forbidden(mode) => write!(f, "Forbidden: このキーではモード {} を実行できません", mode),
+        }
+    }
+}
+
+/// 入力パラメータの検証エラー
+#[derive(Debug, PartialEq)]
+pub enum InitError {
+    Auth(AuthError),
+    /// pkey 以外のパラメータ不正
+    Invalid(String),
+}
+
+impl InitError {
+    pub fn code(&self) -> u16 {
+        match self {
+            InitError::Auth(err) => err.code(),
+            InitError::Invalid(_) => 400,
+        }
+    }
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::Auth(err) => write!(f, "{}", err),
+            InitError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<AuthError> for InitError {
+    fn from(err: AuthError) -> Self {
+        InitError::Auth(err)
+    }
+}
+
+impl From<String> for InitError {
+    fn from(message: String) -> Self {
+        InitError::Invalid(message)
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(err: std::io::Error) -> Self {
+        AppError::Io(err)
+    }
+}
+
+impl From<csv::Error> for AppError {
+    fn from(err: csv::Error) -> Self {
+        AppError::Csv(err)
+    }
+}
+
+impl From<TfIdfError> for AppError {
+    fn from(err: TfIdfError) -> Self {
+        AppError::TfIdf(err)
+    }
+}
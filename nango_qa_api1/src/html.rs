@@ -0,0 +1,116 @@
+use serde_json::Value;
+
+/// HTMLの特殊文字をエスケープする (属性値にも使えるよう引用符も対象)
+pub fn escape(text: &str) -> String {
+    let mut escaped: String = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 予測結果をiframe埋め込み用のHTML断片にする (質問・回答はエスケープ済み)
+/// split_query で文ごとに分けた結果は、文ごとに section で囲む
+pub fn render_predict(res_json: &Value) -> String {
+    render_payload(&res_json["payload"])
+}
+
+/// qa_infos が無い payload は split_query の文ごとの結果 (groups の各 payload を再帰的に描画)
+/// qa_infos がある payload の groups は cluster でまとめた候補
+fn render_payload(payload: &Value) -> String {
+    let groups: Option<&Vec<Value>> = payload["groups"].as_array();
+    match (payload["qa_infos"].as_array(), groups) {
+        (None, Some(groups)) => groups.iter()
+            .map(|group| format!(
+                "<section class=\"nango-qa-group\"><h3>{}</h3>{}</section>",
+                escape(group["que_sentence"].as_str().unwrap_or("")),
+                render_payload(&group["payload"]),
+            ))
+            .collect(),
+        (Some(_), Some(groups)) => groups.iter()
+            .map(|group| {
+                let members = group["members"].as_array().into_iter().flatten();
+                format!("<section class=\"nango-qa-cluster\">{}</section>", render_qa_infos(std::iter::once(&group["representative"]).chain(members)))
+            })
+            .collect(),
+        (qa_infos, None) => render_qa_infos(qa_infos.into_iter().flatten()),
+    }
+}
+
+/// 候補を <ul><li> で並べる (テンプレート整形済みの回答があればそちらを使う)
+fn render_qa_infos<'a>(qa_infos: impl Iterator<Item = &'a Value>) -> String {
+    let items: String = qa_infos
+        .map(|info| {
+            let ans: &str = info["formatted_answer"].as_str().or_else(|| info["ans"].as_str()).unwrap_or("");
+            format!(
+                "<li><p class=\"nango-qa-que\">{}</p><p class=\"nango-qa-ans\">{}</p></li>",
+                escape(info["similar_que"].as_str().unwrap_or("")),
+                escape(ans),
+            )
+        })
+        .collect();
+    format!("<ul class=\"nango-qa\">{}</ul>", items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn escape_test1() {
+        assert_eq!(escape("<script>alert('x')</script>"), "&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;");
+        assert_eq!(escape("A&B \"料金\""), "A&amp;B &quot;料金&quot;");
+        assert_eq!(escape("1500円です"), "1500円です");
+    }
+
+    #[test]
+    fn render_predict_test1() {
+        let res_json: Value = json!({ "payload": { "qa_infos": [
+            { "similar_que": "料金は？", "ans": "<b>1500円</b>です<img src=x onerror=alert(1)>" },
+            { "similar_que": "駐車場は？", "ans": "ありません", "formatted_answer": "回答: ありません" },
+        ] } });
+        // 回答のタグは実行されない文字列としてエスケープされる
+        assert_eq!(render_predict(&res_json), concat!(
+            "<ul class=\"nango-qa\">",
+            "<li><p class=\"nango-qa-que\">料金は？</p><p class=\"nango-qa-ans\">&lt;b&gt;1500円&lt;/b&gt;です&lt;img src=x onerror=alert(1)&gt;</p></li>",
+            "<li><p class=\"nango-qa-que\">駐車場は？</p><p class=\"nango-qa-ans\">回答: ありません</p></li>",
+            "</ul>",
+        ));
+        assert_eq!(render_predict(&json!({ "payload": { "qa_infos": [] } })), "<ul class=\"nango-qa\"></ul>");
+
+        let res_json: Value = json!({ "payload": { "groups": [
+            { "que_sentence": "<営業時間>", "payload": { "qa_infos": [] } },
+        ] } });
+        assert_eq!(render_predict(&res_json), "<section class=\"nango-qa-group\"><h3>&lt;営業時間&gt;</h3><ul class=\"nango-qa\"></ul></section>");
+    }
+
+    #[test]
+    fn render_predict_cluster_test1() {
+        // cluster 指定時の groups は代表の候補から順にまとめて描画する
+        let que = |q: &str| json!({ "similar_que": q, "ans": "回答" });
+        let res_json: Value = json!({ "payload": {
+            "qa_infos": [que("料金は？"), que("駐車場は？"), que("値段は？")],
+            "groups": [
+                { "representative": que("料金は？"), "members": [que("値段は？")] },
+                { "representative": que("駐車場は？"), "members": [] },
+            ],
+        } });
+        assert_eq!(render_predict(&res_json), concat!(
+            "<section class=\"nango-qa-cluster\"><ul class=\"nango-qa\">",
+            "<li><p class=\"nango-qa-que\">料金は？</p><p class=\"nango-qa-ans\">回答</p></li>",
+            "<li><p class=\"nango-qa-que\">値段は？</p><p class=\"nango-qa-ans\">回答</p></li>",
+            "</ul></section>",
+            "<section class=\"nango-qa-cluster\"><ul class=\"nango-qa\">",
+            "<li><p class=\"nango-qa-que\">駐車場は？</p><p class=\"nango-qa-ans\">回答</p></li>",
+            "</ul></section>",
+        ));
+    }
+}
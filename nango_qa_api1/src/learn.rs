@@ -0,0 +1,499 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use crate::error::AppError;
+use crate::nlp::calibration::ScoreStats;
+use crate::nlp::count::CountMatrix;
+use crate::nlp::tf_idf::{TfIdf, TfIdfMatrix};
+use crate::pipeline::Pipeline;
+use crate::response::{DuplicateQuestion, FailedDoc};
+use crate::schema::{ColumnMap, CsvSchema};
+use crate::tenant::Tenant;
+use crate::token_cache::{self, TokenCache};
+use crate::{bundle, cache, config, engine, QaData};
+
+/// 学習の各フェーズ (実行順)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    ReadCsv,
+    Tokenize,
+    Analyze,
+    TfIdf,
+    Output,
+}
+
+const PHASES: [Phase; 5] = [Phase::ReadCsv, Phase::Tokenize, Phase::Analyze, Phase::TfIdf, Phase::Output];
+const DEFAULT_MAX_DOCS: u64 = 50_000;
+const DEFAULT_MAX_VOCAB: u64 = 100_000;
+const DEFAULT_MAX_MEMORY_MB: u64 = 2048;
+
+/// 学習データの規模の上限 (Lambda がメモリ不足で落ちる前にエラーとする)
+/// 環境変数 LEARN_MAX_DOCS, LEARN_MAX_VOCAB, LEARN_MAX_MEMORY_MB で指定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LearnLimits {
+    pub max_docs: u64,
+    pub max_vocab: u64,
+    pub max_memory_bytes: u64,
+}
+
+impl Default for LearnLimits {
+    fn default() -> Self {
+        Self { max_docs: DEFAULT_MAX_DOCS, max_vocab: DEFAULT_MAX_VOCAB, max_memory_bytes: DEFAULT_MAX_MEMORY_MB * 1024 * 1024 }
+    }
+}
+
+impl LearnLimits {
+    pub fn from_env() -> Self {
+        let max_memory_mb: u64 = config::env_or("LEARN_MAX_MEMORY_MB", DEFAULT_MAX_MEMORY_MB);
+        Self {
+            max_docs: config::env_or("LEARN_MAX_DOCS", DEFAULT_MAX_DOCS),
+            max_vocab: config::env_or("LEARN_MAX_VOCAB", DEFAULT_MAX_VOCAB),
+            max_memory_bytes: max_memory_mb.saturating_mul(1024 * 1024),
+        }
+    }
+
+    /// 文書数のみ (読み込み直後)
+    pub fn check_docs(&self, n_docs: usize) -> Result<(), AppError> {
+        check_limit("docs", n_docs as u64, self.max_docs)
+    }
+
+    /// 語彙数と、密行列 (文書数 × 語彙数 × 8byte) のメモリ見積り (TF-IDF計算前)
+    pub fn check_vocab(&self, n_docs: usize, n_vocab: usize) -> Result<(), AppError> {
+        self.check_docs(n_docs)?;
+        check_limit("vocab", n_vocab as u64, self.max_vocab)?;
+        check_limit("memory_bytes", dense_memory_bytes(n_docs, n_vocab), self.max_memory_bytes)
+    }
+}
+
+fn check_limit(name: &'static str, actual: u64, limit: u64) -> Result<(), AppError> {
+    if actual > limit {
+        return Err(AppError::TooLarge { name, actual, limit });
+    }
+    Ok(())
+}
+
+/// 密行列で持った場合のTF-IDFのバイト数 (f64)
+pub fn dense_memory_bytes(n_docs: usize, n_vocab: usize) -> u64 {
+    (n_docs as u64).saturating_mul(n_vocab as u64).saturating_mul(std::mem::size_of::<f64>() as u64)
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::ReadCsv => "read_csv",
+            Phase::Tokenize => "tokenize",
+            Phase::Analyze => "analyze",
+            Phase::TfIdf => "tf_idf",
+            Phase::Output => "output",
+        }
+    }
+}
+
+/// フェーズ完了ごとの進捗
+#[derive(Debug)]
+pub struct Progress {
+    pub phase: Phase,
+    pub rate: f64, // 完了したフェーズの割合 (0〜1)
+    pub elapsed_ms: u128,
+}
+
+/// 学習処理をフェーズ単位に分解したもの
+/// next() を呼ぶたびに1フェーズ実行し、その進捗を返す (エラー後は終了)
+#[derive(Debug, Default)]
+pub struct LearnJob {
+    tenant: Tenant,
+    persist_word_list: bool,
+    column_map: Option<ColumnMap>,
+    next_phase: usize,
+    failed: bool,
+    qa_data: Option<QaData>,
+    rows: Vec<usize>,
+    pipeline: Option<Pipeline>,
+    docs: Option<Vec<Vec<String>>>,
+    tf_idf: Option<TfIdf>,
+    unknown_rate: Option<f64>,
+    warnings: Vec<String>,
+    duplicate_questions: Vec<DuplicateQuestion>,
+    failed_docs: Vec<FailedDoc>,
+    limits: LearnLimits,
+}
+
+/// テナントの学習データCSVで学習し、テナントの出力先に書き出す
+/// column_map が None の場合は既定の列構成で学習データCSVを読む
+pub fn learn_phased(tenant: Tenant, persist_word_list: bool, column_map: Option<ColumnMap>) -> LearnJob {
+    LearnJob { tenant, persist_word_list, column_map, limits: LearnLimits::from_env(), ..Default::default() }
+}
+
+impl LearnJob {
+    pub fn unknown_rate(&self) -> Option<f64> {
+        self.unknown_rate
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub fn duplicate_questions(&self) -> &[DuplicateQuestion] {
+        &self.duplicate_questions
+    }
+
+    pub fn failed_docs(&self) -> &[FailedDoc] {
+        &self.failed_docs
+    }
+
+    fn run_phase(&mut self, phase: Phase) -> Result<(), AppError> {
+        match phase {
+            Phase::ReadCsv => {
+                let schema: CsvSchema = self.column_map.map(CsvSchema::from).unwrap_or_default();
+                let (qa_data, rows) = crate::read_csv_rows(&self.tenant.path(crate::INPUT_CSV_PATH), &crate::schema::answer_langs_from_env(), &schema)?;
+                self.limits.check_docs(qa_data.que_vec.len())?;
+                self.qa_data = Some(qa_data);
+                self.rows = rows;
+            },
+            Phase::Tokenize => {
+                let qa_data: &QaData = self.qa_data.as_ref().unwrap();
+                let pipeline: Pipeline = Pipeline::from_env();
+                // トークナイズモデルか前処理の構成が変わっていれば、キャッシュは破棄される
+                let version: u64 = token_cache::fingerprint(&pipeline, &crate::tokenizer_model_path())?;
+                let mut token_cache: TokenCache = TokenCache::read(&self.tenant.path(crate::TOKEN_CACHE_PATH), version)?;
+                let (docs, failed_docs) = tokenize(&pipeline, &qa_data.que_vec, &self.rows, &mut token_cache)?;
+                token_cache.write(&self.tenant.path(crate::TOKEN_CACHE_PATH))?;
+                self.failed_docs = failed_docs;
+                self.pipeline = Some(pipeline);
+                if self.persist_word_list {
+                    crate::out_csv_word(&self.tenant.path(crate::WORD_LIST_CSV_PATH), &docs)?;
+                }
+                self.docs = Some(docs);
+            },
+            Phase::Analyze => {
+                let unknown_rate_warn: f64 = config::env_or("UNKNOWN_RATE_WARN", crate::DEFAULT_UNKNOWN_RATE_WARN);
+                self.unknown_rate = crate::unknown_rate(self.docs.as_ref().unwrap(), &crate::model_vocab()?);
+                self.warnings = analyze(self.unknown_rate, unknown_rate_warn);
+                self.duplicate_questions = find_duplicates(self.qa_data.as_ref().unwrap(), &self.rows);
+            },
+            Phase::TfIdf => {
+                let docs: &[Vec<String>] = self.docs.as_ref().unwrap();
+                let vocab: HashSet<&String> = docs.iter().flatten().collect();
+                self.limits.check_vocab(docs.len(), vocab.len())?;
+                self.tf_idf = Some(TfIdf::get_tf_idf(docs)?);
+            },
+            Phase::Output => {
+                let tf_idf: TfIdf = self.tf_idf.take().unwrap();
+                // 学習済みモデル出力
+                crate::out_model(&self.tenant.path(&crate::model_csv_path()), &tf_idf)?;
+                // BM25などで使う生の出現回数
+                crate::out_count_csv(&self.tenant.path(crate::COUNT_MATRIX_PATH), &CountMatrix::from_docs(self.docs.as_ref().unwrap()))?;
+                // 語彙ごとの文書頻度・IDF (分析用)
+                crate::out_vocab_stats_csv(&self.tenant.path(crate::VOCAB_STATS_PATH), &tf_idf, self.docs.as_ref().unwrap())?;
+                // 予測時に同じ前処理を通せるよう構成を保存
+                let pipeline: Pipeline = self.pipeline.take().unwrap();
+                let meta_path: String = self.tenant.path(crate::MODEL_META_PATH);
+                let version: u64 = crate::next_model_version(&meta_path)?;
+                pipeline.write_meta(&meta_path)?;
+                // 予測・追加時も同じ列位置で学習データCSVを読み書きする
+                crate::write_column_map(&meta_path, self.column_map.as_ref())?;
+                crate::write_model_info(&meta_path, version, tf_idf.tf_idf_vec.len())?;
+                // モデル・単語リスト・構成をまとめたバンドル (予測時はこちらを優先)
+                let qa_data: &QaData = self.qa_data.as_ref().unwrap();
+                let stats: Option<ScoreStats> = calibration_stats(self.docs.as_ref().unwrap(), &tf_idf);
+                bundle::save_bundle(&self.tenant.path(crate::BUNDLE_PATH), &tf_idf, self.docs.as_ref().unwrap(), &pipeline, &qa_data.que_vec, &qa_data.ans_vec, stats.as_ref())?;
+                let mut engine = engine::lock(self.tenant.id());
+                engine.set_pipeline(pipeline);
+                engine.set_schema(self.column_map.map(CsvSchema::from).unwrap_or_default());
+                engine.set_word_list(self.docs.take().unwrap());
+                engine.set_model(self.qa_data.take().unwrap(), tf_idf);
+            },
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for LearnJob {
+    type Item = Result<Progress, AppError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.next_phase >= PHASES.len() {
+            return None;
+        }
+        let phase: Phase = PHASES[self.next_phase];
+        let start = Instant::now();
+        if let Err(err) = self.run_phase(phase) {
+            self.failed = true;
+            return Some(Err(err));
+        }
+        self.next_phase += 1;
+        Some(Ok(Progress {
+            phase,
+            rate: self.next_phase as f64 / PHASES.len() as f64,
+            elapsed_ms: start.elapsed().as_millis(),
+        }))
+    }
+}
+
+/// 失敗した文書は文書番号がずれないよう空の文書として残し、failed_docs に記録する
+/// 全文書が失敗した場合のみエラーとする
+fn tokenize(pipeline: &Pipeline, que_vec: &[String], rows: &[usize], token_cache: &mut TokenCache) -> Result<(Vec<Vec<String>>, Vec<FailedDoc>), AppError> {
+    let mut docs: Vec<Vec<String>> = Vec::with_capacity(que_vec.len());
+    let mut failed_docs: Vec<FailedDoc> = Vec::new();
+    let mut last_err: Option<AppError> = None;
+    for (id, que) in que_vec.iter().enumerate() {
+        match pipeline.try_run_cached(que.to_owned(), token_cache) {
+            Ok(doc) => docs.push(doc),
+            Err(err) => {
+                println!("skip tokenize doc {}: {}", id, err);
+                failed_docs.push(FailedDoc { row: rows.get(id).copied().unwrap_or(id + 1), question: que.to_owned(), error: err.to_string() });
+                docs.push(Vec::new());
+                last_err = Some(err);
+            },
+        }
+    }
+    match last_err {
+        Some(err) if failed_docs.len() == que_vec.len() => Err(err),
+        _ => Ok((docs, failed_docs)),
+    }
+}
+
+/// 未知語率から警告を作る
+fn analyze(unknown_rate: Option<f64>, unknown_rate_warn: f64) -> Vec<String> {
+    let mut warnings: Vec<String> = Vec::new();
+    if unknown_rate.is_some_and(|rate| rate > unknown_rate_warn) {
+        warnings.push(format!("未知語率が{}を超えています。QAデータとトークナイズモデルのドメイン不一致の可能性があります。", unknown_rate_warn));
+    }
+    warnings
+}
+
+/// 学習に使った各質問をクエリとして検索し、自分自身が1位となる割合 (自己一致率)
+/// 予測と同じく同点は文書順で1位を決め (重複した質問は後の方が不一致)、空の文書は対象外 (対象が無い場合はNone)
+pub fn self_match_rate(docs: &[Vec<String>], tf_idf: &TfIdf) -> Option<f64> {
+    let matrix: &TfIdfMatrix = tf_idf.matrix();
+    let idf: &[f64] = tf_idf.idf();
+    let mut total: usize = 0;
+    let mut matched: usize = 0;
+    for (id, doc) in docs.iter().enumerate().filter(|(_, doc)| !doc.is_empty()) {
+        let cos_vec: Vec<f64> = matrix.cos_all(&tf_idf.query_vector_with_idf(doc, idf));
+        let max: f64 = cos_vec.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        total += 1;
+        if cos_vec.iter().position(|&cos| cos == max) == Some(id) {
+            matched += 1;
+        }
+    }
+    (total > 0).then(|| matched as f64 / total as f64)
+}
+
+/// predict のスコアのキャリブレーション用の統計 (件数は環境変数 CALIBRATION_SAMPLES)
+pub fn calibration_stats(docs: &[Vec<String>], tf_idf: &TfIdf) -> Option<ScoreStats> {
+    score_stats(docs, tf_idf, config::env_or("CALIBRATION_SAMPLES", crate::DEFAULT_CALIBRATION_SAMPLES))
+}
+
+/// キャリブレーション用に、学習に使った質問をクエリとして自分以外で最もスコアの高い文書のcos類似度を集計する
+/// 文書数の2乗の計算になるため、最大 max_samples 件の質問を等間隔に選ぶ (空の文書は対象外、対象が無い場合はNone)
+pub fn score_stats(docs: &[Vec<String>], tf_idf: &TfIdf, max_samples: usize) -> Option<ScoreStats> {
+    if docs.len() < 2 {
+        return None;
+    }
+    let matrix: &TfIdfMatrix = tf_idf.matrix();
+    let idf: &[f64] = tf_idf.idf();
+    let step: usize = docs.len().div_ceil(max_samples.max(1));
+    let scores: Vec<f64> = docs.iter().enumerate()
+        .step_by(step)
+        .filter(|(_, doc)| !doc.is_empty())
+        .map(|(id, doc)| {
+            matrix.cos_all(&tf_idf.query_vector_with_idf(doc, idf)).into_iter()
+                .enumerate()
+                .filter(|(other, _)| *other != id)
+                .map(|(_, cos)| cos)
+                .fold(0.0, f64::max)
+        })
+        .collect();
+    ScoreStats::from_scores(scores)
+}
+
+/// 自己一致率が下限を下回る場合の警告
+pub fn self_match_warning(rate: Option<f64>, min_rate: f64) -> Option<String> {
+    rate.filter(|&rate| rate < min_rate)
+        .map(|rate| format!("自己一致率が{:.2}で、{}を下回っています。質問の表現が似すぎている・前処理の設定が合っていない可能性があります。", rate, min_rate))
+}
+
+/// 正規化後の質問が重複している文書をまとめる (学習データの出現順)
+/// 文書番号がずれないよう統合はせず、警告として返すのみ
+fn find_duplicates(qa_data: &QaData, rows: &[usize]) -> Vec<DuplicateQuestion> {
+    let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+    let mut group_index: HashMap<String, usize> = HashMap::new();
+    for (id, que) in qa_data.que_vec.iter().enumerate() {
+        let question: String = cache::normalize_query(que);
+        let index: usize = *group_index.entry(question.to_owned()).or_insert_with(|| {
+            groups.push((question, Vec::new()));
+            groups.len() - 1
+        });
+        groups[index].1.push(id);
+    }
+    groups.into_iter()
+        .filter(|(_, ids)| ids.len() > 1)
+        .map(|(question, ids)| DuplicateQuestion {
+            question,
+            rows: ids.iter().map(|id| rows.get(*id).copied().unwrap_or(id + 1)).collect(),
+            same_answer: ids.iter().all(|id| qa_data.ans_vec[*id] == qa_data.ans_vec[ids[0]]),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_test1() {
+        let que_vec: Vec<String> = vec!["駐車場はありますか？".to_string()];
+        let (docs, failed_docs) = tokenize(&Pipeline::default(), &que_vec, &[1], &mut TokenCache::default()).unwrap();
+        assert_eq!(docs, vec![vec!["駐車場", "は", "あり", "ます", "か", "？"]]);
+        assert!(failed_docs.is_empty());
+    }
+
+    #[test]
+    fn tokenize_cached_test1() {
+        let pipeline: Pipeline = Pipeline::default();
+        let que_vec: Vec<String> = vec!["駐車場はありますか？".to_string(), "料金は？".to_string()];
+        // キャッシュにある文は分かち書きせずキャッシュのトークン列をそのまま使う (vaporetto では出ない分割)
+        let mut token_cache: TokenCache = TokenCache::default();
+        token_cache.insert(&pipeline.normalize(que_vec[0].to_owned()), vec!["駐車場はありますか".to_string(), "？".to_string()]);
+        let (docs, _) = tokenize(&pipeline, &que_vec, &[1, 2], &mut token_cache).unwrap();
+        assert_eq!(docs, vec![vec!["駐車場はありますか", "？"], vec!["料金", "は", "？"]]);
+        assert_eq!(token_cache.hits(), 1);
+        // 分かち書きした文は次回からヒットする
+        tokenize(&pipeline, &que_vec, &[1, 2], &mut token_cache).unwrap();
+        assert_eq!(token_cache.hits(), 3);
+    }
+
+    /// cargo test --release tokenize_cached_bench -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn tokenize_cached_bench() {
+        let que_vec: Vec<String> = (0..2000).map(|i| format!("{}番目の駐車場の料金はいくらですか？", i)).collect();
+        let rows: Vec<usize> = (1..=que_vec.len()).collect();
+        let pipeline: Pipeline = Pipeline::default();
+        let mut token_cache: TokenCache = TokenCache::default();
+        let start = Instant::now();
+        let (cold, _) = tokenize(&pipeline, &que_vec, &rows, &mut token_cache).unwrap();
+        let cold_elapsed = start.elapsed();
+        let start = Instant::now();
+        let (warm, _) = tokenize(&pipeline, &que_vec, &rows, &mut token_cache).unwrap();
+        let warm_elapsed = start.elapsed();
+        assert_eq!(cold, warm);
+        println!("cold: {:?}, warm: {:?}", cold_elapsed, warm_elapsed);
+        assert!(warm_elapsed < cold_elapsed);
+    }
+
+    #[test]
+    fn tokenize_failed_test1() {
+        // 空の質問は解析できないが、残りは学習される
+        let que_vec: Vec<String> = vec!["料金は？".to_string(), String::new(), "駐車場は？".to_string()];
+        let (docs, failed_docs) = tokenize(&Pipeline::default(), &que_vec, &[1, 2, 4], &mut TokenCache::default()).unwrap();
+        assert_eq!(docs, vec![vec!["料金", "は", "？"], vec![], vec!["駐車場", "は", "？"]]);
+        assert_eq!((failed_docs.len(), failed_docs[0].row), (1, 2));
+        assert!(TfIdf::get_tf_idf(&docs).is_ok());
+        // 全て失敗した場合はエラー
+        assert!(matches!(tokenize(&Pipeline::default(), &[String::new()], &[1], &mut TokenCache::default()), Err(AppError::Tokenize(_))));
+    }
+
+    #[test]
+    fn analyze_test1() {
+        assert!(analyze(None, 0.4).is_empty());
+        assert!(analyze(Some(0.4), 0.4).is_empty());
+        assert_eq!(analyze(Some(0.5), 0.4).len(), 1);
+    }
+
+    #[test]
+    fn self_match_rate_test1() {
+        let to_docs = |v_v: &[&[&str]]| -> Vec<Vec<String>> {
+            v_v.iter().map(|v| v.iter().map(|s| s.to_string()).collect()).collect()
+        };
+        // 内容の異なる小さなQAセットではすべて自分自身が1位
+        let docs: Vec<Vec<String>> = to_docs(&[&["料金", "は", "いくら"], &["駐車場", "は", "ある"], &["営業", "時間", "は"], &[]]);
+        let tf_idf: TfIdf = TfIdf::get_tf_idf(&docs).unwrap();
+        assert_eq!(self_match_rate(&docs, &tf_idf), Some(1.0));
+        assert_eq!(self_match_warning(Some(1.0), 0.5), None);
+        // 同じ質問が並ぶと後の方は先の文書に1位を取られる
+        let docs: Vec<Vec<String>> = to_docs(&[&["料金", "は"], &["料金", "は"], &["駐車場", "は"], &["駐車場", "は"]]);
+        let tf_idf: TfIdf = TfIdf::get_tf_idf(&docs).unwrap();
+        assert_eq!(self_match_rate(&docs, &tf_idf), Some(0.5));
+        assert!(self_match_warning(Some(0.2), 0.5).is_some());
+        assert_eq!(self_match_rate(&[vec![]], &tf_idf), None);
+    }
+
+    #[test]
+    fn score_stats_test1() {
+        let to_docs = |v_v: &[&[&str]]| -> Vec<Vec<String>> {
+            v_v.iter().map(|v| v.iter().map(|s| s.to_string()).collect()).collect()
+        };
+        // 重複した質問は自分以外で1.0、共通語の無い質問は0.0
+        let docs: Vec<Vec<String>> = to_docs(&[&["料金", "は"], &["料金", "は"], &["駐車場"], &[]]);
+        let tf_idf: TfIdf = TfIdf::get_tf_idf(&docs).unwrap();
+        let stats: ScoreStats = score_stats(&docs, &tf_idf, 100).unwrap();
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.quantiles[0], 0.0);
+        assert!((stats.quantiles[10] - 1.0).abs() < 1e-9);
+        // 件数の上限を超える場合は間引く
+        assert_eq!(score_stats(&docs, &tf_idf, 2).unwrap().samples, 2);
+        assert_eq!(score_stats(&docs[..1], &tf_idf, 100), None);
+    }
+
+    #[test]
+    fn find_duplicates_test1() {
+        let qa_data = QaData {
+            que_vec: ["料金は？", "駐車場は？", " 料金は？", "駐車場は？", "営業時間は？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["1500円です", "ありません", "1500円です", "近くにあります", "9時からです"].iter().map(|s| s.to_string()).collect(),
+            cat_vec: vec!["".to_string(); 5],
+            ..Default::default()
+        };
+        // CSVの3行目はスキップされた想定
+        let dups: Vec<DuplicateQuestion> = find_duplicates(&qa_data, &[1, 2, 4, 5, 6]);
+        assert_eq!(dups.len(), 2);
+        assert_eq!((dups[0].question.as_str(), dups[0].rows.as_slice(), dups[0].same_answer), ("料金は？", &[1, 4][..], true));
+        // 回答が異なる重複も警告する
+        assert_eq!((dups[1].question.as_str(), dups[1].rows.as_slice(), dups[1].same_answer), ("駐車場は？", &[2, 5][..], false));
+    }
+
+    #[test]
+    fn learn_limits_test1() {
+        let limits = LearnLimits { max_docs: 10, max_vocab: 100, max_memory_bytes: 4000 };
+        assert!(limits.check_docs(10).is_ok());
+        assert!(matches!(limits.check_docs(11), Err(AppError::TooLarge { name: "docs", .. })));
+        // 10文書 × 50語 × 8byte = 4000byte までは可
+        assert_eq!(dense_memory_bytes(10, 50), 4000);
+        assert!(limits.check_vocab(10, 50).is_ok());
+        let err: AppError = limits.check_vocab(10, 51).unwrap_err();
+        assert!(matches!(err, AppError::TooLarge { name: "memory_bytes", actual: 4080, limit: 4000 }));
+        assert_eq!(err.code(), 400);
+        assert!(matches!(limits.check_vocab(1, 101), Err(AppError::TooLarge { name: "vocab", .. })));
+        // 巨大な入力でも見積りは桁あふれしない
+        assert_eq!(dense_memory_bytes(usize::MAX, usize::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn phase_test1() {
+        let names: Vec<&str> = PHASES.iter().map(|p| p.name()).collect();
+        assert_eq!(names, vec!["read_csv", "tokenize", "analyze", "tf_idf", "output"]);
+    }
+
+    #[test]
+    fn learn_job_test1() {
+        // 前フェーズの結果を与えればフェーズ単体で実行できるか確認
+        let mut job = LearnJob {
+            docs: Some(vec![vec!["料金".to_string(), "は".to_string()]]),
+            ..Default::default()
+        };
+        job.run_phase(Phase::TfIdf).unwrap();
+        assert_eq!(job.tf_idf.unwrap().word_vec, vec!["は", "料金"]);
+
+        // 上限を超える場合はTF-IDFを計算せずにエラー
+        let docs: Vec<Vec<String>> = vec![vec!["料金".to_string(), "は".to_string()], vec!["駐車場".to_string()]];
+        let limits = LearnLimits { max_vocab: 2, ..Default::default() };
+        let mut job = LearnJob { docs: Some(docs.to_owned()), limits, ..Default::default() };
+        assert!(matches!(job.run_phase(Phase::TfIdf), Err(AppError::TooLarge { name: "vocab", actual: 3, limit: 2 })));
+        assert!(job.tf_idf.is_none());
+
+        // 失敗したジョブはそれ以降のフェーズを実行しない
+        let mut job = LearnJob { failed: true, ..Default::default() };
+        assert!(job.next().is_none());
+    }
+}
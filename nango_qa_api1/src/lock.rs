@@ -0,0 +1,112 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::time::{Duration, Instant};
+
+use crate::error::AppError;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// 学習と予測でモデルファイルの読み書きが競合しないようにするロック (ロック用の空ファイルに取る)
+/// 学習 (書き込み) は排他、予測 (読み込み) は共有で取り、drop で解放する
+/// 予測時に更新する統計ファイル用にも、別のロックファイルで排他として使う
+#[derive(Debug)]
+pub struct ModelLock {
+    _file: Option<File>,
+}
+
+impl ModelLock {
+    pub fn exclusive(path: &str, timeout: Duration) -> Result<Self, AppError> {
+        let file: File = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+        Self::acquire(file, timeout, File::try_lock)
+    }
+
+    /// 既存のロックファイルは読み込み専用で開く (読み込み専用のファイルシステムに置いたモデルでも予測できる)
+    /// ロックファイルが無く作れもしない場合は、排他で取る学習・追加も無いためロックせずに返す
+    pub fn shared(path: &str, timeout: Duration) -> Result<Self, AppError> {
+        let file: File = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                match OpenOptions::new().create(true).truncate(false).write(true).open(path) {
+                    Ok(file) => file,
+                    Err(_) => return Ok(Self { _file: None }),
+                }
+            },
+            Err(err) => return Err(err.into()),
+        };
+        Self::acquire(file, timeout, File::try_lock_shared)
+    }
+
+    /// 取得できるまで待ち、timeout を過ぎたら AppError::LockTimeout
+    fn acquire(file: File, timeout: Duration, try_lock: fn(&File) -> Result<(), TryLockError>) -> Result<Self, AppError> {
+        let start = Instant::now();
+        loop {
+            match try_lock(&file) {
+                Ok(()) => return Ok(Self { _file: Some(file) }),
+                Err(TryLockError::WouldBlock) if start.elapsed() < timeout => std::thread::sleep(RETRY_INTERVAL),
+                Err(TryLockError::WouldBlock) => return Err(AppError::LockTimeout { limit_ms: timeout.as_millis() as u64 }),
+                Err(TryLockError::Error(err)) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_lock_test1() {
+        let path = std::env::temp_dir().join("nango_model_lock_test1.lock");
+        let path: &str = path.to_str().unwrap();
+        let timeout = Duration::from_millis(50);
+        // 共有どうしは同時に取れる
+        let a = ModelLock::shared(path, timeout).unwrap();
+        let b = ModelLock::shared(path, timeout).unwrap();
+        assert!(matches!(ModelLock::exclusive(path, timeout), Err(AppError::LockTimeout { limit_ms: 50 })));
+        drop((a, b));
+        // 排他中は共有も取れず、解放後は取れる
+        let lock = ModelLock::exclusive(path, timeout).unwrap();
+        assert_eq!(ModelLock::shared(path, timeout).unwrap_err().code(), 503);
+        drop(lock);
+        assert!(ModelLock::shared(path, timeout).is_ok());
+
+        // 作れないロックファイルは、共有ならロックせずに取れ、排他は取れない
+        let path = std::env::temp_dir().join("nango_model_lock_test1_not_found").join("model.lock");
+        let path: &str = path.to_str().unwrap();
+        assert!(ModelLock::shared(path, timeout).is_ok());
+        assert!(ModelLock::exclusive(path, timeout).is_err());
+    }
+
+    #[test]
+    fn model_lock_concurrent_test1() {
+        let dir = std::env::temp_dir().join("nango_model_lock_concurrent_test1");
+        std::fs::create_dir_all(&dir).unwrap();
+        let lock_path = dir.join("model.lock").to_str().unwrap().to_string();
+        let model_path = dir.join("model.csv");
+        let word_list_path = dir.join("word_list.csv");
+        std::fs::write(&model_path, "0").unwrap();
+        std::fs::write(&word_list_path, "0").unwrap();
+        let timeout = Duration::from_secs(10);
+
+        std::thread::scope(|s| {
+            // 学習: 2つのファイルを間を空けて書き換える
+            s.spawn(|| {
+                for version in 1..=20 {
+                    let _lock = ModelLock::exclusive(&lock_path, timeout).unwrap();
+                    std::fs::write(&model_path, version.to_string()).unwrap();
+                    std::thread::sleep(Duration::from_millis(2));
+                    std::fs::write(&word_list_path, version.to_string()).unwrap();
+                }
+            });
+            // 予測: 読み込んだ2つのファイルが常に同じ版であること
+            s.spawn(|| {
+                for _ in 0..100 {
+                    let _lock = ModelLock::shared(&lock_path, timeout).unwrap();
+                    let model: String = std::fs::read_to_string(&model_path).unwrap();
+                    let word_list: String = std::fs::read_to_string(&word_list_path).unwrap();
+                    assert_eq!(model, word_list);
+                }
+            });
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,345 +1,3148 @@
+use base64::Engine;
 use lambda_runtime::{service_fn, LambdaEvent, Error};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::error::Error as OtherError;
 
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use vaporetto::{Model, Predictor, Sentence};
-use vaporetto_rules::{
-    string_filters::KyteaFullwidthFilter, StringFilter,
-};
+use std::path::Path;
+use std::sync::Arc;
+use vaporetto::Model;
+use unicode_segmentation::UnicodeSegmentation;
 
 
+mod access;
+#[cfg(all(test, feature = "alloc_bench"))]
+mod alloc_bench;
+mod atomic;
+mod bundle;
+mod cache;
+mod config;
+mod engine;
+mod error;
+mod html;
+mod learn;
+mod lock;
+mod metrics;
 mod nlp;
-use nlp::tf_idf;
+mod params;
+mod pipeline;
+mod rate_limit;
+mod request_schema;
+mod response;
+mod schema;
+mod storage;
+mod tenant;
+mod token_cache;
+mod unanswered;
+mod versions;
+use error::{AppError, AuthError, InitError};
+use nlp::{bm25, calibration, cluster, count, distance, ensemble, feedback, keyword, lang, length, mmr, negation, sentence, tf_idf, Retriever};
+use response::JsonCase;
 
 const STR_PKEY: &str = "nango7_ai_nango_kun";
+const INPUT_CSV_PATH: &str = "input/study_qa1.csv";
+const MODEL_CSV_PATH: &str = "output/model_qa1.csv";
+const MODEL_JSON_PATH: &str = "output/model_qa1.json";
+const WORD_LIST_CSV_PATH: &str = "output/word_list.csv";
+const FEEDBACK_CSV_PATH: &str = "input/feedback.csv";
+const MODEL_META_PATH: &str = "output/model_meta.json";
+const COUNT_MATRIX_PATH: &str = "output/count_matrix.csv";
+const VOCAB_STATS_PATH: &str = "output/vocab_stats.csv";
+const BUNDLE_PATH: &str = "output/model_bundle.json";
+const ACCESS_COUNT_PATH: &str = "output/access_count.csv";
+const UNANSWERED_PATH: &str = "output/unanswered.csv";
+const MODEL_LOCK_PATH: &str = "output/model.lock";
+const STATS_LOCK_PATH: &str = "output/stats.lock";
+const MODEL_VERSIONS_DIR: &str = "output/versions";
+const TOKEN_CACHE_PATH: &str = "output/token_cache.csv";
+/// 世代ごとに保存するファイル
+/// 予測は学習データCSVから回答を読むため、学習データと学習時の出力をまとめて戻して文書IDと回答の対応を保つ
+const MODEL_VERSION_PATHS: [&str; 7] = [
+    BUNDLE_PATH, MODEL_META_PATH, MODEL_CSV_PATH, MODEL_JSON_PATH, COUNT_MATRIX_PATH, WORD_LIST_CSV_PATH, INPUT_CSV_PATH,
+];
+const API_VERSION: &str = env!("CARGO_PKG_VERSION");
+const SCHEMA_VERSION: &str = "1";
+const DEFAULT_QUE_MAX_LEN: usize = 1000;
+const DEFAULT_UNKNOWN_RATE_WARN: f64 = 0.4;
+const DEFAULT_SELF_MATCH_RATE_WARN: f64 = 0.5;
+const DEFAULT_JA_MIN_RATIO: f64 = 0.3;
+const DEFAULT_THRESHOLD: f64 = 0.3;
+const AUTO_THRESHOLD_RANGE: (f64, f64) = (0.1, 0.9);
+const DEFAULT_MMR_TOP_K: usize = 10;
+const DEFAULT_SUGGESTION_COUNT: usize = 3;
+const DEFAULT_CLUSTER_THRESHOLD: f64 = 0.5;
+const DEFAULT_SOFTMAX_TEMPERATURE: f64 = 0.1;
+const DEFAULT_RELATED_COUNT: usize = 3;
+const DEFAULT_CALIBRATION_SAMPLES: usize = 1000;
+const DRY_RUN_PREVIEW_ROWS: usize = 5;
+const DEFAULT_TOKENIZE_TIMEOUT_MS: u64 = 3000;
+const DEFAULT_MODEL_LOCK_TIMEOUT_MS: u64 = 10000;
+const DEFAULT_MODEL_VERSIONS_KEEP: usize = 5;
+const DEFAULT_DIFF_MODELS_TOP_K: usize = 5;
+const DEFAULT_DIFF_MODELS_MAX_QUERIES: usize = 100;
+const DEFAULT_TOKENIZER_MODEL_PATH: &str = "./model/bccwj-luw-small.model.zst";
 
-/// 使用例
+/// 使用例 (環境変数 JSON_CASE=camel でレスポンスのキーをcamelCaseにする)
 /// 学習時: {"mode": "l", "pkey": "nango7_ai_nango_kun"}
+/// 学習時(単語リストCSVを出力しない): {"mode": "l", "persist_word_list": false, "pkey": "nango7_ai_nango_kun"}
+/// 学習時(学習した質問で検索し、自分自身が1位となる割合を返す): {"mode": "l", "self_eval": true, "pkey": "nango7_ai_nango_kun"}
+/// 学習時(学習データCSVの列位置を指定、0始まり): {"mode": "l", "column_map": {"question": 1, "answer": 0, "category": 2}, "pkey": "nango7_ai_nango_kun"}
+/// 学習時(テナントのデータセットを作る、学習データは datasets/{dataset}/input/study_qa1.csv): {"mode": "l", "dataset": "front", "pkey": "nango7_ai_nango_kun"}
 /// 予測時: {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "pkey": "nango7_ai_nango_kun"}
+/// 予測時(カテゴリ指定): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "category": "設備", "pkey": "nango7_ai_nango_kun"}
+/// 予測時(TF-IDFとBM25の合成): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "ensemble": 0.7, "pkey": "nango7_ai_nango_kun"}
+/// ドライラン(学習データCSVの件数・列構成・先頭数件のみ返す): {"mode": "d", "pkey": "nango7_ai_nango_kun"}
+/// ドライラン(学習時と同じく列位置を指定): {"mode": "d", "column_map": {"question": 1, "answer": 0}, "pkey": "nango7_ai_nango_kun"}
+/// トークナイズ結果の確認(学習時と同じ前処理、モデルは読み込まない): {"mode": "t", "sentence": "駐車場はありますか？", "pkey": "nango7_ai_nango_kun"}
+/// 管理用(保存先のデータセット一覧、環境変数 ADMIN_PKEY のキーのみ): {"mode": "m", "action": "list_datasets", "pkey": "(ADMIN_PKEY)"}
+/// 管理用(学習・追加のたびに保存したモデルの世代を1つ前に戻す、保持する世代数は環境変数 MODEL_VERSIONS_KEEP): {"mode": "m", "action": "rollback", "pkey": "(ADMIN_PKEY)"}
+/// 管理用(既定以外のテナント・データセットのモデルを戻す、tenant はテナントの pkey): {"mode": "m", "action": "rollback", "tenant": "(TENANT_PKEYS の pkey)", "dataset": "front", "pkey": "(ADMIN_PKEY)"}
+/// 管理用(保存した2世代のモデルで各クエリの上位候補を比較、件数は環境変数 DIFF_MODELS_TOP_K、tenant・dataset は rollback と同じ): {"mode": "m", "action": "diff_models", "from": 1, "to": 2, "queries": ["料金は？"], "pkey": "(ADMIN_PKEY)"}
+/// 追加時: {"mode": "a", "que_sentence": "駐車場はありますか？", "ans_sentence": "近隣のコインパーキングをご利用ください。", "pkey": "nango7_ai_nango_kun"}
+/// 予測時(回答の整形): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "format_template": "お問い合わせありがとうございます。{answer}", "pkey": "nango7_ai_nango_kun"}
+/// 予測時(回答の先頭のみ): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "snippet_len": 20, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(しきい値を自動算出、threshold での手動指定とは併用不可): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "auto_threshold": true, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(学習時の出現回数によるBM25でスコアリング): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "scorer": "bm25", "pkey": "nango7_ai_nango_kun"}
+/// 予測時(似た候補が並ばないようMMRで再ランキング、1で関連度順): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "diversity": 0.7, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(複数の質問を文ごとに検索): {"mode": "p", "que_sentence": "営業時間は？あと駐車場はありますか？", "split_query": true, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(アクセス数の多いQAを優遇、スコア * (1 + popularity * 正規化したアクセス数)): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "popularity": 0.2, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(タイポ対策に文字trigramのJaccardを合成、スコア = (1 - typo_weight) * cos + typo_weight * jaccard): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "typo_weight": 0.3, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(トークン数の少ない候補質問を減点、環境変数 LENGTH_PENALTY_MIN_TOKENS・LENGTH_PENALTY_MAX): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "length_penalty": true, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(候補のスコアを温度付きsoftmaxで確率 probability に変換、温度の既定値は環境変数 SOFTMAX_TEMPERATURE): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "softmax": true, "temperature": 0.1, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(各候補に関連する他のQAを related として付与、件数は環境変数 RELATED_COUNT): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "include_related": true, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(学習時の自己検索スコアの分布を基準に0〜1へ写した calibrated_score を付与): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "calibrate": true, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(各候補にクエリと共通の語ごとのTF-IDF寄与 matched_terms を付与、強調表示用): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "matched_terms": true, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(自分のテナントの複数データセットのモデルを横断して検索、データセットは学習時の dataset): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "datasets": ["front", "facility"], "pkey": "nango7_ai_nango_kun"}
+/// 予測時(候補数の下限・上限、しきい値超えが下限未満なら上位から補う): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "min_results": 1, "max_results": 5, "pkey": "nango7_ai_nango_kun"}
+/// 予測時(候補を意図ごとにグループ化、しきい値は環境変数 CLUSTER_THRESHOLD): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "cluster": true, "pkey": "nango7_ai_nango_kun"}
+/// 環境変数 TENANT_PKEYS (カンマ区切り) の pkey は、自分のテナントの tenants/{pkey}/input/・output/ で学習・予測する (既定の pkey は input/・output/)
+/// 環境変数 MODEL_STORAGE_DIR を指定すると、学習時に学習データ・バンドルをテナントごとのキー (models/{pkey}/) で保存し、予測時はそこから読む
+/// 予測時(候補を <ul><li> のHTML断片で返す、API Gateway経由では Content-Type: text/html): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "format": "html", "pkey": "nango7_ai_nango_kun"}
+/// 予測時(API Gateway経由のフォーム入力、Content-Type: application/x-www-form-urlencoded): mode=p&que_sentence=%E6%96%99%E9%87%91%E3%81%AF%EF%BC%9F&pkey=nango7_ai_nango_kun
+/// 予測時(回答の言語、環境変数 ANSWER_LANGS で列を指定): {"mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "lang": "en", "pkey": "nango7_ai_nango_kun"}
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    // 初期化に失敗した場合はリクエストを受け付けずに起動を止める
+    if let Err(err) = warmup() {
+        println!("error running warmup: {}", err);
+        return Err(err.into());
+    }
     let func = service_fn(func);
     lambda_runtime::run(func).await?;
     Ok(())
 }
 
+/// コールドスタート対策として、最初のリクエストの前に分かち書き器・前処理の構成・学習済みモデルを読み込む
+/// 未学習 (バンドルも移行元のモデルも無い) の場合は学習リクエストを受け付けられるよう読み込みを省く
+fn warmup() -> Result<(), AppError> {
+    let start = std::time::Instant::now();
+    pipeline::predictor()?;
+    let tenant = tenant::Tenant::default();
+    if !has_model(&tenant.path(BUNDLE_PATH), &tenant.path(&model_csv_path())) {
+        println!("warmup skipped: 学習済みモデルがありません");
+        return Ok(());
+    }
+    let model: Arc<engine::LoadedModel> = load_predict_model(&tenant)?;
+    let qa_data: QaData = read_csv(&tenant, &tenant.path(INPUT_CSV_PATH))?;
+    check_doc_count(&qa_data, &model.docs, &model.tfidf)?;
+    model.tfidf.matrix();
+    let doc_count: usize = model.docs.len();
+
+    load_pipeline(&mut engine::lock(tenant.id()), &tenant)?;
+    println!("warmup done: {} docs ({} ms)", doc_count, start.elapsed().as_millis());
+    Ok(())
+}
+
 async fn func(event: LambdaEvent<Value>) -> Result<Value, Error> {
     let (event, _context) = event.into_parts();
+    // API Gateway経由の場合はbodyを入力とし、レスポンスもプロキシ統合の形式で返す
+    match api_gateway_body(&event) {
+        Some(body) => Ok(proxy_handler(body)),
+        None => Ok(handler(event)),
+    }
+}
+
+/// API Gatewayのプロキシ統合イベントであればbody(JSON文字列またはフォーム)を解析して返す
+/// isBase64Encoded が true の場合 (バイナリメディアタイプ指定時など) はデコードしてから解析する
+/// 解析できないbodyはNullとして扱う (pkey無しの400になる)
+/// mode が無い場合はHTTPメソッドから推論する
+fn api_gateway_body(event: &Value) -> Option<Value> {
+    event.get("requestContext")?;
+    let body: &str = event["body"].as_str()?;
+    let body: Value = if event["isBase64Encoded"] == true {
+        base64::engine::general_purpose::STANDARD.decode(body).ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .map_or(Value::Null, |body| parse_body(&body, content_type(event)))
+    } else {
+        parse_body(body, content_type(event))
+    };
+    Some(with_default_mode(body, http_method(event)))
+}
+
+/// ヘッダー名は大文字小文字を区別しない (REST API はそのまま、HTTP API は小文字で届く)
+fn content_type(event: &Value) -> Option<&str> {
+    event["headers"].as_object()?.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .and_then(|(_, value)| value.as_str())
+}
+
+/// Content-Type が application/x-www-form-urlencoded ならフォーム、それ以外はJSONとして解析する
+/// フォームの値は文字列のまま (数値・真偽値のパラメータは文字列でも受け付ける)
+/// 同じキーが複数ある場合は後の値とし、スキーマで配列のパラメータ (datasets) は全ての値を配列にする
+fn parse_body(body: &str, content_type: Option<&str>) -> Value {
+    let is_form: bool = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/x-www-form-urlencoded"));
+    if !is_form {
+        return serde_json::from_str(body).unwrap_or(Value::Null);
+    }
+    let mut params: serde_json::Map<String, Value> = serde_json::Map::new();
+    for (key, value) in form_urlencoded::parse(body.as_bytes()) {
+        if request_schema::is_array(&key) {
+            let values: &mut Value = params.entry(key.into_owned()).or_insert_with(|| json!([]));
+            if let Some(values) = values.as_array_mut() {
+                values.push(json!(value));
+            }
+        } else {
+            params.insert(key.into_owned(), json!(value));
+        }
+    }
+    Value::Object(params)
+}
+
+/// REST API (v1) は httpMethod、HTTP API (v2) は requestContext.http.method
+fn http_method(event: &Value) -> Option<&str> {
+    event["httpMethod"].as_str().or_else(|| event["requestContext"]["http"]["method"].as_str())
+}
+
+/// 明示的な mode が無い場合のみ、GET は予測、POST は学習とする
+fn with_default_mode(mut body: Value, method: Option<&str>) -> Value {
+    let mode: &str = match method.map(|m| m.to_ascii_uppercase()).as_deref() {
+        Some("GET") => "p",
+        Some("POST") => "l",
+        _ => return body,
+    };
+    let has_mode: bool = body["mode"].as_str().is_some_and(|m| !m.trim().is_empty());
+    if let (Some(obj), false) = (body.as_object_mut(), has_mode) {
+        obj.insert("mode".to_string(), json!(mode));
+    }
+    body
+}
+
+/// API Gatewayが期待する形式 (bodyは文字列) に変換
+/// serde_json は非ASCII文字をエスケープしないため、日本語はUTF-8のまま返る
+/// HTML形式の予測結果 (html) はHTML断片を、書き出し済みの予測結果 (body) はそのまま body とする
+/// (書き出し済みの body は複製せずに移す)
+fn to_proxy_response(mut res_json: Value) -> Value {
+    if res_json["body"].is_string() {
+        return json!({
+            "statusCode": res_json["code"].as_u64().unwrap_or(200),
+            "headers": { "Content-Type": "application/json; charset=utf-8" },
+            "body": res_json["body"].take(),
+        });
+    }
+    if let Some(html) = res_json["html"].as_str() {
+        return json!({
+            "statusCode": res_json["code"].as_u64().unwrap_or(200),
+            "headers": { "Content-Type": "text/html; charset=utf-8" },
+            "body": html,
+        });
+    }
+    json!({
+        "statusCode": res_json["code"].as_u64().unwrap_or(200),
+        "headers": { "Content-Type": "application/json; charset=utf-8" },
+        "body": res_json.to_string(),
+    })
+}
+
+fn handler(event: Value) -> Value {
+    respond(event, false)
+}
+
+/// API Gateway経由のリクエストをプロキシ統合の形式で返す
+/// JSONの予測結果は、キーを camelCase に変換しない場合のみ候補を1件ずつ body に書き出す
+fn proxy_handler(event: Value) -> Value {
+    let json_case: JsonCase = config::env_or("JSON_CASE", JsonCase::Snake);
+    to_proxy_response(respond(event, json_case == JsonCase::Snake))
+}
+
+fn respond(event: Value, stream: bool) -> Value {
+    let res_json: Value = catch_panic(|| handle_event(event, stream));
+    metrics::emit_metrics(&res_json);
+    response::convert_case(wrap_response(res_json), config::env_or("JSON_CASE", JsonCase::Snake))
+}
+
+/// stream 指定時は、JSONの予測結果を API Gateway の body に書き出すようにする (ResponseFormat::Body)
+fn handle_event(event: Value, stream: bool) -> Value {
     // 入力パラメータを得る
-    let exec_mode: Result<ExecMode, String> = ExecMode::new(event);
-    match exec_mode {
+    let pkey: String = event["pkey"].as_str().unwrap_or("").to_string();
+    // 未認証のリクエストにはパラメータの検証結果を返さず、レート制限のバケットも作らない
+    let authorized: Result<(), InitError> = authorize_event(&event).map_err(InitError::from);
+    if authorized.is_ok() {
+        if let Some(limited) = check_rate_limit(&mut rate_limit::lock(), &pkey) {
+            return limited;
+        }
+    }
+    let exec_mode: Result<ExecMode, InitError> = authorized
+        .and_then(|_| request_schema::validate(&event).map_err(InitError::from))
+        .and_then(|_| ExecMode::new(event))
+        .map(|mode| if stream { mode.streamed() } else { mode });
+    let res_json: Value = match exec_mode {
         Err(error) => {
             let message = format!("error running init: {}", error);
-            let res_err_json: Value = json!({
-                "code": 400,
-                "success": false,
-                "message": message,
-            });
-            Ok(res_err_json)
+            response::to_value(&response::ErrorResponse { code: error.code(), success: false, message })
         },
         Ok(mode) => {
-            let res_json: Value = run(mode);
-            Ok(res_json)
+            let mode_str: &'static str = mode.mode_str();
+            match run(mode, &pkey) {
+                Ok(res_json) => res_json,
+                Err(error) => {
+                    println!("error running {}: {}", mode_str, error);
+                    response::to_value(&response::ErrorResponse {
+                        code: error.code(),
+                        success: false,
+                        message: error.to_string(),
+                    })
+                },
+            }
         }
+    };
+    res_json
+}
+
+/// 認証済みの pkey ごとの呼び出し回数を制限し、超過時は429のレスポンスを返す
+fn check_rate_limit(limiter: &mut rate_limit::RateLimiter, pkey: &str) -> Option<Value> {
+    if limiter.try_acquire(pkey, std::time::Instant::now()) {
+        return None;
+    }
+    Some(response::to_value(&response::ErrorResponse {
+        code: 429,
+        success: false,
+        message: "リクエストが多すぎます。しばらくしてから再度お試しください。".to_string(),
+    }))
+}
+
+/// 処理中のパニックを500のエラーレスポンスに変換する
+/// パニックの内容はログのみに出し、レスポンスには含めない
+fn catch_panic<F: FnOnce() -> Value + std::panic::UnwindSafe>(f: F) -> Value {
+    match std::panic::catch_unwind(f) {
+        Ok(res_json) => res_json,
+        Err(payload) => {
+            let message: &str = payload.downcast_ref::<&str>().copied()
+                .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+                .unwrap_or("unknown");
+            println!("error running panic: {}", message);
+            response::to_value(&response::ErrorResponse {
+                code: 500,
+                success: false,
+                message: "internal error".to_string(),
+            })
+        },
+    }
+}
+
+/// 全レスポンス共通でAPI・スキーマのバージョンを付与
+fn wrap_response(mut body: Value) -> Value {
+    if let Some(obj) = body.as_object_mut() {
+        obj.insert("api_version".to_string(), json!(API_VERSION));
+        obj.insert("schema_version".to_string(), json!(SCHEMA_VERSION));
     }
+    body
 }
 
 #[derive(Debug)]
 enum ExecMode {
-    Learn,
-    Predict { que_sentence: String },
+    /// column_map が None の場合は既定の列構成 (質問3列目・回答2列目) で読む
+    /// dataset 指定時はテナントのモデルではなく、テナントのデータセットのモデルを学習する
+    Learn { persist_word_list: bool, self_eval: bool, column_map: Option<schema::ColumnMap>, dataset: Option<String> },
+    /// datasets が空で無い場合は、テナントのモデルではなくテナントの各データセットのモデルで検索する
+    Predict { que_sentence: String, options: Box<PredictOptions>, format: ResponseFormat, datasets: Vec<String> },
+    Add { que_sentence: String, ans_sentence: String },
+    /// 学習データCSVの読み込みのみ確認し、モデルは出力しない
+    /// column_map が None の場合は予測・追加時と同じ列構成で読む
+    DryRun { column_map: Option<schema::ColumnMap> },
+    /// 管理用 (管理用 pkey のみ)
+    Admin { action: AdminAction },
+    /// 前処理・分かち書きの結果のみ返す
+    Tokenize { sentence: String },
+}
+
+/// 予測時のオプション
+#[derive(Debug, Default, Clone)]
+struct PredictOptions {
+    category: Option<String>,
+    ensemble: Option<f64>,
+    format_template: Option<String>,
+    snippet_len: Option<usize>,
+    threshold: Option<f64>,
+    auto_threshold: bool,
+    lang: Option<String>, // 回答の言語 (無い場合はデフォルト言語)
+    scorer: Scorer,
+    diversity: Option<f64>, // MMRのλ (0〜1)
+    split_query: bool, // 文に分割して文ごとに検索
+    popularity: Option<f64>, // 人気度の重みα (0以上)
+    cluster: bool, // 候補をグループ化した groups も返す
+    typo_weight: Option<f64>, // 文字trigramのJaccardの重み (0〜1)
+    min_results: Option<usize>, // しきい値以下でも上位からこの件数まで返す
+    max_results: Option<usize>, // 返す候補数の上限
+    length_penalty: bool, // 短すぎる候補質問を減点
+    softmax: Option<f64>, // 候補の確率を算出する softmax の温度 (0より大きい)
+    include_related: bool, // 各候補に関連QA related を付与
+    calibrate: bool, // 各候補に calibrated_score を付与
+    matched_terms: bool, // 各候補に語ごとのTF-IDF寄与 matched_terms を付与
+}
+
+/// 管理用の操作
+/// target は操作するテナント (データセットを含む) で、管理用 pkey のテナントではない
+#[derive(Debug, PartialEq)]
+enum AdminAction {
+    ListDatasets,
+    Rollback { target: tenant::Tenant },
+    /// 保存した2世代のモデル (世代番号) での queries の検索結果を比較する
+    DiffModels { target: tenant::Tenant, from: u64, to: u64, queries: Vec<String> },
+}
+
+/// 予測結果の出力形式
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum ResponseFormat {
+    #[default]
+    Json,
+    Html, // JSONに加えて候補をレンダリングしたHTML断片 (html) を返す
+    Body, // API Gateway経由のJSON (リクエストでは指定できない)、候補を1件ずつ body に書き出せる場合は書き出す
+}
+
+/// 予測時のスコアリング方法
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Scorer {
+    #[default]
+    TfIdf, // 学習済みTF-IDFのコサイン類似度
+    Bm25, // 学習時に保存した出現回数によるBM25
 }
 
 impl ExecMode {
-    fn new(event: Value) -> Result<ExecMode, String> {
-        let mode: &str = event["mode"].as_str().unwrap_or("");
-        let que_sentence = event["que_sentence"].as_str().unwrap_or("");
-        let pkey = event["pkey"].as_str().unwrap_or("");
+    // レスポンスの mode (バリアントとの対応は mode_str にのみ書く)
+    const LEARN: &'static str = "learn";
+    const PREDICT: &'static str = "predict";
+    const ADD: &'static str = "add";
+    const DRY_RUN: &'static str = "dry_run";
+    const ADMIN: &'static str = "admin";
+    const TOKENIZE: &'static str = "tokenize";
+    // リクエストの mode に指定する文字と、対応するレスポンスの mode
+    const MODE_CODES: [(&'static str, &'static str); 6] = [
+        ("l", Self::LEARN), ("p", Self::PREDICT), ("a", Self::ADD), ("d", Self::DRY_RUN), ("t", Self::TOKENIZE), ("m", Self::ADMIN),
+    ];
+
+    /// レスポンスの mode に返す文字列
+    fn mode_str(&self) -> &'static str {
+        match self {
+            ExecMode::Learn { .. } => Self::LEARN,
+            ExecMode::Predict { .. } => Self::PREDICT,
+            ExecMode::Add { .. } => Self::ADD,
+            ExecMode::DryRun { .. } => Self::DRY_RUN,
+            ExecMode::Admin { .. } => Self::ADMIN,
+            ExecMode::Tokenize { .. } => Self::TOKENIZE,
+        }
+    }
 
-        if pkey.len() == 0 || pkey != STR_PKEY {
-            return Err("Not executable".to_string());
+    /// JSONの予測結果を API Gateway の body に書き出す形式にする (他の mode・形式はそのまま)
+    fn streamed(self) -> Self {
+        match self {
+            ExecMode::Predict { que_sentence, options, format: ResponseFormat::Json, datasets } => {
+                ExecMode::Predict { que_sentence, options, format: ResponseFormat::Body, datasets }
+            },
+            mode => mode,
         }
+    }
+
+    fn new(event: Value) -> Result<ExecMode, InitError> {
+        // mode は大文字小文字・前後空白を区別しない (pkey は厳密一致のまま)
+        let mode: String = event["mode"].as_str().unwrap_or("").trim().to_lowercase();
+        let mode: &str = mode.as_str();
+        // 未認証のリクエストにはパラメータの検証結果を返さない
+        authorize_event(&event)?;
+        let que_sentence = event["que_sentence"].as_str().unwrap_or("").trim();
+        let ans_sentence = event["ans_sentence"].as_str().unwrap_or("");
+        let category = event["category"].as_str().filter(|s| !s.is_empty()).map(|s| s.to_string());
+        let persist_word_list: bool = params::get_bool(&event, "persist_word_list").unwrap_or(true);
+        let self_eval: bool = params::get_bool(&event, "self_eval").unwrap_or(false);
+        let ensemble: Option<f64> = params::get_f64(&event, "ensemble")?;
+        let format_template = event["format_template"].as_str().filter(|s| !s.is_empty()).map(|s| s.to_string());
+        let snippet_len: Option<usize> = params::get_usize(&event, "snippet_len");
+        let threshold: Option<f64> = params::get_f64(&event, "threshold")?;
+        let auto_threshold: bool = params::get_bool(&event, "auto_threshold").unwrap_or(false);
+        let lang = event["lang"].as_str().filter(|s| !s.is_empty()).map(|s| s.to_string());
+        let scorer: Option<&str> = event["scorer"].as_str();
+        let format: Option<&str> = event["format"].as_str();
+        let diversity: Option<f64> = params::get_f64(&event, "diversity")?;
+        let split_query: bool = params::get_bool(&event, "split_query").unwrap_or(false);
+        let popularity: Option<f64> = params::get_f64(&event, "popularity")?;
+        let cluster: bool = params::get_bool(&event, "cluster").unwrap_or(false);
+        let typo_weight: Option<f64> = params::get_f64(&event, "typo_weight")?;
+        let min_results: Option<usize> = params::get_usize(&event, "min_results");
+        let max_results: Option<usize> = params::get_usize(&event, "max_results");
+        let length_penalty: bool = params::get_bool(&event, "length_penalty").unwrap_or(false);
+        let softmax: bool = params::get_bool(&event, "softmax").unwrap_or(false);
+        let temperature: Option<f64> = params::get_f64(&event, "temperature")?;
+        let include_related: bool = params::get_bool(&event, "include_related").unwrap_or(false);
+        let calibrate: bool = params::get_bool(&event, "calibrate").unwrap_or(false);
+        let matched_terms: bool = params::get_bool(&event, "matched_terms").unwrap_or(false);
 
         match mode {
             "l" => {
-                Ok(ExecMode::Learn)
+                let column_map: Option<schema::ColumnMap> = parse_column_map(&event["column_map"])?;
+                let dataset: Option<String> = parse_dataset(&event["dataset"])?;
+                Ok(ExecMode::Learn { persist_word_list, self_eval, column_map, dataset })
             },
             "p" => {
-                if que_sentence.len() > 0 {
-                    Ok(ExecMode::Predict { que_sentence: que_sentence.to_string() })
+                if que_sentence.is_empty() {
+                    return Err("予測時は、質問文を入力してください。".to_string().into());
+                }
+                // 書記素クラスタ単位で文字数を数える
+                let max_len: usize = config::env_or("QUE_SENTENCE_MAX_LEN", DEFAULT_QUE_MAX_LEN);
+                if que_sentence.graphemes(true).count() > max_len {
+                    return Err(format!("質問文は{}文字以内で入力してください。", max_len).into());
+                }
+                if ensemble.is_some_and(|w| !(0.0..=1.0).contains(&w)) {
+                    return Err("ensemble は0〜1の範囲で指定してください。".to_string().into());
+                }
+                if snippet_len == Some(0) {
+                    return Err("snippet_len は1以上で指定してください。".to_string().into());
+                }
+                if threshold.is_some_and(|t| !(0.0..=1.0).contains(&t)) {
+                    return Err("threshold は0〜1の範囲で指定してください。".to_string().into());
+                }
+                if threshold.is_some() && auto_threshold {
+                    return Err("threshold と auto_threshold は同時に指定できません。".to_string().into());
+                }
+                let scorer: Scorer = match scorer {
+                    None | Some("tfidf") => Scorer::TfIdf,
+                    Some("bm25") => Scorer::Bm25,
+                    Some(_) => return Err("scorer は tfidf か bm25 を指定してください。".to_string().into()),
+                };
+                if scorer == Scorer::Bm25 && ensemble.is_some() {
+                    return Err("ensemble は scorer が tfidf の場合のみ指定できます。".to_string().into());
+                }
+                if diversity.is_some_and(|l| !(0.0..=1.0).contains(&l)) {
+                    return Err("diversity は0〜1の範囲で指定してください。".to_string().into());
+                }
+                if popularity.is_some_and(|alpha| !(alpha >= 0.0 && alpha.is_finite())) {
+                    return Err("popularity は0以上で指定してください。".to_string().into());
+                }
+                if typo_weight.is_some_and(|w| !(0.0..=1.0).contains(&w)) {
+                    return Err("typo_weight は0〜1の範囲で指定してください。".to_string().into());
+                }
+                if max_results == Some(0) {
+                    return Err("max_results は1以上で指定してください。".to_string().into());
+                }
+                if let (Some(min), Some(max)) = (min_results, max_results) {
+                    if min > max {
+                        return Err("min_results は max_results 以下で指定してください。".to_string().into());
+                    }
+                }
+                // 温度は softmax を使う場合のみ検証する
+                let softmax: Option<f64> = if softmax {
+                    let temperature: f64 = temperature.unwrap_or_else(|| config::env_or("SOFTMAX_TEMPERATURE", DEFAULT_SOFTMAX_TEMPERATURE));
+                    if !(temperature > 0.0 && temperature.is_finite()) {
+                        return Err("temperature は0より大きい値で指定してください。".to_string().into());
+                    }
+                    Some(temperature)
                 } else {
-                    Err("予測時は、質問文を入力してください。".to_string())
+                    None
+                };
+                let options = PredictOptions { category, ensemble, format_template, snippet_len, threshold, auto_threshold, lang, scorer, diversity, split_query, popularity, cluster, typo_weight, min_results, max_results, length_penalty, softmax, include_related, calibrate, matched_terms };
+                let format: ResponseFormat = match format.map(|f| f.trim().to_lowercase()).as_deref() {
+                    None | Some("json") => ResponseFormat::Json,
+                    Some("html") => ResponseFormat::Html,
+                    Some(_) => return Err("format は json か html を指定してください。".to_string().into()),
+                };
+                let datasets: Vec<String> = parse_datasets(&event["datasets"])?;
+                // 手元の学習データ・モデルを使うオプションはデータセット横断検索では使えない
+                let local_only: [(bool, &str); 12] = [
+                    (options.category.is_some(), "category"),
+                    (options.ensemble.is_some(), "ensemble"),
+                    (options.lang.is_some(), "lang"),
+                    (options.scorer == Scorer::Bm25, "scorer"),
+                    (options.diversity.is_some(), "diversity"),
+                    (options.split_query, "split_query"),
+                    (options.popularity.is_some(), "popularity"),
+                    (options.cluster, "cluster"),
+                    (options.typo_weight.is_some(), "typo_weight"),
+                    (options.length_penalty, "length_penalty"),
+                    (options.include_related, "include_related"),
+                    (options.matched_terms, "matched_terms"),
+                ];
+                if let Some((_, name)) = local_only.iter().find(|(used, _)| *used).filter(|_| !datasets.is_empty()) {
+                    return Err(format!("{} は datasets と同時に指定できません。", name).into());
+                }
+                Ok(ExecMode::Predict { que_sentence: que_sentence.to_string(), options: Box::new(options), format, datasets })
+            },
+            "a" => {
+                if que_sentence.is_empty() || ans_sentence.is_empty() {
+                    return Err("追加時は、質問文と回答文を入力してください。".to_string().into());
+                }
+                Ok(ExecMode::Add { que_sentence: que_sentence.to_string(), ans_sentence: ans_sentence.to_string() })
+            },
+            "d" => {
+                let column_map: Option<schema::ColumnMap> = parse_column_map(&event["column_map"])?;
+                Ok(ExecMode::DryRun { column_map })
+            },
+            "t" => {
+                let sentence: &str = event["sentence"].as_str().unwrap_or("").trim();
+                if sentence.is_empty() {
+                    return Err("トークナイズする文を入力してください。".to_string().into());
                 }
+                Ok(ExecMode::Tokenize { sentence: sentence.to_string() })
+            },
+            "m" => {
+                let action: AdminAction = match event["action"].as_str().unwrap_or("") {
+                    "list_datasets" => AdminAction::ListDatasets,
+                    "rollback" => AdminAction::Rollback { target: parse_admin_target(&event)? },
+                    "diff_models" => parse_diff_models(&event)?,
+                    action => return Err(format!("未知の action です: {}", action).into()),
+                };
+                Ok(ExecMode::Admin { action })
             },
             _ => {
-                Err("学習: l、予測: p を指定してください。".to_string())
+                let modes: Vec<String> = Self::MODE_CODES.iter().map(|(code, mode)| format!("{} ({})", code, mode)).collect();
+                Err(format!("mode は {} のいずれかを指定してください。", modes.join("、")).into())
             }
         }
     }
 }
 
-fn run(mode: ExecMode) -> Value {
-    match mode {
-        ExecMode::Learn => {
-            learn()
-        },
-        ExecMode::Predict { que_sentence } => {
-            predict(que_sentence)
-        },
+/// dataset はデータセット名 (未指定は None)
+fn parse_dataset(value: &Value) -> Result<Option<String>, InitError> {
+    match value.as_str().map(str::trim) {
+        Some(dataset) if tenant::is_dataset_name(dataset) => Ok(Some(dataset.to_string())),
+        Some(dataset) => Err(format!("dataset のデータセット名が不正です: {}", dataset).into()),
+        None => Ok(None),
     }
 }
 
-fn learn() -> Value {
-    let qa_data: QaData = read_csv().unwrap_or_else(|err| {
-        println!("error running read: {}", err);
-        std::process::exit(1);
-    });
+/// 管理用の操作の対象 (tenant は既定の pkey か TENANT_PKEYS の pkey で、未指定は既定のテナント、dataset はそのテナントのデータセット)
+fn parse_admin_target(event: &Value) -> Result<tenant::Tenant, InitError> {
+    let target: tenant::Tenant = match event["tenant"].as_str().map(str::trim) {
+        None => tenant::Tenant::default(),
+        Some(pkey) if pkey == STR_PKEY || tenant_pkeys().iter().any(|tenant_pkey| tenant_pkey == pkey) => tenant::Tenant::new(pkey, STR_PKEY),
+        Some(_) => return Err("tenant は既定の pkey か環境変数 TENANT_PKEYS の pkey を指定してください。".to_string().into()),
+    };
+    Ok(match parse_dataset(&event["dataset"])? {
+        Some(dataset) => target.dataset(&dataset),
+        None => target,
+    })
+}
 
-    let mut docs: Vec<Vec<String>> = Vec::new();
-    for input_qa in qa_data.que_vec {
-        let doc_vec: Vec<String> = get_tokenizer(input_qa);
-        docs.push(doc_vec);
+/// datasets はデータセット名 (学習時の dataset) の配列 (重複は除く、未指定は空)
+fn parse_datasets(value: &Value) -> Result<Vec<String>, InitError> {
+    let mut datasets: Vec<String> = Vec::new();
+    for dataset in value.as_array().map(|v| v.as_slice()).unwrap_or(&[]) {
+        let Some(dataset) = dataset.as_str().map(str::trim).filter(|d| tenant::is_dataset_name(d)) else {
+            return Err(format!("datasets のデータセット名が不正です: {}", dataset).into());
+        };
+        if !datasets.iter().any(|d| d == dataset) {
+            datasets.push(dataset.to_string());
+        }
     }
+    Ok(datasets)
+}
 
-    out_csv_word(&docs).unwrap_or_else(|err| {
-        println!("error running out_csv_word csv: {}", err);
-        std::process::exit(1);
-    });
-
-    let tf_idf_res = tf_idf::TfIdf::get_tf_idf(&docs);
-    // 学習済みモデル出力
-    out_csv(tf_idf_res).unwrap_or_else(|err| {
-        println!("error running output csv: {}", err);
-        std::process::exit(1);
-    });
+/// 学習データCSVの列位置の指定 (question・answer は必須、category は任意)
+/// 列位置が重複する指定は誤りとみなす (列数を超える指定は学習時に検出する)
+fn parse_column_map(value: &Value) -> Result<Option<schema::ColumnMap>, InitError> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    let column = |key: &str| value[key].as_u64().map(|col| col as usize);
+    let (Some(question), Some(answer)) = (column("question"), column("answer")) else {
+        return Err("column_map は question・answer の列位置 (0以上の整数) を指定してください。".to_string().into());
+    };
+    if !value["category"].is_null() && column("category").is_none() {
+        return Err("column_map の category は0以上の整数で指定してください。".to_string().into());
+    }
+    let category: Option<usize> = column("category");
+    if question == answer || category.is_some_and(|col| col == question || col == answer) {
+        return Err("column_map の列位置が重複しています。".to_string().into());
+    }
+    Ok(Some(schema::ColumnMap { question, answer, category }))
+}
 
-    let res_json: Value = json!({
-        "code": 200,
-        "success": true,
-        "mode": "learn",
-    });
-    res_json
+/// from・to は世代番号、queries は空で無い質問文の配列 (件数の上限は環境変数 DIFF_MODELS_MAX_QUERIES)
+fn parse_diff_models(event: &Value) -> Result<AdminAction, InitError> {
+    let (Some(from), Some(to)) = (params::get_usize(event, "from"), params::get_usize(event, "to")) else {
+        return Err("diff_models は比較するモデルの世代 from・to を指定してください。".to_string().into());
+    };
+    let mut queries: Vec<String> = Vec::new();
+    for query in event["queries"].as_array().map(|v| v.as_slice()).unwrap_or(&[]) {
+        let Some(query) = query.as_str().map(str::trim).filter(|q| !q.is_empty()) else {
+            return Err(format!("queries の質問文が不正です: {}", query).into());
+        };
+        queries.push(query.to_string());
+    }
+    let max_queries: usize = config::env_or("DIFF_MODELS_MAX_QUERIES", DEFAULT_DIFF_MODELS_MAX_QUERIES);
+    if queries.is_empty() || queries.len() > max_queries {
+        return Err(format!("queries は1〜{}件の質問文で指定してください。", max_queries).into());
+    }
+    Ok(AdminAction::DiffModels { target: parse_admin_target(event)?, from: from as u64, to: to as u64, queries })
 }
 
-fn predict(que_sentence: String) -> Value {
-    let qa_data: QaData = read_csv().unwrap_or_else(|err| {
-        println!("error running read: {}", err);
-        std::process::exit(1);
-    });
+/// リクエストの pkey を、環境変数 PREDICT_PKEY・ADMIN_PKEY の設定で検証する (mode は大文字小文字・前後空白を区別しない)
+fn authorize_event(event: &Value) -> Result<(), AuthError> {
+    let mode: String = event["mode"].as_str().unwrap_or("").trim().to_lowercase();
+    let pkey: &str = event["pkey"].as_str().unwrap_or("");
+    let predict_pkey: String = config::env_or("PREDICT_PKEY", String::new());
+    let admin_pkey: String = config::env_or("ADMIN_PKEY", String::new());
+    authorize(pkey, &mode, &predict_pkey, &admin_pkey, &tenant_pkeys())
+}
 
-    let docs: Vec<Vec<String>> = read_word_list_csv().unwrap_or_else(|err| {
-        println!("error running read: {}", err);
-        std::process::exit(1);
-    });
+/// 既定の pkey の他に、自分のテナントで学習・予測できる pkey (環境変数 TENANT_PKEYS、カンマ区切り)
+fn tenant_pkeys() -> Vec<String> {
+    config::env_or("TENANT_PKEYS", String::new())
+        .split(',')
+        .map(|pkey| pkey.trim().to_string())
+        .filter(|pkey| !pkey.is_empty())
+        .collect()
+}
 
-    let tfidf: tf_idf::TfIdf = read_model_csv().unwrap();
-    let trg: Vec<String> = get_tokenizer(que_sentence.to_owned());
-    let ans_vec: Vec<(usize, f64)> = tf_idf::TfIdf::predict(tfidf, &docs, &trg);
+/// pkey を検証する
+/// 既定の pkey・テナントの pkey (tenant_pkeys) は、管理用以外を自分のテナントで実行できる
+/// 予測専用キー (環境変数 PREDICT_PKEY) は予測のみ実行でき、学習・追加は権限外とする
+/// 管理用 (mode m) は管理用 pkey のみ、管理用 pkey は管理用のみ実行できる
+fn authorize(pkey: &str, mode: &str, predict_pkey: &str, admin_pkey: &str, tenant_pkeys: &[String]) -> Result<(), AuthError> {
+    if pkey.is_empty() {
+        return Err(AuthError::MissingKey);
+    }
+    let is_admin: bool = !admin_pkey.is_empty() && pkey == admin_pkey;
+    let is_tenant: bool = pkey == STR_PKEY || tenant_pkeys.iter().any(|tenant_pkey| tenant_pkey == pkey);
+    match (mode, is_admin) {
+        ("m", true) => return Ok(()),
+        (_, true) => return Err(AuthError::Forbidden(mode.to_string())),
+        ("m", false) if is_tenant || (!predict_pkey.is_empty() && pkey == predict_pkey) => {
+            return Err(AuthError::Forbidden(mode.to_string()));
+        },
+        ("m", false) => return Err(AuthError::InvalidKey),
+        _ => {},
+    }
+    if is_tenant {
+        return Ok(());
+    }
+    if !predict_pkey.is_empty() && pkey == predict_pkey {
+        return match mode {
+            "l" | "a" | "d" => Err(AuthError::Forbidden(mode.to_string())),
+            _ => Ok(()),
+        };
+    }
+    Err(AuthError::InvalidKey)
+}
 
-    let res_json: Value = make_json(que_sentence, qa_data, ans_vec);
-    res_json
+/// pkey のテナント (予測専用キー・管理用 pkey は既定のテナント)
+fn tenant_of(pkey: &str) -> tenant::Tenant {
+    let predict_pkey: String = config::env_or("PREDICT_PKEY", String::new());
+    let admin_pkey: String = config::env_or("ADMIN_PKEY", String::new());
+    if [predict_pkey, admin_pkey].iter().any(|key| !key.is_empty() && key == pkey) {
+        return tenant::Tenant::default();
+    }
+    tenant::Tenant::new(pkey, STR_PKEY)
 }
 
+/// pkey のテナントの学習データ・学習結果で実行する
+/// モデルを書き換える学習・追加は排他ロック、読み込む予測は共有ロックを取る (待ち時間の上限は環境変数 MODEL_LOCK_TIMEOUT_MS)
+fn run(mode: ExecMode, pkey: &str) -> Result<Value, AppError> {
+    let storage: Option<storage::LocalStorage> = storage::LocalStorage::from_env();
+    let lock_timeout = std::time::Duration::from_millis(config::env_or("MODEL_LOCK_TIMEOUT_MS", DEFAULT_MODEL_LOCK_TIMEOUT_MS));
+    let tenant: tenant::Tenant = tenant_of(pkey);
+    let lock_path: String = tenant.path(MODEL_LOCK_PATH);
+    match mode {
+        ExecMode::Learn { persist_word_list, self_eval, column_map, dataset } => {
+            let tenant: tenant::Tenant = match &dataset {
+                Some(dataset) => tenant.dataset(dataset),
+                None => tenant,
+            };
+            let lock_path: String = tenant.path(MODEL_LOCK_PATH);
+            tenant.create_dirs()?;
+            let _lock = lock::ModelLock::exclusive(&lock_path, lock_timeout)?;
+            let res_json: Value = learn(&tenant, persist_word_list, self_eval, column_map)?;
+            model_versions(&tenant).save()?;
+            if let Some(storage) = &storage {
+                save_model(storage, &tenant)?;
+            }
+            Ok(res_json)
+        },
+        ExecMode::Predict { que_sentence, options, format, datasets } if !datasets.is_empty() => {
+            // 呼び出し元のテナントのデータセットのみ検索する (ロックはデータセットごとに取る)
+            let storage: Option<&dyn storage::Storage> = storage.as_ref().map(|s| s as &dyn storage::Storage);
+            let mut res_json: Value = predict_datasets(&tenant, &que_sentence, &datasets, storage, lock_timeout, &options)?;
+            if format == ResponseFormat::Html {
+                res_json["html"] = json!(html::render_predict(&res_json));
+            }
+            Ok(res_json)
+        },
+        ExecMode::Predict { que_sentence, options, format, .. } => {
+            if let Some(storage) = &storage {
+                fetch_model(storage, &tenant, lock_timeout)?;
+            }
+            require_model(&tenant)?;
+            let _lock = lock::ModelLock::shared(&lock_path, lock_timeout)?;
+            let mut res_json: Value = if options.split_query {
+                predict_split(&tenant, que_sentence, *options)?
+            } else {
+                predict_as(&tenant, que_sentence, *options, format == ResponseFormat::Body)?
+            };
+            if format == ResponseFormat::Html {
+                res_json["html"] = json!(html::render_predict(&res_json));
+            }
+            Ok(res_json)
+        },
+        ExecMode::Add { que_sentence, ans_sentence } => {
+            // 他のインスタンスで更新したモデルに追加する
+            if let Some(storage) = &storage {
+                fetch_model(storage, &tenant, lock_timeout)?;
+            }
+            require_model(&tenant)?;
+            let _lock = lock::ModelLock::exclusive(&lock_path, lock_timeout)?;
+            let res_json: Value = add(&tenant, que_sentence, ans_sentence)?;
+            model_versions(&tenant).save()?;
+            if let Some(storage) = &storage {
+                save_model(storage, &tenant)?;
+            }
+            Ok(res_json)
+        },
+        ExecMode::DryRun { column_map } => {
+            let csv_schema: schema::CsvSchema = match column_map {
+                Some(column_map) => column_map.into(),
+                None => input_schema(&tenant)?,
+            };
+            Ok(dry_run(&tenant.path(INPUT_CSV_PATH), &csv_schema))
+        },
+        ExecMode::Tokenize { sentence } => {
+            tokenize_sentence(&sentence, pipeline::Pipeline::from_env())
+        },
+        ExecMode::Admin { action } => {
+            match action {
+                AdminAction::ListDatasets => list_datasets(storage.as_ref().map(|s| s as &dyn storage::Storage)),
+                AdminAction::Rollback { target } => {
+                    require_model(&target)?;
+                    let _lock = lock::ModelLock::exclusive(&target.path(MODEL_LOCK_PATH), lock_timeout)?;
+                    let version: u64 = model_versions(&target).rollback()?;
+                    // メモリ上の学習結果を捨て、戻したバンドルから読み直させる
+                    *engine::lock(target.id()) = engine::QaEngine::default();
+                    // 保存先の新しいバンドルで予測時に上書きされないよう、戻したバンドルを保存先にも書き込む
+                    if let Some(storage) = &storage {
+                        save_model(storage, &target)?;
+                    }
+                    Ok(response::to_value(&response::RollbackResponse { code: 200, success: true, mode: ExecMode::ADMIN, version }))
+                },
+                AdminAction::DiffModels { target, from, to, queries } => diff_models(&model_versions(&target), from, to, &queries),
+            }
+        },
+    }
+}
 
-fn make_json(que_sentence: String, qa_data: QaData, ans_vec: Vec<(usize, f64)>) -> Value {
-    let mut qa_infos: Vec<Value> = Vec::new();
-    for (id, cos_val) in ans_vec {
-        if cos_val > 0.3 {
-            qa_infos.push(json!({
-                "que": que_sentence,
-                "ans": qa_data.ans_vec[id],
-                "cos_val": cos_val,
-                "similar_que": qa_data.que_vec[id]
-            }));
+/// 学習時と同じ前処理 (環境変数の構成) で分かち書きし、正規化後の文・トークン列・含まれるストップワードを返す
+/// ストップワードはキーワード抽出 (query_keywords) で除外される語で、トークン列からは除かない
+fn tokenize_sentence(sentence: &str, pipeline: pipeline::Pipeline) -> Result<Value, AppError> {
+    let normalized: String = pipeline.normalize(sentence.to_owned());
+    let steps: Vec<&'static str> = pipeline.names();
+    let filters: Vec<String> = pipeline.filter_names().to_vec();
+    let timeout_ms: u64 = config::env_or("TOKENIZE_TIMEOUT_MS", DEFAULT_TOKENIZE_TIMEOUT_MS);
+    let tokens: Vec<String> = pipeline.run_with_timeout(sentence.to_owned(), std::time::Duration::from_millis(timeout_ms))?;
+    let config = keyword::KeywordConfig::from_env();
+    let mut stop_words: Vec<String> = Vec::new();
+    for token in &tokens {
+        if config.stop_words.contains(token) && !stop_words.contains(token) {
+            stop_words.push(token.to_owned());
         }
     }
+    Ok(response::to_value(&response::TokenizeResponse {
+        code: 200,
+        success: true,
+        mode: ExecMode::TOKENIZE,
+        payload: response::TokenizePayload { sentence: sentence.to_owned(), normalized, tokens, stop_words, steps, filters },
+    }))
+}
 
-    let res_json: Value = json!({
-        "code": 200,
-        "success": true,
-        "mode": "predict",
-        "payload": {
-            "qa_infos": qa_infos
-        }
-    });
-    res_json
+/// テナントの学習結果の世代管理
+fn model_versions(tenant: &tenant::Tenant) -> versions::ModelVersions {
+    versions::ModelVersions {
+        dir: tenant.path(MODEL_VERSIONS_DIR),
+        paths: MODEL_VERSION_PATHS.iter().map(|path| tenant.path(path)).collect(),
+        keep: config::env_or("MODEL_VERSIONS_KEEP", DEFAULT_MODEL_VERSIONS_KEEP),
+    }
 }
 
-fn get_tokenizer(doc: String) -> Vec<String> {
-    let mut f = zstd::Decoder::new(File::open("./model/bccwj-luw-small.model.zst").unwrap()).unwrap();
-    let model = Model::read(&mut f).unwrap();
-    let predictor = Predictor::new(model, true).unwrap();
+/// 保存した2世代のモデル (バンドル) で各クエリを検索し、上位候補の順位・スコアの変化を返す
+/// 各世代のバンドルに記録した前処理で分かち書きする
+fn diff_models(versions: &versions::ModelVersions, from: u64, to: u64, queries: &[String]) -> Result<Value, AppError> {
+    let top_k: usize = config::env_or("DIFF_MODELS_TOP_K", DEFAULT_DIFF_MODELS_TOP_K);
+    let timeout = std::time::Duration::from_millis(config::env_or("TOKENIZE_TIMEOUT_MS", DEFAULT_TOKENIZE_TIMEOUT_MS));
+    let load = |version: u64| -> Result<bundle::Bundle, AppError> {
+        let bytes: Vec<u8> = versions.read(BUNDLE_PATH, version)?.ok_or(AppError::UnknownVersion(version))?;
+        bundle::Bundle::from_slice(&bytes)
+    };
+    let (from_bundle, to_bundle) = (load(from)?, load(to)?);
+    let from_questions: Option<Vec<String>> = from_bundle.qa().map(|(questions, _)| questions.to_vec());
+    let to_questions: Option<Vec<String>> = to_bundle.qa().map(|(questions, _)| questions.to_vec());
+    let (from_tfidf, from_docs, from_pipeline) = from_bundle.into_parts()?;
+    let (to_tfidf, to_docs, to_pipeline) = to_bundle.into_parts()?;
 
-    let pre_filters: Vec<Box<dyn StringFilter<String>>> = vec![
-        Box::new(KyteaFullwidthFilter),
-    ];
-    
-    let preproc_input = pre_filters.iter().fold(doc, |s, filter| filter.filter(s));
-    
-    let mut sentence = Sentence::from_raw(preproc_input).unwrap();
-    predictor.predict(&mut sentence);
-    
-    let mut buf = String::new();
-    sentence.write_tokenized_text(&mut buf);
-    // output the tokens
-    let docs: Vec<String> = buf.split(" ").map(|s| s.to_string()).collect();
-    // println!("{:?}", docs);
+    let mut diffs: Vec<response::QueryDiff> = Vec::new();
+    for query in queries {
+        let from_trg: Vec<String> = from_pipeline.clone().run_with_timeout(query.to_owned(), timeout)?;
+        let to_trg: Vec<String> = to_pipeline.clone().run_with_timeout(query.to_owned(), timeout)?;
+        let from_top = top_candidates(from_tfidf.search(&from_docs, &from_trg), from_questions.as_deref(), top_k);
+        let to_top = top_candidates(to_tfidf.search(&to_docs, &to_trg), to_questions.as_deref(), top_k);
+        diffs.push(diff_rankings(query, from_top, to_top));
+    }
+    Ok(response::to_value(&response::DiffModelsResponse { code: 200, success: true, mode: ExecMode::ADMIN, from, to, queries: diffs }))
+}
 
-    docs
+/// スコア順の検索結果のうち、スコアが0より大きい上位 top_k 件
+fn top_candidates(ans_vec: Vec<(usize, f64)>, questions: Option<&[String]>, top_k: usize) -> Vec<response::DiffCandidate> {
+    ans_vec.into_iter()
+        .filter(|(_, cos_val)| *cos_val > 0.0)
+        .take(top_k)
+        .enumerate()
+        .map(|(i, (doc_id, cos_val))| response::DiffCandidate {
+            doc_id,
+            similar_que: questions.and_then(|questions| questions.get(doc_id)).cloned(),
+            rank: i + 1,
+            cos_val,
+        })
+        .collect()
 }
 
-#[derive(Debug)]
-struct QaData {
-    que_vec: Vec<String>,
-    ans_vec: Vec<String>,
+/// 2つの上位候補を比較する
+/// 両方に学習データの質問があれば質問文で、無ければ文書IDで同じ候補とみなす (再学習で文書IDがずれても追える)
+fn diff_rankings(query: &str, from: Vec<response::DiffCandidate>, to: Vec<response::DiffCandidate>) -> response::QueryDiff {
+    let same = |a: &response::DiffCandidate, b: &response::DiffCandidate| match (&a.similar_que, &b.similar_que) {
+        (Some(a_que), Some(b_que)) => a_que == b_que,
+        _ => a.doc_id == b.doc_id,
+    };
+    let mut kept: Vec<response::RankChange> = Vec::new();
+    let mut added: Vec<response::DiffCandidate> = Vec::new();
+    for candidate in &to {
+        match from.iter().find(|before| same(before, candidate)) {
+            Some(before) => kept.push(response::RankChange {
+                doc_id: candidate.doc_id,
+                similar_que: candidate.similar_que.to_owned(),
+                from_rank: before.rank,
+                to_rank: candidate.rank,
+                rank_change: before.rank as i64 - candidate.rank as i64,
+                from_cos_val: before.cos_val,
+                to_cos_val: candidate.cos_val,
+            }),
+            None => added.push(candidate.to_owned()),
+        }
+    }
+    let removed: Vec<response::DiffCandidate> = from.into_iter()
+        .filter(|before| !to.iter().any(|candidate| same(before, candidate)))
+        .collect();
+    response::QueryDiff { query: query.to_owned(), kept, added, removed }
 }
 
-fn read_csv() -> Result<QaData, Box<dyn OtherError>> {
-    let csv_file_path = "input/study_qa1.csv";
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(false) // ヘッダーが無い事を明示的に設定
-        .from_path(csv_file_path)?;
+/// 保存先にある全テナントのデータセットの文書数と更新日時 (UNIX時間、秒)
+/// 保存先が未設定の場合は空
+fn list_datasets(storage: Option<&dyn storage::Storage>) -> Result<Value, AppError> {
+    let mut datasets: Vec<response::DatasetInfo> = Vec::new();
+    for object in storage.map(|s| s.list(storage::MODELS_PREFIX)).transpose()?.unwrap_or_default() {
+        let Some((tenant, dataset)) = storage::dataset_of(&object.key) else {
+            continue;
+        };
+        let Some(bytes) = storage.and_then(|s| s.get(&object.key).transpose()).transpose()? else {
+            continue;
+        };
+        datasets.push(response::DatasetInfo {
+            tenant: tenant.to_string(),
+            dataset: dataset.to_string(),
+            doc_count: bundle::Bundle::from_slice(&bytes)?.doc_count(),
+            updated_at: object.modified,
+        });
+    }
+    Ok(response::to_value(&response::AdminResponse {
+        code: 200,
+        success: true,
+        mode: ExecMode::ADMIN,
+        datasets,
+    }))
+}
 
-    let mut que_vec: Vec<String> = Vec::new();
-    let mut ans_vec: Vec<String> = Vec::new();
-    for result in rdr.records() {
-        let record = result?;
-        que_vec.push(record[3].to_string());
-        ans_vec.push(record[2].to_string())
+/// 学習データCSVを学習時と同じく読み込み、件数・列構成・先頭数件を返す
+/// 読み込めない場合もエラー内容を返す (valid: false)
+fn dry_run(csv_file_path: &str, schema: &schema::CsvSchema) -> Value {
+    let langs: Vec<String> = schema::answer_langs_from_env();
+    let delimiter: Option<u8> = schema::delimiter_from_env()
+        .or_else(|| std::fs::read(csv_file_path).ok().map(|bytes| schema::detect_delimiter(&bytes)));
+    let columns = response::DryRunColumns {
+        answer: schema.answer_col,
+        question: schema.question_col,
+        category: schema.category_col,
+        langs: langs.to_owned(),
+    };
+    let mut payload = response::DryRunPayload {
+        valid: false,
+        error: None,
+        doc_count: 0,
+        delimiter: delimiter.map(|d| if d == b'\t' { "tab".to_string() } else { (d as char).to_string() }),
+        columns,
+        skipped_rows: Vec::new(),
+        preview: Vec::new(),
+    };
+    match read_csv_rows(csv_file_path, &langs, schema) {
+        Ok((qa_data, rows)) => {
+            payload.valid = true;
+            payload.doc_count = rows.len();
+            // 読み込んだ行の番号の間にある行はスキップされた行 (最後に読み込んだ行より後ろは判定しない)
+            let mut expected: usize = 1;
+            for &row in &rows {
+                payload.skipped_rows.extend(expected..row);
+                expected = row + 1;
+            }
+            payload.preview = rows.iter().enumerate().take(DRY_RUN_PREVIEW_ROWS)
+                .map(|(id, &row)| response::PreviewRow {
+                    row,
+                    question: qa_data.que_vec[id].to_owned(),
+                    answer: qa_data.ans_vec[id].to_owned(),
+                    category: qa_data.cat_vec[id].to_owned(),
+                })
+                .collect();
+        },
+        Err(err) => payload.error = Some(err.to_string()),
     }
-    Ok(QaData { que_vec, ans_vec })
+    response::to_value(&response::DryRunResponse { code: 200, success: true, mode: ExecMode::DRY_RUN, payload })
 }
 
-fn read_word_list_csv() -> Result<Vec<Vec<String>>, Box<dyn OtherError>> {
-    let csv_file_path = "output/word_list.csv";
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(false) // ヘッダーが無い事を明示的に設定
-        .flexible(true) // 可変長で読み込み
-        .from_path(csv_file_path)?;
+fn learn(tenant: &tenant::Tenant, persist_word_list: bool, self_eval: bool, column_map: Option<schema::ColumnMap>) -> Result<Value, AppError> {
+    let mut job = learn::learn_phased(tenant.clone(), persist_word_list, column_map);
+    let mut phases: Vec<response::PhaseStat> = Vec::new();
+    for progress in job.by_ref() {
+        let progress: learn::Progress = progress?;
+        println!("learn {}: {:.0}% ({} ms)", progress.phase.name(), progress.rate * 100.0, progress.elapsed_ms);
+        phases.push(response::PhaseStat { phase: progress.phase.name(), elapsed_ms: progress.elapsed_ms as u64 });
+    }
 
-    let mut word_v_v: Vec<Vec<String>> = Vec::new();
-    for (index, result) in rdr.records().enumerate() { // ヘッダーは除く
-        let record = result?;
-        word_v_v.push(vec![]);
-        for col in &record {
-            word_v_v[index].push(col.to_string());
+    let mut messages: Vec<String> = job.warnings().to_vec();
+    let mut self_match_rate: Option<f64> = None;
+    if self_eval {
+        let engine = engine::lock(tenant.id());
+        if let (Some(docs), Some((_, tf_idf))) = (engine.word_list(), engine.model()) {
+            self_match_rate = learn::self_match_rate(docs, tf_idf);
         }
+        let min_rate: f64 = config::env_or("SELF_MATCH_RATE_WARN", DEFAULT_SELF_MATCH_RATE_WARN);
+        messages.extend(learn::self_match_warning(self_match_rate, min_rate));
     }
 
-    Ok(word_v_v)
+    Ok(response::to_value(&response::LearnResponse {
+        code: 200,
+        success: true,
+        mode: ExecMode::LEARN,
+        stats: response::LearnStats { unknown_rate: job.unknown_rate(), self_match_rate, phases },
+        warnings: response::LearnWarnings {
+            messages,
+            duplicate_questions: job.duplicate_questions().to_vec(),
+            failed_docs: job.failed_docs().to_vec(),
+        },
+    }))
 }
 
-fn read_model_csv() -> Result<tf_idf::TfIdf, Box<dyn OtherError>> {
-    let model_csv_file_path = "output/model_qa1.csv";
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(false) // ヘッダーが無い事を明示的に設定
-        .from_path(model_csv_file_path)?;
+/// 保存先に置くテナントのファイル (予測時に取得する、バンドルは最後に書き込む)
+const STORED_PATHS: [&str; 3] = [INPUT_CSV_PATH, MODEL_META_PATH, BUNDLE_PATH];
 
-    let mut rec_v_v: Vec<Vec<String>> = Vec::new();
-    for (index, result) in rdr.records().enumerate() { // ヘッダーは除く
-        let record = result?;
-        rec_v_v.push(vec![]);
-        for col in &record {
-            rec_v_v[index].push(col.to_string());
-        }
-    }
-    let word_vec: Vec<String> = (rec_v_v[0][1..]).to_vec(); // "id"の文字以降を格納
-    let mut tf_idf_vec: Vec<Vec<f64>> = Vec::new();
-    for (index, rec_v) in rec_v_v.iter().skip(1).enumerate() { // ヘッダーは除く
-        tf_idf_vec.push(vec![]);
-        for tf_idf in rec_v {
-            let tf_idf_val: f64 = tf_idf.parse::<f64>().unwrap();
-            tf_idf_vec[index].push(tf_idf_val);
-        }
+/// テナントの学習データ・モデルメタ・バンドルをテナントのキーで保存先に書き込む
+/// 書き込んだバンドルの etag を記録し、同じインスタンスでの次の予測では取得し直さない
+fn save_model(storage: &dyn storage::Storage, tenant: &tenant::Tenant) -> Result<(), AppError> {
+    for path in STORED_PATHS {
+        storage.put(&tenant.storage_key(path), &std::fs::read(tenant.path(path))?)?;
     }
+    let etag: Option<String> = storage.etag(&tenant.storage_key(BUNDLE_PATH))?;
+    engine::lock(tenant.id()).set_fetched(etag);
+    Ok(())
+}
 
-    let tfidf: tf_idf::TfIdf = tf_idf::TfIdf {
-        word_vec,
-        tf_idf_vec
+/// 保存先のテナントのバンドルが前回の取得・書き込みから更新されていれば、学習データ・モデルメタ・バンドルを手元に取得する (取得した場合はtrue)
+/// 更新の有無は etag のみで判定し、取得した場合はメモリ上の学習結果を捨てて取得したバンドルから読み直させる
+/// 保存先にテナントのモデルが無い場合は、手元のモデルを使わず AppError::ModelNotFound
+fn fetch_model(storage: &dyn storage::Storage, tenant: &tenant::Tenant, lock_timeout: std::time::Duration) -> Result<bool, AppError> {
+    let Some(etag) = storage.etag(&tenant.storage_key(BUNDLE_PATH))? else {
+        return Err(AppError::ModelNotFound(tenant.id().to_string()));
     };
+    if engine::lock(tenant.id()).fetched() == Some(etag.as_str()) {
+        return Ok(false);
+    }
+    tenant.create_dirs()?;
+    let _lock = lock::ModelLock::exclusive(&tenant.path(MODEL_LOCK_PATH), lock_timeout)?;
+    for path in STORED_PATHS {
+        // 学習データ・モデルメタを保存していない以前の保存先は、手元の学習データ・モデルメタを使う
+        if let Some(bytes) = storage.get(&tenant.storage_key(path))? {
+            atomic::write(&tenant.path(path), &bytes)?;
+        }
+    }
+    let mut engine = engine::lock(tenant.id());
+    *engine = engine::QaEngine::default();
+    engine.set_fetched(Some(etag));
+    Ok(true)
+}
 
-    Ok(tfidf)
+/// テナントの学習済みモデルが無ければ AppError::ModelNotFound (他のテナントのモデルは使わない)
+fn require_model(tenant: &tenant::Tenant) -> Result<(), AppError> {
+    if !has_model(&tenant.path(BUNDLE_PATH), &tenant.path(&model_csv_path())) {
+        return Err(AppError::ModelNotFound(tenant.id().to_string()));
+    }
+    Ok(())
 }
 
-/// csv出力
-/// https://qiita.com/algebroid/items/c456d4ec555ae04c7f92
-fn out_csv(tf_idf_res: tf_idf::TfIdf) -> Result<(), Box<dyn OtherError>> {
-    let csv_file_out_path = "output/model_qa1.csv";
-    let mut wtr = csv::WriterBuilder::new()
-        .quote_style(csv::QuoteStyle::Always)
-        .from_path(csv_file_out_path)?;
+/// QAを1件追加し、テナントのモデル・単語リスト・学習データCSVを更新する
+/// メモリ上に学習結果が無い場合はバンドル (保存先から取得したものを含む) から復元してから追加する
+fn add(tenant: &tenant::Tenant, que_sentence: String, ans_sentence: String) -> Result<Value, AppError> {
+    let mut engine = engine::lock(tenant.id());
+    let csv_schema: schema::CsvSchema = *load_schema(&mut engine, tenant)?;
+    if engine.model().is_none() {
+        let (qa_data, _) = read_csv_rows(&tenant.path(INPUT_CSV_PATH), &schema::answer_langs_from_env(), &csv_schema)?;
+        ensure_bundle(tenant)?;
+        let (tf_idf, docs, _) = bundle::load_bundle(&tenant.path(BUNDLE_PATH))?.into_parts()?;
+        engine.set_word_list(docs);
+        engine.set_model(qa_data, tf_idf);
+    }
+    load_pipeline(&mut engine, tenant)?;
+    let doc_id: usize = engine.add_document(que_sentence.to_owned(), ans_sentence.to_owned());
 
-    let mut w_vec = vec!["id"];
-    let mut w_add_vec: Vec<&str> = tf_idf_res.word_vec.iter().map(|s| s.as_str()).collect();
-    w_vec.append(&mut w_add_vec);
-    wtr.write_record(&w_vec)?;
+    let (_, tf_idf_res) = engine.model().unwrap();
+    out_model(&tenant.path(&model_csv_path()), tf_idf_res)?;
+    if let Some(docs) = engine.word_list() {
+        out_csv_word(&tenant.path(WORD_LIST_CSV_PATH), docs)?;
+        out_count_csv(&tenant.path(COUNT_MATRIX_PATH), &count::CountMatrix::from_docs(docs))?;
+        let (qa_data, _) = engine.model().unwrap();
+        // 追加した文書を含めた自己検索スコアの分布で統計を取り直す
+        let stats: Option<calibration::ScoreStats> = learn::calibration_stats(docs, tf_idf_res);
+        bundle::save_bundle(&tenant.path(BUNDLE_PATH), tf_idf_res, docs, engine.pipeline().unwrap(), &qa_data.que_vec, &qa_data.ans_vec, stats.as_ref())?;
+    }
+    append_csv(&tenant.path(INPUT_CSV_PATH), doc_id, &que_sentence, &ans_sentence, &csv_schema)?;
+    let meta_path: String = tenant.path(MODEL_META_PATH);
+    write_model_info(&meta_path, next_model_version(&meta_path)?, doc_id + 1)?;
 
-    for (index, tf_idf_vec) in tf_idf_res.tf_idf_vec.iter().enumerate() {
-        let mut s_vec: Vec<String> = vec![index.to_string()];
-        let mut s_add_vec: Vec<String> = tf_idf_vec.iter().map(|s| s.to_string()).collect();
-        s_vec.append(&mut s_add_vec);
-        wtr.write_record(s_vec)?;
+    Ok(response::to_value(&response::AddResponse { code: 200, success: true, mode: ExecMode::ADD, doc_id }))
+}
+
+/// 全トークンのうち語彙に無いものの割合
+/// 語彙が空(辞書を持たないモデル)の場合は判定できないのでNone
+fn unknown_rate(docs: &[Vec<String>], vocab: &HashSet<String>) -> Option<f64> {
+    let total: usize = docs.iter().map(|d| d.len()).sum();
+    if vocab.is_empty() || total == 0 {
+        return None;
     }
+    let unknown: usize = docs.iter().flatten().filter(|w| !vocab.contains(*w)).count();
+    Some(unknown as f64 / total as f64)
+}
 
-    wtr.flush()?;
-    Ok(())
+/// トークナイズモデルが持つ語彙 (辞書の語と品詞推定対象の語)
+/// 同梱モデルのように辞書を持たない場合は、モデルが学習した文字n-gramを語彙とみなす
+fn model_vocab() -> Result<HashSet<String>, AppError> {
+    let model: Model = load_tokenizer_model()?;
+    let mut vocab: HashSet<String> = model.dictionary().iter().map(|r| r.get_word().to_string()).collect();
+    vocab.extend(model.tag_models().iter().map(|t| t.token().to_string()));
+    if vocab.is_empty() {
+        vocab = char_ngram_vocab(&tokenizer_model_path())?;
+    }
+    Ok(vocab)
 }
 
-fn out_csv_word(docs: &Vec<Vec<String>>) -> Result<(), Box<dyn OtherError>> {
-    let csv_file_out_path = "output/word_list.csv";
-    let mut wtr = csv::WriterBuilder::new()
-        .quote_style(csv::QuoteStyle::Always)
-        .flexible(true) // 可変長で書き込み
-        .from_path(csv_file_out_path)?;
+/// vaporetto のモデル形式 (マジックナンバーに続く bincode の先頭が文字n-gramの素性)
+const TOKENIZER_MODEL_MAGIC: &[u8] = b"VaporettoTokenizer 0.5.0\n";
 
-    for doc in docs {
-        let s_vec: Vec<String> = doc.iter().map(|s| s.to_string()).collect();
-        wtr.write_record(s_vec)?;
+#[derive(bincode::Decode)]
+struct CharNgram {
+    ngram: String,
+    _weights: Vec<i32>,
+}
+
+/// トークナイズモデルの文字n-gram素性
+fn char_ngram_vocab(model_path: &str) -> Result<HashSet<String>, AppError> {
+    let invalid = |message: String| -> AppError { std::io::Error::new(std::io::ErrorKind::InvalidData, message).into() };
+    let mut f = zstd::Decoder::new(File::open(model_path)?)?;
+    let mut magic: Vec<u8> = vec![0; TOKENIZER_MODEL_MAGIC.len()];
+    std::io::Read::read_exact(&mut f, &mut magic)?;
+    if magic != TOKENIZER_MODEL_MAGIC {
+        return Err(invalid("model version mismatch".to_string()));
     }
+    let ngrams: Vec<CharNgram> = bincode::decode_from_std_read(&mut f, bincode::config::standard())
+        .map_err(|err| invalid(err.to_string()))?;
+    Ok(ngrams.into_iter().map(|n| n.ngram).collect())
+}
 
-    wtr.flush()?;
-    Ok(())
+/// 環境変数 TOKENIZER_MODEL_PATH で指定されたトークナイズモデルのパス
+fn tokenizer_model_path() -> String {
+    config::env_or("TOKENIZER_MODEL_PATH", DEFAULT_TOKENIZER_MODEL_PATH.to_string())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// 品詞推定を行う場合は、環境変数 TOKENIZER_MODEL_PATH で品詞モデル入りのモデルを指定する
+fn load_tokenizer_model() -> Result<Model, AppError> {
+    let mut f = zstd::Decoder::new(File::open(tokenizer_model_path())?)?;
+    Model::read(&mut f).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()).into())
+}
 
-    #[test]
-    fn learn_test1() {
-        let res = learn();
-        // println!("{:?}", res.to_string());
-        let exp: Value = json!({
-            "code": 200,
-            "success": true,
-            "mode": "learn",
-        });
-        assert_eq!(res, exp);
+/// 同梱モデルは日本語用のため、日本語以外の質問は受け付けない (閾値は環境変数 JA_MIN_RATIO)
+fn check_language(que_sentence: &str) -> Result<(), AppError> {
+    let ja_min_ratio: f64 = config::env_or("JA_MIN_RATIO", DEFAULT_JA_MIN_RATIO);
+    if !lang::is_japanese(que_sentence, ja_min_ratio) {
+        return Err(AppError::UnsupportedLanguage(lang::japanese_ratio(que_sentence).unwrap_or(0.0)));
     }
+    Ok(())
+}
 
-    #[test]
-    fn predict_test1() {
-        let que_sentence: String = "おすすめのメニュー教えてください。".to_string();
-        let res = predict(que_sentence.to_owned());
-        // println!("{} {} {}", res["code"], res["mode"], res["payload"]["qa_infos"][0]);
-        let tmp_res_vec: Vec<String> = vec![&res["code"], &res["mode"], &res["payload"]["qa_infos"][0]["que"]]
-            .into_iter().map(|v| v.to_string() ).collect();
-        let res_vec: Vec<&str> = tmp_res_vec.iter().map(|s| s.as_str()).collect();
-        let exp_que: String = "\"".to_string() + &que_sentence.as_str() + "\"";
-        let exp_vec = vec!["200", "\"predict\"", exp_que.as_str()];
-        assert_eq!(res_vec, exp_vec);
-    }
+/// テナントの学習データ・モデルで予測する
+fn predict(tenant: &tenant::Tenant, que_sentence: String, options: PredictOptions) -> Result<Value, AppError> {
+    predict_as(tenant, que_sentence, options, false)
+}
+
+/// stream 指定時 (API Gateway経由) は、候補を書き換える後処理が無ければ候補を1件ずつ body に書き出す (stream_body)
+fn predict_as(tenant: &tenant::Tenant, que_sentence: String, options: PredictOptions, stream: bool) -> Result<Value, AppError> {
+    check_language(&que_sentence)?;
+
+    let qa_data: QaData = read_csv(tenant, &tenant.path(INPUT_CSV_PATH))?;
+    // クエリの分かち書き・モデルの読み込みは1回のみ行い、検索・後処理で共有する
+    let tokens: Vec<String> = query_tokens(tenant, &que_sentence)?;
+    let model: Arc<engine::LoadedModel> = load_predict_model(tenant)?;
+
+    // 登録済みの質問そのままであればTF-IDFを計算せずに返す (しきい値に関わらず候補に残す)
+    if let Some(id) = find_exact_match(&qa_data, &que_sentence, options.category.as_deref()) {
+        let ans_vec: Vec<(usize, f64)> = vec![(id, 1.0)];
+        let threshold: f64 = applied_threshold(&ans_vec, &options);
+        let mut qa_info: response::QaInfo = make_qa_info(&que_sentence, &qa_data, id, 1.0, &options, &BandConfig::from_env());
+        qa_info.exact_match = true;
+        let mut qa_infos: Vec<response::QaInfo> = vec![qa_info];
+        rank_qa_infos(&mut qa_infos, &options);
+        return with_post_processing(tenant, predict_response(qa_infos, threshold, None), &qa_data, &model, &tokens, &options);
+    }
+
+    let mut ans_vec: Vec<(usize, f64)> = search_cached(tenant, &qa_data, &model, &que_sentence, &tokens, options.scorer, options.ensemble)?;
+    // 合成時も cos_val・しきい値には合成前のcos類似度を使い、合成スコアは並び順と ensemble_score のみに使う
+    let raw_cos: Option<HashMap<usize, f64>> = match options.ensemble {
+        Some(_) => Some(search_cached(tenant, &qa_data, &model, &que_sentence, &tokens, options.scorer, None)?.into_iter().collect()),
+        None => None,
+    };
+    // 語単位では一致しないタイポを文字trigramで補う
+    if let Some(weight) = options.typo_weight {
+        ans_vec = fuse_trigram(ans_vec, &que_sentence, &qa_data.que_vec, weight);
+    }
+    // 短すぎる候補質問を減点 (トークン数は学習時の単語リスト)
+    if options.length_penalty {
+        ans_vec = length::adjust_scores(ans_vec, &model.docs, &length::LengthPenaltyConfig::from_env());
+    }
+    // 否定表現の有無が食い違う候補を減点
+    ans_vec = negation::adjust_scores(&que_sentence, ans_vec, &qa_data.que_vec, &negation::NegationConfig::from_env());
+    if let Some(category) = &options.category {
+        ans_vec = filter_category(ans_vec, &qa_data, category)?;
+    }
+    // アクセス数は予測のたびに変わるため、キャッシュ後に反映する
+    if let Some(alpha) = options.popularity {
+        ans_vec = access::apply_popularity(ans_vec, &access::read_counts(&tenant.path(ACCESS_COUNT_PATH))?, alpha);
+    }
+    // 候補間の類似度は学習済みTF-IDFベクトルのcos類似度 (上位 MMR_TOP_K 件を並べ替え)
+    if let Some(lambda) = options.diversity {
+        let top_k: usize = config::env_or("MMR_TOP_K", DEFAULT_MMR_TOP_K);
+        ans_vec = mmr::rerank(&ans_vec, lambda, top_k, |a, b| model.tfidf.doc_cos(a, b));
+    }
+    let ensemble_scores: Option<Vec<(usize, f64)>> = raw_cos.map(|raw_cos| {
+        let ensemble_scores: Vec<(usize, f64)> = ans_vec.to_owned();
+        ans_vec = ans_vec.iter().map(|(id, _)| (*id, raw_cos.get(id).copied().unwrap_or(0.0))).collect();
+        ensemble_scores
+    });
+
+    // 記録に失敗しても予測結果は返す
+    if let Err(err) = log_unanswered(tenant, &que_sentence, &ans_vec, applied_threshold(&ans_vec, &options)) {
+        println!("error running unanswered: {}", err);
+    }
+
+    if stream && !rewrites_candidates(&options) {
+        return stream_body(tenant, &que_sentence, &qa_data, ans_vec, &options, &model, &tokens);
+    }
+    let fallback_contact: Option<response::FallbackContact> = response::FallbackContact::from_env();
+    let res_json: Value = make_json(que_sentence.to_owned(), &qa_data, ans_vec, &options, &BandConfig::from_env(), fallback_contact.as_ref());
+    let res_json: Value = with_ensemble_scores(res_json, ensemble_scores.as_deref());
+    with_post_processing(tenant, res_json, &qa_data, &model, &tokens, &options)
+}
+
+/// 候補ごとの値を Value 上で付与・付け直す後処理 (with_related など) を使うか
+fn rewrites_candidates(options: &PredictOptions) -> bool {
+    options.include_related || options.calibrate || options.matched_terms || options.cluster || options.ensemble.is_some()
+}
+
+/// with_post_processing と同じ付加情報を候補以外の部分 (head) に付け、候補は1件ずつ生成しながら API Gateway の body に書き出す
+/// 返す Value は code・success・mode と body (JSON文字列)、メトリクス用の候補のスコア (cos_vals) のみ
+/// 候補ゼロの場合は suggestions を付けるため、従来どおり Value にまとめて返す
+fn stream_body(tenant: &tenant::Tenant, que_sentence: &str, qa_data: &QaData, ans_vec: Vec<(usize, f64)>, options: &PredictOptions, model: &engine::LoadedModel, tokens: &[String]) -> Result<Value, AppError> {
+    let threshold: f64 = applied_threshold(&ans_vec, options);
+    let candidates: Vec<(usize, f64)> = select_candidates(qa_data, ans_vec, options, threshold);
+    let Some((doc_id, _)) = candidates.first() else {
+        let fallback_contact: Option<response::FallbackContact> = response::FallbackContact::from_env();
+        let res_json: Value = predict_response(Vec::new(), threshold, fallback_contact.as_ref());
+        return with_post_processing(tenant, res_json, qa_data, model, tokens, options);
+    };
+    record_access(tenant, *doc_id);
+    let cos_vals: Vec<f64> = candidates.iter().map(|(_, cos_val)| *cos_val).collect();
+    let head: Value = with_model_info(predict_response(Vec::new(), threshold, None), &tenant.path(MODEL_META_PATH))?;
+    let head: Value = with_query_keywords(with_query_coverage(head, tokens, &model.tfidf), tokens, &model.tfidf);
+    let head: Value = wrap_response(with_debug_info(head, tokens, &model.tfidf));
+    let band_config = BandConfig::from_env();
+    let predicted = predict_stream(que_sentence, qa_data, candidates, threshold, options, &band_config, None);
+    Ok(write_body(head, predicted.payload.qa_infos, cos_vals))
+}
+
+/// head (qa_infos が空の予測結果) の qa_infos に、qa_infos を書き出しながら差し込んだ body
+fn write_body(head: Value, qa_infos: impl Serialize, cos_vals: Vec<f64>) -> Value {
+    let head: serde_json::Map<String, Value> = match head {
+        Value::Object(head) => head,
+        _ => serde_json::Map::new(),
+    };
+    let body: String = serde_json::to_string(&response::StreamedPredict { head: &head, qa_infos }).unwrap();
+    json!({
+        "code": head["code"],
+        "success": head["success"],
+        "mode": head["mode"],
+        "body": body,
+        "cos_vals": cos_vals,
+    })
+}
+
+/// 候補の予測結果に、オプションで指定した付加情報を付与する (tokens は分かち書き済みのクエリ)
+fn with_post_processing(tenant: &tenant::Tenant, res_json: Value, qa_data: &QaData, model: &engine::LoadedModel, tokens: &[String], options: &PredictOptions) -> Result<Value, AppError> {
+    let res_json: Value = with_suggestions(res_json, qa_data, tenant)?;
+    let res_json: Value = with_related(res_json, qa_data, options.include_related, &model.tfidf);
+    let res_json: Value = with_calibration(res_json, options.calibrate, model.score_stats.as_ref());
+    let res_json: Value = with_matched_terms(res_json, tokens, options.matched_terms, &model.tfidf);
+    let res_json: Value = with_model_info(with_groups(res_json, options.cluster, &model.tfidf), &tenant.path(MODEL_META_PATH))?;
+    let res_json: Value = with_query_keywords(with_query_coverage(res_json, tokens, &model.tfidf), tokens, &model.tfidf);
+    Ok(with_debug_info(res_json, tokens, &model.tfidf))
+}
+
+/// スコア順の候補 (テナント・モデルのハッシュ・クエリ・検索方法ごとにキャッシュする)
+/// ensemble 指定時は scorer の検索と BM25 の合成スコア、tokens は que_sentence を分かち書きしたもの
+fn search_cached(tenant: &tenant::Tenant, qa_data: &QaData, model: &engine::LoadedModel, que_sentence: &str, tokens: &[String], scorer: Scorer, ensemble: Option<f64>) -> Result<Vec<(usize, f64)>, AppError> {
+    let feedback_path: String = tenant.path(FEEDBACK_CSV_PATH);
+    let mut query: String = cache::normalize_query(que_sentence);
+    if let Some(weight) = ensemble {
+        query = format!("{}#ensemble={}", query, weight); // 合成時は別の結果としてキャッシュ
+    }
+    if scorer == Scorer::Bm25 {
+        query = format!("{}#scorer=bm25", query);
+    }
+    if let Ok(feedback_hash) = cache::model_hash(&feedback_path) {
+        query = format!("{}#feedback={}", query, feedback_hash); // フィードバック更新後は再計算
+    }
+    if let Some(ans_vec) = cache::lock().get(tenant.id(), model.hash, &query) {
+        return Ok(ans_vec);
+    }
+    let (docs, tfidf) = (&model.docs, &model.tfidf);
+    check_doc_count(qa_data, docs, tfidf)?;
+    let tfidf: Box<dyn Retriever + '_> = match scorer {
+        Scorer::TfIdf => {
+            let feedback: Vec<feedback::Feedback> = read_feedback_csv(&feedback_path)?.into_iter()
+                .map(|(que, doc_id)| Ok(feedback::Feedback { query: query_tokens(tenant, &que)?, doc_id }))
+                .collect::<Result<_, AppError>>()?;
+            if feedback.is_empty() {
+                Box::new(tfidf)
+            } else {
+                Box::new(feedback::FeedbackRetriever { tfidf, feedback, config: feedback::FeedbackConfig::from_env() })
+            }
+        },
+        Scorer::Bm25 => {
+            // 出現回数を保存していない旧形式のモデルは単語リストから集計する
+            let counts: count::CountMatrix = read_count_csv(&tenant.path(COUNT_MATRIX_PATH), docs.len())?
+                .unwrap_or_else(|| count::CountMatrix::from_docs(docs));
+            Box::new(bm25::CountBm25 { bm25: bm25::Bm25::default(), counts })
+        },
+    };
+    let retriever: Box<dyn Retriever + '_> = match ensemble {
+        Some(weight) => Box::new(ensemble::Ensemble {
+            a: tfidf,
+            b: Box::new(bm25::Bm25::default()),
+            weight,
+        }),
+        None => tfidf,
+    };
+    let ans_vec: Vec<(usize, f64)> = retriever.search(docs, tokens);
+    cache::lock().put(tenant.id(), model.hash, query, ans_vec.to_owned());
+    Ok(ans_vec)
+}
+
+/// 呼び出し元のテナントの各データセットのモデルで検索し、候補をスコア順に統合する (同スコアは datasets の順)
+/// 他のテナントのデータセットは同じ名前でも検索しない (無いデータセットは AppError::UnknownDataset)
+/// cos類似度はデータセットごとのTF-IDF空間で長さを正規化した値 (0〜1) のため、データセット間でそのまま比較する
+/// しきい値・min_results・max_results は全データセットの候補を統合してから適用する
+fn predict_datasets(tenant: &tenant::Tenant, que_sentence: &str, datasets: &[String], storage: Option<&dyn storage::Storage>, lock_timeout: std::time::Duration, options: &PredictOptions) -> Result<Value, AppError> {
+    check_language(que_sentence)?;
+    // 各データセットの学習データをつなげ、つなげた後の文書IDで候補を扱う
+    let mut qa_data: QaData = QaData::default();
+    let mut origins: Vec<(&str, usize)> = Vec::new(); // つなげた後の文書IDごとの (データセット名, データセット内の文書ID)
+    let mut candidates: Vec<(usize, f64, Option<f64>)> = Vec::new(); // (文書ID, cos類似度, キャリブレーション後のスコア)
+    for dataset in datasets {
+        let workspace: tenant::Tenant = tenant.dataset(dataset);
+        // 他のインスタンスで学習したデータセットは保存先から取得する
+        let found: Result<(), AppError> = match storage {
+            Some(storage) => fetch_model(storage, &workspace, lock_timeout).and_then(|_| require_model(&workspace)),
+            None => require_model(&workspace),
+        };
+        if let Err(AppError::ModelNotFound(_)) = found {
+            return Err(AppError::UnknownDataset(dataset.to_owned()));
+        }
+        found?;
+        let _lock = lock::ModelLock::shared(&workspace.path(MODEL_LOCK_PATH), lock_timeout)?;
+        let dataset_qa: QaData = read_csv(&workspace, &workspace.path(INPUT_CSV_PATH))?;
+        // 学習時と同じ前処理で分かち書きする (データセットごとに構成が異なり得る)
+        let tokens: Vec<String> = query_tokens(&workspace, que_sentence)?;
+        let model: Arc<engine::LoadedModel> = load_predict_model(&workspace)?;
+        let ans_vec: Vec<(usize, f64)> = search_cached(&workspace, &dataset_qa, &model, que_sentence, &tokens, Scorer::TfIdf, None)?;
+        let ans_vec: Vec<(usize, f64)> = negation::adjust_scores(que_sentence, ans_vec, &dataset_qa.que_vec, &negation::NegationConfig::from_env());
+        // データセットごとの統計でキャリブレーションし、データセット間でスコアを比較できるようにする
+        let score_stats: Option<&calibration::ScoreStats> = model.score_stats.as_ref().filter(|_| options.calibrate);
+        let (offset, len) = (qa_data.que_vec.len(), dataset_qa.que_vec.len().min(dataset_qa.ans_vec.len()));
+        candidates.extend(ans_vec.into_iter()
+            .filter(|(id, _)| *id < len)
+            .map(|(id, cos_val)| (offset + id, cos_val, score_stats.map(|stats| calibration::calibrate_score(cos_val, stats)))));
+        qa_data.que_vec.extend(dataset_qa.que_vec.into_iter().take(len));
+        qa_data.ans_vec.extend(dataset_qa.ans_vec.into_iter().take(len));
+        qa_data.cat_vec.resize(offset, String::new());
+        qa_data.cat_vec.extend(dataset_qa.cat_vec.into_iter().take(len));
+        qa_data.answers.resize(offset, HashMap::new());
+        qa_data.answers.extend(dataset_qa.answers.into_iter().take(len));
+        origins.extend((0..len).map(|id| (dataset.as_str(), id)));
+    }
+
+    // キャリブレーションした候補はキャリブレーション後のスコアで並べる
+    candidates.sort_by(|a, b| b.2.unwrap_or(b.1).total_cmp(&a.2.unwrap_or(a.1)));
+    let calibrated: HashMap<usize, f64> = candidates.iter().filter_map(|(id, _, score)| Some((*id, (*score)?))).collect();
+    let ans_vec: Vec<(usize, f64)> = candidates.into_iter().map(|(id, cos_val, _)| (id, cos_val)).collect();
+    let threshold: f64 = applied_threshold(&ans_vec, options);
+    let mut qa_infos: Vec<response::QaInfo> = make_qa_infos(que_sentence, &qa_data, ans_vec, options, &BandConfig::from_env(), threshold);
+    for qa_info in qa_infos.iter_mut() {
+        let (dataset, doc_id) = origins[qa_info.doc_id];
+        qa_info.calibrated_score = calibrated.get(&qa_info.doc_id).copied();
+        qa_info.dataset = Some(dataset.to_owned());
+        qa_info.doc_id = doc_id;
+    }
+    let fallback_contact: Option<response::FallbackContact> = response::FallbackContact::from_env();
+    Ok(predict_response(qa_infos, threshold, fallback_contact.as_ref()))
+}
+
+/// しきい値を超える候補が無いクエリを、QA追加の検討用にテナントの未回答ログへ記録する (記録した場合はtrue)
+fn log_unanswered(tenant: &tenant::Tenant, que_sentence: &str, ans_vec: &[(usize, f64)], threshold: f64) -> Result<bool, AppError> {
+    let max_score: f64 = ans_vec.iter().map(|(_, score)| *score).fold(0.0, f64::max);
+    if max_score > threshold {
+        return Ok(false);
+    }
+    let timestamp: u64 = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    with_stats_lock(tenant, || unanswered::record(&tenant.path(UNANSWERED_PATH), que_sentence, max_score, timestamp))?;
+    Ok(true)
+}
+
+/// 予測時に書き換えるアクセス数・未回答ログは、モデルの共有ロック中に並行して更新され得るため排他ロックを取る
+/// (モデルのロックとは別のテナントごとのファイルで、他のテナントの予測は待たない、待ち時間の上限は MODEL_LOCK_TIMEOUT_MS)
+fn with_stats_lock<T>(tenant: &tenant::Tenant, f: impl FnOnce() -> Result<T, AppError>) -> Result<T, AppError> {
+    let lock_timeout = std::time::Duration::from_millis(config::env_or("MODEL_LOCK_TIMEOUT_MS", DEFAULT_MODEL_LOCK_TIMEOUT_MS));
+    let _lock = lock::ModelLock::exclusive(&tenant.path(STATS_LOCK_PATH), lock_timeout)?;
+    f()
+}
+
+/// 質問との文字trigramのJaccardを (1 - weight) * cos + weight * jaccard で合成し、スコア順に並べ直す (同スコアは元の順)
+fn fuse_trigram(ans_vec: Vec<(usize, f64)>, que_sentence: &str, que_vec: &[String], weight: f64) -> Vec<(usize, f64)> {
+    let mut fused: Vec<(usize, f64)> = ans_vec.into_iter()
+        .map(|(id, cos_val)| {
+            let jaccard: f64 = que_vec.get(id).map_or(0.0, |que| distance::trigram_jaccard(que_sentence, que));
+            (id, (1.0 - weight) * cos_val + weight * jaccard)
+        })
+        .collect();
+    fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    fused
+}
+
+/// 先頭の候補のアクセス数を記録する (記録に失敗しても予測結果は返す)
+fn record_access(tenant: &tenant::Tenant, doc_id: usize) {
+    if let Err(err) = with_stats_lock(tenant, || access::increment(&tenant.path(ACCESS_COUNT_PATH), doc_id)) {
+        println!("error running access: {}", err);
+    }
+}
+
+/// 学習時に記録したモデル情報を model_info として付与 (記録の無い旧モデルはnull)
+fn with_model_info(mut res_json: Value, info_path: &str) -> Result<Value, AppError> {
+    res_json["payload"]["model_info"] = response::to_value(&read_model_info(info_path)?);
+    Ok(res_json)
+}
+
+/// cluster 指定時は、候補を質問のTF-IDFベクトルのcos類似度でグループ化した groups を付与
+/// グループ化のしきい値は環境変数 CLUSTER_THRESHOLD
+fn with_groups(mut res_json: Value, cluster: bool, tfidf: &tf_idf::TfIdf) -> Value {
+    if !cluster {
+        return res_json;
+    }
+    let threshold: f64 = config::env_or("CLUSTER_THRESHOLD", DEFAULT_CLUSTER_THRESHOLD);
+    let groups: Vec<response::CandidateGroup> = group_candidates(&res_json["payload"]["qa_infos"], threshold, |a, b| tfidf.doc_cos(a, b));
+    res_json["payload"]["groups"] = response::to_value(&groups);
+    res_json
+}
+
+/// calibrate 指定時は、各候補のcos類似度をバンドルの自己検索スコアの分布で写した calibrated_score を付与
+/// 統計を記録する前のモデルでは付与しない
+fn with_calibration(mut res_json: Value, calibrate: bool, stats: Option<&calibration::ScoreStats>) -> Value {
+    let Some(stats) = stats.filter(|_| calibrate) else {
+        return res_json;
+    };
+    if let Some(qa_infos) = res_json["payload"]["qa_infos"].as_array_mut() {
+        for qa_info in qa_infos {
+            let Some(cos_val) = qa_info["cos_val"].as_f64() else { continue };
+            qa_info["calibrated_score"] = json!(calibration::calibrate_score(cos_val, stats));
+        }
+    }
+    res_json
+}
+
+/// ensemble 指定時は、各候補に合成スコア ensemble_score を付与し、順位・パーセンタイルを合成スコアで付け直す
+/// (候補は合成スコア順、cos_val は合成前のcos類似度)
+fn with_ensemble_scores(mut res_json: Value, ensemble_scores: Option<&[(usize, f64)]>) -> Value {
+    let (Some(ensemble_scores), Some(qa_infos)) = (ensemble_scores, res_json["payload"]["qa_infos"].as_array_mut()) else {
+        return res_json;
+    };
+    let scores: HashMap<usize, f64> = ensemble_scores.iter().copied().collect();
+    let score_of = |qa_info: &Value| -> f64 {
+        qa_info["doc_id"].as_u64().and_then(|id| scores.get(&(id as usize)).copied()).unwrap_or(0.0)
+    };
+    let score_vals: Vec<f64> = qa_infos.iter().map(score_of).collect();
+    for (qa_info, score) in qa_infos.iter_mut().zip(&score_vals) {
+        let (rank, percentile) = rank_percentile(*score, &score_vals);
+        qa_info["ensemble_score"] = json!(score);
+        qa_info["rank"] = json!(rank);
+        qa_info["percentile"] = json!(percentile);
+    }
+    res_json
+}
+
+/// matched_terms 指定時は、各候補にクエリと共通の語のTF-IDF寄与 (cos類似度の内積を語ごとに分解した値) を重みの大きい順に付与
+/// 重みの合計は学習済みTF-IDFのcos類似度で、否定表現の減点などの調整後の cos_val とは一致しない場合がある
+fn with_matched_terms(mut res_json: Value, tokens: &[String], matched_terms: bool, tfidf: &tf_idf::TfIdf) -> Value {
+    if !matched_terms {
+        return res_json;
+    }
+    let query_vec: HashMap<usize, f64> = tfidf.query_vector(tokens);
+    if let Some(qa_infos) = res_json["payload"]["qa_infos"].as_array_mut() {
+        for qa_info in qa_infos {
+            let Some(doc_id) = qa_info["doc_id"].as_u64() else { continue };
+            let terms: Vec<response::MatchedTerm> = tfidf.term_contributions(&query_vec, doc_id as usize).into_iter()
+                .map(|(term, weight)| response::MatchedTerm { term, weight })
+                .collect();
+            qa_info["matched_terms"] = response::to_value(&terms);
+        }
+    }
+    res_json
+}
+
+/// 各候補に、候補質問とのcos類似度が高い他のQA (件数は環境変数 RELATED_COUNT) を related として付与
+fn with_related(mut res_json: Value, qa_data: &QaData, include_related: bool, tfidf: &tf_idf::TfIdf) -> Value {
+    if !include_related {
+        return res_json;
+    }
+    let n: usize = config::env_or("RELATED_COUNT", DEFAULT_RELATED_COUNT);
+    if let Some(qa_infos) = res_json["payload"]["qa_infos"].as_array_mut() {
+        for qa_info in qa_infos {
+            let Some(doc_id) = qa_info["doc_id"].as_u64() else { continue };
+            let related: Vec<response::RelatedQa> = related_qas(doc_id as usize, qa_data, n, |a, b| tfidf.doc_cos(a, b));
+            qa_info["related"] = response::to_value(&related);
+        }
+    }
+    res_json
+}
+
+/// 類似度が0より大きい他の文書を類似度の高い順に最大n件 (自分自身は除く、同スコアは文書順)
+fn related_qas<F>(doc_id: usize, qa_data: &QaData, n: usize, sim: F) -> Vec<response::RelatedQa>
+where
+    F: Fn(usize, usize) -> f64,
+{
+    let mut scored: Vec<(usize, f64)> = (0..qa_data.que_vec.len())
+        .filter(|&id| id != doc_id)
+        .map(|id| (id, sim(doc_id, id)))
+        .filter(|(_, score)| *score > 0.0)
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored.into_iter()
+        .take(n)
+        .map(|(id, _)| response::RelatedQa { que: qa_data.que_vec[id].to_owned(), ans: qa_data.ans_vec[id].to_owned() })
+        .collect()
+}
+
+/// 各グループの先頭 (最もスコアの高い候補) を representative とし、残りを members とする
+fn group_candidates<F>(qa_infos: &Value, threshold: f64, sim: F) -> Vec<response::CandidateGroup>
+where
+    F: Fn(usize, usize) -> f64,
+{
+    let qa_infos: &[Value] = qa_infos.as_array().map(|v| v.as_slice()).unwrap_or(&[]);
+    let ids: Vec<usize> = (0..qa_infos.len()).collect();
+    let doc_id = |index: usize| qa_infos[index]["doc_id"].as_u64().unwrap_or(0) as usize;
+    cluster::agglomerate(&ids, threshold, |a, b| sim(doc_id(a), doc_id(b)))
+        .into_iter()
+        .map(|group| response::CandidateGroup {
+            representative: qa_infos[group[0]].to_owned(),
+            members: group[1..].iter().map(|&index| qa_infos[index].to_owned()).collect(),
+        })
+        .collect()
+}
+
+/// クエリの語彙ヒット率を query_coverage として付与
+fn with_query_coverage(mut res_json: Value, tokens: &[String], tfidf: &tf_idf::TfIdf) -> Value {
+    let coverage: response::QueryCoverage = query_coverage(tokens, &tfidf.word_vec);
+    res_json["payload"]["query_coverage"] = response::to_value(&coverage);
+    res_json
+}
+
+/// vocab は辞書順 (学習済みモデルの語彙)
+fn query_coverage(tokens: &[String], vocab: &[String]) -> response::QueryCoverage {
+    let matched: usize = tokens.iter().filter(|token| vocab.binary_search(token).is_ok()).count();
+    let ratio: f64 = if tokens.is_empty() { 0.0 } else { matched as f64 / tokens.len() as f64 };
+    response::QueryCoverage { total: tokens.len(), matched, ratio }
+}
+
+/// クエリのトークンのうち学習データでのIDFが大きい (希少な) 語を query_keywords として付与
+/// 件数・ストップワードは環境変数 QUERY_KEYWORD_COUNT・QUERY_KEYWORD_STOP_WORDS
+fn with_query_keywords(mut res_json: Value, tokens: &[String], tfidf: &tf_idf::TfIdf) -> Value {
+    let config = keyword::KeywordConfig::from_env();
+    let keywords: Vec<String> = keyword::extract(tokens, |word| tfidf.word_idf(word), &config);
+    res_json["payload"]["query_keywords"] = response::to_value(&keywords);
+    res_json
+}
+
+/// 先頭の候補のアクセス数を記録し、候補ゼロの場合はアクセス数上位のQA (件数は環境変数 SUGGESTION_COUNT) を suggestions として付与
+/// アクセス数の記録に失敗しても予測結果は返す
+fn with_suggestions(mut res_json: Value, qa_data: &QaData, tenant: &tenant::Tenant) -> Result<Value, AppError> {
+    if let Some(doc_id) = res_json["payload"]["qa_infos"][0]["doc_id"].as_u64() {
+        record_access(tenant, doc_id as usize);
+        return Ok(res_json);
+    }
+    let n: usize = config::env_or("SUGGESTION_COUNT", DEFAULT_SUGGESTION_COUNT);
+    let suggestions: Vec<response::Suggestion> = access::top_ids(&access::read_counts(&tenant.path(ACCESS_COUNT_PATH))?, qa_data.que_vec.len(), n)
+        .into_iter()
+        .map(|id| response::Suggestion { doc_id: id, que: qa_data.que_vec[id].to_owned(), ans: qa_data.ans_vec[id].to_owned() })
+        .collect();
+    res_json["payload"]["suggestions"] = response::to_value(&suggestions);
+    Ok(res_json)
+}
+
+/// 文に分割し、文ごとの予測結果をまとめて返す (1文のみの場合は通常の予測と同じ)
+fn predict_split(tenant: &tenant::Tenant, que_sentence: String, options: PredictOptions) -> Result<Value, AppError> {
+    let sentences: Vec<String> = sentence::split_sentences(&que_sentence);
+    if sentences.len() <= 1 {
+        return predict(tenant, que_sentence, options);
+    }
+    let options = PredictOptions { split_query: false, ..options };
+    let groups: Vec<response::QueryGroup> = sentences.into_iter()
+        .map(|sentence| {
+            let mut res_json: Value = predict(tenant, sentence.to_owned(), options.clone())?;
+            Ok(response::QueryGroup { que_sentence: sentence, payload: res_json["payload"].take() })
+        })
+        .collect::<Result<_, AppError>>()?;
+    Ok(response::to_value(&response::SplitPredictResponse {
+        code: 200,
+        success: true,
+        mode: ExecMode::PREDICT,
+        payload: response::SplitPredictPayload { groups },
+    }))
+}
+
+/// 学習時と同じ前処理パイプラインでクエリをトークン化
+/// 上限時間 (環境変数 TOKENIZE_TIMEOUT_MS) を超えた場合は AppError::Timeout
+fn query_tokens(tenant: &tenant::Tenant, que_sentence: &str) -> Result<Vec<String>, AppError> {
+    let pipeline = {
+        let mut engine = engine::lock(tenant.id());
+        load_pipeline(&mut engine, tenant)?.clone()
+    };
+    let timeout_ms: u64 = config::env_or("TOKENIZE_TIMEOUT_MS", DEFAULT_TOKENIZE_TIMEOUT_MS);
+    pipeline.run_with_timeout(que_sentence.to_owned(), std::time::Duration::from_millis(timeout_ms))
+}
+
+/// 学習時の前処理パイプラインを取得
+/// メモリ上に無ければバンドル・モデルメタの順に復元し、どちらも無い旧形式のモデルは環境変数の設定を使う
+fn load_pipeline<'a>(engine: &'a mut engine::QaEngine, tenant: &tenant::Tenant) -> Result<&'a pipeline::Pipeline, AppError> {
+    if engine.pipeline().is_none() {
+        let bundle_path: String = tenant.path(BUNDLE_PATH);
+        let pipeline = if Path::new(&bundle_path).exists() {
+            bundle::load_bundle(&bundle_path)?.into_parts()?.2
+        } else {
+            pipeline::Pipeline::read_meta(&tenant.path(MODEL_META_PATH))?.unwrap_or_else(pipeline::Pipeline::from_env)
+        };
+        engine.set_pipeline(pipeline);
+    }
+    Ok(engine.pipeline().unwrap())
+}
+
+/// バンドルが無い旧形式 (モデル・単語リスト・モデルメタの別ファイル) の場合はバンドルへ移行する
+/// バンドルか、バンドルへ移行できるモデルのCSVがあるか
+fn has_model(bundle_path: &str, model_csv_path: &str) -> bool {
+    Path::new(bundle_path).exists() || Path::new(model_csv_path).exists()
+}
+
+fn ensure_bundle(tenant: &tenant::Tenant) -> Result<(), AppError> {
+    let bundle_path: String = tenant.path(BUNDLE_PATH);
+    if !Path::new(&bundle_path).exists() {
+        println!("migrate model files to {}", bundle_path);
+        bundle::migrate(&tenant.path(&model_csv_path()), &tenant.path(WORD_LIST_CSV_PATH), &tenant.path(MODEL_META_PATH), &bundle_path)?;
+    }
+    Ok(())
+}
+
+/// テナントの予測に使う単語リストと学習済みモデル (読み込み済みでなければバンドルから読んで保持する)
+fn load_predict_model(tenant: &tenant::Tenant) -> Result<Arc<engine::LoadedModel>, AppError> {
+    if let Some(model) = engine::lock(tenant.id()).loaded() {
+        return Ok(model);
+    }
+    // バンドルの読み込み中は学習結果のロックを取らない
+    ensure_bundle(tenant)?;
+    let bytes: Vec<u8> = std::fs::read(tenant.path(BUNDLE_PATH))?;
+    let bundle: bundle::Bundle = bundle::Bundle::from_slice(&bytes)?;
+    let score_stats: Option<calibration::ScoreStats> = bundle.score_stats().cloned();
+    let (tfidf, docs, _) = bundle.into_parts()?;
+    let model: Arc<engine::LoadedModel> = Arc::new(engine::LoadedModel { docs, tfidf, score_stats, hash: cache::hash_bytes(&bytes) });
+    engine::lock(tenant.id()).set_loaded(Arc::clone(&model));
+    Ok(model)
+}
+
+/// 環境変数 DEBUG_MODE が明示的に有効 ("1" または "true") な場合のみ中間データを付与
+fn with_debug_info(mut res_json: Value, tokens: &[String], tfidf: &tf_idf::TfIdf) -> Value {
+    if !is_debug_mode(std::env::var("DEBUG_MODE").ok().as_deref()) {
+        return res_json;
+    }
+    let debug: response::DebugInfo = make_debug_info(tokens.to_vec(), tfidf);
+    res_json["debug"] = response::to_value(&debug);
+    res_json
+}
+
+fn is_debug_mode(value: Option<&str>) -> bool {
+    matches!(value.map(str::trim), Some("1") | Some("true"))
+}
+
+fn make_debug_info(query_tokens: Vec<String>, tfidf: &tf_idf::TfIdf) -> response::DebugInfo {
+    let query_tfidf_nonzero: BTreeMap<String, f64> = tfidf.query_vector(&query_tokens).into_iter()
+        .map(|(index, val)| (tfidf.word_vec[index].to_owned(), val))
+        .collect();
+    response::DebugInfo { query_tokens, query_tfidf_nonzero }
+}
+
+/// 学習データ・単語リスト・モデルの文書数が一致するか検証
+fn check_doc_count(qa_data: &QaData, docs: &[Vec<String>], tfidf: &tf_idf::TfIdf) -> Result<(), AppError> {
+    let (qa_data, word_list, model) = (qa_data.que_vec.len().min(qa_data.ans_vec.len()), docs.len(), tfidf.tf_idf_vec.len());
+    if qa_data != word_list || qa_data != model {
+        return Err(AppError::DocCountMismatch { qa_data, word_list, model });
+    }
+    Ok(())
+}
+
+/// 正規化後の質問文が学習データの質問と完全一致する文書を探す (カテゴリ指定時はそのカテゴリ内のみ)
+fn find_exact_match(qa_data: &QaData, que_sentence: &str, category: Option<&str>) -> Option<usize> {
+    let query: String = cache::normalize_query(que_sentence);
+    qa_data.que_vec.iter().enumerate()
+        .filter(|(id, _)| category.is_none_or(|c| qa_data.cat_vec[*id] == c))
+        .find(|(_, que)| cache::normalize_query(que) == query)
+        .map(|(id, _)| id)
+}
+
+/// 指定カテゴリの文書のみに候補を限定
+fn filter_category(ans_vec: Vec<(usize, f64)>, qa_data: &QaData, category: &str) -> Result<Vec<(usize, f64)>, AppError> {
+    if !qa_data.cat_vec.iter().any(|c| c == category) {
+        return Err(AppError::UnknownCategory(category.to_string()));
+    }
+    let filtered: Vec<(usize, f64)> = ans_vec.into_iter()
+        .filter(|(id, _)| qa_data.cat_vec.get(*id).is_some_and(|c| c == category))
+        .collect();
+    Ok(filtered)
+}
+
+/// 信頼度バンドの境界 (各境界値ちょうどは上位のバンドに含める)
+#[derive(Debug)]
+struct BandConfig {
+    high: f64,
+    medium: f64,
+}
+
+impl Default for BandConfig {
+    fn default() -> Self {
+        Self { high: 0.6, medium: 0.4 }
+    }
+}
+
+impl BandConfig {
+    /// 環境変数 BAND_HIGH, BAND_MEDIUM で境界を上書き
+    fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            high: config::env_or("BAND_HIGH", default.high),
+            medium: config::env_or("BAND_MEDIUM", default.medium),
+        }
+    }
+
+    fn band(&self, cos_val: f64) -> &'static str {
+        if cos_val >= self.high {
+            "high"
+        } else if cos_val >= self.medium {
+            "medium"
+        } else {
+            "low"
+        }
+    }
+}
+
+/// 後処理 (with_*) で候補を書き換えるため、予測結果は Value にまとめて返す
+fn make_json(que_sentence: String, qa_data: &QaData, ans_vec: Vec<(usize, f64)>, options: &PredictOptions, band_config: &BandConfig, fallback_contact: Option<&response::FallbackContact>) -> Value {
+    let threshold: f64 = applied_threshold(&ans_vec, options);
+    let candidates: Vec<(usize, f64)> = select_candidates(qa_data, ans_vec, options, threshold);
+    response::to_value(&predict_stream(&que_sentence, qa_data, candidates, threshold, options, band_config, fallback_contact))
+}
+
+/// make_json と同じ内容の予測結果を、選んだ候補 (select_candidates の結果) から1件ずつ生成しながら書き出すもの
+/// serde_json::to_writer などのシリアライザに直接渡した場合 (stream_body) のみ、全候補をまとめて保持しない
+fn predict_stream<'a>(que_sentence: &'a str, qa_data: &'a QaData, candidates: Vec<(usize, f64)>, threshold: f64, options: &'a PredictOptions, band_config: &'a BandConfig, fallback_contact: Option<&response::FallbackContact>)
+    -> response::PredictResponse<response::QaInfoSeq<impl Fn(usize) -> response::QaInfo + 'a>> {
+    // 順位・確率は返す候補群のスコアのみから決まるため、先に求めておく
+    let cos_vals: Vec<f64> = candidates.iter().map(|(_, cos_val)| *cos_val).collect();
+    let probabilities: Option<Vec<f64>> = options.softmax.map(|temperature| softmax(&cos_vals, temperature));
+    let fallback_contact: Option<response::FallbackContact> = fallback_contact.filter(|_| candidates.is_empty()).cloned();
+    let len: usize = candidates.len();
+    let qa_info = move |i: usize| {
+        let (id, cos_val) = candidates[i];
+        let mut qa_info: response::QaInfo = make_qa_info(que_sentence, qa_data, id, cos_val, options, band_config);
+        (qa_info.rank, qa_info.percentile) = rank_percentile(cos_val, &cos_vals);
+        qa_info.probability = probabilities.as_ref().map(|probabilities| probabilities[i]);
+        qa_info
+    };
+    response::PredictResponse {
+        code: 200,
+        success: true,
+        mode: ExecMode::PREDICT,
+        payload: response::PredictPayload { qa_infos: response::QaInfoSeq { len, qa_info }, applied_threshold: threshold, fallback_contact },
+    }
+}
+
+/// 候補に採用するスコアのしきい値 (指定が無ければ既定値)
+fn applied_threshold(ans_vec: &[(usize, f64)], options: &PredictOptions) -> f64 {
+    if options.auto_threshold {
+        let cos_vals: Vec<f64> = ans_vec.iter().map(|(_, cos_val)| *cos_val).collect();
+        return auto_threshold(&cos_vals);
+    }
+    options.threshold.unwrap_or(DEFAULT_THRESHOLD)
+}
+
+/// 全候補スコアの平均+標準偏差をしきい値とする (極端な値にならないよう範囲内に収める)
+fn auto_threshold(cos_vals: &[f64]) -> f64 {
+    if cos_vals.is_empty() {
+        return DEFAULT_THRESHOLD;
+    }
+    let n: f64 = cos_vals.len() as f64;
+    let mean: f64 = cos_vals.iter().sum::<f64>() / n;
+    let std_dev: f64 = (cos_vals.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+    (mean + std_dev).clamp(AUTO_THRESHOLD_RANGE.0, AUTO_THRESHOLD_RANGE.1)
+}
+
+/// ans_vec はスコア順 (しきい値超えが min_results 未満の場合は、続く候補で補う)
+fn make_qa_infos(que_sentence: &str, qa_data: &QaData, ans_vec: Vec<(usize, f64)>, options: &PredictOptions, band_config: &BandConfig, threshold: f64) -> Vec<response::QaInfo> {
+    let mut qa_infos: Vec<response::QaInfo> = select_candidates(qa_data, ans_vec, options, threshold).into_iter()
+        .map(|(id, cos_val)| make_qa_info(que_sentence, qa_data, id, cos_val, options, band_config))
+        .collect();
+    rank_qa_infos(&mut qa_infos, options);
+    qa_infos
+}
+
+/// しきい値超え (足りなければ min_results 件まで補う) の候補を max_results 件まで選ぶ
+fn select_candidates(qa_data: &QaData, ans_vec: Vec<(usize, f64)>, options: &PredictOptions, threshold: f64) -> Vec<(usize, f64)> {
+    let mut candidates: Vec<(usize, f64)> = Vec::new();
+    let min_results: usize = options.min_results.unwrap_or(0);
+    for (id, cos_val) in ans_vec {
+        // 学習データに存在しない文書IDは返さない
+        if id >= qa_data.ans_vec.len() || id >= qa_data.que_vec.len() {
+            continue;
+        }
+        if cos_val > threshold || candidates.len() < min_results {
+            candidates.push((id, cos_val));
+        }
+    }
+    if let Some(max_results) = options.max_results {
+        candidates.truncate(max_results);
+    }
+    candidates
+}
+
+/// 候補1件分 (順位・パーセンタイル・確率は候補群から後で付ける)
+fn make_qa_info(que_sentence: &str, qa_data: &QaData, id: usize, cos_val: f64, options: &PredictOptions, band_config: &BandConfig) -> response::QaInfo {
+    let similar_que: &String = &qa_data.que_vec[id];
+    let ans: &String = options.lang.as_ref()
+        .and_then(|lang| qa_data.answers.get(id)?.get(lang))
+        .unwrap_or(&qa_data.ans_vec[id]);
+    response::QaInfo {
+        que: que_sentence.to_owned(),
+        ans: ans.to_owned(),
+        cos_val,
+        similar_que: similar_que.to_owned(),
+        band: band_config.band(cos_val),
+        doc_id: id,
+        edit_distance: distance::normalized_levenshtein(que_sentence, similar_que),
+        formatted_answer: options.format_template.as_ref().map(|template| format_answer(template, ans)),
+        snippet: options.snippet_len.map(|len| make_snippet(ans, len)),
+        exact_match: false,
+        rank: 0,
+        percentile: 0.0,
+        probability: None,
+        calibrated_score: None,
+        dataset: None,
+        ensemble_score: None,
+    }
+}
+
+/// スコア順の候補を max_results 件に切り詰め、順位・パーセンタイル・softmax の確率を付ける
+fn rank_qa_infos(qa_infos: &mut Vec<response::QaInfo>, options: &PredictOptions) {
+    if let Some(max_results) = options.max_results {
+        qa_infos.truncate(max_results);
+    }
+    let cos_vals: Vec<f64> = qa_infos.iter().map(|info| info.cos_val).collect();
+    for qa_info in qa_infos.iter_mut() {
+        (qa_info.rank, qa_info.percentile) = rank_percentile(qa_info.cos_val, &cos_vals);
+    }
+    if let Some(temperature) = options.softmax {
+        for (qa_info, probability) in qa_infos.iter_mut().zip(softmax(&cos_vals, temperature)) {
+            qa_info.probability = Some(probability);
+        }
+    }
+}
+
+/// 温度付きsoftmax (合計1、全て同スコアなら一様)
+/// 桁あふれしないよう最大値を引いてから指数を取る
+fn softmax(scores: &[f64], temperature: f64) -> Vec<f64> {
+    let max: f64 = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let exps: Vec<f64> = scores.iter().map(|score| ((score - max) / temperature).exp()).collect();
+    let sum: f64 = exps.iter().sum();
+    exps.into_iter().map(|exp| exp / sum).collect()
+}
+
+/// 返す候補群の中での順位 (1始まり、同点は同順位) とパーセンタイル (自分以下のスコアの割合、1件なら100)
+fn rank_percentile(cos_val: f64, cos_vals: &[f64]) -> (usize, f64) {
+    let rank: usize = cos_vals.iter().filter(|v| **v > cos_val).count() + 1;
+    let below: usize = cos_vals.iter().filter(|v| **v <= cos_val).count();
+    (rank, below as f64 / cos_vals.len() as f64 * 100.0)
+}
+
+/// 候補ゼロの場合のみ問い合わせ先を含める
+fn predict_response(qa_infos: Vec<response::QaInfo>, applied_threshold: f64, fallback_contact: Option<&response::FallbackContact>) -> Value {
+    let fallback_contact: Option<response::FallbackContact> = fallback_contact.filter(|_| qa_infos.is_empty()).cloned();
+    response::to_value(&response::PredictResponse {
+        code: 200,
+        success: true,
+        mode: ExecMode::PREDICT,
+        payload: response::PredictPayload { qa_infos, applied_threshold, fallback_contact },
+    })
+}
+
+/// 回答の先頭 len 書記素を切り出す (切り詰めた場合は末尾に…を付ける)
+fn make_snippet(ans: &str, len: usize) -> String {
+    let graphemes: Vec<&str> = ans.graphemes(true).collect();
+    if graphemes.len() <= len {
+        return ans.to_string();
+    }
+    graphemes[..len].concat() + "…"
+}
+
+/// テンプレートの {answer} を回答に置き換える
+fn format_answer(template: &str, ans: &str) -> String {
+    template.replace("{answer}", ans)
+}
+
+/// 学習・予測共通のトークナイズ
+/// 環境変数 STRIP_MARKUP=true でHTMLタグ・Markdown記法を除去してから分割する
+/// 環境変数 NORMALIZE_WIDTH=true で全角英数字・記号を半角に揃え、数字の桁区切りを除いてから分割する
+/// 環境変数 NORMALIZE_POLITENESS=true で文末の敬体を常体へ寄せてから分割する
+/// 環境変数 POS_FILTER=true で名詞・動詞・形容詞のみ残す
+/// 環境変数 LEMMATIZE=true で活用語尾を原形へ寄せる (学習時と予測時で同じ設定にすること)
+#[derive(Debug, Default)]
+struct QaData {
+    que_vec: Vec<String>,
+    ans_vec: Vec<String>, // デフォルト言語の回答
+    cat_vec: Vec<String>, // カテゴリ列が無い場合は空文字
+    answers: Vec<HashMap<String, String>>, // 他言語の回答 (言語 → 回答)
+}
+
+/// テナントの列構成で学習データCSVを読み込む
+fn read_csv(tenant: &tenant::Tenant, csv_file_path: &str) -> Result<QaData, AppError> {
+    read_csv_rows(csv_file_path, &schema::answer_langs_from_env(), &input_schema(tenant)?).map(|(qa_data, _)| qa_data)
+}
+
+/// 学習データと、各文書のCSV上の行番号 (1始まり、スキップした行は含まない) を読み込む
+/// langs は他言語の回答列の言語 (カテゴリ列の後ろに並ぶ順)
+fn read_csv_rows(csv_file_path: &str, langs: &[String], schema: &schema::CsvSchema) -> Result<(QaData, Vec<usize>), AppError> {
+    let bytes: Vec<u8> = std::fs::read(csv_file_path)?;
+    // 区切り文字は環境変数の指定を優先し、無ければ先頭行から推定
+    let delimiter: u8 = schema::delimiter_from_env().unwrap_or_else(|| schema::detect_delimiter(&bytes));
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false) // ヘッダーが無い事を明示的に設定
+        .flexible(true) // 列数はスキーマで検証
+        .delimiter(delimiter)
+        .from_reader(bytes.as_slice());
+
+    let mut que_vec: Vec<String> = Vec::new();
+    let mut ans_vec: Vec<String> = Vec::new();
+    let mut cat_vec: Vec<String> = Vec::new();
+    let mut answers: Vec<HashMap<String, String>> = Vec::new();
+    let mut rows: Vec<usize> = Vec::new();
+    for (index, result) in rdr.records().enumerate() {
+        let record = result?;
+        if let Err(err) = schema.validate(&record, index + 1) {
+            // 1行目が合わない場合はCSV自体がスキーマ違い (列位置の指定時は指定が範囲外) とみなす
+            if index == 0 {
+                if schema.explicit {
+                    return Err(AppError::ColumnOutOfRange { expected: schema.min_columns(), actual: record.len() });
+                }
+                return Err(err);
+            }
+            println!("skip row: {}", err);
+            continue;
+        }
+        que_vec.push(schema.question(&record).to_string());
+        ans_vec.push(schema.answer(&record).to_string());
+        cat_vec.push(schema.category(&record).to_string());
+        answers.push(schema.answers(&record, langs));
+        rows.push(index + 1);
+    }
+    Ok((QaData { que_vec, ans_vec, cat_vec, answers }, rows))
+}
+
+/// 正解とされたQAのフィードバック (質問文, 正解の文書index) を読み込む
+/// ファイルが無い場合はフィードバック無し、文書indexが数値でない行は読み飛ばす
+fn read_feedback_csv(csv_file_path: &str) -> Result<Vec<(String, usize)>, AppError> {
+    let bytes: Vec<u8> = match std::fs::read(csv_file_path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false) // ヘッダーが無い事を明示的に設定
+        .flexible(true)
+        .from_reader(bytes.as_slice());
+
+    let mut feedback: Vec<(String, usize)> = Vec::new();
+    for result in rdr.records() {
+        let record = result?;
+        match (record.get(0), record.get(1).and_then(|id| id.trim().parse::<usize>().ok())) {
+            (Some(que), Some(doc_id)) if !que.is_empty() => feedback.push((que.to_string(), doc_id)),
+            _ => println!("skip feedback row: {:?}", record),
+        }
+    }
+    Ok(feedback)
+}
+
+/// 出現回数を疎形式 (文書id,語,回数) で書き出す
+/// 文書id・語の順に並べ、同じ学習データからは同一のファイルになるようにする
+fn out_count_csv(csv_file_out_path: &str, counts: &count::CountMatrix) -> Result<(), AppError> {
+    atomic::write_csv(csv_file_out_path, &csv::WriterBuilder::new(), |wtr| {
+        for (id, row) in counts.rows.iter().enumerate() {
+            let mut entries: Vec<(&String, &u32)> = row.iter().collect();
+            entries.sort();
+            for (word, count) in entries {
+                wtr.write_record([id.to_string(), word.to_owned(), count.to_string()])?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// 語彙ごとの文書頻度とIDFを (word, df, idf) で書き出す (ヘッダー付き)
+/// 文書頻度の降順・同数は語の辞書順とし、ストップワード候補を探しやすくする
+fn out_vocab_stats_csv(csv_file_out_path: &str, tf_idf: &tf_idf::TfIdf, docs: &[Vec<String>]) -> Result<(), AppError> {
+    let mut df: HashMap<&str, usize> = HashMap::new();
+    for doc in docs {
+        for word in doc.iter().collect::<HashSet<&String>>() {
+            *df.entry(word.as_str()).or_insert(0) += 1;
+        }
+    }
+    let idf: Vec<f64> = tf_idf.idf_vec();
+    let mut stats: Vec<(&str, usize, f64)> = tf_idf.word_vec.iter().zip(idf)
+        .map(|(word, idf)| (word.as_str(), df.get(word.as_str()).copied().unwrap_or(0), idf))
+        .collect();
+    stats.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    atomic::write_csv(csv_file_out_path, &csv::WriterBuilder::new(), |wtr| {
+        wtr.write_record(["word", "df", "idf"])?;
+        for (word, df, idf) in stats {
+            wtr.write_record([word.to_string(), df.to_string(), idf.to_string()])?;
+        }
+        Ok(())
+    })
+}
+
+/// 疎形式の出現回数を読み込む (ファイルが無い旧形式のモデルはNone)
+/// 語が1つも無い文書は行が無いため、文書数 n_docs を指定する
+fn read_count_csv(csv_file_path: &str, n_docs: usize) -> Result<Option<count::CountMatrix>, AppError> {
+    let bytes: Vec<u8> = match std::fs::read(csv_file_path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false) // ヘッダーが無い事を明示的に設定
+        .from_reader(bytes.as_slice());
+
+    let mut rows: Vec<HashMap<String, u32>> = vec![HashMap::new(); n_docs];
+    for (index, result) in rdr.records().enumerate() {
+        let record = result?;
+        let parse_err = |col: usize| AppError::ParseFloat { row: index + 1, col, value: record.get(col - 1).unwrap_or("").to_string() };
+        let id: usize = record.get(0).and_then(|s| s.parse().ok()).filter(|&id| id < n_docs).ok_or_else(|| parse_err(1))?;
+        let count: u32 = record.get(2).and_then(|s| s.parse().ok()).ok_or_else(|| parse_err(3))?;
+        rows[id].insert(record.get(1).unwrap_or("").to_string(), count);
+    }
+    Ok(Some(count::CountMatrix { rows }))
+}
+
+fn read_word_list_csv(csv_file_path: &str) -> Result<Vec<Vec<String>>, AppError> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false) // ヘッダーが無い事を明示的に設定
+        .flexible(true) // 可変長で読み込み
+        .from_path(csv_file_path)?;
+
+    let mut word_v_v: Vec<Vec<String>> = Vec::new();
+    for (index, result) in rdr.records().enumerate() { // ヘッダーは除く
+        let record = result?;
+        word_v_v.push(vec![]);
+        for col in &record {
+            word_v_v[index].push(col.to_string());
+        }
+    }
+
+    Ok(word_v_v)
+}
+
+/// 学習済みモデルの保存先 (圧縮は予測時に読むバンドルのみ、環境変数 MODEL_COMPRESS)
+fn model_csv_path() -> String {
+    // 他システムから参照しやすいJSON形式 (環境変数 MODEL_FORMAT=json)
+    if config::env_or("MODEL_FORMAT", String::new()) == "json" {
+        return MODEL_JSON_PATH.to_string();
+    }
+    MODEL_CSV_PATH.to_string()
+}
+
+fn is_json(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|ext| ext == "json")
+}
+
+/// 拡張子でJSON・CSVを判別してモデルを読み込む
+fn read_model(model_file_path: &str) -> Result<tf_idf::TfIdf, AppError> {
+    if is_json(model_file_path) {
+        return import_model_json(model_file_path);
+    }
+    read_model_csv(model_file_path)
+}
+
+/// 拡張子でJSON・CSVを判別してモデルを書き出す (docs はJSONのIDF算出に使う)
+fn out_model(model_file_path: &str, tf_idf_res: &tf_idf::TfIdf) -> Result<(), AppError> {
+    if is_json(model_file_path) {
+        return export_model_json(model_file_path, tf_idf_res);
+    }
+    out_csv(model_file_path, tf_idf_res)
+}
+
+/// JSON形式のモデル
+/// tf_idf は文書ごとの非ゼロ要素 [語彙index, TF-IDF値] (index順)
+#[derive(Debug, Serialize, Deserialize)]
+struct ModelJson {
+    words: Vec<String>,
+    idf: BTreeMap<String, f64>,
+    tf_idf: Vec<Vec<(usize, tf_idf::Weight)>>,
+}
+
+impl ModelJson {
+    fn new(tf_idf_res: &tf_idf::TfIdf) -> Self {
+        ModelJson {
+            words: tf_idf_res.word_vec.to_owned(),
+            idf: tf_idf_res.word_vec.iter().cloned().zip(tf_idf_res.idf_vec()).collect(),
+            tf_idf: tf_idf_res.tf_idf_vec.iter()
+                .map(|row| {
+                    let mut entries: Vec<(usize, tf_idf::Weight)> = row.iter().map(|(&index, &val)| (index, val)).collect();
+                    entries.sort_by_key(|(index, _)| *index);
+                    entries
+                })
+                .collect(),
+        }
+    }
+
+    fn into_tf_idf(self) -> tf_idf::TfIdf {
+        tf_idf::TfIdf::new(self.words, self.tf_idf.into_iter().map(|row| row.into_iter().collect()).collect())
+    }
+}
+
+fn export_model_json(json_file_out_path: &str, tf_idf_res: &tf_idf::TfIdf) -> Result<(), AppError> {
+    let model = ModelJson::new(tf_idf_res);
+    atomic::write(json_file_out_path, &serde_json::to_vec(&model).map_err(std::io::Error::from)?)
+}
+
+fn import_model_json(json_file_path: &str) -> Result<tf_idf::TfIdf, AppError> {
+    let model: ModelJson = serde_json::from_slice(&std::fs::read(json_file_path)?).map_err(std::io::Error::from)?;
+    Ok(model.into_tf_idf())
+}
+
+fn read_model_csv(model_csv_file_path: &str) -> Result<tf_idf::TfIdf, AppError> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false) // ヘッダーが無い事を明示的に設定
+        .from_reader(File::open(model_csv_file_path)?);
+
+    // ヘッダー(語彙)のみ先に読み、本体は1行ずつ疎ベクトルに変換する
+    let mut records = rdr.records();
+    let header = records.next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "model csv is empty"))??;
+    let word_vec: Vec<String> = header.iter().skip(1).map(|s| s.to_string()).collect(); // "id"の文字以降を格納
+    let mut tf_idf_vec: Vec<HashMap<usize, tf_idf::Weight>> = Vec::new();
+    for (index, result) in records.enumerate() {
+        let record = result?;
+        let mut tf_idf_map: HashMap<usize, tf_idf::Weight> = HashMap::new();
+        for (col, tf_idf) in record.iter().enumerate().skip(1) { // "id"列は除く
+            let tf_idf_val: tf_idf::Weight = tf_idf.parse::<tf_idf::Weight>().map_err(|_| AppError::ParseFloat {
+                row: index + 2, // ヘッダー行の分を加算
+                col: col + 1,
+                value: tf_idf.to_string(),
+            })?;
+            if tf_idf_val != 0.0 { // 非ゼロのみ保持
+                tf_idf_map.insert(col - 1, tf_idf_val);
+            }
+        }
+        tf_idf_vec.push(tf_idf_map);
+    }
+
+    let tfidf: tf_idf::TfIdf = tf_idf::TfIdf::new(word_vec, tf_idf_vec);
+
+    Ok(tfidf)
+}
+
+/// csv出力
+/// https://qiita.com/algebroid/items/c456d4ec555ae04c7f92
+fn out_csv(csv_file_out_path: &str, tf_idf_res: &tf_idf::TfIdf) -> Result<(), AppError> {
+    let mut wtr = csv::WriterBuilder::new()
+        .quote_style(csv::QuoteStyle::Always)
+        .from_writer(Vec::new());
+
+    let mut w_vec = vec!["id"];
+    let mut w_add_vec: Vec<&str> = tf_idf_res.word_vec.iter().map(|s| s.as_str()).collect();
+    w_vec.append(&mut w_add_vec);
+    wtr.write_record(&w_vec)?;
+
+    for index in 0..tf_idf_res.tf_idf_vec.len() {
+        let mut s_vec: Vec<String> = vec![index.to_string()];
+        let mut s_add_vec: Vec<String> = tf_idf_res.dense_row(index).iter().map(|s| s.to_string()).collect();
+        s_vec.append(&mut s_add_vec);
+        wtr.write_record(s_vec)?;
+    }
+
+    let csv_bytes: Vec<u8> = wtr.into_inner().map_err(|err| err.into_error())?;
+    atomic::write(csv_file_out_path, &csv_bytes)
+}
+
+/// モデルメタに記録したモデル情報 (記録の無い旧形式のモデルはNone)
+fn read_model_info(meta_path: &str) -> Result<Option<response::ModelInfo>, AppError> {
+    read_meta_field(meta_path, "model_info")
+}
+
+/// 次に記録するモデル情報の版 (前回の版に1を足す)
+/// 学習時はモデルメタを書き直す前に読むこと
+fn next_model_version(meta_path: &str) -> Result<u64, AppError> {
+    Ok(read_model_info(meta_path)?.map_or(1, |info| info.version + 1))
+}
+
+/// モデルメタに前処理の構成と併せて記録した項目 (メタ・項目が無い場合はNone)
+fn read_meta_field<T: serde::de::DeserializeOwned>(meta_path: &str, key: &str) -> Result<Option<T>, AppError> {
+    let meta: Value = match std::fs::read(meta_path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(std::io::Error::from)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    match &meta[key] {
+        Value::Null => Ok(None),
+        value => Ok(Some(serde_json::from_value(value.to_owned()).map_err(std::io::Error::from)?)),
+    }
+}
+
+/// 書き出し済みのモデルメタに項目を加える (None の場合は削除)
+fn write_meta_field<T: Serialize>(meta_path: &str, key: &str, value: Option<&T>) -> Result<(), AppError> {
+    let mut meta: Value = serde_json::from_slice(&std::fs::read(meta_path)?).map_err(std::io::Error::from)?;
+    match value {
+        Some(value) => meta[key] = response::to_value(value),
+        None => {
+            if let Some(meta) = meta.as_object_mut() {
+                meta.remove(key);
+            }
+        },
+    }
+    atomic::write(meta_path, meta.to_string().as_bytes())
+}
+
+/// 学習リクエストで指定した学習データCSVの列位置 (予測・追加時も同じ列で読み書きする)
+fn read_column_map(meta_path: &str) -> Result<Option<schema::ColumnMap>, AppError> {
+    read_meta_field(meta_path, "column_map")
+}
+
+fn write_column_map(meta_path: &str, column_map: Option<&schema::ColumnMap>) -> Result<(), AppError> {
+    write_meta_field(meta_path, "column_map", column_map)
+}
+
+/// 学習データCSVの列構成 (学習時に列位置を指定していればモデルメタの列位置、無ければ既定)
+fn input_schema(tenant: &tenant::Tenant) -> Result<schema::CsvSchema, AppError> {
+    load_schema(&mut engine::lock(tenant.id()), tenant).copied()
+}
+
+/// 保持中の列構成が無い場合のみテナントのモデルメタから読み込む
+fn load_schema<'a>(engine: &'a mut engine::QaEngine, tenant: &tenant::Tenant) -> Result<&'a schema::CsvSchema, AppError> {
+    if engine.schema().is_none() {
+        engine.set_schema(read_column_map(&tenant.path(MODEL_META_PATH))?.map(schema::CsvSchema::from).unwrap_or_default());
+    }
+    Ok(engine.schema().unwrap())
+}
+
+/// 現在時刻を学習日時として、書き出し済みのモデルメタにモデル情報を加える
+fn write_model_info(meta_path: &str, version: u64, num_docs: usize) -> Result<(), AppError> {
+    let trained_at: u64 = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    write_meta_field(meta_path, "model_info", Some(&response::ModelInfo { version, trained_at, num_docs }))
+}
+
+/// 学習データCSVの末尾に1行追記 (id列は1始まり)
+/// 列位置を指定した構成では、回答・質問以外の列は空欄とする
+fn append_csv(csv_file_path: &str, doc_id: usize, que: &str, ans: &str, schema: &schema::CsvSchema) -> Result<(), AppError> {
+    let mut file = std::fs::OpenOptions::new().append(true).open(csv_file_path)?;
+    // 末尾に改行が無いCSVにも追記できるようにする
+    let content: Vec<u8> = std::fs::read(csv_file_path)?;
+    if content.last().is_some_and(|b| *b != b'\n') {
+        std::io::Write::write_all(&mut file, b"\n")?;
+    }
+    let delimiter: u8 = schema::delimiter_from_env().unwrap_or_else(|| schema::detect_delimiter(&content));
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .delimiter(delimiter) // 既存ファイルと同じ区切り文字で追記
+        .from_writer(file);
+    if schema.explicit {
+        let mut record: Vec<&str> = vec![""; schema.min_columns()];
+        record[schema.answer_col] = ans;
+        record[schema.question_col] = que;
+        wtr.write_record(&record)?;
+    } else {
+        let id: String = (doc_id + 1).to_string();
+        wtr.write_record([id.as_str(), "T", ans, que])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn out_csv_word(csv_file_out_path: &str, docs: &Vec<Vec<String>>) -> Result<(), AppError> {
+    let mut builder = csv::WriterBuilder::new();
+    builder
+        .quote_style(csv::QuoteStyle::Always)
+        .flexible(true); // 可変長で書き込み
+    atomic::write_csv(csv_file_out_path, &builder, |wtr| {
+        for doc in docs {
+            wtr.write_record(doc)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants, clippy::needless_borrow, clippy::op_ref)] // 既存テストの書き方をそのまま残す
+mod tests {
+    use super::*;
+    use storage::Storage;
+
+    /// 全行を溜めてから変換する従来の読み込み (比較用)
+    fn read_model_csv_buffered(model_csv_file_path: &str) -> tf_idf::TfIdf {
+        let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_path(model_csv_file_path).unwrap();
+        let rec_v_v: Vec<Vec<String>> = rdr.records()
+            .map(|r| r.unwrap().iter().map(|s| s.to_string()).collect())
+            .collect();
+        let word_vec: Vec<String> = (rec_v_v[0][1..]).to_vec();
+        let tf_idf_vec: Vec<HashMap<usize, tf_idf::Weight>> = rec_v_v.iter().skip(1)
+            .map(|rec_v| rec_v.iter().enumerate().skip(1)
+                .map(|(col, v)| (col - 1, v.parse::<tf_idf::Weight>().unwrap()))
+                .filter(|(_, v)| *v != 0.0)
+                .collect())
+            .collect();
+        tf_idf::TfIdf::new(word_vec, tf_idf_vec)
+    }
+
+    fn sample_model_csv(name: &str, n_docs: usize, n_vocab: u64) -> String {
+        let mut seed: u64 = 1;
+        let docs: Vec<Vec<String>> = (0..n_docs).map(|_| {
+            (0..20).map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                format!("w{}", (seed >> 33) % n_vocab)
+            }).collect()
+        }).collect();
+        let path = std::env::temp_dir().join(name);
+        let path_str: String = path.to_str().unwrap().to_string();
+        out_csv(&path_str, &tf_idf::TfIdf::get_tf_idf(&docs).unwrap()).unwrap();
+        path_str
+    }
+
+    #[test]
+    fn read_model_csv_stream_test1() {
+        // 従来の読み込みと結果が一致するか確認
+        let path: String = sample_model_csv("nango_read_model_csv_stream_test1.csv", 20, 50);
+        let res = read_model_csv(&path).unwrap();
+        let exp = read_model_csv_buffered(&path);
+        assert_eq!(res.word_vec, exp.word_vec);
+        assert_eq!(res.tf_idf_vec, exp.tf_idf_vec);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// 大きなモデルでの読み込み時のメモリピーク比較
+    /// cargo test --release --features alloc_bench read_model_csv_peak_bench -- --ignored --nocapture --test-threads=1
+    #[cfg(feature = "alloc_bench")]
+    #[test]
+    #[ignore]
+    fn read_model_csv_peak_bench() {
+        let path: String = sample_model_csv("nango_read_model_csv_peak_bench.csv", 300, 5000);
+        let (buffered, buffered_peak) = alloc_bench::peak(|| read_model_csv_buffered(&path));
+        drop(buffered);
+        let (streamed, streamed_peak) = alloc_bench::peak(|| read_model_csv(&path).unwrap());
+        drop(streamed);
+
+        println!("peak: buffered {} bytes, streamed {} bytes", buffered_peak, streamed_peak);
+        std::fs::remove_file(&path).unwrap();
+        assert!(streamed_peak < buffered_peak);
+    }
+
+    fn sample_predict_qa_data(n_docs: usize) -> QaData {
+        QaData {
+            que_vec: (0..n_docs).map(|i| format!("{}番目の駐車場の料金はいくらですか？", i)).collect(),
+            ans_vec: (0..n_docs).map(|i| format!("{}番目の駐車場は1時間300円です。", i).repeat(10)).collect(),
+            cat_vec: vec![String::new(); n_docs],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn predict_stream_test1() {
+        // 全候補をまとめてから変換する従来の結果と一致するか確認
+        let qa_data: QaData = sample_predict_qa_data(5);
+        let ans_vec: Vec<(usize, f64)> = vec![(3, 0.9), (9, 0.8), (1, 0.7), (0, 0.2), (2, 0.1)];
+        let options = PredictOptions { min_results: Some(4), max_results: Some(3), softmax: Some(1.0), snippet_len: Some(5), ..Default::default() };
+        let threshold: f64 = applied_threshold(&ans_vec, &options);
+        let exp: Value = predict_response(make_qa_infos("料金は？", &qa_data, ans_vec.to_owned(), &options, &BandConfig::default(), threshold), threshold, None);
+        let res: Value = make_json("料金は？".to_string(), &qa_data, ans_vec.to_owned(), &options, &BandConfig::default(), None);
+        assert_eq!(res, exp);
+        assert_eq!(res["payload"]["qa_infos"].as_array().unwrap().len(), 3);
+
+        let mut bytes: Vec<u8> = Vec::new();
+        let candidates: Vec<(usize, f64)> = select_candidates(&qa_data, ans_vec, &options, threshold);
+        serde_json::to_writer(&mut bytes, &predict_stream("料金は？", &qa_data, candidates, threshold, &options, &BandConfig::default(), None)).unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&bytes).unwrap(), exp);
+    }
+
+    /// 大量候補の予測結果を API Gateway の body にするときのメモリピーク比較
+    /// cargo test --release --features alloc_bench predict_stream_peak_bench -- --ignored --nocapture --test-threads=1
+    #[cfg(feature = "alloc_bench")]
+    #[test]
+    #[ignore]
+    fn predict_stream_peak_bench() {
+        let qa_data: QaData = sample_predict_qa_data(20000);
+        let ans_vec: Vec<(usize, f64)> = (0..qa_data.que_vec.len()).map(|id| (id, 0.9)).collect();
+        let options = PredictOptions::default();
+        let band_config = BandConfig::default();
+        let threshold: f64 = applied_threshold(&ans_vec, &options);
+        // 全候補を Value にまとめてから body にする (候補を書き換える後処理を使う場合)
+        let (buffered, buffered_peak) = alloc_bench::peak(|| {
+            to_proxy_response(make_json("料金は？".to_string(), &qa_data, ans_vec.to_owned(), &options, &band_config, None))
+        });
+        // 候補を1件ずつ body に書き出す (stream_body と同じ書き出し)
+        let (streamed, streamed_peak) = alloc_bench::peak(|| {
+            let candidates: Vec<(usize, f64)> = select_candidates(&qa_data, ans_vec.to_owned(), &options, threshold);
+            let cos_vals: Vec<f64> = candidates.iter().map(|(_, cos_val)| *cos_val).collect();
+            let predicted = predict_stream("料金は？", &qa_data, candidates, threshold, &options, &band_config, None);
+            to_proxy_response(write_body(predict_response(Vec::new(), threshold, None), predicted.payload.qa_infos, cos_vals))
+        });
+        let body = |res: &Value| serde_json::from_str::<Value>(res["body"].as_str().unwrap()).unwrap();
+        assert_eq!(body(&streamed), body(&buffered));
+
+        println!("peak: buffered {} bytes, streamed {} bytes", buffered_peak, streamed_peak);
+        assert!(streamed_peak < buffered_peak);
+    }
+
+    /// 手元の学習データ・モデルを書き換えるテストは同時に実行しない
+    fn output_lock() -> std::sync::MutexGuard<'static, ()> {
+        static OUTPUT_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        OUTPUT_LOCK.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    #[test]
+    fn learn_test1() {
+        let _output = output_lock();
+        let mut res = learn(&tenant::Tenant::default(), true, false, None).unwrap();
+        // println!("{:?}", res.to_string());
+        // フェーズの所要時間は実行ごとに変わるので、フェーズ名のみ確認
+        let phases: Vec<Value> = res["stats"]["phases"].as_array().unwrap().iter().map(|p| p["phase"].clone()).collect();
+        assert_eq!(phases, vec!["read_csv", "tokenize", "analyze", "tf_idf", "output"]);
+        res["stats"].as_object_mut().unwrap().remove("phases");
+        // 同梱のQAはモデルと同じドメインのため、未知語率は警告の閾値未満
+        let unknown_rate: f64 = res["stats"].as_object_mut().unwrap().remove("unknown_rate").unwrap().as_f64().unwrap();
+        assert!(unknown_rate > 0.0 && unknown_rate < DEFAULT_UNKNOWN_RATE_WARN);
+        let exp: Value = json!({
+            "code": 200,
+            "success": true,
+            "mode": "learn",
+            "stats": {},
+            "warnings": {
+                "messages": [],
+                "duplicate_questions": [
+                    { "question": "配信", "rows": [92, 93], "same_answer": true },
+                ],
+                "failed_docs": [],
+            },
+        });
+        assert_eq!(res, exp);
+    }
+
+    #[test]
+    fn predict_test1() {
+        let que_sentence: String = "おすすめのメニュー教えてください。".to_string();
+        let res = predict(&tenant::Tenant::default(), que_sentence.to_owned(), PredictOptions::default()).unwrap();
+        // println!("{} {} {}", res["code"], res["mode"], res["payload"]["qa_infos"][0]);
+        let tmp_res_vec: Vec<String> = vec![&res["code"], &res["mode"], &res["payload"]["qa_infos"][0]["que"]]
+            .into_iter().map(|v| v.to_string() ).collect();
+        let res_vec: Vec<&str> = tmp_res_vec.iter().map(|s| s.as_str()).collect();
+        let exp_que: String = "\"".to_string() + &que_sentence.as_str() + "\"";
+        let exp_vec = vec!["200", "\"predict\"", exp_que.as_str()];
+        assert_eq!(res_vec, exp_vec);
+        // 元の文書indexが含まれるか確認
+        let doc_id = res["payload"]["qa_infos"][0]["doc_id"].as_u64().unwrap() as usize;
+        let similar_que = res["payload"]["qa_infos"][0]["similar_que"].as_str().unwrap();
+        assert_eq!(read_csv(&tenant::Tenant::default(), INPUT_CSV_PATH).unwrap().que_vec[doc_id], similar_que);
+        // 完全一致しない質問は通常の検索結果となる
+        assert!(res["payload"]["qa_infos"][0].get("exact_match").is_none());
+    }
+
+    #[test]
+    fn run_concurrent_test1() {
+        let _output = output_lock();
+        // 学習中に並行して予測しても、ロックを待って学習前後いずれかの揃ったモデルで答える
+        let predict_mode = || ExecMode::Predict {
+            que_sentence: "料金はいくらですか".to_string(),
+            options: Box::default(),
+            format: ResponseFormat::Json,
+            datasets: vec![],
+        };
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..2 {
+                    run(ExecMode::Learn { persist_word_list: true, self_eval: false, column_map: None, dataset: None }, STR_PKEY).unwrap();
+                }
+            });
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        let res = run(predict_mode(), STR_PKEY).unwrap();
+                        assert_eq!(res["payload"]["qa_infos"][0]["similar_que"], "料金はいくら？");
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn with_stats_lock_test1() {
+        // 並行したアクセス数の更新が失われない
+        let tenant = tenant::Tenant::new("with_stats_lock_test1", STR_PKEY);
+        let _ = std::fs::remove_dir_all(tenant.path(""));
+        tenant.create_dirs().unwrap();
+        let path: String = tenant.path(ACCESS_COUNT_PATH);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        with_stats_lock(&tenant, || access::increment(&path, 0)).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(access::read_counts(&path).unwrap()[&0], 40);
+        // ロックファイルはテナントごと (他のテナントの予測と同じファイルを待たない)
+        assert!(Path::new(&tenant.path(STATS_LOCK_PATH)).exists());
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
+    }
+
+    #[test]
+    fn warmup_test1() {
+        // 分かち書き器・学習結果が読み込み済みとなる
+        warmup().unwrap();
+        let engine = engine::lock(tenant::Tenant::default().id());
+        assert!(engine.pipeline().is_some());
+        let model: Arc<engine::LoadedModel> = engine.loaded().unwrap();
+        assert_eq!(model.docs.len(), model.tfidf.tf_idf_vec.len());
+    }
+
+    #[test]
+    fn has_model_test1() {
+        // 未学習ならウォームアップを省く
+        let dir = std::env::temp_dir().join("nango_has_model_test1");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let bundle_path = dir.join("model_bundle.json");
+        let model_path = dir.join("model_qa1.csv");
+        let (bundle_path, model_path) = (bundle_path.to_str().unwrap(), model_path.to_str().unwrap());
+        assert!(!has_model(bundle_path, model_path));
+        std::fs::write(model_path, "").unwrap();
+        assert!(has_model(bundle_path, model_path));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn predict_split_test1() {
+        // 2つの質問を含む文は文ごとに候補を返す
+        let options = PredictOptions { split_query: true, ..Default::default() };
+        let res: Value = predict_split(&tenant::Tenant::default(), "営業時間は？あと駐車場はありますか？".to_string(), options.clone()).unwrap();
+        let groups: &Vec<Value> = res["payload"]["groups"].as_array().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0]["que_sentence"], "営業時間は？");
+        assert_eq!(groups[1]["que_sentence"], "あと駐車場はありますか？");
+        assert!(groups.iter().all(|group| group["payload"]["qa_infos"].is_array()));
+        assert_eq!(groups[1]["payload"]["qa_infos"][0]["que"], "あと駐車場はありますか？");
+        // 1文のみの場合は通常の予測結果
+        let res: Value = predict_split(&tenant::Tenant::default(), "おすすめのメニュー教えてください。".to_string(), options).unwrap();
+        assert!(res["payload"]["qa_infos"].is_array());
+    }
+
+    #[test]
+    fn predict_language_test1() {
+        // 英語のみの質問は400
+        match predict(&tenant::Tenant::default(), "Can I play instruments here?".to_string(), PredictOptions::default()) {
+            Err(error @ AppError::UnsupportedLanguage(_)) => {
+                assert_eq!(error.code(), 400);
+                assert!(error.to_string().starts_with("unsupported_language"));
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn predict_exact_match_test1() {
+        // 登録済みの質問そのままなら完全一致で返す
+        let que_sentence: String = " 料金はいくら？ ".to_string();
+        let res = predict(&tenant::Tenant::default(), que_sentence, PredictOptions::default()).unwrap();
+        let qa_infos = res["payload"]["qa_infos"].as_array().unwrap();
+        assert_eq!(qa_infos.len(), 1);
+        assert_eq!(qa_infos[0]["cos_val"], 1.0);
+        assert_eq!(qa_infos[0]["exact_match"], true);
+        assert_eq!(qa_infos[0]["similar_que"], "料金はいくら？");
+    }
+
+    #[test]
+    fn predict_exact_match_test2() {
+        // しきい値で全候補が落ちる指定でも完全一致の候補は返す
+        let options = PredictOptions { threshold: Some(1.0), ..PredictOptions::default() };
+        let res = predict(&tenant::Tenant::default(), "料金はいくら？".to_string(), options).unwrap();
+        let qa_infos = res["payload"]["qa_infos"].as_array().unwrap();
+        assert_eq!(qa_infos.len(), 1);
+        assert_eq!(qa_infos[0]["exact_match"], true);
+    }
+
+    #[test]
+    fn predict_ensemble_test1() {
+        // 合成時も cos_val・しきい値は合成前のcos類似度で、並び順と順位は ensemble_score
+        let que_sentence: &str = "お店で楽器は演奏できますか";
+        let qa_data: QaData = read_csv(&tenant::Tenant::default(), INPUT_CSV_PATH).unwrap();
+        let tokens: Vec<String> = query_tokens(&tenant::Tenant::default(), que_sentence).unwrap();
+        let raw_cos: HashMap<usize, f64> = search_cached(&tenant::Tenant::default(), &qa_data, &load_predict_model(&tenant::Tenant::default()).unwrap(), que_sentence, &tokens, Scorer::TfIdf, None).unwrap().into_iter().collect();
+        let res = predict(&tenant::Tenant::default(), que_sentence.to_string(), PredictOptions { ensemble: Some(0.5), ..Default::default() }).unwrap();
+        let qa_infos = res["payload"]["qa_infos"].as_array().unwrap();
+        assert!(!qa_infos.is_empty());
+        for qa_info in qa_infos {
+            let cos_val: f64 = qa_info["cos_val"].as_f64().unwrap();
+            assert_eq!(cos_val, raw_cos[&(qa_info["doc_id"].as_u64().unwrap() as usize)]);
+            assert!(cos_val > DEFAULT_THRESHOLD);
+        }
+        let scores: Vec<f64> = qa_infos.iter().map(|info| info["ensemble_score"].as_f64().unwrap()).collect();
+        assert!(scores.windows(2).all(|w| w[0] >= w[1]));
+        assert_eq!(qa_infos[0]["rank"], 1);
+        // 合成しない場合は出力しない
+        let res = predict(&tenant::Tenant::default(), que_sentence.to_string(), PredictOptions::default()).unwrap();
+        assert!(res["payload"]["qa_infos"][0].get("ensemble_score").is_none());
+    }
+
+    #[test]
+    fn find_exact_match_test1() {
+        let qa_data = QaData {
+            que_vec: ["料金は？", "駐車場は？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["1500円です", "ありません"].iter().map(|s| s.to_string()).collect(),
+            cat_vec: ["料金", "設備"].iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(find_exact_match(&qa_data, "駐車場は？", None), Some(1));
+        // 一致しない場合・カテゴリが異なる場合は通常の検索に回す
+        assert_eq!(find_exact_match(&qa_data, "駐車場はありますか？", None), None);
+        assert_eq!(find_exact_match(&qa_data, "駐車場は？", Some("料金")), None);
+    }
+
+    #[test]
+    fn init_pkey_test1() {
+        let event: Value = json!({
+            "mode": "l", // pkeyがない場合にエラーとなるか確認
+        });
+        let res = ExecMode::new(event);
+        match res {
+            Err(error) => {
+                assert_eq!(error, InitError::Auth(AuthError::MissingKey));
+                assert_eq!(error.code(), 401);
+                assert_eq!(error.to_string(), "Not executable");
+            },
+            Ok(_) => {
+                assert!(false);
+            }
+        }
+    }
+
+    #[test]
+    fn init_pkey_test2() {
+        let event: Value = json!({
+            "mode": "l",
+            "pkey": "" // pkeyが不正な場合(空)、エラーとなるか確認
+        });
+        let res = ExecMode::new(event);
+        match res {
+            Err(error) => {
+                assert_eq!(error, InitError::Auth(AuthError::MissingKey));
+                assert_eq!(error.code(), 401);
+                assert_eq!(error.to_string(), "Not executable");
+            },
+            Ok(_) => {
+                assert!(false);
+            }
+        }
+    }
+
+    #[test]
+    fn init_pkey_test3() {
+        let event: Value = json!({
+            "mode": "l",
+            "pkey": "abc" // pkeyが不正な場合(間違い)、エラーとなるか確認
+        });
+        let res = ExecMode::new(event);
+        match res {
+            Err(error) => {
+                assert_eq!(error, InitError::Auth(AuthError::InvalidKey));
+                assert_eq!(error.code(), 401);
+                assert_eq!(error.to_string(), "Not executable");
+            },
+            Ok(_) => {
+                assert!(false);
+            }
+        }
+    }
 
     #[test]
-    fn init_pkey_test1() {
+    fn authorize_test1() {
+        // 予測専用キーは予測のみ可、学習・追加は403
+        assert_eq!(authorize(STR_PKEY, "l", "predict_only", "", &[]), Ok(()));
+        assert_eq!(authorize("predict_only", "p", "predict_only", "", &[]), Ok(()));
+        let error = authorize("predict_only", "l", "predict_only", "", &[]).unwrap_err();
+        assert_eq!(error, AuthError::Forbidden("l".to_string()));
+        assert_eq!(error.code(), 403);
+        assert_eq!(authorize("predict_only", "a", "predict_only", "", &[]).unwrap_err().code(), 403);
+        // 予測専用キー未設定時は空文字と一致させない
+        assert_eq!(authorize("", "p", "", "", &[]), Err(AuthError::MissingKey));
+        assert_eq!(authorize("abc", "p", "", "", &[]), Err(AuthError::InvalidKey));
+        // 不正なパラメータは400
+        let event: Value = json!({ "mode": "x", "pkey": STR_PKEY });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+    }
+
+    #[test]
+    fn handle_event_schema_test1() {
+        // スキーマ違反は ExecMode::new・実行の前に400で返す
+        let res: Value = handle_event(json!({ "mode": "p", "pkey": STR_PKEY, "que_sentence": "料金は？", "threshold": [0.5] }), false);
+        assert_eq!(res["code"], 400);
+        assert!(res["message"].as_str().unwrap().contains("threshold"));
+        let res: Value = handle_event(json!({ "mode": "a", "pkey": STR_PKEY, "que_sentence": "料金は？" }), false);
+        assert_eq!(res["code"], 400);
+        assert!(res["message"].as_str().unwrap().contains("ans_sentence"));
+        // 認証前には検証しない (スキーマ違反でも pkey が不正なら401)
+        let res: Value = handle_event(json!({ "mode": "p", "pkey": "wrong", "que_sentence": "料金は？", "threshold": [0.5] }), false);
+        assert_eq!((res["code"].as_u64(), res["message"].as_str()), (Some(401), Some("error running init: Not executable")));
+        let res: Value = handle_event(json!({ "mode": "p", "pkey": 1 }), false);
+        assert_eq!(res["code"], 401);
+    }
+
+    #[test]
+    fn tokenize_sentence_test1() {
+        let res: Value = tokenize_sentence("駐車場はありますか?", pipeline::Pipeline::default()).unwrap();
+        assert_eq!(res["mode"], "tokenize");
+        // 既定のフィルタで半角は全角に揃えてから分かち書きする
+        assert_eq!(res["payload"]["sentence"], "駐車場はありますか?");
+        assert_eq!(res["payload"]["normalized"], "駐車場はありますか？");
+        assert_eq!(res["payload"]["tokens"], json!(["駐車場", "は", "あり", "ます", "か", "？"]));
+        assert_eq!(res["payload"]["stop_words"], json!(["は", "あり", "ます", "か"]));
+        assert_eq!(res["payload"]["filters"], json!(["kytea_fullwidth"]));
+
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "t", "sentence": " " });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "t", "sentence": "料金は？" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Tokenize { sentence }) if sentence == "料金は？"));
+    }
+
+    #[test]
+    fn rollback_predict_test1() {
+        let _output = output_lock();
+        // 途中で失敗しても学習データCSVは元に戻す
+        struct RestoreFile(&'static str, Vec<u8>);
+        impl Drop for RestoreFile {
+            fn drop(&mut self) {
+                std::fs::write(self.0, &self.1).unwrap();
+            }
+        }
+        let original = RestoreFile(INPUT_CSV_PATH, std::fs::read(INPUT_CSV_PATH).unwrap());
+        let learn_mode = || ExecMode::Learn { persist_word_list: true, self_eval: false, column_map: None, dataset: None };
+        let top_answer = |que_sentence: &str| -> Value {
+            predict(&tenant::Tenant::default(), que_sentence.to_string(), PredictOptions::default()).unwrap()["payload"]["qa_infos"][0]["ans"].clone()
+        };
+        let que_sentence: &str = "ロールバックの確認用の質問はありますか？";
+
+        // 学習 → QA追加 → 再学習の3世代を保存する
+        run(learn_mode(), STR_PKEY).unwrap();
+        run(ExecMode::Add { que_sentence: que_sentence.to_string(), ans_sentence: "確認用の回答です".to_string() }, STR_PKEY).unwrap();
+        run(learn_mode(), STR_PKEY).unwrap();
+        assert_eq!(top_answer(que_sentence), "確認用の回答です");
+
+        // 追加前の世代まで戻すと、学習データCSVも戻り、予測は追加したQAを返さない
+        let rollback = || run(ExecMode::Admin { action: AdminAction::Rollback { target: tenant::Tenant::default() } }, STR_PKEY).unwrap();
+        rollback();
+        assert_eq!(top_answer(que_sentence), "確認用の回答です");
+        rollback();
+        assert_eq!(std::fs::read(INPUT_CSV_PATH).unwrap(), original.1);
+        assert_ne!(top_answer(que_sentence), "確認用の回答です");
+        // 戻した世代の文書IDは、戻した学習データの回答を指す
+        let res: Value = predict(&tenant::Tenant::default(), "料金はいくらですか".to_string(), PredictOptions::default()).unwrap();
+        let doc_id: usize = res["payload"]["qa_infos"][0]["doc_id"].as_u64().unwrap() as usize;
+        assert_eq!(read_csv(&tenant::Tenant::default(), INPUT_CSV_PATH).unwrap().ans_vec[doc_id], res["payload"]["qa_infos"][0]["ans"].as_str().unwrap());
+    }
+
+    #[test]
+    fn rollback_tenant_test1() {
+        let _output = output_lock();
+        let pkey: &str = "rollback_tenant_test1";
+        let tenant = tenant::Tenant::new(pkey, STR_PKEY);
+        let _ = std::fs::remove_dir_all(tenant.path(""));
+        tenant.create_dirs().unwrap();
+        std::fs::write(tenant.path(INPUT_CSV_PATH), "1,T,あります,駐車場はありますか？\n2,T,不要です,予約は必要ですか？\n").unwrap();
+        run(ExecMode::Learn { persist_word_list: true, self_eval: false, column_map: None, dataset: None }, pkey).unwrap();
+        run(ExecMode::Add { que_sentence: "ロールバックの確認用の質問はありますか？".to_string(), ans_sentence: "確認用の回答です".to_string() }, pkey).unwrap();
+
+        // 管理用 pkey のテナントではなく、指定したテナントのモデルを戻す
+        let event: Value = json!({ "mode": "m", "action": "rollback", "tenant": STR_PKEY, "dataset": " front " });
+        assert_eq!(parse_admin_target(&event), Ok(tenant::Tenant::default().dataset("front")));
+        let res: Value = run(ExecMode::Admin { action: AdminAction::Rollback { target: tenant.clone() } }, "admin_key").unwrap();
+        assert_eq!(res["version"], 1);
+        let res: Value = predict(&tenant, "ロールバックの確認用の質問はありますか？".to_string(), PredictOptions::default()).unwrap();
+        assert_ne!(res["payload"]["qa_infos"][0]["ans"], "確認用の回答です");
+        // 学習していないデータセットは戻せない
+        assert_eq!(run(ExecMode::Admin { action: AdminAction::Rollback { target: tenant.dataset("front") } }, "admin_key").unwrap_err().code(), 400);
+
+        // TENANT_PKEYS に無い pkey・不正なデータセット名は400
+        for event in [json!({ "tenant": "unknown_tenant_pkey" }), json!({ "dataset": "../front" })] {
+            assert_eq!(parse_admin_target(&event).unwrap_err().code(), 400);
+        }
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
+    }
+
+    #[test]
+    fn diff_rankings_test1() {
+        let candidate = |doc_id: usize, que: &str, rank: usize, cos_val: f64| response::DiffCandidate { doc_id, similar_que: Some(que.to_string()), rank, cos_val };
+        let from = vec![candidate(0, "料金は？", 1, 0.9), candidate(1, "駐車場は？", 2, 0.5), candidate(2, "予約は？", 3, 0.4)];
+        // 再学習で文書IDがずれても質問文で追う
+        let to = vec![candidate(3, "駐車場は？", 1, 0.8), candidate(0, "料金は？", 2, 0.7), candidate(4, "営業時間は？", 3, 0.3)];
+        let diff: response::QueryDiff = diff_rankings("料金は？", from, to);
+        let kept: Vec<(usize, usize, i64)> = diff.kept.iter().map(|c| (c.from_rank, c.to_rank, c.rank_change)).collect();
+        assert_eq!(kept, vec![(2, 1, 1), (1, 2, -1)]);
+        assert_eq!(diff.kept[0].doc_id, 3);
+        assert_eq!(diff.added, vec![candidate(4, "営業時間は？", 3, 0.3)]);
+        assert_eq!(diff.removed, vec![candidate(2, "予約は？", 3, 0.4)]);
+
+        // 学習データの質問が無いバンドルは文書IDで比較する
+        let no_que = |doc_id: usize, rank: usize| response::DiffCandidate { doc_id, similar_que: None, rank, cos_val: 0.5 };
+        let diff: response::QueryDiff = diff_rankings("料金は？", vec![no_que(0, 1)], vec![no_que(1, 1), no_que(0, 2)]);
+        assert_eq!((diff.kept.len(), diff.added.len(), diff.removed.len()), (1, 1, 0));
+    }
+
+    #[test]
+    fn diff_models_test1() {
+        let root = std::env::temp_dir().join("nango_diff_models_test1");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("versions")).unwrap();
+        let dir: String = root.join("versions").to_string_lossy().to_string();
+        let versions = versions::ModelVersions { dir, paths: vec![BUNDLE_PATH.to_string()], keep: 5 };
+        // 世代のファイルは BUNDLE_PATH の名前で dir に保存する (現行のバンドルは書き換えない)
+        let save_version = |version: u64, questions: &[&str]| {
+            let pipeline = pipeline::Pipeline::default();
+            let questions: Vec<String> = questions.iter().map(|que| que.to_string()).collect();
+            let docs: Vec<Vec<String>> = questions.iter().map(|que| pipeline.try_run(que.to_owned()).unwrap()).collect();
+            let path: String = root.join("versions").join(format!("model_bundle.v{}.json", version)).to_string_lossy().to_string();
+            bundle::save_bundle(&path, &tf_idf::TfIdf::get_tf_idf(&docs).unwrap(), &docs, &pipeline, &questions, &questions, None).unwrap();
+        };
+        save_version(1, &["駐車場の料金はいくらですか？", "営業時間は何時からですか？"]);
+        save_version(2, &["営業時間は何時からですか？", "駐車場の料金はいくらですか？", "駐車場はありますか？"]);
+
+        let res: Value = diff_models(&versions, 1, 2, &["駐車場の料金は？".to_string()]).unwrap();
+        assert_eq!((res["from"].as_u64(), res["to"].as_u64()), (Some(1), Some(2)));
+        let diff: &Value = &res["queries"][0];
+        assert_eq!(diff["query"], "駐車場の料金は？");
+        assert_eq!(diff["kept"][0]["similar_que"], "駐車場の料金はいくらですか？");
+        assert_eq!((diff["kept"][0]["from_rank"].as_u64(), diff["kept"][0]["to_rank"].as_u64()), (Some(1), Some(1)));
+        assert_eq!(diff["kept"][0]["doc_id"], 1);
+        assert_eq!(diff["added"][0]["similar_que"], "駐車場はありますか？");
+        assert_eq!(diff["removed"], json!([]));
+
+        // 保存していない世代は400
+        assert_eq!(diff_models(&versions, 1, 3, &["料金は？".to_string()]).unwrap_err().code(), 400);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let event: Value = json!({ "mode": "m", "action": "diff_models", "pkey": "admin_key", "from": 1, "to": "2", "queries": [" 料金は？ "] });
+        assert_eq!(request_schema::validate(&event), Ok(()));
+        assert_eq!(parse_diff_models(&event), Ok(AdminAction::DiffModels { target: tenant::Tenant::default(), from: 1, to: 2, queries: vec!["料金は？".to_string()] }));
+        // 世代の欠落・空の queries・文字列以外の質問文は400
+        for event in [json!({ "to": 2, "queries": ["料金は？"] }), json!({ "from": 1, "to": 2, "queries": [] }), json!({ "from": 1, "to": 2, "queries": [1] })] {
+            assert_eq!(parse_diff_models(&event).unwrap_err().code(), 400);
+        }
+    }
+
+    #[test]
+    fn authorize_admin_test1() {
+        // 管理用は管理用キーのみ
+        assert_eq!(authorize("admin_key", "m", "predict_only", "admin_key", &[]), Ok(()));
+        assert_eq!(authorize(STR_PKEY, "m", "predict_only", "admin_key", &[]).unwrap_err().code(), 403);
+        assert_eq!(authorize("predict_only", "m", "predict_only", "admin_key", &[]).unwrap_err().code(), 403);
+        assert_eq!(authorize("abc", "m", "predict_only", "admin_key", &[]), Err(AuthError::InvalidKey));
+        // 管理用キー未設定時は誰も実行できない
+        assert_eq!(authorize(STR_PKEY, "m", "", "", &[]).unwrap_err().code(), 403);
+        assert_eq!(authorize("", "m", "", "", &[]), Err(AuthError::MissingKey));
+        // 管理用キーでは学習・予測はできない
+        assert_eq!(authorize("admin_key", "p", "", "admin_key", &[]).unwrap_err().code(), 403);
+        assert_eq!(authorize("admin_key", "l", "", "admin_key", &[]).unwrap_err().code(), 403);
+    }
+
+    #[test]
+    fn authorize_tenant_test1() {
+        // テナントの pkey は管理用以外を実行できる
+        let tenant_pkeys: Vec<String> = vec!["tenant_a".to_string()];
+        for mode in ["l", "p", "a", "d", "t"] {
+            assert_eq!(authorize("tenant_a", mode, "predict_only", "admin_key", &tenant_pkeys), Ok(()));
+        }
+        assert_eq!(authorize("tenant_a", "m", "predict_only", "admin_key", &tenant_pkeys).unwrap_err().code(), 403);
+        assert_eq!(authorize("tenant_b", "l", "predict_only", "admin_key", &tenant_pkeys), Err(AuthError::InvalidKey));
+        // 予測専用キー・管理用 pkey は既定のテナント
+        assert_eq!(tenant_of("tenant_a"), tenant::Tenant::new("tenant_a", STR_PKEY));
+        assert_eq!(tenant_of(STR_PKEY), tenant::Tenant::default());
+    }
+
+    #[test]
+    fn list_datasets_test1() {
+        let storage = storage::LocalStorage { root: std::env::temp_dir().join("nango_list_datasets_test1") };
+        let _ = std::fs::remove_dir_all(&storage.root);
+        let bundle_path = std::env::temp_dir().join("nango_list_datasets_test1.json");
+        let docs: Vec<Vec<String>> = vec![vec!["料金".to_string()], vec!["駐車場".to_string()]];
+        bundle::save_bundle(bundle_path.to_str().unwrap(), &tf_idf::TfIdf::get_tf_idf(&docs).unwrap(), &docs, &pipeline::Pipeline::default(), &[], &[], None).unwrap();
+        for (pkey, dataset) in [("tenant_a", "front"), ("tenant_b", "front")] {
+            let key: String = tenant::Tenant::new(pkey, STR_PKEY).dataset(dataset).storage_key(BUNDLE_PATH);
+            storage.put(&key, &std::fs::read(&bundle_path).unwrap()).unwrap();
+        }
+        // テナントのモデルはデータセットに含めない
+        storage.put(&tenant::Tenant::new("tenant_a", STR_PKEY).storage_key(BUNDLE_PATH), &std::fs::read(&bundle_path).unwrap()).unwrap();
+
+        let res: Value = list_datasets(Some(&storage)).unwrap();
+        let datasets: Vec<(&str, &str, u64)> = res["datasets"].as_array().unwrap().iter()
+            .map(|d| (d["tenant"].as_str().unwrap(), d["dataset"].as_str().unwrap(), d["doc_count"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(datasets, vec![("tenant_a", "front", 2), ("tenant_b", "front", 2)]);
+        assert!(res["datasets"][0]["updated_at"].as_u64().unwrap() > 0);
+        assert_eq!(list_datasets(None).unwrap()["datasets"], json!([]));
+
+        std::fs::remove_dir_all(&storage.root).unwrap();
+        std::fs::remove_file(&bundle_path).unwrap();
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "m", "action": "list_datasets" });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 403);
+    }
+
+    #[test]
+    fn predict_datasets_test1() {
+        let _output = output_lock();
+        let pkey: &str = "predict_datasets_test1";
+        let tenant = tenant::Tenant::new(pkey, STR_PKEY);
+        let lock_timeout = std::time::Duration::from_millis(DEFAULT_MODEL_LOCK_TIMEOUT_MS);
+        // 学習時に dataset を指定してテナントのデータセットを作る
+        for (dataset, csv) in [
+            ("front", "1,T,10時からです,営業時間は何時からですか？\n2,T,不要です,予約は必要ですか？\n"),
+            ("facility", "1,T,あります,駐車場はありますか？\n2,T,ありません,駐輪場はありますか？\n"),
+        ] {
+            let workspace: tenant::Tenant = tenant.dataset(dataset);
+            workspace.create_dirs().unwrap();
+            std::fs::write(workspace.path(INPUT_CSV_PATH), csv).unwrap();
+            let mode = ExecMode::Learn { persist_word_list: true, self_eval: false, column_map: None, dataset: Some(dataset.to_string()) };
+            run(mode, pkey).unwrap();
+            assert!(Path::new(&workspace.path(BUNDLE_PATH)).exists());
+        }
+        // テナントのモデルは作らない
+        assert!(!Path::new(&tenant.path(BUNDLE_PATH)).exists());
+
+        // 各データセットの候補が、どのデータセット由来かを付けてスコア順に統合される
+        let datasets: Vec<String> = vec!["front".to_string(), "facility".to_string()];
+        let predict_mode = |options: PredictOptions| ExecMode::Predict {
+            que_sentence: "駐車場はありますか？".to_string(),
+            options: Box::new(options),
+            format: ResponseFormat::Json,
+            datasets: datasets.to_owned(),
+        };
+        let res: Value = run(predict_mode(PredictOptions::default()), pkey).unwrap();
+        let qa_infos: &Vec<Value> = res["payload"]["qa_infos"].as_array().unwrap();
+        assert_eq!((qa_infos[0]["dataset"].as_str(), qa_infos[0]["ans"].as_str()), (Some("facility"), Some("あります")));
+        assert_eq!((qa_infos[0]["rank"].as_u64(), qa_infos[0]["doc_id"].as_u64()), (Some(1), Some(0)));
+        let cos_vals: Vec<f64> = qa_infos.iter().map(|info| info["cos_val"].as_f64().unwrap()).collect();
+        assert!(cos_vals.windows(2).all(|w| w[0] >= w[1]));
+        assert!(qa_infos[0].get("calibrated_score").is_none());
+        // min_results・max_results は統合後の候補数 (データセット数倍にならない)
+        let res: Value = run(predict_mode(PredictOptions { threshold: Some(2.0), min_results: Some(1), ..Default::default() }), pkey).unwrap();
+        assert_eq!(res["payload"]["qa_infos"].as_array().unwrap().len(), 1);
+        let res: Value = run(predict_mode(PredictOptions { threshold: Some(2.0), min_results: Some(3), ..Default::default() }), pkey).unwrap();
+        let qa_infos: &Vec<Value> = res["payload"]["qa_infos"].as_array().unwrap();
+        assert_eq!(qa_infos.len(), 3);
+        assert!(qa_infos.iter().any(|info| info["dataset"] == "front"));
+        let res: Value = run(predict_mode(PredictOptions { min_results: Some(4), max_results: Some(2), ..Default::default() }), pkey).unwrap();
+        assert_eq!(res["payload"]["qa_infos"].as_array().unwrap().len(), 2);
+        // calibrate 指定時は各データセットの統計で写したスコアを付与し、その順に並べる
+        let res: Value = run(predict_mode(PredictOptions { calibrate: true, ..Default::default() }), pkey).unwrap();
+        let calibrated: Vec<f64> = res["payload"]["qa_infos"].as_array().unwrap().iter().map(|info| info["calibrated_score"].as_f64().unwrap()).collect();
+        assert!(!calibrated.is_empty() && calibrated.windows(2).all(|w| w[0] >= w[1]));
+        assert_eq!(res["payload"]["qa_infos"][0]["dataset"], "facility");
+
+        // 無いデータセット・他のテナントのデータセットは400
+        let unknown: Vec<String> = vec!["front".to_string(), "hr".to_string()];
+        let options = PredictOptions::default();
+        assert!(matches!(predict_datasets(&tenant, "駐車場はありますか？", &unknown, None, lock_timeout, &options), Err(AppError::UnknownDataset(d)) if d == "hr"));
+        for other in [tenant::Tenant::default(), tenant::Tenant::new("predict_datasets_test1_other", STR_PKEY)] {
+            let res = predict_datasets(&other, "駐車場はありますか？", &datasets, None, lock_timeout, &options);
+            assert!(matches!(res, Err(AppError::UnknownDataset(d)) if d == "front"));
+        }
+
+        // 保存先を使う場合は、他のインスタンスで学習したデータセットを取得して検索する
+        let storage = storage::LocalStorage { root: std::env::temp_dir().join("nango_predict_datasets_test1") };
+        let _ = std::fs::remove_dir_all(&storage.root);
+        for dataset in &datasets {
+            save_model(&storage, &tenant.dataset(dataset)).unwrap();
+            // 学習したインスタンスとは別のインスタンスとして、手元の学習結果を持たない状態にする
+            *engine::lock(tenant.dataset(dataset).id()) = engine::QaEngine::default();
+        }
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
+        let res: Value = predict_datasets(&tenant, "駐車場はありますか？", &datasets, Some(&storage), lock_timeout, &options).unwrap();
+        assert_eq!(res["payload"]["qa_infos"][0]["ans"], "あります");
+        let other = tenant::Tenant::new("predict_datasets_test1_other", STR_PKEY);
+        assert!(matches!(predict_datasets(&other, "駐車場はありますか？", &datasets, Some(&storage), lock_timeout, &options), Err(AppError::UnknownDataset(_))));
+        std::fs::remove_dir_all(&storage.root).unwrap();
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
+        let _ = std::fs::remove_dir_all(other.path(""));
+    }
+
+    #[test]
+    fn init_datasets_test1() {
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "datasets": ["a", " b ", "a"] });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Predict { datasets, .. }) if datasets == vec!["a", "b"]));
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Predict { datasets, .. }) if datasets.is_empty()));
+        for datasets in [json!(["../a"]), json!([1]), json!([""])] {
+            let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "datasets": datasets });
+            assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+        }
+        // 手元のモデルを使うオプションとは併用できない
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "datasets": ["a"], "scorer": "bm25" });
+        assert_eq!(ExecMode::new(event).unwrap_err().to_string(), "scorer は datasets と同時に指定できません。");
+        // キャリブレーションはデータセットごとの統計で行うため併用できる
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "datasets": ["a"], "calibrate": true });
+        assert!(ExecMode::new(event).is_ok());
+    }
+
+    #[test]
+    fn mode_str_test1() {
+        let predict = ExecMode::Predict { que_sentence: String::new(), options: Box::default(), format: ResponseFormat::Json, datasets: vec![] };
+        let modes: Vec<(ExecMode, &str)> = vec![
+            (ExecMode::Learn { persist_word_list: true, self_eval: false, column_map: None, dataset: None }, "learn"),
+            (predict, "predict"),
+            (ExecMode::Add { que_sentence: String::new(), ans_sentence: String::new() }, "add"),
+            (ExecMode::DryRun { column_map: None }, "dry_run"),
+            (ExecMode::Admin { action: AdminAction::ListDatasets }, "admin"),
+            (ExecMode::Tokenize { sentence: String::new() }, "tokenize"),
+        ];
+        for (mode, expected) in &modes {
+            assert_eq!(mode.mode_str(), *expected);
+        }
+        // レスポンスの mode と一致する
+        assert_eq!(predict_response(vec![], DEFAULT_THRESHOLD, None)["mode"], modes[1].1);
+        assert_eq!(dry_run(INPUT_CSV_PATH, &schema::CsvSchema::default())["mode"], modes[3].1);
+        assert_eq!(list_datasets(None).unwrap()["mode"], modes[4].1);
+        assert_eq!(tokenize_sentence("料金は？", pipeline::Pipeline::default()).unwrap()["mode"], modes[5].1);
+    }
+
+    #[test]
+    fn init_test1() {
         let event: Value = json!({
-            "mode": "l", // pkeyがない場合にエラーとなるか確認
+            "mode": "x", // 不正なモードでエラーとなるか確認
+            "pkey": "nango7_ai_nango_kun"
+        });
+        let res = ExecMode::new(event);
+        match res {
+            Err(error) => {
+                assert_eq!(error.to_string(), "mode は l (learn)、p (predict)、a (add)、d (dry_run)、t (tokenize)、m (admin) のいずれかを指定してください。");
+            },
+            Ok(_) => {
+                assert!(false);
+            }
+        }
+    }
+
+    #[test]
+    fn init_test2() {
+        let event: Value = json!({
+            "mode": "l", // 学習モードで処理実行されるか確認
+            "pkey": "nango7_ai_nango_kun",
+        });
+        let res = ExecMode::new(event);
+        match res {
+            Err(_) => {
+                assert!(false);
+            },
+            Ok(_) => {
+                assert!(true);
+            }
+        }
+    }
+
+    #[test]
+    fn init_test3() {
+        let event: Value = json!({
+            "mode": "p", // 類推モードで処理実行されるか確認
+            "que_sentence": "お店で楽器は演奏できますか？",
+            "pkey": "nango7_ai_nango_kun",
+        });
+        let res = ExecMode::new(event);
+        match res {
+            Err(_) => {
+                assert!(false);
+            },
+            Ok(_) => {
+                assert!(true);
+            }
+        }
+    }
+
+    #[test]
+    fn init_test4() {
+        let event: Value = json!({
+            "mode": "p", // 類推モードで処理実行されるか確認
+            "que_sentence": "", // 質問文が未入力時にエラーとなるか確認
+            "pkey": "nango7_ai_nango_kun",
         });
         let res = ExecMode::new(event);
         match res {
             Err(error) => {
-                assert_eq!(error, "Not executable".to_string());
+                assert_eq!(error.to_string(), "予測時は、質問文を入力してください。");
             },
             Ok(_) => {
                 assert!(false);
@@ -348,107 +3151,1414 @@ mod tests {
     }
 
     #[test]
-    fn init_pkey_test2() {
-        let event: Value = json!({
-            "mode": "l",
-            "pkey": "" // pkeyが不正な場合(空)、エラーとなるか確認
-        });
-        let res = ExecMode::new(event);
-        match res {
-            Err(error) => {
-                assert_eq!(error, "Not executable".to_string());
-            },
-            Ok(_) => {
-                assert!(false);
-            }
-        }
+    fn read_model_csv_test1() {
+        // 壊れた値を含むモデルでパニックせず、位置情報付きエラーとなるか確認
+        let path = std::env::temp_dir().join("nango_read_model_csv_test1.csv");
+        std::fs::write(&path, "\"id\",\"犬\",\"猫\"\n\"0\",\"0.5\",\"0\"\n\"1\",\"abc\",\"0.2\"\n").unwrap();
+        let res = read_model_csv(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        match res {
+            Err(AppError::ParseFloat { row, col, value }) => {
+                assert_eq!((row, col, value.as_str()), (3, 2, "abc"));
+            },
+            _ => {
+                unreachable!();
+            }
+        }
+    }
+
+    #[test]
+    fn filter_category_test1() {
+        let qa_data = QaData {
+            que_vec: ["営業時間は？", "料金は？", "駐車場はある？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["18時からです", "1500円です", "ありません"].iter().map(|s| s.to_string()).collect(),
+            cat_vec: ["店舗", "料金", "店舗"].iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        let ans_vec: Vec<(usize, f64)> = vec![(1, 0.9), (2, 0.5), (0, 0.4)];
+        // 他カテゴリの文書が結果に出ないか確認
+        let res = filter_category(ans_vec.to_owned(), &qa_data, "店舗").unwrap();
+        assert_eq!(res, vec![(2, 0.5), (0, 0.4)]);
+
+        // 未知カテゴリはエラーとなるか確認
+        match filter_category(ans_vec, &qa_data, "メニュー") {
+            Err(AppError::UnknownCategory(category)) => {
+                assert_eq!(category, "メニュー");
+            },
+            _ => {
+                unreachable!();
+            }
+        }
+    }
+
+    #[test]
+    fn wrap_response_test1() {
+        let res = wrap_response(json!({ "code": 200, "success": true }));
+        let exp: Value = json!({
+            "code": 200,
+            "success": true,
+            "api_version": env!("CARGO_PKG_VERSION"),
+            "schema_version": "1",
+        });
+        assert_eq!(res, exp);
+    }
+
+    #[test]
+    fn handler_test1() {
+        let event: Value = json!({
+            "mode": "l", // エラー時もバージョン情報が付与されるか確認
+            "pkey": "abc"
+        });
+        let exp: Value = json!({
+            "code": 401,
+            "success": false,
+            "message": "error running init: Not executable",
+            "api_version": env!("CARGO_PKG_VERSION"),
+            "schema_version": "1",
+        });
+        assert_eq!(handler(event), exp);
+    }
+
+    #[test]
+    fn init_test5() {
+        let event: Value = json!({
+            "mode": "p", // 質問文が上限ちょうどの場合は受け付けるか確認
+            "que_sentence": "あ".repeat(1000),
+            "pkey": "nango7_ai_nango_kun",
+        });
+        assert!(ExecMode::new(event).is_ok());
+    }
+
+    #[test]
+    fn init_test6() {
+        let event: Value = json!({
+            "mode": "p", // 質問文が上限を1文字超えた場合にエラーとなるか確認
+            "que_sentence": "あ".repeat(1000) + "👨‍👩‍👧",
+            "pkey": "nango7_ai_nango_kun",
+        });
+        match ExecMode::new(event) {
+            Err(error) => {
+                assert_eq!(error.to_string(), "質問文は1000文字以内で入力してください。");
+            },
+            Ok(_) => {
+                unreachable!();
+            }
+        }
+    }
+
+    #[test]
+    fn init_learn_test1() {
+        // 単語リストCSVの出力有無の指定(デフォルトは出力する)
+        let event: Value = json!({ "mode": "l", "pkey": "nango7_ai_nango_kun" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Learn { persist_word_list: true, .. })));
+
+        let event: Value = json!({ "mode": "l", "persist_word_list": false, "pkey": "nango7_ai_nango_kun" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Learn { persist_word_list: false, .. })));
+        // 自己評価は指定時のみ
+        let event: Value = json!({ "mode": "l", "self_eval": true, "pkey": "nango7_ai_nango_kun" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Learn { self_eval: true, column_map: None, dataset: None, .. })));
+        // データセットの学習 (データセット名はパス区切りなどを含まないもののみ)
+        let event: Value = json!({ "mode": "l", "dataset": " front ", "pkey": "nango7_ai_nango_kun" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Learn { dataset: Some(dataset), .. }) if dataset == "front"));
+        for dataset in ["../a", "", "a/b"] {
+            let event: Value = json!({ "mode": "l", "dataset": dataset, "pkey": "nango7_ai_nango_kun" });
+            assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+        }
+
+        let event: Value = json!({ "mode": "l", "column_map": { "question": 1, "answer": 0 }, "pkey": "nango7_ai_nango_kun" });
+        assert!(matches!(
+            ExecMode::new(event),
+            Ok(ExecMode::Learn { column_map: Some(schema::ColumnMap { question: 1, answer: 0, category: None }), .. })
+        ));
+        // 必須の列位置の欠落・整数以外・重複は400
+        for column_map in [json!({ "question": 1 }), json!({ "question": 1, "answer": -1 }), json!({ "question": 1, "answer": 0, "category": "2" }), json!({ "question": 1, "answer": 1 })] {
+            let event: Value = json!({ "mode": "l", "column_map": column_map, "pkey": "nango7_ai_nango_kun" });
+            assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+        }
+    }
+
+    #[test]
+    fn check_rate_limit_test1() {
+        let mut limiter = rate_limit::RateLimiter::new(1.0, 0.0);
+        assert!(check_rate_limit(&mut limiter, STR_PKEY).is_none());
+        // 上限を超えたら429
+        let res: Value = check_rate_limit(&mut limiter, STR_PKEY).unwrap();
+        assert_eq!(res["code"], 429);
+        assert_eq!(res["success"], false);
+    }
+
+    #[test]
+    fn catch_panic_test1() {
+        // 意図的にパニックさせ、内容を出さずに500が返るか確認
+        let res: Value = catch_panic(|| panic!("model index out of range"));
+        let exp: Value = json!({
+            "code": 500,
+            "success": false,
+            "message": "internal error",
+        });
+        assert_eq!(res, exp);
+
+        let res: Value = catch_panic(|| json!({ "code": 200, "success": true }));
+        assert_eq!(res["code"], 200);
+    }
+
+    #[test]
+    fn init_add_test1() {
+        // 追加時は質問文・回答文の両方が必要
+        let event: Value = json!({ "mode": "a", "que_sentence": "駐車場は？", "pkey": "nango7_ai_nango_kun" });
+        assert_eq!(ExecMode::new(event).unwrap_err().to_string(), "追加時は、質問文と回答文を入力してください。");
+
+        let event: Value = json!({ "mode": "a", "que_sentence": "駐車場は？", "ans_sentence": "ありません。", "pkey": "nango7_ai_nango_kun" });
+        match ExecMode::new(event) {
+            Ok(ExecMode::Add { que_sentence, ans_sentence }) => {
+                assert_eq!((que_sentence.as_str(), ans_sentence.as_str()), ("駐車場は？", "ありません。"));
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn out_csv_deterministic_test1() {
+        // 同じ入力から繰り返し学習しても同一のモデルCSVになるか確認
+        let docs: Vec<Vec<String>> = vec![
+            ["料金", "は", "いくら", "？"].iter().map(|s| s.to_string()).collect(),
+            ["駐車場", "は", "あり", "ます", "か", "？"].iter().map(|s| s.to_string()).collect(),
+            ["予約", "の", "料金", "は", "？"].iter().map(|s| s.to_string()).collect(),
+        ];
+        let mut outputs: Vec<Vec<u8>> = Vec::new();
+        for i in 0..3 {
+            let path = std::env::temp_dir().join(format!("nango_out_csv_deterministic_test1_{}.csv", i));
+            let path_str: &str = path.to_str().unwrap();
+            out_csv(path_str, &tf_idf::TfIdf::get_tf_idf(&docs).unwrap()).unwrap();
+            outputs.push(std::fs::read(path_str).unwrap());
+            std::fs::remove_file(path_str).unwrap();
+        }
+        assert!(outputs.windows(2).all(|w| w[0] == w[1]));
+    }
+
+    #[test]
+    fn read_csv_columns_test1() {
+        let path = std::env::temp_dir().join("nango_read_csv_columns_test1.csv");
+        let path_str: &str = path.to_str().unwrap();
+        // 列不足の行はパニックせずスキップされるか確認
+        std::fs::write(path_str, "1,T,1500円です。,料金は？,料金\n2,T,ありません。\n3,T,あります。,Wi-Fiは？\n").unwrap();
+        let qa_data: QaData = read_csv(&tenant::Tenant::default(), path_str).unwrap();
+        assert_eq!(qa_data.que_vec, vec!["料金は？", "Wi-Fiは？"]);
+        assert_eq!(qa_data.cat_vec, vec!["料金", ""]);
+
+        // 1行目が列不足ならエラー
+        std::fs::write(path_str, "1,T\n2,T,あります。,Wi-Fiは？\n").unwrap();
+        assert!(matches!(read_csv(&tenant::Tenant::default(), path_str), Err(AppError::CsvColumns { row: 1, .. })));
+        std::fs::remove_file(path_str).unwrap();
+    }
+
+    #[test]
+    fn read_csv_tsv_test1() {
+        let path = std::env::temp_dir().join("nango_read_csv_tsv_test1.tsv");
+        let path_str: &str = path.to_str().unwrap();
+        // TSVも区切り文字を推定して読めるか確認 (値中のカンマは区切りとみなさない)
+        std::fs::write(path_str, "1\tT\t1500円です。また,ドリンクは500円です。\t料金は？\n2\tT\tありません。\t駐車場は？\n").unwrap();
+        let qa_data: QaData = read_csv(&tenant::Tenant::default(), path_str).unwrap();
+        assert_eq!(qa_data.que_vec, vec!["料金は？", "駐車場は？"]);
+        assert_eq!(qa_data.ans_vec, vec!["1500円です。また,ドリンクは500円です。", "ありません。"]);
+        std::fs::remove_file(path_str).unwrap();
+    }
+
+    #[test]
+    fn model_csv_roundtrip_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            ["料金", "は", "いくら", "？"].iter().map(|s| s.to_string()).collect(),
+            ["駐車場", "は", "あり", "ます", "か", "？"].iter().map(|s| s.to_string()).collect(),
+        ];
+        let tfidf = tf_idf::TfIdf::get_tf_idf(&docs).unwrap();
+        // 書き出した内容がそのまま読めるか確認
+        let path = std::env::temp_dir().join("nango_model_csv_roundtrip_test1.csv");
+        let path_str: &str = path.to_str().unwrap();
+        out_csv(path_str, &tfidf).unwrap();
+        let res = read_model_csv(path_str).unwrap();
+        assert_eq!(res.word_vec, tfidf.word_vec);
+        assert_eq!(res.tf_idf_vec, tfidf.tf_idf_vec);
+        std::fs::remove_file(path_str).unwrap();
+    }
+
+    #[test]
+    fn model_json_roundtrip_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            ["料金", "は", "いくら", "？"].iter().map(|s| s.to_string()).collect(),
+            ["駐車場", "は", "あり", "ます", "か", "？"].iter().map(|s| s.to_string()).collect(),
+        ];
+        let tfidf = tf_idf::TfIdf::get_tf_idf(&docs).unwrap();
+        // CSVとJSONで同じモデルが得られるか確認
+        let csv_path = std::env::temp_dir().join("nango_model_json_roundtrip_test1.csv");
+        let json_path = std::env::temp_dir().join("nango_model_json_roundtrip_test1.json");
+        out_model(csv_path.to_str().unwrap(), &tfidf).unwrap();
+        out_model(json_path.to_str().unwrap(), &tfidf).unwrap();
+        let from_csv = read_model(csv_path.to_str().unwrap()).unwrap();
+        let from_json = read_model(json_path.to_str().unwrap()).unwrap();
+        assert_eq!(from_json.word_vec, from_csv.word_vec);
+        assert_eq!(from_json.tf_idf_vec, from_csv.tf_idf_vec);
+
+        // 全文書に出現する語のIDFは1
+        let json: Value = serde_json::from_slice(&std::fs::read(&json_path).unwrap()).unwrap();
+        assert_eq!(json["idf"]["は"], 1.0);
+        assert!(json["idf"]["料金"].as_f64().unwrap() > 1.0);
+        std::fs::remove_file(csv_path).unwrap();
+        std::fs::remove_file(json_path).unwrap();
+    }
+
+    #[test]
+    fn read_feedback_csv_test1() {
+        let path = std::env::temp_dir().join("nango_read_feedback_csv_test1.csv");
+        std::fs::write(&path, "料金は？,0\n駐車場は？,x\nチャージは？,3\n").unwrap();
+        let feedback: Vec<(String, usize)> = read_feedback_csv(path.to_str().unwrap()).unwrap();
+        assert_eq!(feedback, vec![("料金は？".to_string(), 0), ("チャージは？".to_string(), 3)]);
+        std::fs::remove_file(path).unwrap();
+        // ファイルが無い場合はフィードバック無し
+        assert!(read_feedback_csv("not_found_feedback.csv").unwrap().is_empty());
+    }
+
+    #[test]
+    fn vocab_stats_csv_test1() {
+        let docs: Vec<Vec<String>> = [&["料金", "は", "いくら"][..], &["駐車場", "は", "ある"], &["料金", "の", "支払い", "は"], &[]]
+            .iter().map(|doc| doc.iter().map(|s| s.to_string()).collect()).collect();
+        let tfidf = tf_idf::TfIdf::get_tf_idf(&docs).unwrap();
+        let path = std::env::temp_dir().join("nango_vocab_stats_csv_test1.csv");
+        out_vocab_stats_csv(path.to_str().unwrap(), &tfidf, &docs).unwrap();
+        let mut rdr = csv::Reader::from_path(&path).unwrap();
+        let rows: Vec<(String, usize, f64)> = rdr.records()
+            .map(|r| r.unwrap())
+            .map(|r| (r[0].to_string(), r[1].parse().unwrap(), r[2].parse().unwrap()))
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        // 文書頻度の降順、同数は辞書順
+        let words: Vec<&str> = rows.iter().map(|(word, _, _)| word.as_str()).collect();
+        assert_eq!(words, vec!["は", "料金", "ある", "いくら", "の", "支払い", "駐車場"]);
+        assert_eq!((rows[0].1, rows[1].1, rows[2].1), (3, 2, 1));
+        // TF-IDF値 = TF * 出力したIDF となるか確認
+        for (word, _, idf) in &rows {
+            let index: usize = tfidf.word_vec.binary_search(word).unwrap();
+            for (doc, row) in docs.iter().zip(&tfidf.tf_idf_vec) {
+                let tf: f64 = doc.iter().filter(|w| *w == word).count() as f64 / doc.len().max(1) as f64;
+                let val: f64 = row.get(&index).map_or(0.0, |v| tf_idf::to_f64(*v));
+                assert!((val - tf * idf).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn count_csv_roundtrip_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            ["料金", "は", "料金", "\"カンマ,入り\""].iter().map(|s| s.to_string()).collect(),
+            vec![],
+            ["演奏", "でき", "ます"].iter().map(|s| s.to_string()).collect(),
+        ];
+        let counts = count::CountMatrix::from_docs(&docs);
+        let path = std::env::temp_dir().join("nango_count_csv_roundtrip_test1.csv");
+        let path: &str = path.to_str().unwrap();
+        out_count_csv(path, &counts).unwrap();
+        // 疎形式のため語の無い文書は行を持たない
+        assert_eq!(std::fs::read_to_string(path).unwrap().lines().count(), 6);
+        assert_eq!(read_count_csv(path, docs.len()).unwrap(), Some(counts));
+        // 文書数を超える文書idは不正
+        assert!(read_count_csv(path, 2).is_err());
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(read_count_csv("not_found_count.csv", 3).unwrap(), None);
+    }
+
+    #[test]
+    fn init_scorer_test1() {
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "scorer": "bm25" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Predict { options, .. }) if options.scorer == Scorer::Bm25));
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Predict { options, .. }) if options.scorer == Scorer::TfIdf));
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "scorer": "lsi" });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "scorer": "bm25", "ensemble": 0.5 });
+        assert!(ExecMode::new(event).is_err());
+    }
+
+    #[test]
+    fn init_diversity_test1() {
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "diversity": 0.5 });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Predict { options, .. }) if options.diversity == Some(0.5)));
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "diversity": 1.5 });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+    }
+
+    #[test]
+    fn with_suggestions_test1() {
+        let qa_data = QaData {
+            que_vec: ["料金は？", "駐車場は？", "営業時間は？", "予約は？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["1500円です", "ありません", "18時からです", "不要です"].iter().map(|s| s.to_string()).collect(),
+            cat_vec: vec![String::new(); 4],
+            ..Default::default()
+        };
+        let tenant = tenant::Tenant::new("with_suggestions_test1", STR_PKEY);
+        let _ = std::fs::remove_dir_all(tenant.path(""));
+        tenant.create_dirs().unwrap();
+        let suggestion_ids = |res: &Value| -> Vec<u64> {
+            res["payload"]["suggestions"].as_array().unwrap().iter().map(|s| s["doc_id"].as_u64().unwrap()).collect()
+        };
+
+        // アクセス記録が無い初期状態は先頭から
+        let res: Value = with_suggestions(predict_response(vec![], DEFAULT_THRESHOLD, None), &qa_data, &tenant).unwrap();
+        assert_eq!(suggestion_ids(&res), vec![0, 1, 2]);
+        assert_eq!(res["payload"]["suggestions"][0]["que"], "料金は？");
+
+        // 候補がある場合は先頭候補のアクセス数を記録し、サジェストは付けない
+        let ans_vec: Vec<(usize, f64)> = vec![(3, 0.9), (1, 0.5)];
+        for _ in 0..2 {
+            let res: Value = with_suggestions(make_json("予約は？".to_string(), &qa_data, ans_vec.to_owned(), &PredictOptions::default(), &BandConfig::default(), None), &qa_data, &tenant).unwrap();
+            assert!(res["payload"].get("suggestions").is_none());
+        }
+        let res: Value = with_suggestions(predict_response(vec![], DEFAULT_THRESHOLD, None), &qa_data, &tenant).unwrap();
+        assert_eq!(suggestion_ids(&res), vec![3, 0, 1]);
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
+
+        // アクセス数を書き込めなくても (ディレクトリの無いテナント) 予測結果は返す
+        let res = with_suggestions(make_json("予約は？".to_string(), &qa_data, ans_vec, &PredictOptions::default(), &BandConfig::default(), None), &qa_data, &tenant);
+        assert_eq!(res.unwrap()["payload"]["qa_infos"][0]["doc_id"], 3);
+    }
+
+    #[test]
+    fn init_popularity_test1() {
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "popularity": 0.2 });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Predict { options, .. }) if options.popularity == Some(0.2)));
+        // 負・NaN・無限大は全候補のスコアを壊すため400
+        for popularity in [json!(-1), json!("NaN"), json!("inf"), json!("-inf")] {
+            let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "popularity": popularity });
+            assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+        }
+    }
+
+    #[test]
+    fn init_normalize_test1() {
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "P", "que_sentence": "  料金は？ " });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Predict { que_sentence, .. }) if que_sentence == "料金は？"));
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": " l " });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Learn { .. })));
+        // 空白のみの質問は未入力扱い
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "   " });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+        // pkey は前後空白を許さない
+        let event: Value = json!({ "pkey": format!(" {} ", STR_PKEY), "mode": "p", "que_sentence": "料金は？" });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 401);
+    }
+
+    #[test]
+    fn model_info_test1() {
+        let path = std::env::temp_dir().join("nango_model_info_test1.json");
+        let path_str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path_str);
+        // 記録の無い旧モデルはnull
+        assert_eq!(read_model_info(path_str).unwrap(), None);
+        assert_eq!(with_model_info(json!({ "payload": {} }), path_str).unwrap()["payload"]["model_info"], Value::Null);
+
+        pipeline::Pipeline::default().write_meta(path_str).unwrap();
+        write_model_info(path_str, next_model_version(path_str).unwrap(), 3).unwrap();
+        write_model_info(path_str, next_model_version(path_str).unwrap(), 4).unwrap();
+        let info: response::ModelInfo = read_model_info(path_str).unwrap().unwrap();
+        assert_eq!((info.version, info.num_docs), (2, 4));
+        assert!(info.trained_at > 0);
+        let res: Value = with_model_info(json!({ "payload": {} }), path_str).unwrap();
+        assert_eq!(res["payload"]["model_info"], json!({ "version": 2, "trained_at": info.trained_at, "num_docs": 4 }));
+        // モデルメタの前処理の構成はそのまま読める
+        assert!(pipeline::Pipeline::read_meta(path_str).unwrap().is_some());
+        std::fs::remove_file(path_str).unwrap();
+    }
+
+    #[test]
+    fn log_unanswered_test1() {
+        let tenant = tenant::Tenant::new("log_unanswered_test1", STR_PKEY);
+        let _ = std::fs::remove_dir_all(tenant.path(""));
+        tenant.create_dirs().unwrap();
+        let path: String = tenant.path(UNANSWERED_PATH);
+        // しきい値を超える候補がある場合は記録しない
+        assert!(!log_unanswered(&tenant, "料金は？", &[(0, 0.9), (1, 0.1)], 0.3).unwrap());
+        assert!(!Path::new(&path).exists());
+        // 候補ゼロ・低スコアのみの場合は記録し、同じクエリは件数を増やす
+        assert!(log_unanswered(&tenant, "駐車場は？", &[], 0.3).unwrap());
+        assert!(log_unanswered(&tenant, "駐車場は？", &[(1, 0.2), (0, 0.25)], 0.3).unwrap());
+        let entries: Vec<unanswered::Unanswered> = unanswered::read_unanswered(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].query.as_str(), entries[0].max_score, entries[0].count), ("駐車場は？", 0.25, 2));
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
+    }
+
+    #[test]
+    fn fuse_trigram_test1() {
+        let que_vec: Vec<String> = ["料金はいくらですか？", "駐車場はありますか？"].iter().map(|s| s.to_string()).collect();
+        // タイポ (場→城) で「駐車場」が語として一致せず、別の候補が上位になっている
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.35), (1, 0.3)];
+        let ids = |v: &[(usize, f64)]| -> Vec<usize> { v.iter().map(|(id, _)| *id).collect() };
+        assert_eq!(ids(&fuse_trigram(ans_vec.clone(), "駐車城はありますか？", &que_vec, 0.5)), vec![1, 0]);
+        // 重み0では元のスコアのまま
+        assert_eq!(fuse_trigram(ans_vec.clone(), "駐車城はありますか？", &que_vec, 0.0), ans_vec);
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "typo_weight": 1.5 });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+    }
+
+    #[test]
+    fn storage_model_test1() {
+        let storage = storage::LocalStorage { root: std::env::temp_dir().join("nango_storage_model_test1") };
+        let _ = std::fs::remove_dir_all(&storage.root);
+        let tenant = tenant::Tenant::new("storage_model_test1", STR_PKEY);
+        let timeout = std::time::Duration::from_secs(10);
+
+        // 保存先に無いテナントは手元のモデルを使わずエラー
+        assert!(matches!(fetch_model(&storage, &tenant, timeout), Err(AppError::ModelNotFound(_))));
+        tenant.create_dirs().unwrap();
+        for path in STORED_PATHS {
+            std::fs::write(tenant.path(path), path).unwrap();
+        }
+        save_model(&storage, &tenant).unwrap();
+        assert_eq!(storage.get("models/storage_model_test1/study_qa1.csv").unwrap(), Some(INPUT_CSV_PATH.as_bytes().to_vec()));
+        // 書き込んだ版は取得し直さない
+        assert!(!fetch_model(&storage, &tenant, timeout).unwrap());
+
+        // 他のインスタンスで更新された場合は取得し直し、保持中の学習結果を捨てる
+        engine::lock(tenant.id()).set_word_list(vec![]);
+        storage.put(&tenant.storage_key(BUNDLE_PATH), b"{\"format_version\":1}").unwrap();
+        assert!(fetch_model(&storage, &tenant, timeout).unwrap());
+        assert_eq!(std::fs::read(tenant.path(BUNDLE_PATH)).unwrap(), b"{\"format_version\":1}");
+        assert!(engine::lock(tenant.id()).word_list().is_none());
+        assert!(!fetch_model(&storage, &tenant, timeout).unwrap());
+
+        // 他のテナントのモデルには切り替わらない
+        let other = tenant::Tenant::new("storage_model_test1_other", STR_PKEY);
+        assert!(matches!(fetch_model(&storage, &other, timeout), Err(AppError::ModelNotFound(_))));
+        assert!(matches!(require_model(&other), Err(AppError::ModelNotFound(_))));
+
+        std::fs::remove_dir_all(&storage.root).unwrap();
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
+    }
+
+    #[test]
+    fn tenant_learn_predict_test1() {
+        let _output = output_lock();
+        let pkey: &str = "tenant_learn_predict_test1";
+        let tenant = tenant::Tenant::new(pkey, STR_PKEY);
+        tenant.create_dirs().unwrap();
+        std::fs::write(tenant.path(INPUT_CSV_PATH), "1,T,テナントの回答です。,テナント専用の質問はありますか？\n2,T,二件目です。,駐車場はありますか？\n").unwrap();
+        let default_bundle: Vec<u8> = std::fs::read(BUNDLE_PATH).unwrap();
+
+        // 未学習のテナントは既定のテナントのモデルを使わない
+        let predict_mode = || ExecMode::Predict {
+            que_sentence: "テナント専用の質問はありますか？".to_string(),
+            options: Box::default(),
+            format: ResponseFormat::Json,
+            datasets: vec![],
+        };
+        assert_eq!(run(predict_mode(), pkey).unwrap_err().code(), 400);
+
+        // テナントの学習データで学習し、テナントの出力先に書き出す (既定のテナントのモデルは書き換えない)
+        run(ExecMode::Learn { persist_word_list: true, self_eval: false, column_map: None, dataset: None }, pkey).unwrap();
+        assert!(Path::new(&tenant.path(BUNDLE_PATH)).exists());
+        assert_eq!(std::fs::read(BUNDLE_PATH).unwrap(), default_bundle);
+        let res: Value = run(predict_mode(), pkey).unwrap();
+        assert_eq!(res["payload"]["qa_infos"][0]["ans"], "テナントの回答です。");
+        // 既定のテナントの予測は、テナントの学習結果・キャッシュに影響されない
+        let res: Value = predict(&tenant::Tenant::default(), "テナント専用の質問はありますか？".to_string(), PredictOptions::default()).unwrap();
+        assert_ne!(res["payload"]["qa_infos"][0]["ans"], "テナントの回答です。");
+
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
     }
 
     #[test]
-    fn init_pkey_test3() {
-        let event: Value = json!({
-            "mode": "l",
-            "pkey": "abc" // pkeyが不正な場合(間違い)、エラーとなるか確認
-        });
-        let res = ExecMode::new(event);
-        match res {
-            Err(error) => {
-                assert_eq!(error, "Not executable".to_string());
-            },
-            Ok(_) => {
-                assert!(false);
-            }
+    fn predict_broken_csv_test1() {
+        let _output = output_lock();
+        let pkey: &str = "predict_broken_csv_test1";
+        let tenant = tenant::Tenant::new(pkey, STR_PKEY);
+        tenant.create_dirs().unwrap();
+        std::fs::write(tenant.path(INPUT_CSV_PATH), "1,T,あります,駐車場はありますか？\n2,T,不要です,予約は必要ですか？\n").unwrap();
+        run(ExecMode::Learn { persist_word_list: true, self_eval: false, column_map: None, dataset: None }, pkey).unwrap();
+
+        // 学習後に列の足りない学習データCSVに置き換わっても、プロセスを終了せずエラー (学習データの不備のため500) を返す
+        std::fs::write(tenant.path(INPUT_CSV_PATH), "1,T\n2,T\n").unwrap();
+        let predict_mode = ExecMode::Predict {
+            que_sentence: "駐車場はありますか？".to_string(),
+            options: Box::default(),
+            format: ResponseFormat::Json,
+            datasets: vec![],
+        };
+        let err: AppError = run(predict_mode, pkey).unwrap_err();
+        assert!(matches!(err, AppError::CsvColumns { row: 1, .. }));
+        assert_eq!(err.code(), 500);
+
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn predict_readonly_lock_test1() {
+        let _output = output_lock();
+        let pkey: &str = "predict_readonly_lock_test1";
+        let tenant = tenant::Tenant::new(pkey, STR_PKEY);
+        tenant.create_dirs().unwrap();
+        std::fs::write(tenant.path(INPUT_CSV_PATH), "1,T,あります,駐車場はありますか？\n2,T,不要です,予約は必要ですか？\n").unwrap();
+        run(ExecMode::Learn { persist_word_list: true, self_eval: false, column_map: None, dataset: None }, pkey).unwrap();
+
+        // 読み込み専用のファイルシステムと同じく、ロックファイルを作れない状態にする (作成先のディレクトリが無いリンク)
+        let lock_path: String = tenant.path(MODEL_LOCK_PATH);
+        let missing_dir = std::env::temp_dir().join("nango_predict_readonly_lock_test1");
+        let _ = std::fs::remove_dir_all(&missing_dir);
+        let _ = std::fs::remove_file(&lock_path);
+        std::os::unix::fs::symlink(missing_dir.join("model.lock"), &lock_path).unwrap();
+        let predict_mode = ExecMode::Predict {
+            que_sentence: "駐車場はありますか？".to_string(),
+            options: Box::default(),
+            format: ResponseFormat::Json,
+            datasets: vec![],
+        };
+        let res: Value = run(predict_mode, pkey).unwrap();
+        assert_eq!(res["payload"]["qa_infos"][0]["ans"], "あります");
+        // 学習はロックを取れないため失敗する
+        assert!(run(ExecMode::Learn { persist_word_list: true, self_eval: false, column_map: None, dataset: None }, pkey).is_err());
+
+        std::fs::remove_dir_all(tenant.path("")).unwrap();
+    }
+
+    #[test]
+    fn dry_run_test1() {
+        let path = std::env::temp_dir().join("nango_dry_run_test1.csv");
+        let path_str = path.to_str().unwrap();
+        std::fs::write(path_str, "1,T,1500円です。,料金は？,料金\n2,T\n3,T,あります。,Wi-Fiは？\n").unwrap();
+        let res: Value = dry_run(path_str, &schema::CsvSchema::default());
+        let payload: &Value = &res["payload"];
+        assert_eq!((payload["valid"].as_bool(), payload["doc_count"].as_u64()), (Some(true), Some(2)));
+        assert_eq!(payload["skipped_rows"], json!([2]));
+        assert_eq!(payload["preview"][1], json!({ "row": 3, "question": "Wi-Fiは？", "answer": "あります。", "category": "" }));
+
+        // 1行目から列が足りない壊れたCSVはエラー内容を返す
+        std::fs::write(path_str, "1,T\n2,T,ありません。\n").unwrap();
+        let res: Value = dry_run(path_str, &schema::CsvSchema::default());
+        std::fs::remove_file(path_str).unwrap();
+        assert_eq!(res["payload"]["valid"], false);
+        assert!(res["payload"]["error"].as_str().unwrap().contains("列数が不足しています"));
+        assert_eq!(res["payload"]["preview"], json!([]));
+        // ファイルが無い場合
+        assert!(dry_run("not_found_dry_run.csv", &schema::CsvSchema::default())["payload"]["error"].is_string());
+
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "d" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::DryRun { column_map: None })));
+    }
+
+    #[test]
+    fn load_schema_test1() {
+        // 保持中の列構成があればモデルメタを読み直さない
+        let mut engine = engine::QaEngine::default();
+        let csv_schema: schema::CsvSchema = schema::ColumnMap { question: 0, answer: 1, category: None }.into();
+        engine.set_schema(csv_schema);
+        assert_eq!(*load_schema(&mut engine, &tenant::Tenant::default()).unwrap(), csv_schema);
+    }
+
+    #[test]
+    fn dry_run_column_map_test1() {
+        let path = std::env::temp_dir().join("nango_dry_run_column_map_test1.csv");
+        let path_str = path.to_str().unwrap();
+        std::fs::write(path_str, "料金は？,1500円です。
+Wi-Fiは？,あります。
+").unwrap();
+        // 学習時と同じ列位置で読む
+        let column_map = schema::ColumnMap { question: 0, answer: 1, category: None };
+        let res: Value = dry_run(path_str, &column_map.into());
+        std::fs::remove_file(path_str).unwrap();
+        let payload: &Value = &res["payload"];
+        assert_eq!((payload["valid"].as_bool(), payload["doc_count"].as_u64()), (Some(true), Some(2)));
+        assert_eq!((payload["columns"]["question"].as_u64(), payload["columns"]["answer"].as_u64()), (Some(0), Some(1)));
+        assert_eq!(payload["preview"][0], json!({ "row": 1, "question": "料金は？", "answer": "1500円です。", "category": "" }));
+
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "d", "column_map": { "question": 0, "answer": 1 } });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::DryRun { column_map: Some(map) }) if map == column_map));
+    }
+
+    #[test]
+    fn group_candidates_test1() {
+        // 文書0と2は似た質問、1は別の質問
+        let qa_infos: Value = json!([{ "doc_id": 0 }, { "doc_id": 1 }, { "doc_id": 2 }]);
+        let sim = |a: usize, b: usize| if a.min(b) == 0 && a.max(b) == 2 { 0.8 } else { 0.1 };
+        let groups = group_candidates(&qa_infos, 0.5, sim);
+        let doc_ids: Vec<(u64, Vec<u64>)> = groups.iter()
+            .map(|g| (g.representative["doc_id"].as_u64().unwrap(), g.members.iter().map(|m| m["doc_id"].as_u64().unwrap()).collect()))
+            .collect();
+        assert_eq!(doc_ids, vec![(0, vec![2]), (1, vec![])]);
+        assert!(group_candidates(&json!([]), 0.5, sim).is_empty());
+    }
+
+    #[test]
+    fn predict_html_cluster_test1() {
+        // cluster 指定時も、まとめた候補をすべてHTMLに描画する
+        let options = PredictOptions { cluster: true, ..Default::default() };
+        let res: Value = predict(&tenant::Tenant::default(), "お店で楽器は演奏できますか？".to_string(), options).unwrap();
+        let html: String = html::render_predict(&res);
+        assert!(!res["payload"]["groups"].as_array().unwrap().is_empty());
+        assert_eq!(html.matches("<section class=\"nango-qa-cluster\">").count(), res["payload"]["groups"].as_array().unwrap().len());
+        for info in res["payload"]["qa_infos"].as_array().unwrap() {
+            assert!(html.contains(&html::escape(info["similar_que"].as_str().unwrap())));
         }
     }
 
     #[test]
-    fn init_test1() {
-        let event: Value = json!({
-            "mode": "x", // 不正なモードでエラーとなるか確認
-            "pkey": "nango7_ai_nango_kun"
-        });
-        let res = ExecMode::new(event);
-        match res {
-            Err(error) => {
-                assert_eq!(error, "学習: l、予測: p を指定してください。".to_string());
-            },
-            Ok(_) => {
-                assert!(false);
-            }
+    fn related_qas_test1() {
+        let qa_data = QaData {
+            que_vec: ["駐車場はありますか？", "駐車場の料金は？", "予約は必要ですか？", "駐輪場はありますか？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["あります", "無料です", "不要です", "ありません"].iter().map(|s| s.to_string()).collect(),
+            cat_vec: vec![String::new(); 4],
+            ..Default::default()
+        };
+        let docs: Vec<Vec<String>> = qa_data.que_vec.iter().map(|que| query_tokens(&tenant::Tenant::default(), que).unwrap()).collect();
+        let tfidf = tf_idf::TfIdf::get_tf_idf(&docs).unwrap();
+        let ques = |related: Vec<response::RelatedQa>| -> Vec<String> { related.into_iter().map(|r| r.que).collect() };
+        // 自分自身は含まず、駐車場・ありますかを共有する質問が上位
+        let related: Vec<String> = ques(related_qas(0, &qa_data, 2, |a, b| tfidf.doc_cos(a, b)));
+        assert_eq!(related.len(), 2);
+        assert!(!related.contains(&qa_data.que_vec[0]));
+        assert!(!related.contains(&qa_data.que_vec[2]));
+        assert_eq!(related_qas(1, &qa_data, 1, |a, b| tfidf.doc_cos(a, b))[0], response::RelatedQa { que: "駐車場はありますか？".to_string(), ans: "あります".to_string() });
+        // 類似度0の文書は返さない
+        assert!(related_qas(0, &qa_data, 3, |_, _| 0.0).is_empty());
+    }
+
+    #[test]
+    fn with_calibration_test1() {
+        let qa_data = QaData {
+            que_vec: ["料金は？", "駐車場は？", "予約は？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["1500円です", "ありません", "不要です"].iter().map(|s| s.to_string()).collect(),
+            cat_vec: vec![String::new(); 3],
+            ..Default::default()
+        };
+        let res: Value = make_json("料金は？".to_string(), &qa_data, vec![(0, 0.9), (1, 0.5), (2, 0.35)], &PredictOptions::default(), &BandConfig::default(), None);
+        // 統計を記録する前のモデルでは付与しない
+        assert!(with_calibration(res.to_owned(), true, None)["payload"]["qa_infos"][0].get("calibrated_score").is_none());
+
+        let stats = calibration::ScoreStats::from_scores(vec![0.2, 0.3, 0.4, 0.5, 0.6]).unwrap();
+        let calibrated: Vec<f64> = with_calibration(res.to_owned(), true, Some(&stats))["payload"]["qa_infos"].as_array().unwrap().iter()
+            .map(|info| info["calibrated_score"].as_f64().unwrap())
+            .collect();
+        // 候補の順位は変わらない
+        assert!(calibrated.windows(2).all(|w| w[0] > w[1]));
+        assert!(with_calibration(res, false, Some(&stats))["payload"]["qa_infos"][0].get("calibrated_score").is_none());
+    }
+
+    #[test]
+    fn predict_related_test1() {
+        let options = PredictOptions { include_related: true, ..Default::default() };
+        let res: Value = predict(&tenant::Tenant::default(), "おすすめのメニュー教えてください。".to_string(), options).unwrap();
+        let qa_info: &Value = &res["payload"]["qa_infos"][0];
+        let related: &Vec<Value> = qa_info["related"].as_array().unwrap();
+        assert!(!related.is_empty() && related.len() <= DEFAULT_RELATED_COUNT);
+        assert!(related.iter().all(|r| r["que"] != qa_info["similar_que"]));
+        // 指定しない場合は付与しない
+        let res: Value = predict(&tenant::Tenant::default(), "おすすめのメニュー教えてください。".to_string(), PredictOptions::default()).unwrap();
+        assert!(res["payload"]["qa_infos"][0].get("related").is_none());
+    }
+
+    #[test]
+    fn query_coverage_test1() {
+        let vocab: Vec<String> = ["いくら", "は", "料金"].iter().map(|s| s.to_string()).collect();
+        let tokens = |v: &[&str]| -> Vec<String> { v.iter().map(|s| s.to_string()).collect() };
+        assert_eq!(query_coverage(&tokens(&["料金", "は", "いくら"]), &vocab), response::QueryCoverage { total: 3, matched: 3, ratio: 1.0 });
+        // 未知語ばかりのクエリはヒット率が低い
+        let coverage = query_coverage(&tokens(&["ギター", "の", "弦", "は"]), &vocab);
+        assert_eq!((coverage.total, coverage.matched, coverage.ratio), (4, 1, 0.25));
+        assert_eq!(query_coverage(&[], &vocab).ratio, 0.0);
+    }
+
+    #[test]
+    fn predict_coverage_test1() {
+        // 予測結果に語彙ヒット率が含まれる
+        let res: Value = predict(&tenant::Tenant::default(), "おすすめのメニュー教えてください。".to_string(), PredictOptions::default()).unwrap();
+        let coverage: &Value = &res["payload"]["query_coverage"];
+        assert!(coverage["matched"].as_u64().unwrap() <= coverage["total"].as_u64().unwrap());
+        assert!(coverage["ratio"].as_f64().unwrap() > 0.5);
+    }
+
+    #[test]
+    fn predict_matched_terms_test1() {
+        let options = PredictOptions { matched_terms: true, ..Default::default() };
+        let res: Value = predict(&tenant::Tenant::default(), "お店で楽器は演奏できますか？".to_string(), options).unwrap();
+        for qa_info in res["payload"]["qa_infos"].as_array().unwrap() {
+            let weights: Vec<f64> = qa_info["matched_terms"].as_array().unwrap().iter().map(|term| term["weight"].as_f64().unwrap()).collect();
+            assert!(weights.windows(2).all(|w| w[0] >= w[1]));
+            // 重みの合計は候補のスコアに近い
+            let sum: f64 = weights.iter().sum();
+            assert!((sum - qa_info["cos_val"].as_f64().unwrap()).abs() < 1e-6, "{} {}", sum, qa_info["cos_val"]);
         }
+        assert!(!res["payload"]["qa_infos"][0]["matched_terms"][0]["term"].as_str().unwrap().is_empty());
+        // 指定しない場合は付与しない
+        let res: Value = predict(&tenant::Tenant::default(), "お店で楽器は演奏できますか？".to_string(), PredictOptions::default()).unwrap();
+        assert!(res["payload"]["qa_infos"][0].get("matched_terms").is_none());
     }
 
     #[test]
-    fn init_test2() {
-        let event: Value = json!({
-            "mode": "l", // 学習モードで処理実行されるか確認
-            "pkey": "nango7_ai_nango_kun",
-        });
-        let res = ExecMode::new(event);
-        match res {
-            Err(_) => {
-                assert!(false);
-            },
-            Ok(_) => {
-                assert!(true);
+    fn api_gateway_body_test1() {
+        let event: Value = json!({ "requestContext": {}, "body": "{\"mode\": \"l\"}" });
+        assert_eq!(api_gateway_body(&event), Some(json!({ "mode": "l" })));
+        assert_eq!(api_gateway_body(&json!({ "requestContext": {}, "body": "{" })), Some(Value::Null));
+        // 直接呼び出しのイベントはそのまま扱う
+        assert_eq!(api_gateway_body(&json!({ "mode": "l", "body": "{}" })), None);
+    }
+
+    #[test]
+    fn api_gateway_form_test1() {
+        let body: &str = "mode=p&pkey=nango7_ai_nango_kun&que_sentence=%E6%96%99%E9%87%91%E3%81%AF%EF%BC%9F+%E9%A7%90%E8%BB%8A%E5%A0%B4&max_results=3&datasets=a&datasets=b";
+        let event: Value = json!({ "requestContext": {}, "headers": { "Content-Type": "application/x-www-form-urlencoded; charset=UTF-8" }, "body": body });
+        let body: Value = api_gateway_body(&event).unwrap();
+        assert_eq!(body, json!({ "mode": "p", "pkey": STR_PKEY, "que_sentence": "料金は？ 駐車場", "max_results": "3", "datasets": ["a", "b"] }));
+        assert_eq!(request_schema::validate(&body), Ok(()));
+        let Ok(ExecMode::Predict { que_sentence, options, datasets, .. }) = ExecMode::new(body) else {
+            panic!("予測として解析できません");
+        };
+        assert_eq!((que_sentence.as_str(), options.max_results), ("料金は？ 駐車場", Some(3)));
+        assert_eq!(datasets, vec!["a", "b"]);
+
+        // HTTP API のヘッダー名は小文字、mode はメソッドから推論する
+        let event: Value = json!({ "requestContext": { "http": { "method": "GET" } }, "headers": { "content-type": "application/x-www-form-urlencoded" }, "body": "pkey=key&que_sentence=a%26b" });
+        assert_eq!(api_gateway_body(&event), Some(json!({ "mode": "p", "pkey": "key", "que_sentence": "a&b" })));
+        // Content-Type がフォーム以外の場合はJSONとして扱う
+        let event: Value = json!({ "requestContext": {}, "headers": { "Content-Type": "text/plain" }, "body": "mode=p" });
+        assert_eq!(api_gateway_body(&event), Some(Value::Null));
+    }
+
+    #[test]
+    fn api_gateway_base64_test1() {
+        // isBase64Encoded の場合はデコードしてから解析する (フォーム・JSONとも)
+        let body: &str = "bW9kZT1wJnBrZXk9a2V5JnF1ZV9zZW50ZW5jZT0lRTYlOTYlOTklRTklODclOTE=";
+        let event: Value = json!({ "requestContext": {}, "headers": { "content-type": "application/x-www-form-urlencoded" }, "body": body, "isBase64Encoded": true });
+        assert_eq!(api_gateway_body(&event), Some(json!({ "mode": "p", "pkey": "key", "que_sentence": "料金" })));
+        let event: Value = json!({ "requestContext": {}, "body": "eyJtb2RlIjoicCJ9", "isBase64Encoded": true });
+        assert_eq!(api_gateway_body(&event), Some(json!({ "mode": "p" })));
+        // デコードできないbodyはNull
+        let event: Value = json!({ "requestContext": {}, "body": "not base64!", "isBase64Encoded": true });
+        assert_eq!(api_gateway_body(&event), Some(Value::Null));
+    }
+
+    #[test]
+    fn with_default_mode_test1() {
+        let body = |mode: Option<&str>| -> String {
+            match mode {
+                Some(mode) => json!({ "mode": mode, "pkey": STR_PKEY }).to_string(),
+                None => json!({ "pkey": STR_PKEY }).to_string(),
             }
-        }
+        };
+        let mode = |event: Value| -> Value { api_gateway_body(&event).unwrap()["mode"].clone() };
+        // mode が無い場合はメソッドから推論 (REST API / HTTP API)
+        assert_eq!(mode(json!({ "requestContext": {}, "httpMethod": "GET", "body": body(None) })), "p");
+        assert_eq!(mode(json!({ "requestContext": {}, "httpMethod": "POST", "body": body(None) })), "l");
+        assert_eq!(mode(json!({ "requestContext": { "http": { "method": "GET" } }, "body": body(None) })), "p");
+        assert_eq!(mode(json!({ "requestContext": { "http": { "method": "POST" } }, "body": body(Some("")) })), "l");
+        // 明示的な mode を優先
+        assert_eq!(mode(json!({ "requestContext": {}, "httpMethod": "GET", "body": body(Some("a")) })), "a");
+        assert_eq!(mode(json!({ "requestContext": {}, "httpMethod": "POST", "body": body(Some("p")) })), "p");
+        // 対象外のメソッド・メソッド無しは推論しない
+        assert_eq!(mode(json!({ "requestContext": {}, "httpMethod": "PUT", "body": body(None) })), Value::Null);
+        assert_eq!(mode(json!({ "requestContext": {}, "body": body(None) })), Value::Null);
     }
 
     #[test]
-    fn init_test3() {
+    fn to_proxy_response_test1() {
+        let res_json: Value = json!({ "code": 400, "success": false, "message": "予測時は、質問文を入力してください。" });
+        let res: Value = to_proxy_response(res_json.to_owned());
+        assert_eq!(res["statusCode"], 400);
+        // bodyは文字列で、日本語が\uXXXXにエスケープされない
+        let body: &str = res["body"].as_str().unwrap();
+        assert!(body.contains("予測時は、質問文を入力してください。"));
+        assert!(!body.contains("\\u"));
+        assert_eq!(serde_json::from_str::<Value>(body).unwrap(), res_json);
+    }
+
+    #[test]
+    fn proxy_handler_stream_test1() {
+        let _output = output_lock();
+        // 候補は1件ずつ body に書き出し、内容は Value にまとめて返す場合と同じ
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "max_results": 3 });
+        let streamed: Value = respond(event.to_owned(), true);
+        assert!(streamed["body"].is_string());
+        assert!(streamed.get("payload").is_none());
+        assert_eq!(streamed["cos_vals"].as_array().unwrap().len(), 3);
+        let res: Value = proxy_handler(event.to_owned());
+        assert_eq!((res["statusCode"].as_u64(), res["headers"]["Content-Type"].as_str()), (Some(200), Some("application/json; charset=utf-8")));
+        let body: Value = serde_json::from_str(res["body"].as_str().unwrap()).unwrap();
+        assert_eq!(body, handler(event));
+        assert_eq!(body["payload"]["qa_infos"].as_array().unwrap().len(), 3);
+
+        // 候補を書き換える後処理を使う場合・候補ゼロの場合は Value にまとめる
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "include_related": true });
+        assert!(respond(event, true).get("body").is_none());
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "お店で楽器は演奏できますか？", "threshold": 0.99 });
+        let res: Value = respond(event, true);
+        assert!(res.get("body").is_none() && res["payload"]["qa_infos"] == json!([]));
+    }
+
+    #[test]
+    fn to_proxy_response_html_test1() {
+        let res_json: Value = json!({ "code": 200, "success": true, "html": "<ul class=\"nango-qa\"></ul>" });
+        let res: Value = to_proxy_response(res_json.to_owned());
+        assert_eq!(res["statusCode"], 200);
+        assert_eq!(res["headers"]["Content-Type"], "text/html; charset=utf-8");
+        assert_eq!(res["body"], "<ul class=\"nango-qa\"></ul>");
+
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "format": "HTML" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Predict { format: ResponseFormat::Html, .. })));
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "format": "xml" });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+    }
+
+    #[test]
+    fn append_csv_test1() {
+        let path = std::env::temp_dir().join("nango_append_csv_test1.csv");
+        let path_str: &str = path.to_str().unwrap();
+        std::fs::write(path_str, "1,T,1500円です。,料金は？").unwrap(); // 末尾改行なし
+        append_csv(path_str, 1, "駐車場は、ありますか？", "ありません。", &schema::CsvSchema::default()).unwrap();
+
+        let qa_data: QaData = read_csv(&tenant::Tenant::default(), path_str).unwrap();
+        assert_eq!(qa_data.que_vec, vec!["料金は？", "駐車場は、ありますか？"]);
+        assert_eq!(qa_data.ans_vec, vec!["1500円です。", "ありません。"]);
+        std::fs::remove_file(path_str).unwrap();
+    }
+
+    #[test]
+    fn append_csv_column_map_test1() {
+        let path = std::env::temp_dir().join("nango_append_csv_column_map_test1.csv");
+        let path_str: &str = path.to_str().unwrap();
+        std::fs::write(path_str, "料金は？,1500円です。\n").unwrap();
+        let csv_schema: schema::CsvSchema = schema::ColumnMap { question: 0, answer: 1, category: None }.into();
+        append_csv(path_str, 1, "駐車場は、ありますか？", "ありません。", &csv_schema).unwrap();
+
+        let (qa_data, _) = read_csv_rows(path_str, &[], &csv_schema).unwrap();
+        assert_eq!(qa_data.que_vec, vec!["料金は？", "駐車場は、ありますか？"]);
+        assert_eq!(qa_data.ans_vec, vec!["1500円です。", "ありません。"]);
+        std::fs::remove_file(path_str).unwrap();
+    }
+
+    #[test]
+    fn make_json_band_test1() {
+        let qa_data = QaData {
+            que_vec: ["料金は？", "料金いくら？", "金額は？", "値段は？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["1500円です"; 4].iter().map(|s| s.to_string()).collect(),
+            cat_vec: vec![String::new(); 4],
+            ..Default::default()
+        };
+        // 境界値ちょうどは上位のバンド、しきい値以下は候補外となるか確認
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.6), (1, 0.4), (2, 0.35), (3, 0.3)];
+        let res = make_json("料金は？".to_string(), &qa_data, ans_vec, &PredictOptions::default(), &BandConfig::default(), None);
+        let bands: Vec<&str> = res["payload"]["qa_infos"].as_array().unwrap()
+            .iter().map(|v| v["band"].as_str().unwrap()).collect();
+        assert_eq!(bands, vec!["high", "medium", "low"]);
+    }
+
+    #[test]
+    fn make_json_results_test1() {
+        let qa_data = QaData {
+            que_vec: ["料金は？", "料金いくら？", "金額は？", "値段は？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["1500円です"; 4].iter().map(|s| s.to_string()).collect(),
+            cat_vec: vec![String::new(); 4],
+            ..Default::default()
+        };
+        let doc_ids = |ans_vec: Vec<(usize, f64)>, min_results: Option<usize>, max_results: Option<usize>| -> Vec<u64> {
+            let options = PredictOptions { min_results, max_results, ..Default::default() };
+            let res = make_json("料金は？".to_string(), &qa_data, ans_vec, &options, &BandConfig::default(), None);
+            res["payload"]["qa_infos"].as_array().unwrap().iter().map(|v| v["doc_id"].as_u64().unwrap()).collect()
+        };
+        // しきい値超えが少ない場合は上位から下限まで補う
+        let few: Vec<(usize, f64)> = vec![(0, 0.6), (1, 0.2), (2, 0.1), (3, 0.0)];
+        assert_eq!(doc_ids(few.clone(), None, None), vec![0]);
+        assert_eq!(doc_ids(few.clone(), Some(3), Some(5)), vec![0, 1, 2]);
+        assert_eq!(doc_ids(vec![(1, 0.2)], Some(1), None), vec![1]);
+        // 多い場合は上限で切り詰める
+        let many: Vec<(usize, f64)> = vec![(0, 0.9), (1, 0.8), (2, 0.7), (3, 0.6)];
+        assert_eq!(doc_ids(many.clone(), Some(1), Some(2)), vec![0, 1]);
+        assert_eq!(doc_ids(many, Some(1), None).len(), 4);
+
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "min_results": 3, "max_results": 2 });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "max_results": 0 });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+    }
+
+    #[test]
+    fn band_config_test1() {
+        let band_config = BandConfig { high: 0.8, medium: 0.5 };
+        assert_eq!(band_config.band(0.8), "high");
+        assert_eq!(band_config.band(0.79), "medium");
+        assert_eq!(band_config.band(0.5), "medium");
+        assert_eq!(band_config.band(0.49), "low");
+    }
+
+    #[test]
+    fn init_ensemble_test1() {
         let event: Value = json!({
-            "mode": "p", // 類推モードで処理実行されるか確認
+            "mode": "p", // 合成の重みが範囲外の場合にエラーとなるか確認
             "que_sentence": "お店で楽器は演奏できますか？",
+            "ensemble": 1.5,
             "pkey": "nango7_ai_nango_kun",
         });
-        let res = ExecMode::new(event);
-        match res {
-            Err(_) => {
-                assert!(false);
+        match ExecMode::new(event) {
+            Err(error) => {
+                assert_eq!(error.to_string(), "ensemble は0〜1の範囲で指定してください。");
             },
             Ok(_) => {
-                assert!(true);
+                unreachable!();
             }
         }
     }
 
     #[test]
-    fn init_test4() {
+    fn init_params_test1() {
+        // 数値・真偽値を文字列で受け取っても解釈できるか確認
         let event: Value = json!({
-            "mode": "p", // 類推モードで処理実行されるか確認
-            "que_sentence": "", // 質問文が未入力時にエラーとなるか確認
+            "mode": "p",
+            "que_sentence": "お店で楽器は演奏できますか？",
+            "ensemble": "0.7",
             "pkey": "nango7_ai_nango_kun",
         });
-        let res = ExecMode::new(event);
-        match res {
-            Err(error) => {
-                assert_eq!(error, "予測時は、質問文を入力してください。".to_string());
-            },
-            Ok(_) => {
-                assert!(false);
-            }
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Predict { options, .. }) if options.ensemble == Some(0.7)));
+
+        let event: Value = json!({ "mode": "l", "persist_word_list": "false", "pkey": "nango7_ai_nango_kun" });
+        assert!(matches!(ExecMode::new(event), Ok(ExecMode::Learn { persist_word_list: false, .. })));
+    }
+
+    #[test]
+    fn unknown_rate_test1() {
+        let vocab: HashSet<String> = ["料金", "は", "？"].iter().map(|s| s.to_string()).collect();
+        let docs: Vec<Vec<String>> = vec![
+            ["料金", "は", "いくら", "？"].iter().map(|s| s.to_string()).collect(),
+            ["チャージ", "料金", "について"].iter().map(|s| s.to_string()).collect(),
+        ];
+        // 7トークン中3つが未知語
+        assert_eq!(unknown_rate(&docs, &vocab), Some(3.0 / 7.0));
+        assert!(unknown_rate(&docs, &vocab).unwrap() > DEFAULT_UNKNOWN_RATE_WARN);
+        // 語彙が無い場合は判定しない
+        assert_eq!(unknown_rate(&docs, &HashSet::new()), None);
+    }
+
+    #[test]
+    fn char_ngram_vocab_test1() {
+        // 同梱モデルは辞書を持たないため、文字n-gramを語彙とする
+        let vocab: HashSet<String> = char_ngram_vocab(DEFAULT_TOKENIZER_MODEL_PATH).unwrap();
+        assert!(vocab.contains("は"));
+        assert!(!model_vocab().unwrap().is_empty());
+        // vaporetto のモデルでなければエラー
+        assert!(char_ngram_vocab(INPUT_CSV_PATH).is_err());
+    }
+
+    #[test]
+    fn handler_test2() {
+        // レスポンスはJSONのまま返し、base64エンコード扱いにしないことを確認
+        let event: Value = json!({ "mode": "x", "pkey": "nango7_ai_nango_kun" });
+        let res = handler(event);
+        assert!(res.is_object());
+        assert!(res.get("isBase64Encoded").is_none() && res.get("is_base64_encoded").is_none());
+        let body: Value = serde_json::from_str(&res.to_string()).unwrap();
+        assert_eq!(body["message"], "error running init: mode は l (learn)、p (predict)、a (add)、d (dry_run)、t (tokenize)、m (admin) のいずれかを指定してください。");
+    }
+
+    #[test]
+    fn read_csv_roundtrip_test1() {
+        // 回答内の引用符・改行・カンマが列ズレせずに読み書きできるか確認
+        let ans: &str = "チャージ料金 \"1500円\"です。\nまた、アルコール類は500円,ソフトドリンクは300円です。\r\n詳しくはお問い合わせください。";
+        let path = std::env::temp_dir().join("nango_read_csv_roundtrip_test1.csv");
+        let mut wtr = csv::WriterBuilder::new()
+            .quote_style(csv::QuoteStyle::Always)
+            .from_path(&path).unwrap();
+        wtr.write_record(["1", "T", ans, "料金は？"]).unwrap();
+        wtr.write_record(["2", "T", "18時から\n翌2時までです", "営業時間は？"]).unwrap();
+        wtr.flush().unwrap();
+
+        let qa_data = read_csv(&tenant::Tenant::default(), path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(qa_data.ans_vec, vec![ans.to_string(), "18時から\n翌2時までです".to_string()]);
+        assert_eq!(qa_data.que_vec, vec!["料金は？".to_string(), "営業時間は？".to_string()]);
+    }
+
+    #[test]
+    fn word_list_csv_roundtrip_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            ["\"", "料金", ",", "は"].iter().map(|s| s.to_string()).collect(),
+            ["複数\n行", "\"引用\""].iter().map(|s| s.to_string()).collect(),
+        ];
+        let path = std::env::temp_dir().join("nango_word_list_csv_roundtrip_test1.csv");
+        out_csv_word(path.to_str().unwrap(), &docs).unwrap();
+        let res = read_word_list_csv(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(res, docs);
+    }
+
+    /// バッファを明示しない従来の書き込み (比較用)
+    fn out_csv_word_unbuffered(csv_file_out_path: &str, docs: &[Vec<String>]) {
+        let mut wtr = csv::WriterBuilder::new()
+            .quote_style(csv::QuoteStyle::Always)
+            .flexible(true)
+            .from_path(csv_file_out_path).unwrap();
+        for doc in docs {
+            wtr.write_record(doc).unwrap();
         }
+        wtr.flush().unwrap();
+    }
+
+    fn sample_docs(n_docs: usize) -> Vec<Vec<String>> {
+        (0..n_docs).map(|id| (0..20).map(|w| format!("語{}", (id * 7 + w) % 500)).collect()).collect()
+    }
+
+    #[test]
+    fn word_list_csv_buffered_test1() {
+        // 書き込み内容が従来と同一か確認
+        let docs: Vec<Vec<String>> = sample_docs(100);
+        let path = std::env::temp_dir().join("nango_word_list_csv_buffered_test1.csv");
+        let exp_path = std::env::temp_dir().join("nango_word_list_csv_buffered_test1_exp.csv");
+        out_csv_word(path.to_str().unwrap(), &docs).unwrap();
+        out_csv_word_unbuffered(exp_path.to_str().unwrap(), &docs);
+        assert_eq!(std::fs::read(&path).unwrap(), std::fs::read(&exp_path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&exp_path).unwrap();
+    }
+
+    /// 大きな単語リストの書き込み時間の比較 (cargo test --release word_list_csv_write_bench -- --ignored --nocapture)
+    #[test]
+    #[ignore]
+    fn word_list_csv_write_bench() {
+        let docs: Vec<Vec<String>> = sample_docs(200_000);
+        let path = std::env::temp_dir().join("nango_word_list_csv_write_bench.csv");
+        let path_str = path.to_str().unwrap();
+
+        let start = std::time::Instant::now();
+        out_csv_word_unbuffered(path_str, &docs);
+        let unbuffered = start.elapsed();
+        let start = std::time::Instant::now();
+        out_csv_word(path_str, &docs).unwrap();
+        let buffered = start.elapsed();
+
+        // csv::Writer 自体も8KBのバッファを持つため、差はページキャッシュの状況により小さい
+        println!("write: unbuffered {:?}, buffered {:?}", unbuffered, buffered);
+        std::fs::remove_file(path_str).unwrap();
+    }
+
+    #[test]
+    fn make_json_template_test1() {
+        let qa_data = QaData {
+            que_vec: vec!["料金は？".to_string()],
+            ans_vec: vec!["1500円です".to_string()],
+            cat_vec: vec![String::new()],
+            ..Default::default()
+        };
+        let options = PredictOptions {
+            format_template: Some("お問い合わせありがとうございます。{answer}".to_string()),
+            ..Default::default()
+        };
+        let res = make_json("料金は？".to_string(), &qa_data, vec![(0, 1.0)], &options, &BandConfig::default(), None);
+        assert_eq!(res["payload"]["qa_infos"][0]["formatted_answer"], "お問い合わせありがとうございます。1500円です");
+        assert_eq!(res["payload"]["qa_infos"][0]["ans"], "1500円です");
+    }
+
+    #[test]
+    fn make_json_template_test2() {
+        // テンプレート未指定時は追加フィールド無し
+        let qa_data = QaData {
+            que_vec: vec!["料金は？".to_string()],
+            ans_vec: vec!["1500円です".to_string()],
+            cat_vec: vec![String::new()],
+            ..Default::default()
+        };
+        let res = make_json("料金は？".to_string(), &qa_data, vec![(0, 1.0)], &PredictOptions::default(), &BandConfig::default(), None);
+        assert!(res["payload"]["qa_infos"][0].get("formatted_answer").is_none());
+    }
+
+    #[test]
+    fn make_json_snippet_test1() {
+        let qa_data = QaData {
+            que_vec: vec!["料金は？".to_string()],
+            ans_vec: vec!["チャージ料金は1500円です。".to_string()],
+            cat_vec: vec![String::new()],
+            ..Default::default()
+        };
+        let options = PredictOptions { snippet_len: Some(6), ..Default::default() };
+        let res = make_json("料金は？".to_string(), &qa_data, vec![(0, 1.0)], &options, &BandConfig::default(), None);
+        assert_eq!(res["payload"]["qa_infos"][0]["snippet"], "チャージ料金…");
+        // ans はそのまま残す
+        assert_eq!(res["payload"]["qa_infos"][0]["ans"], "チャージ料金は1500円です。");
+    }
+
+    #[test]
+    fn make_json_fallback_test1() {
+        let qa_data = QaData {
+            que_vec: vec!["料金は？".to_string()],
+            ans_vec: vec!["1500円です".to_string()],
+            cat_vec: vec![String::new()],
+            ..Default::default()
+        };
+        let contact = response::FallbackContact {
+            message: Some("スタッフにお問い合わせください。".to_string()),
+            url: Some("https://example.com/contact".to_string()),
+        };
+        // 候補ゼロの場合は問い合わせ先を含める
+        let res = make_json("駐車場は？".to_string(), &qa_data, vec![(0, 0.1)], &PredictOptions::default(), &BandConfig::default(), Some(&contact));
+        assert_eq!(res["payload"]["fallback_contact"], json!({
+            "message": "スタッフにお問い合わせください。",
+            "url": "https://example.com/contact",
+        }));
+    }
+
+    #[test]
+    fn make_json_fallback_test2() {
+        let qa_data = QaData {
+            que_vec: vec!["料金は？".to_string()],
+            ans_vec: vec!["1500円です".to_string()],
+            cat_vec: vec![String::new()],
+            ..Default::default()
+        };
+        let contact = response::FallbackContact { message: Some("スタッフにお問い合わせください。".to_string()), url: None };
+        // 候補がある場合は含めない
+        let res = make_json("料金は？".to_string(), &qa_data, vec![(0, 1.0)], &PredictOptions::default(), &BandConfig::default(), Some(&contact));
+        assert!(res["payload"].get("fallback_contact").is_none());
+
+        // 設定が無い場合は候補ゼロでも省略
+        let qa_data = QaData::default();
+        let res = make_json("駐車場は？".to_string(), &qa_data, vec![], &PredictOptions::default(), &BandConfig::default(), None);
+        assert!(res["payload"].get("fallback_contact").is_none());
+    }
+
+    #[test]
+    fn softmax_test1() {
+        let probabilities: Vec<f64> = softmax(&[0.9, 0.5, 0.1], 0.1);
+        assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(probabilities[0] > probabilities[1] && probabilities[1] > probabilities[2]);
+        // 温度が高いほど一様に近づく
+        assert!(softmax(&[0.9, 0.5, 0.1], 10.0)[0] < probabilities[0]);
+        // 1件・全て同スコア・極端な温度
+        assert_eq!(softmax(&[0.3], 0.1), vec![1.0]);
+        assert_eq!(softmax(&[0.4; 4], 0.1), vec![0.25; 4]);
+        assert_eq!(softmax(&[1.0, 0.0], 1e-6), vec![1.0, 0.0]);
+        assert!(softmax(&[], 0.1).is_empty());
+    }
+
+    #[test]
+    fn make_json_softmax_test1() {
+        let qa_data = QaData {
+            que_vec: ["料金は？", "料金いくら？", "金額は？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["1500円です"; 3].iter().map(|s| s.to_string()).collect(),
+            cat_vec: vec![String::new(); 3],
+            ..Default::default()
+        };
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.9), (1, 0.6), (2, 0.4), (0, 0.1)];
+        let options = PredictOptions { softmax: Some(0.2), ..Default::default() };
+        let res = make_json("料金は？".to_string(), &qa_data, ans_vec.to_owned(), &options, &BandConfig::default(), None);
+        // 返す候補 (しきい値超え) の中で合計1
+        let probabilities: Vec<f64> = res["payload"]["qa_infos"].as_array().unwrap().iter()
+            .map(|info| info["probability"].as_f64().unwrap())
+            .collect();
+        assert_eq!(probabilities.len(), 3);
+        assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        // 未指定時は出力しない
+        let res = make_json("料金は？".to_string(), &qa_data, ans_vec, &PredictOptions::default(), &BandConfig::default(), None);
+        assert!(res["payload"]["qa_infos"][0].get("probability").is_none());
+
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "softmax": true, "temperature": 0 });
+        assert_eq!(ExecMode::new(event).unwrap_err().code(), 400);
+        // softmax を使わない場合は温度を見ない
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "temperature": 0 });
+        assert!(ExecMode::new(event).is_ok());
+    }
+
+    #[test]
+    fn make_json_rank_test1() {
+        let qa_data = QaData {
+            que_vec: ["料金は？", "料金いくら？", "金額は？", "値段は？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["1500円です"; 4].iter().map(|s| s.to_string()).collect(),
+            cat_vec: [""; 4].iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        let ans_vec: Vec<(usize, f64)> = vec![(2, 0.9), (0, 0.7), (3, 0.7), (1, 0.5)];
+        let res = make_json("料金は？".to_string(), &qa_data, ans_vec, &PredictOptions::default(), &BandConfig::default(), None);
+        // 降順スコアと順位が整合し、同点は同順位
+        let ranks: Vec<(u64, f64)> = res["payload"]["qa_infos"].as_array().unwrap().iter()
+            .map(|info| (info["rank"].as_u64().unwrap(), info["percentile"].as_f64().unwrap()))
+            .collect();
+        assert_eq!(ranks, vec![(1, 100.0), (2, 75.0), (2, 75.0), (4, 25.0)]);
+    }
+
+    #[test]
+    fn is_debug_mode_test1() {
+        assert!(is_debug_mode(Some("1")));
+        assert!(is_debug_mode(Some("true")));
+        // 明示的に有効化されていなければ無効
+        assert!(!is_debug_mode(None));
+        assert!(!is_debug_mode(Some("")));
+        assert!(!is_debug_mode(Some("0")));
+    }
+
+    #[test]
+    fn debug_info_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            vec!["料金".to_string(), "は".to_string()],
+            vec!["営業".to_string(), "時間".to_string(), "は".to_string()],
+        ];
+        let tfidf: tf_idf::TfIdf = tf_idf::TfIdf::get_tf_idf(&docs).unwrap();
+        let debug: response::DebugInfo = make_debug_info(vec!["料金".to_string(), "は".to_string()], &tfidf);
+
+        // トークン列と非ゼロのTF-IDF値のみを含む
+        let debug: Value = response::to_value(&debug);
+        assert_eq!(debug["query_tokens"], json!(["料金", "は"]));
+        let nonzero = debug["query_tfidf_nonzero"].as_object().unwrap();
+        assert!(nonzero["料金"].as_f64().unwrap() > 0.0);
+        assert!(nonzero.keys().all(|word| word == "料金" || word == "は"));
+
+        // DEBUG_MODE 未設定時は debug キー自体が付かない
+        let res: Value = with_debug_info(predict_response(vec![], DEFAULT_THRESHOLD, None), &["料金".to_string()], &tf_idf::TfIdf::new(vec![], vec![]));
+        assert!(res.get("debug").is_none());
+    }
+
+    #[test]
+    fn check_doc_count_test1() {
+        let qa_data = QaData {
+            que_vec: vec!["料金は？".to_string(), "営業時間は？".to_string()],
+            ans_vec: vec!["1500円です".to_string(), "9時からです".to_string()],
+            cat_vec: vec!["".to_string(); 2],
+            ..Default::default()
+        };
+        let docs: Vec<Vec<String>> = vec![vec!["料金".to_string()], vec!["営業".to_string(), "時間".to_string()]];
+        let tfidf: tf_idf::TfIdf = tf_idf::TfIdf::get_tf_idf(&docs).unwrap();
+        assert!(check_doc_count(&qa_data, &docs, &tfidf).is_ok());
+
+        // 単語リストだけ件数がずれている
+        let err: AppError = check_doc_count(&qa_data, &docs[..1], &tfidf).unwrap_err();
+        assert!(matches!(err, AppError::DocCountMismatch { qa_data: 2, word_list: 1, model: 2 }));
+        assert_eq!(err.code(), 500);
+    }
+
+    #[test]
+    fn make_json_doc_count_test1() {
+        let qa_data = QaData {
+            que_vec: vec!["料金は？".to_string()],
+            ans_vec: vec!["1500円です".to_string()],
+            cat_vec: vec!["".to_string()],
+            ..Default::default()
+        };
+        // 学習データより多い文書IDが来ても添字パニックせず除外する
+        let res = make_json("料金は？".to_string(), &qa_data, vec![(3, 0.9), (0, 0.8)], &PredictOptions::default(), &BandConfig::default(), None);
+        let qa_infos = res["payload"]["qa_infos"].as_array().unwrap();
+        assert_eq!(qa_infos.len(), 1);
+        assert_eq!(qa_infos[0]["doc_id"], 0);
+    }
+
+    #[test]
+    fn auto_threshold_test1() {
+        // 上位だけが突出した分布では平均+標準偏差が上位と下位の間に入る
+        let cos_vals: Vec<f64> = vec![0.9, 0.8, 0.2, 0.1, 0.05, 0.0, 0.0, 0.0];
+        let threshold: f64 = auto_threshold(&cos_vals);
+        assert!(0.2 < threshold && threshold < 0.8);
+        // 極端な分布でも範囲内に収める
+        assert_eq!(auto_threshold(&[0.0; 10]), AUTO_THRESHOLD_RANGE.0);
+        assert_eq!(auto_threshold(&[1.0; 10]), AUTO_THRESHOLD_RANGE.1);
+        assert_eq!(auto_threshold(&[]), DEFAULT_THRESHOLD);
+    }
+
+    #[test]
+    fn make_json_threshold_test1() {
+        let qa_data = QaData {
+            que_vec: ["料金は？", "金額は？", "駐車場は？"].iter().map(|s| s.to_string()).collect(),
+            ans_vec: ["1500円です", "1500円です", "ありません"].iter().map(|s| s.to_string()).collect(),
+            cat_vec: vec!["".to_string(); 3],
+            ..Default::default()
+        };
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.9), (1, 0.35), (2, 0.2)];
+
+        // 手動指定のしきい値
+        let options = PredictOptions { threshold: Some(0.5), ..Default::default() };
+        let res = make_json("料金は？".to_string(), &qa_data, ans_vec.to_owned(), &options, &BandConfig::default(), None);
+        assert_eq!(res["payload"]["applied_threshold"], 0.5);
+        assert_eq!(res["payload"]["qa_infos"].as_array().unwrap().len(), 1);
+
+        // 自動算出したしきい値を返す
+        let qa_data = QaData { que_vec: vec!["".to_string(); 3], ans_vec: vec!["".to_string(); 3], cat_vec: vec!["".to_string(); 3], ..Default::default() };
+        let options = PredictOptions { auto_threshold: true, ..Default::default() };
+        let res = make_json("料金は？".to_string(), &qa_data, ans_vec, &options, &BandConfig::default(), None);
+        assert_eq!(res["payload"]["applied_threshold"].as_f64().unwrap(), auto_threshold(&[0.9, 0.35, 0.2]));
+    }
+
+    #[test]
+    fn init_threshold_test1() {
+        // 手動しきい値と自動算出は排他
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "threshold": 0.5, "auto_threshold": true });
+        assert!(ExecMode::new(event).is_err());
+        let event: Value = json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "threshold": 1.5 });
+        assert!(ExecMode::new(event).is_err());
+        // 数値として読めない文字列は未指定扱いにせず400
+        let res: Value = handle_event(json!({ "pkey": STR_PKEY, "mode": "p", "que_sentence": "料金は？", "threshold": "abc" }), false);
+        assert_eq!((res["code"].as_u64(), res["message"].as_str()), (Some(400), Some("error running init: threshold は数値で指定してください。")));
+    }
+
+    #[test]
+    fn make_json_lang_test1() {
+        let qa_data = QaData {
+            que_vec: vec!["料金は？".to_string(), "駐車場は？".to_string()],
+            ans_vec: vec!["1500円です".to_string(), "ありません".to_string()],
+            cat_vec: vec!["".to_string(); 2],
+            answers: vec![
+                HashMap::from([("en".to_string(), "It costs 1500 yen.".to_string())]),
+                HashMap::new(),
+            ],
+        };
+        let options = PredictOptions { lang: Some("en".to_string()), ..Default::default() };
+        let res = make_json("料金は？".to_string(), &qa_data, vec![(0, 0.9), (1, 0.8)], &options, &BandConfig::default(), None);
+        assert_eq!(res["payload"]["qa_infos"][0]["ans"], "It costs 1500 yen.");
+        // 指定言語の回答が無い場合はデフォルト言語
+        assert_eq!(res["payload"]["qa_infos"][1]["ans"], "ありません");
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn read_csv_lang_test1() {
+        let path = std::env::temp_dir().join("nango_read_csv_lang_test1.csv");
+        std::fs::write(&path, "1,T,1500円です,料金は？,料金,It costs 1500 yen.\n2,T,ありません,駐車場は？\n").unwrap();
+        let langs: Vec<String> = vec!["en".to_string()];
+        let (qa_data, _) = read_csv_rows(path.to_str().unwrap(), &langs, &schema::CsvSchema::default()).unwrap();
+        assert_eq!(qa_data.ans_vec, vec!["1500円です", "ありません"]);
+        assert_eq!(qa_data.answers[0]["en"], "It costs 1500 yen.");
+        assert!(qa_data.answers[1].is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn read_csv_column_map_test1() {
+        let path = std::env::temp_dir().join("nango_read_csv_column_map_test1.csv");
+        std::fs::write(&path, "1500円です,料金は？,料金,It costs 1500 yen.\nありません,駐車場は？,設備\n").unwrap();
+        let langs: Vec<String> = vec!["en".to_string()];
+        let column_map = schema::ColumnMap { question: 1, answer: 0, category: Some(2) };
+        let (qa_data, _) = read_csv_rows(path.to_str().unwrap(), &langs, &column_map.into()).unwrap();
+        assert_eq!(qa_data.que_vec, vec!["料金は？", "駐車場は？"]);
+        assert_eq!(qa_data.ans_vec, vec!["1500円です", "ありません"]);
+        assert_eq!(qa_data.cat_vec, vec!["料金", "設備"]);
+        // カテゴリ列より後ろは他言語の回答
+        assert_eq!(qa_data.answers[0]["en"], "It costs 1500 yen.");
+
+        // 列数を超える列位置は400
+        let column_map = schema::ColumnMap { question: 5, answer: 0, category: None };
+        let err: AppError = read_csv_rows(path.to_str().unwrap(), &langs, &column_map.into()).unwrap_err();
+        assert!(matches!(err, AppError::ColumnOutOfRange { expected: 6, actual: 4 }));
+        assert_eq!(err.code(), 400);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rank_percentile_test1() {
+        // 候補1件なら1位・100
+        assert_eq!(rank_percentile(0.8, &[0.8]), (1, 100.0));
+        assert_eq!(rank_percentile(0.4, &[0.8, 0.4]), (2, 50.0));
+    }
+
+    #[test]
+    fn make_snippet_test1() {
+        // N以下の回答はそのまま
+        assert_eq!(make_snippet("はい", 5), "はい");
+        assert_eq!(make_snippet("はい。", 3), "はい。");
+        // 結合文字・絵文字の途中で切らない
+        assert_eq!(make_snippet("が👨‍👩‍👧席あり", 2), "が👨‍👩‍👧…");
+    }
+
+    #[test]
+    fn format_answer_test1() {
+        assert_eq!(format_answer("【回答】{answer}（以上）", "18時からです"), "【回答】18時からです（以上）");
+        assert_eq!(format_answer("{answer}/{answer}", "はい"), "はい/はい");
+        assert_eq!(format_answer("固定文", "はい"), "固定文");
+    }
+}
@@ -0,0 +1,137 @@
+use serde_json::{json, Map, Value};
+
+use crate::config;
+
+const DEFAULT_NAMESPACE: &str = "NangoQaApi";
+const DEFAULT_DIMENSIONS: &str = "mode";
+
+/// メトリクスの出力設定
+/// 環境変数 METRICS_NAMESPACE で名前空間、METRICS_DIMENSIONS (カンマ区切り) でディメンションに使うレスポンスのキーを指定
+#[derive(Debug)]
+pub struct MetricsConfig {
+    pub namespace: String,
+    pub dimensions: Vec<String>,
+}
+
+impl MetricsConfig {
+    pub fn from_env() -> Self {
+        let dimensions: String = config::env_or("METRICS_DIMENSIONS", DEFAULT_DIMENSIONS.to_string());
+        Self {
+            namespace: config::env_or("METRICS_NAMESPACE", DEFAULT_NAMESPACE.to_string()),
+            dimensions: dimensions.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect(),
+        }
+    }
+}
+
+/// レスポンスからメトリクスを集計し、CloudWatch Embedded Metric Format のJSONを組み立てる
+pub fn build_emf(res_json: &Value, config: &MetricsConfig, timestamp_ms: u128) -> Value {
+    let mut metrics: Vec<(&str, &str, f64)> = vec![
+        ("RequestCount", "Count", 1.0),
+        ("ErrorCount", "Count", if res_json["success"] == true { 0.0 } else { 1.0 }),
+    ];
+    // API Gateway の body に書き出し済みの予測結果は、候補のスコアのみ cos_vals に持つ
+    let scores: Option<(usize, Vec<f64>)> = match (res_json["payload"]["qa_infos"].as_array(), res_json["cos_vals"].as_array()) {
+        (Some(qa_infos), _) => Some((qa_infos.len(), qa_infos.iter().filter_map(|info| info["cos_val"].as_f64()).collect())),
+        (None, Some(cos_vals)) => Some((cos_vals.len(), cos_vals.iter().filter_map(Value::as_f64).collect())),
+        (None, None) => None,
+    };
+    if let Some((count, scores)) = scores {
+        // CloudWatch上で平均を取ると候補ゼロ率になる
+        metrics.push(("NoCandidate", "Count", if count == 0 { 1.0 } else { 0.0 }));
+        if !scores.is_empty() {
+            metrics.push(("AverageScore", "None", scores.iter().sum::<f64>() / scores.len() as f64));
+        }
+    }
+
+    let mut emf: Map<String, Value> = Map::new();
+    emf.insert("_aws".to_string(), json!({
+        "Timestamp": timestamp_ms as u64,
+        "CloudWatchMetrics": [{
+            "Namespace": config.namespace,
+            "Dimensions": [config.dimensions],
+            "Metrics": metrics.iter().map(|(name, unit, _)| json!({ "Name": name, "Unit": unit })).collect::<Vec<Value>>(),
+        }],
+    }));
+    for dimension in &config.dimensions {
+        // ディメンションの値は文字列で出力 (該当キーが無い場合は unknown)
+        let value: String = match &res_json[dimension.as_str()] {
+            Value::String(s) => s.to_string(),
+            Value::Null => "unknown".to_string(),
+            other => other.to_string(),
+        };
+        emf.insert(dimension.to_string(), json!(value));
+    }
+    for (name, _, value) in metrics {
+        emf.insert(name.to_string(), json!(value));
+    }
+    Value::Object(emf)
+}
+
+/// メトリクスをEMF形式でログに出力
+pub fn emit_metrics(res_json: &Value) {
+    let timestamp_ms: u128 = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    println!("{}", build_emf(res_json, &MetricsConfig::from_env(), timestamp_ms));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_sample() -> MetricsConfig {
+        MetricsConfig { namespace: "Test".to_string(), dimensions: vec!["mode".to_string()] }
+    }
+
+    #[test]
+    fn build_emf_test1() {
+        let res_json: Value = json!({
+            "code": 200,
+            "success": true,
+            "mode": "predict",
+            "payload": { "qa_infos": [{ "cos_val": 0.75 }, { "cos_val": 0.5 }] },
+        });
+        let exp: Value = json!({
+            "_aws": {
+                "Timestamp": 1700000000000_u64,
+                "CloudWatchMetrics": [{
+                    "Namespace": "Test",
+                    "Dimensions": [["mode"]],
+                    "Metrics": [
+                        { "Name": "RequestCount", "Unit": "Count" },
+                        { "Name": "ErrorCount", "Unit": "Count" },
+                        { "Name": "NoCandidate", "Unit": "Count" },
+                        { "Name": "AverageScore", "Unit": "None" },
+                    ],
+                }],
+            },
+            "mode": "predict",
+            "RequestCount": 1.0,
+            "ErrorCount": 0.0,
+            "NoCandidate": 0.0,
+            "AverageScore": 0.625,
+        });
+        assert_eq!(build_emf(&res_json, &config_sample(), 1700000000000), exp);
+        // body に書き出し済みの予測結果は cos_vals で集計する
+        let res_json: Value = json!({ "code": 200, "success": true, "mode": "predict", "body": "{}", "cos_vals": [0.75, 0.5] });
+        assert_eq!(build_emf(&res_json, &config_sample(), 1700000000000), exp);
+    }
+
+    #[test]
+    fn build_emf_test2() {
+        // エラー時はスコア系のメトリクスを出さず、ディメンションは unknown
+        let res_json: Value = json!({ "code": 400, "success": false, "message": "error" });
+        let emf: Value = build_emf(&res_json, &config_sample(), 0);
+        assert_eq!(emf["mode"], "unknown");
+        assert_eq!(emf["ErrorCount"], 1.0);
+        assert!(emf.get("NoCandidate").is_none());
+        assert_eq!(emf["_aws"]["CloudWatchMetrics"][0]["Metrics"].as_array().unwrap().len(), 2);
+
+        // 候補ゼロ
+        let res_json: Value = json!({ "code": 200, "success": true, "mode": "predict", "payload": { "qa_infos": [] } });
+        let emf: Value = build_emf(&res_json, &config_sample(), 0);
+        assert_eq!(emf["NoCandidate"], 1.0);
+        assert!(emf.get("AverageScore").is_none());
+    }
+}
@@ -1 +1,31 @@
-pub mod tf_idf;
\ No newline at end of file
+pub mod bm25;
+pub mod calibration;
+pub mod cluster;
+pub mod count;
+pub mod distance;
+pub mod ensemble;
+pub mod feedback;
+pub mod keyword;
+pub mod lang;
+pub mod lemmatize;
+pub mod length;
+pub mod mmr;
+pub mod negation;
+pub mod normalize;
+pub mod politeness;
+pub mod pos;
+pub mod sentence;
+pub mod tf_idf;
+
+/// 検索アルゴリズムの共通インターフェース
+/// 各文書のスコアを大きい順に返す (同スコアは文書順)
+pub trait Retriever {
+    fn search(&self, docs: &[Vec<String>], query: &[String]) -> Vec<(usize, f64)>;
+}
+
+/// 保持中のモデルを借りたまま検索器として使う
+impl<T: Retriever + ?Sized> Retriever for &T {
+    fn search(&self, docs: &[Vec<String>], query: &[String]) -> Vec<(usize, f64)> {
+        (**self).search(docs, query)
+    }
+}
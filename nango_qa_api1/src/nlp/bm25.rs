@@ -0,0 +1,107 @@
+use std::collections::{ HashSet, HashMap };
+
+use super::count::CountMatrix;
+use super::Retriever;
+
+/// BM25によるスコア計算
+/// https://en.wikipedia.org/wiki/Okapi_BM25
+#[derive(Debug)]
+pub struct Bm25 {
+    pub k1: f64,
+    pub b: f64,
+}
+
+impl Default for Bm25 {
+    fn default() -> Self {
+        Self { k1: 1.2, b: 0.75 }
+    }
+}
+
+impl Bm25 {
+    /// 文書ごとの出現回数からスコアを計算
+    pub fn score_counts(&self, counts: &CountMatrix, query: &[String]) -> Vec<(usize, f64)> {
+        let n: f64 = counts.rows.len() as f64;
+        let avgdl: f64 = (0..counts.rows.len()).map(|id| counts.doc_len(id) as f64).sum::<f64>() / n.max(1.0);
+        let terms: HashSet<&String> = query.iter().collect();
+        let df: HashMap<&String, f64> = terms.iter()
+            .map(|&t| (t, counts.df(t) as f64))
+            .collect();
+
+        let mut score_vec: Vec<(usize, f64)> = Vec::new();
+        for (id, row) in counts.rows.iter().enumerate() {
+            let dl: f64 = counts.doc_len(id) as f64;
+            let mut score: f64 = 0.0;
+            for term in &terms {
+                let tf: f64 = row.get(*term).copied().unwrap_or(0) as f64;
+                if tf == 0.0 {
+                    continue;
+                }
+                let idf: f64 = ((n - df[term] + 0.5) / (df[term] + 0.5) + 1.0).ln();
+                score += idf * tf * (self.k1 + 1.0) / (tf + self.k1 * (1.0 - self.b + self.b * dl / avgdl));
+            }
+            score_vec.push((id, score));
+        }
+        score_vec.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap()); // スコアの大きい順に並び替え
+        score_vec
+    }
+}
+
+impl Retriever for Bm25 {
+    fn search(&self, docs: &[Vec<String>], query: &[String]) -> Vec<(usize, f64)> {
+        self.score_counts(&CountMatrix::from_docs(docs), query)
+    }
+}
+
+/// 学習時に保存した出現回数を使うBM25
+/// 文書のトークン列からの再集計を省く
+#[derive(Debug)]
+pub struct CountBm25 {
+    pub bm25: Bm25,
+    pub counts: CountMatrix,
+}
+
+impl Retriever for CountBm25 {
+    fn search(&self, _docs: &[Vec<String>], query: &[String]) -> Vec<(usize, f64)> {
+        self.bm25.score_counts(&self.counts, query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_docs(v_v: &[&[&str]]) -> Vec<Vec<String>> {
+        v_v.iter().map(|v| v.iter().map(|s| s.to_string()).collect()).collect()
+    }
+
+    #[test]
+    fn search_test1() {
+        let docs = to_docs(&[
+            &["犬", "可愛い", "犬", "大きい"],
+            &["猫", "小さい", "猫", "可愛い", "可愛い"],
+            &["虫", "小さい", "可愛くない"],
+        ]);
+        let query: Vec<String> = ["猫", "小さい"].iter().map(|s| s.to_string()).collect();
+        let res = Bm25::default().search(&docs, &query);
+        let ids: Vec<usize> = res.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 2, 0]);
+        assert_eq!(res[2].1, 0.0);
+    }
+
+    #[test]
+    fn search_test2() {
+        // 未知語のみのクエリは全文書0点
+        let docs = to_docs(&[&["犬", "可愛い"], &["猫", "小さい"]]);
+        let query: Vec<String> = vec!["ギター".to_string()];
+        assert_eq!(Bm25::default().search(&docs, &query), vec![(0, 0.0), (1, 0.0)]);
+    }
+
+    #[test]
+    fn count_bm25_test1() {
+        // 保存済みの出現回数から計算しても同じスコアになる
+        let docs = to_docs(&[&["犬", "可愛い", "犬"], &["猫", "小さい"], &["猫"]]);
+        let query: Vec<String> = vec!["猫".to_string(), "犬".to_string()];
+        let retriever = CountBm25 { bm25: Bm25::default(), counts: CountMatrix::from_docs(&docs) };
+        assert_eq!(retriever.search(&[], &query), Bm25::default().search(&docs, &query));
+    }
+}
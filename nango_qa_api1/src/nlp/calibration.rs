@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+
+/// 保存する分位点の数 (最小値〜最大値を等間隔に10分割)
+const QUANTILE_POINTS: usize = 11;
+/// 分位点を丸める桁 (cos類似度の加算順による末尾の揺れで、同じ学習データでもメタが変わらないようにする)
+const QUANTILE_SCALE: f64 = 1e12;
+
+/// 学習データの自己検索スコアの分布
+/// 各質問をクエリとしたときの、自分以外で最もスコアの高い文書のcos類似度の分位点 (昇順)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoreStats {
+    pub quantiles: Vec<f64>,
+    pub samples: usize,
+}
+
+impl ScoreStats {
+    /// スコアが無い場合はNone
+    pub fn from_scores(mut scores: Vec<f64>) -> Option<Self> {
+        if scores.is_empty() {
+            return None;
+        }
+        scores.sort_by(f64::total_cmp);
+        // 順位の間は線形補間する
+        let last: f64 = (scores.len() - 1) as f64;
+        let quantiles: Vec<f64> = (0..QUANTILE_POINTS)
+            .map(|i| {
+                let pos: f64 = last * i as f64 / (QUANTILE_POINTS - 1) as f64;
+                let (lower, upper) = (scores[pos.floor() as usize], scores[pos.ceil() as usize]);
+                ((lower + (upper - lower) * pos.fract()) * QUANTILE_SCALE).round() / QUANTILE_SCALE
+            })
+            .collect();
+        Some(Self { quantiles, samples: scores.len() })
+    }
+}
+
+/// 生のcos類似度を、学習データの自己検索スコアの分布での位置 (0〜1) に写像する
+/// (0, 0)・各分位点・(1, 1) を結ぶ折れ線で、分位点が重なる場合は低い方の点を使うため単調非減少
+/// 「自分以外の質問どうしがどの程度似て見えるか」を基準にするので、データセット間で比較できる
+pub fn calibrate_score(raw: f64, stats: &ScoreStats) -> f64 {
+    let raw: f64 = raw.clamp(0.0, 1.0);
+    let n: f64 = (stats.quantiles.len() + 1) as f64;
+    let mut knots: Vec<(f64, f64)> = vec![(0.0, 0.0)];
+    let points = stats.quantiles.iter().enumerate().map(|(i, q)| (q.clamp(0.0, 1.0), (i + 1) as f64 / n));
+    for (x, y) in points.chain([(1.0, 1.0)]) {
+        match knots.last_mut() {
+            Some(last) if x <= last.0 => {
+                // 終点 (1, 1) は最大値が1の場合も1に写す
+                if y == 1.0 {
+                    last.1 = 1.0;
+                }
+            },
+            _ => knots.push((x, y)),
+        }
+    }
+    for pair in knots.windows(2) {
+        let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+        if raw <= x1 {
+            return y0 + (y1 - y0) * (raw - x0) / (x1 - x0);
+        }
+    }
+    knots.last().map_or(raw, |(_, y)| *y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score_stats_test1() {
+        let stats: ScoreStats = ScoreStats::from_scores(vec![1.0, 0.0, 0.5, 0.2, 0.8]).unwrap();
+        assert_eq!(stats.quantiles.len(), QUANTILE_POINTS);
+        assert_eq!((stats.quantiles[0], stats.quantiles[10], stats.samples), (0.0, 1.0, 5));
+        assert!(stats.quantiles.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(ScoreStats::from_scores(vec![]), None);
+    }
+
+    #[test]
+    fn calibrate_score_test1() {
+        let grid: Vec<f64> = (0..=100).map(|i| i as f64 / 100.0).collect();
+        for scores in [
+            vec![0.1, 0.2, 0.25, 0.3, 0.4, 0.6],
+            vec![0.0, 0.0, 0.0, 0.2, 0.2, 0.9], // 重なる分位点
+            vec![0.5],
+            vec![1.0, 0.3],
+        ] {
+            let stats: ScoreStats = ScoreStats::from_scores(scores).unwrap();
+            let calibrated: Vec<f64> = grid.iter().map(|&raw| calibrate_score(raw, &stats)).collect();
+            // 単調非減少で 0〜1 に収まり、端点は保たれる
+            assert!(calibrated.windows(2).all(|w| w[0] <= w[1]), "{:?}", calibrated);
+            assert!(calibrated.iter().all(|v| (0.0..=1.0).contains(v)));
+            assert_eq!((calibrated[0], calibrated[100]), (0.0, 1.0));
+        }
+        // 分布の中央付近のスコアは 0.5 前後に写る
+        let stats: ScoreStats = ScoreStats::from_scores(vec![0.1, 0.2, 0.3, 0.4, 0.5]).unwrap();
+        assert!((calibrate_score(0.3, &stats) - 0.5).abs() < 1e-9);
+        // 似た質問の多いデータセットでは、同じ生スコアでも低く写る
+        let dense: ScoreStats = ScoreStats::from_scores(vec![0.6, 0.7, 0.8]).unwrap();
+        assert!(calibrate_score(0.5, &dense) < calibrate_score(0.5, &stats));
+        // 範囲外は丸める
+        assert_eq!(calibrate_score(1.5, &stats), 1.0);
+        assert_eq!(calibrate_score(-0.1, &stats), 0.0);
+    }
+}
@@ -0,0 +1,58 @@
+/// しきい値ベースの凝集型クラスタリング (単連結)
+/// 類似度が threshold 以上の組を同じグループにまとめる
+/// ids は関連度順とし、各グループは元の順を保ち、先頭の要素の順にグループを並べる
+pub fn agglomerate<F>(ids: &[usize], threshold: f64, sim: F) -> Vec<Vec<usize>>
+where
+    F: Fn(usize, usize) -> f64,
+{
+    // 各要素が属するグループの番号 (最初は要素ごとに別グループ)
+    let mut group_of: Vec<usize> = (0..ids.len()).collect();
+    for i in 0..ids.len() {
+        for j in (i + 1)..ids.len() {
+            if group_of[i] == group_of[j] || sim(ids[i], ids[j]) < threshold {
+                continue;
+            }
+            // 番号の小さい方へ統合する
+            let (keep, merged) = (group_of[i].min(group_of[j]), group_of[i].max(group_of[j]));
+            for group in group_of.iter_mut().filter(|group| **group == merged) {
+                *group = keep;
+            }
+        }
+    }
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut index_of_group: Vec<Option<usize>> = vec![None; ids.len()];
+    for (index, &group) in group_of.iter().enumerate() {
+        match index_of_group[group] {
+            Some(g) => groups[g].push(ids[index]),
+            None => {
+                index_of_group[group] = Some(groups.len());
+                groups.push(vec![ids[index]]);
+            },
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0と2、2と3は似た内容、1は別の内容
+    fn sim(a: usize, b: usize) -> f64 {
+        match (a.min(b), a.max(b)) {
+            (0, 2) => 0.8,
+            (2, 3) => 0.6,
+            (0, 3) => 0.3,
+            _ => 0.0,
+        }
+    }
+
+    #[test]
+    fn agglomerate_test1() {
+        // 似た候補が同じグループに入り、関連度順が保たれるか確認
+        assert_eq!(agglomerate(&[0, 1, 2, 3], 0.5, sim), vec![vec![0, 2, 3], vec![1]]);
+        // しきい値を上げると連結が切れる
+        assert_eq!(agglomerate(&[0, 1, 2, 3], 0.7, sim), vec![vec![0, 2], vec![1], vec![3]]);
+        assert!(agglomerate(&[], 0.5, sim).is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+/// 文書ごとの語の出現回数 (生のTF)
+/// 出現した語のみ保持する疎行列
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CountMatrix {
+    pub rows: Vec<HashMap<String, u32>>,
+}
+
+impl CountMatrix {
+    pub fn from_docs(docs: &[Vec<String>]) -> Self {
+        let rows: Vec<HashMap<String, u32>> = docs.iter()
+            .map(|doc| {
+                let mut row: HashMap<String, u32> = HashMap::new();
+                for word in doc {
+                    *row.entry(word.to_owned()).or_insert(0) += 1;
+                }
+                row
+            })
+            .collect();
+        Self { rows }
+    }
+
+    /// 文書の長さ (トークン数)
+    pub fn doc_len(&self, id: usize) -> u32 {
+        self.rows[id].values().sum()
+    }
+
+    /// 語を含む文書数
+    pub fn df(&self, word: &str) -> usize {
+        self.rows.iter().filter(|row| row.contains_key(word)).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_docs_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            ["猫", "可愛い", "猫"].iter().map(|s| s.to_string()).collect(),
+            vec![],
+        ];
+        let counts = CountMatrix::from_docs(&docs);
+        assert_eq!(counts.rows[0], HashMap::from([("猫".to_string(), 2), ("可愛い".to_string(), 1)]));
+        assert!(counts.rows[1].is_empty());
+        assert_eq!((counts.doc_len(0), counts.doc_len(1)), (3, 0));
+        assert_eq!(counts.df("猫"), 1);
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// 書記素単位のレーベンシュタイン距離
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<&str> = a.graphemes(true).collect();
+    let b: Vec<&str> = b.graphemes(true).collect();
+    // 1行分のDPテーブルのみ保持
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut prev: usize = row[0];
+        row[0] = i;
+        for j in 1..=b.len() {
+            let cost: usize = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let next: usize = (row[j] + 1).min(row[j - 1] + 1).min(prev + cost);
+            prev = row[j];
+            row[j] = next;
+        }
+    }
+    row[b.len()]
+}
+
+/// 長い方の書記素数で割った距離 (0: 一致 〜 1: 全く異なる)
+/// 両方とも空文字の場合は一致とみなして0
+pub fn normalized_levenshtein(a: &str, b: &str) -> f64 {
+    let max_len: usize = a.graphemes(true).count().max(b.graphemes(true).count());
+    if max_len == 0 {
+        return 0.0;
+    }
+    levenshtein(a, b) as f64 / max_len as f64
+}
+
+/// 書記素単位の文字trigram (3文字未満の文は文全体を1つとする)
+pub fn char_trigrams(s: &str) -> HashSet<String> {
+    let chars: Vec<&str> = s.graphemes(true).collect();
+    if chars.len() < 3 {
+        return (!chars.is_empty()).then(|| chars.concat()).into_iter().collect();
+    }
+    chars.windows(3).map(|w| w.concat()).collect()
+}
+
+/// 文字trigram集合のJaccard係数 (0〜1、両方とも空の場合は0)
+pub fn trigram_jaccard(a: &str, b: &str) -> f64 {
+    let a: HashSet<String> = char_trigrams(a);
+    let b: HashSet<String> = char_trigrams(b);
+    let union: usize = a.union(&b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f64 / union as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levenshtein_test1() {
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("料金はいくら？", "料金はいくら"), 1);
+        assert_eq!(levenshtein("料金は？", "金額は？"), 2);
+        // 結合文字・絵文字は1文字として数える
+        assert_eq!(levenshtein("👨‍👩‍👧", "👨"), 1);
+        assert_eq!(levenshtein("", "abc"), 3);
+    }
+
+    #[test]
+    fn normalized_levenshtein_test1() {
+        assert_eq!(normalized_levenshtein("", ""), 0.0);
+        assert_eq!(normalized_levenshtein("", "料金"), 1.0);
+        assert_eq!(normalized_levenshtein("料金は？", "料金は？"), 0.0);
+        assert_eq!(normalized_levenshtein("料金は？", "金額は？"), 0.5);
+    }
+
+    #[test]
+    fn trigram_jaccard_test1() {
+        assert_eq!(char_trigrams("料金は？").len(), 2);
+        assert_eq!(char_trigrams("料金"), HashSet::from(["料金".to_string()]));
+        assert_eq!(trigram_jaccard("料金は？", "料金は？"), 1.0);
+        // 1文字違いでも一部のtrigramが一致する
+        assert_eq!(trigram_jaccard("駐車場はある", "駐車城はある"), 1.0 / 7.0);
+        assert_eq!(trigram_jaccard("", ""), 0.0);
+    }
+}
@@ -0,0 +1,74 @@
+use super::Retriever;
+
+/// 2つの検索器のスコアを合成する検索器
+pub struct Ensemble<'a> {
+    pub a: Box<dyn Retriever + 'a>,
+    pub b: Box<dyn Retriever + 'a>,
+    pub weight: f64, // aの重み(0〜1)
+}
+
+impl Retriever for Ensemble<'_> {
+    fn search(&self, docs: &[Vec<String>], query: &[String]) -> Vec<(usize, f64)> {
+        combine(&self.a.search(docs, query), &self.b.search(docs, query), self.weight)
+    }
+}
+
+/// 2つのスコアをmin-max正規化してから加重合成する
+/// weight はaのスコアの重み(0〜1)、結果はスコアの大きい順 (同スコアは文書順)
+pub fn combine(a_vec: &[(usize, f64)], b_vec: &[(usize, f64)], weight: f64) -> Vec<(usize, f64)> {
+    let n: usize = a_vec.len().max(b_vec.len());
+    let a_norm: Vec<f64> = min_max_normalize(a_vec, n);
+    let b_norm: Vec<f64> = min_max_normalize(b_vec, n);
+
+    let mut score_vec: Vec<(usize, f64)> = (0..n)
+        .map(|id| (id, weight * a_norm[id] + (1.0 - weight) * b_norm[id]))
+        .collect();
+    score_vec.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    score_vec
+}
+
+/// 文書id順の正規化済みスコアを返す (全て同値の場合は0)
+fn min_max_normalize(score_vec: &[(usize, f64)], n: usize) -> Vec<f64> {
+    let mut norm_vec: Vec<f64> = vec![0.0; n];
+    let min: f64 = score_vec.iter().map(|(_, s)| *s).fold(f64::INFINITY, f64::min);
+    let max: f64 = score_vec.iter().map(|(_, s)| *s).fold(f64::NEG_INFINITY, f64::max);
+    if max > min {
+        for &(id, score) in score_vec {
+            norm_vec[id] = (score - min) / (max - min);
+        }
+    }
+    norm_vec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(score_vec: &[(usize, f64)]) -> Vec<usize> {
+        score_vec.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn combine_test1() {
+        let tfidf_vec: Vec<(usize, f64)> = vec![(2, 0.8), (0, 0.5), (1, 0.2), (3, 0.0)];
+        let bm25_vec: Vec<(usize, f64)> = vec![(1, 3.0), (3, 2.0), (0, 1.0), (2, 0.0)];
+        // 重み1.0で純TF-IDF、0.0で純BM25の順位に一致するか確認
+        assert_eq!(ids(&combine(&tfidf_vec, &bm25_vec, 1.0)), ids(&tfidf_vec));
+        assert_eq!(ids(&combine(&tfidf_vec, &bm25_vec, 0.0)), ids(&bm25_vec));
+        assert_eq!(combine(&tfidf_vec, &bm25_vec, 1.0)[0], (2, 1.0));
+    }
+
+    #[test]
+    fn combine_test2() {
+        let tfidf_vec: Vec<(usize, f64)> = vec![(0, 1.0), (1, 0.0)];
+        let bm25_vec: Vec<(usize, f64)> = vec![(1, 4.0), (0, 0.0)];
+        assert_eq!(combine(&tfidf_vec, &bm25_vec, 0.5), vec![(0, 0.5), (1, 0.5)]);
+    }
+
+    #[test]
+    fn min_max_normalize_test1() {
+        assert_eq!(min_max_normalize(&[(1, 3.0), (0, 1.0), (2, 2.0)], 3), vec![0.0, 1.0, 0.5]);
+        // 全て同値の場合
+        assert_eq!(min_max_normalize(&[(0, 0.4), (1, 0.4)], 2), vec![0.0, 0.0]);
+    }
+}
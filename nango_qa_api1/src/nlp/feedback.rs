@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::tf_idf::{self, TfIdf};
+use super::Retriever;
+use crate::config;
+
+const DEFAULT_BOOST: f64 = 0.3;
+const DEFAULT_MIN_SIMILARITY: f64 = 0.5;
+
+/// 過去のフィードバック (クエリのトークン列と正解とされた文書)
+#[derive(Debug, Clone)]
+pub struct Feedback {
+    pub query: Vec<String>,
+    pub doc_id: usize,
+}
+
+/// フィードバックによるクエリ拡張の設定
+/// 環境変数 FEEDBACK_BOOST で加算の係数、FEEDBACK_MIN_SIMILARITY で反映する過去クエリの類似度の下限を指定
+#[derive(Debug)]
+pub struct FeedbackConfig {
+    pub boost: f64,
+    pub min_similarity: f64,
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> Self {
+        Self { boost: DEFAULT_BOOST, min_similarity: DEFAULT_MIN_SIMILARITY }
+    }
+}
+
+impl FeedbackConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            boost: config::env_or("FEEDBACK_BOOST", default.boost),
+            min_similarity: config::env_or("FEEDBACK_MIN_SIMILARITY", default.min_similarity),
+        }
+    }
+}
+
+/// クエリと類似する過去クエリの正解文書のベクトルをクエリベクトルに加算する
+/// 過剰適合を避けるため、加算量はクエリのノルムに対する割合 (boost×類似度) に抑え、同じ文書は最も類似した1件のみ反映する
+pub fn apply_feedback_boost(query_vec: &HashMap<usize, f64>, query: &[String], feedback: &[Feedback], tfidf: &TfIdf, config: &FeedbackConfig) -> HashMap<usize, f64> {
+    let mut boosted: HashMap<usize, f64> = query_vec.clone();
+    let query_norm: f64 = tf_idf::sparse_norm(query_vec);
+    if query_norm == 0.0 {
+        return boosted;
+    }
+    let mut best_similarity: BTreeMap<usize, f64> = BTreeMap::new();
+    for fb in feedback {
+        let similarity: f64 = jaccard(query, &fb.query);
+        if similarity >= config.min_similarity {
+            let best: &mut f64 = best_similarity.entry(fb.doc_id).or_insert(0.0);
+            *best = best.max(similarity);
+        }
+    }
+    for (doc_id, similarity) in best_similarity {
+        let Some(row) = tfidf.tf_idf_vec.get(doc_id) else {
+            continue;
+        };
+        let row_norm: f64 = tf_idf::sparse_norm(row);
+        if row_norm == 0.0 {
+            continue;
+        }
+        let scale: f64 = config.boost * similarity * query_norm / row_norm;
+        for (&index, &val) in row {
+            *boosted.entry(index).or_insert(0.0) += scale * tf_idf::to_f64(val);
+        }
+    }
+    boosted
+}
+
+/// トークン集合のJaccard係数
+fn jaccard(a: &[String], b: &[String]) -> f64 {
+    let a_set: HashSet<&String> = a.iter().collect();
+    let b_set: HashSet<&String> = b.iter().collect();
+    let union: usize = a_set.union(&b_set).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a_set.intersection(&b_set).count() as f64 / union as f64
+}
+
+/// フィードバックでクエリを拡張してからTF-IDFで検索する検索器
+pub struct FeedbackRetriever<'a> {
+    pub tfidf: &'a TfIdf,
+    pub feedback: Vec<Feedback>,
+    pub config: FeedbackConfig,
+}
+
+impl Retriever for FeedbackRetriever<'_> {
+    fn search(&self, _docs: &[Vec<String>], query: &[String]) -> Vec<(usize, f64)> {
+        let query_vec: HashMap<usize, f64> = self.tfidf.query_vector(query);
+        self.tfidf.search_vector(&apply_feedback_boost(&query_vec, query, &self.feedback, self.tfidf, &self.config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<String> {
+        s.split(' ').map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn jaccard_test1() {
+        assert_eq!(jaccard(&tokens("料金 は"), &tokens("料金 は")), 1.0);
+        assert_eq!(jaccard(&tokens("料金 は"), &tokens("料金 いくら")), 1.0 / 3.0);
+        assert_eq!(jaccard(&[], &[]), 0.0);
+    }
+
+    #[test]
+    fn feedback_retriever_test1() {
+        let docs: Vec<Vec<String>> = vec![tokens("料金 は いくら"), tokens("チャージ の 金額"), tokens("駐車場 は ある")];
+        let tfidf: TfIdf = TfIdf::get_tf_idf(&docs).unwrap();
+        let query: Vec<String> = tokens("料金 は");
+
+        // フィードバックが無ければ語が一致する文書のみヒット
+        let plain = FeedbackRetriever { tfidf: &tfidf, feedback: vec![], config: FeedbackConfig::default() };
+        let res: Vec<(usize, f64)> = plain.search(&docs, &query);
+        assert_eq!(res[0].0, 0);
+        assert_eq!(res.iter().find(|(id, _)| *id == 1).unwrap().1, 0.0);
+
+        // 類似クエリでチャージの文書が正解とされていればスコアが付く (順位は元の一致文書が上のまま)
+        let feedback: Vec<Feedback> = vec![Feedback { query: tokens("料金 は ？"), doc_id: 1 }];
+        let boosted = FeedbackRetriever { tfidf: &tfidf, feedback, config: FeedbackConfig::default() };
+        let res: Vec<(usize, f64)> = boosted.search(&docs, &query);
+        assert_eq!(res[0].0, 0);
+        assert!(res.iter().find(|(id, _)| *id == 1).unwrap().1 > 0.0);
+    }
+
+    #[test]
+    fn apply_feedback_boost_test1() {
+        let docs: Vec<Vec<String>> = vec![tokens("料金 は いくら"), tokens("チャージ の 金額")];
+        let tfidf: TfIdf = TfIdf::get_tf_idf(&docs).unwrap();
+        let query: Vec<String> = tokens("料金 は");
+        let query_vec: HashMap<usize, f64> = tfidf.query_vector(&query);
+        // 類似度が下限未満のフィードバック・存在しない文書は反映しない
+        let feedback: Vec<Feedback> = vec![
+            Feedback { query: tokens("駐車場 ある"), doc_id: 1 },
+            Feedback { query: tokens("料金 は"), doc_id: 9 },
+        ];
+        assert_eq!(apply_feedback_boost(&query_vec, &query, &feedback, &tfidf, &FeedbackConfig::default()), query_vec);
+    }
+}
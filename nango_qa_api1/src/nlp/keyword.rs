@@ -0,0 +1,71 @@
+use std::collections::HashSet;
+
+use crate::config;
+
+const DEFAULT_COUNT: usize = 3;
+/// 助詞・助動詞など、キーワードにならない語
+const DEFAULT_STOP_WORDS: [&str; 28] = [
+    "は", "が", "を", "に", "で", "と", "の", "も", "へ", "や", "か", "な", "ね", "よ", "から", "まで", "より",
+    "です", "ます", "でしょう", "ください", "ある", "あり", "いる", "する", "し", "こと", "どう",
+];
+
+/// クエリの重要語の抽出の設定
+/// 環境変数 QUERY_KEYWORD_COUNT で件数、QUERY_KEYWORD_STOP_WORDS (カンマ区切り) でストップワードを指定
+#[derive(Debug)]
+pub struct KeywordConfig {
+    pub count: usize,
+    pub stop_words: Vec<String>,
+}
+
+impl Default for KeywordConfig {
+    fn default() -> Self {
+        Self { count: DEFAULT_COUNT, stop_words: DEFAULT_STOP_WORDS.iter().map(|s| s.to_string()).collect() }
+    }
+}
+
+impl KeywordConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let stop_words: Vec<String> = match std::env::var("QUERY_KEYWORD_STOP_WORDS") {
+            Ok(words) => words.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect(),
+            Err(_) => default.stop_words,
+        };
+        Self { count: config::env_or("QUERY_KEYWORD_COUNT", default.count), stop_words }
+    }
+}
+
+/// クエリのトークンをIDFの大きい順に count 語まで返す (同じIDFはクエリ内の順)
+/// ストップワード・記号のみのトークン・IDFの無い未知語 (idf が None) は除外し、重複は1語とする
+pub fn extract<F: Fn(&str) -> Option<f64>>(tokens: &[String], idf: F, config: &KeywordConfig) -> Vec<String> {
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut keywords: Vec<(&str, f64)> = tokens.iter()
+        .map(|token| token.as_str())
+        .filter(|token| seen.insert(token))
+        .filter(|token| token.chars().any(char::is_alphanumeric))
+        .filter(|token| !config.stop_words.iter().any(|w| w == token))
+        .filter_map(|token| idf(token).map(|idf| (token, idf)))
+        .collect();
+    keywords.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    keywords.into_iter().take(config.count).map(|(token, _)| token.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_test1() {
+        let tokens: Vec<String> = ["駐車", "場", "は", "駐車", "あり", "ます", "か", "？", "xyz"].iter().map(|s| s.to_string()).collect();
+        let idf = |word: &str| match word {
+            "駐車" => Some(2.5),
+            "場" => Some(1.2),
+            "は" | "あり" | "ます" | "か" | "？" => Some(1.0),
+            _ => None, // 未知語
+        };
+        // 希少な語ほど上位、ストップワード・記号・未知語は含めない
+        assert_eq!(extract(&tokens, idf, &KeywordConfig::default()), vec!["駐車", "場"]);
+        let config = KeywordConfig { count: 1, ..Default::default() };
+        assert_eq!(extract(&tokens, idf, &config), vec!["駐車"]);
+        assert!(extract(&[], idf, &config).is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+/// 文字 (記号・数字・空白を除く) のうち日本語の文字 (ひらがな・カタカナ・漢字) の割合
+/// 判定対象の文字が無い場合は None
+pub fn japanese_ratio(sentence: &str) -> Option<f64> {
+    let letters: Vec<char> = sentence.chars().filter(|c| c.is_alphabetic()).collect();
+    if letters.is_empty() {
+        return None;
+    }
+    let japanese: usize = letters.iter().filter(|c| is_japanese_char(**c)).count();
+    Some(japanese as f64 / letters.len() as f64)
+}
+
+/// 日本語の割合が閾値以上か (判定できない場合は日本語とみなす)
+pub fn is_japanese(sentence: &str, min_ratio: f64) -> bool {
+    japanese_ratio(sentence).is_none_or(|ratio| ratio >= min_ratio)
+}
+
+fn is_japanese_char(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{309F}' // ひらがな
+        | '\u{30A0}'..='\u{30FF}' // カタカナ
+        | '\u{31F0}'..='\u{31FF}' // カタカナ拡張
+        | '\u{FF66}'..='\u{FF9F}' // 半角カタカナ
+        | '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' // 漢字
+        | '々')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn japanese_ratio_test1() {
+        assert_eq!(japanese_ratio("料金はいくら？"), Some(1.0));
+        assert_eq!(japanese_ratio("How much is the charge?"), Some(0.0));
+        assert_eq!(japanese_ratio("1500？"), None);
+    }
+
+    #[test]
+    fn is_japanese_test1() {
+        assert!(is_japanese("お店で楽器は演奏できますか？", 0.3));
+        // 英単語混じりの日本語は日本語とみなす
+        assert!(is_japanese("Wi-Fiは使えますか？", 0.3));
+        assert!(is_japanese("PayPayで支払えますか", 0.3));
+        assert!(!is_japanese("Can I play instruments here?", 0.3));
+        assert!(!is_japanese("Is there parking? 駐車場", 0.3));
+        assert!(is_japanese("???", 0.3));
+    }
+}
@@ -0,0 +1,89 @@
+/// サ変動詞の活用語尾 (「予約し」「予約さ」など)
+const SURU_ENDINGS: [&str; 7] = ["する", "して", "しろ", "すれ", "し", "さ", "せ"];
+/// 可能形の活用語尾 (「予約でき」「予約できれ」など)
+const DEKIRU_ENDINGS: [&str; 4] = ["できる", "できれ", "でき", "できて"];
+/// シク活用形容詞の活用語尾 (「美味しかっ」「美味しく」など)
+const SHII_ENDINGS: [&str; 4] = ["しかっ", "しくて", "しく", "しけれ"];
+/// 否定の活用形 (単独トークンのみ)
+const NAI_FORMS: [&str; 4] = ["なかっ", "なくて", "なく", "なけれ"];
+
+/// 活用語尾を原形へ寄せる簡易的な語尾正規化
+/// 同梱モデルは原形を推定しないため、誤変換の少ない形 (サ変・可能形・シク活用形容詞・否定) のみ対象とする
+/// 学習・予測の両方で同じ処理を通すこと
+pub fn lemmatize(tokens: Vec<String>) -> Vec<String> {
+    tokens.into_iter().map(|token| lemmatize_token(&token)).collect()
+}
+
+fn lemmatize_token(token: &str) -> String {
+    if NAI_FORMS.contains(&token) {
+        return "ない".to_string();
+    }
+    if let Some(stem) = strip_ending(token, &DEKIRU_ENDINGS) {
+        if !stem.is_empty() {
+            return format!("{}できる", stem);
+        }
+    }
+    if let Some(stem) = strip_ending(token, &SURU_ENDINGS) {
+        // 「少し」「話し」などを避けるため、漢字・カタカナ2文字以上の語幹のみ
+        if stem.chars().count() >= 2 && stem.chars().all(is_kanji_or_katakana) {
+            return format!("{}する", stem);
+        }
+    }
+    if let Some(stem) = strip_ending(token, &SHII_ENDINGS) {
+        if !stem.is_empty() {
+            return format!("{}しい", stem);
+        }
+    }
+    token.to_string()
+}
+
+/// 語尾の候補のうち最初に一致したものを除いた語幹を返す
+fn strip_ending<'a>(token: &'a str, endings: &[&str]) -> Option<&'a str> {
+    endings.iter().find_map(|ending| token.strip_suffix(ending))
+}
+
+fn is_kanji_or_katakana(c: char) -> bool {
+    matches!(c, '\u{4E00}'..='\u{9FFF}' | '\u{30A0}'..='\u{30FF}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nlp::{tf_idf::TfIdf, Retriever};
+
+    fn to_tokens(words: &[&str]) -> Vec<String> {
+        words.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn lemmatize_test1() {
+        assert_eq!(lemmatize(to_tokens(&["予約し", "ます"])), vec!["予約する", "ます"]);
+        assert_eq!(lemmatize(to_tokens(&["予約でき", "ます", "か"])), vec!["予約できる", "ます", "か"]);
+        assert_eq!(lemmatize(to_tokens(&["美味しかっ", "た"])), vec!["美味しい", "た"]);
+        assert_eq!(lemmatize(to_tokens(&["美味しく", "なかっ", "た"])), vec!["美味しい", "ない", "た"]);
+        // 対象外の語はそのまま
+        assert_eq!(lemmatize(to_tokens(&["少し", "話し", "行っ", "し"])), vec!["少し", "話し", "行っ", "し"]);
+    }
+
+    #[test]
+    fn lemmatize_search_test1() {
+        // 活用形違いの質問が同じQAにヒットするか確認
+        let docs: Vec<Vec<String>> = [
+            to_tokens(&["席", "を", "予約する"]),
+            to_tokens(&["駐車場", "は", "あり", "ます", "か", "？"]),
+            to_tokens(&["ライブ", "の", "予定", "は", "？"]),
+        ].into_iter().map(lemmatize).collect();
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        for query in [
+            to_tokens(&["席", "を", "予約し", "ます"]),
+            to_tokens(&["席", "を", "予約し", "た"]),
+            to_tokens(&["席", "を", "予約し", "たい", "です"]),
+        ] {
+            let res = tfidf.search(&docs, &lemmatize(query.to_owned()));
+            assert_eq!(res[0].0, 0);
+            // 正規化しない場合より類似度が上がる
+            let raw = tfidf.search(&docs, &query);
+            assert!(res[0].1 > raw.iter().find(|(id, _)| *id == 0).unwrap().1);
+        }
+    }
+}
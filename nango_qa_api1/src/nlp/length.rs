@@ -0,0 +1,77 @@
+use crate::config;
+
+const DEFAULT_MIN_TOKENS: usize = 3;
+const DEFAULT_MAX_PENALTY: f64 = 0.5;
+
+/// 短すぎる候補質問の減点の設定
+/// トークン数が min_tokens 未満の候補は、不足するトークン数に比例して最大 max_penalty の割合まで減点する
+/// 環境変数 LENGTH_PENALTY_MIN_TOKENS・LENGTH_PENALTY_MAX で指定
+#[derive(Debug)]
+pub struct LengthPenaltyConfig {
+    pub min_tokens: usize,
+    pub max_penalty: f64,
+}
+
+impl Default for LengthPenaltyConfig {
+    fn default() -> Self {
+        Self { min_tokens: DEFAULT_MIN_TOKENS, max_penalty: DEFAULT_MAX_PENALTY }
+    }
+}
+
+impl LengthPenaltyConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            min_tokens: config::env_or("LENGTH_PENALTY_MIN_TOKENS", default.min_tokens),
+            max_penalty: config::env_or("LENGTH_PENALTY_MAX", default.max_penalty).clamp(0.0, 1.0),
+        }
+    }
+
+    /// スコアに掛ける係数 (トークン数0で 1 - max_penalty、min_tokens 以上で1)
+    pub fn factor(&self, n_tokens: usize) -> f64 {
+        if n_tokens >= self.min_tokens {
+            return 1.0;
+        }
+        let shortage: f64 = (self.min_tokens - n_tokens) as f64 / self.min_tokens as f64;
+        1.0 - self.max_penalty * shortage
+    }
+}
+
+/// 候補質問のトークン数 (学習時の単語リスト) に応じて減点し、スコア順に並べ直す
+pub fn adjust_scores(ans_vec: Vec<(usize, f64)>, docs: &[Vec<String>], config: &LengthPenaltyConfig) -> Vec<(usize, f64)> {
+    let mut adjusted: Vec<(usize, f64)> = ans_vec.into_iter()
+        .map(|(id, cos_val)| (id, cos_val * config.factor(docs.get(id).map_or(0, |doc| doc.len()))))
+        .collect();
+    adjusted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap()); // 同点は元の順序を保つ
+    adjusted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn factor_test1() {
+        let config = LengthPenaltyConfig { min_tokens: 4, max_penalty: 0.4 };
+        assert_eq!(config.factor(4), 1.0);
+        assert_eq!(config.factor(10), 1.0);
+        assert!((config.factor(2) - 0.8).abs() < 1e-9);
+        assert!((config.factor(0) - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn adjust_scores_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            vec!["はい".to_string()],
+            ["駐車場", "は", "あり", "ます", "か"].iter().map(|s| s.to_string()).collect(),
+        ];
+        // 汎用語だけで一致した短い候補が、長い候補より上位にならない
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.6), (1, 0.5)];
+        let res = adjust_scores(ans_vec.to_owned(), &docs, &LengthPenaltyConfig::default());
+        assert_eq!(res[0], (1, 0.5));
+        assert!((res[1].1 - 0.6 * (1.0 - 0.5 * 2.0 / 3.0)).abs() < 1e-9);
+        // 減点0なら元のまま
+        let config = LengthPenaltyConfig { max_penalty: 0.0, ..Default::default() };
+        assert_eq!(adjust_scores(ans_vec.to_owned(), &docs, &config), ans_vec);
+    }
+}
@@ -0,0 +1,62 @@
+/// MMR (Maximal Marginal Relevance) による再ランキング
+/// 関連度と、選択済み候補との類似度の最大値とのトレードオフで順に選ぶ
+/// lambda=1 で関連度順そのまま、小さいほど多様性を重視する
+/// 上位 top_k 件のみ並べ替え、残りは元の順で後ろに続ける (スコアは元の関連度のまま)
+pub fn rerank<F>(ans_vec: &[(usize, f64)], lambda: f64, top_k: usize, sim: F) -> Vec<(usize, f64)>
+where
+    F: Fn(usize, usize) -> f64,
+{
+    let split: usize = top_k.min(ans_vec.len());
+    let mut rest: Vec<(usize, f64)> = ans_vec[..split].to_vec();
+    let mut selected: Vec<(usize, f64)> = Vec::with_capacity(ans_vec.len());
+    while !rest.is_empty() {
+        let mmr = |&(id, score): &(usize, f64)| -> f64 {
+            let max_sim: f64 = selected.iter().map(|&(s_id, _)| sim(id, s_id)).fold(0.0, f64::max);
+            lambda * score - (1.0 - lambda) * max_sim
+        };
+        // 同値の場合は元の順位が上の候補を選ぶ
+        let mut best: usize = 0;
+        let mut best_val: f64 = mmr(&rest[0]);
+        for (index, candidate) in rest.iter().enumerate().skip(1) {
+            let val: f64 = mmr(candidate);
+            if val > best_val {
+                best = index;
+                best_val = val;
+            }
+        }
+        selected.push(rest.remove(best));
+    }
+    selected.extend_from_slice(&ans_vec[split..]);
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0と1はほぼ同じ内容、2は別の内容
+    fn sim(a: usize, b: usize) -> f64 {
+        match (a.min(b), a.max(b)) {
+            (0, 1) => 0.95,
+            _ => 0.0,
+        }
+    }
+
+    #[test]
+    fn rerank_test1() {
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.9), (1, 0.85), (2, 0.6), (3, 0.1)];
+        // λ=1では関連度順のまま
+        assert_eq!(rerank(&ans_vec, 1.0, 10, sim), ans_vec);
+        // λ<1では似た候補1より別内容の候補2が先になる
+        let ids: Vec<usize> = rerank(&ans_vec, 0.5, 10, sim).iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![0, 2, 3, 1]);
+    }
+
+    #[test]
+    fn rerank_test2() {
+        // top_k外は並べ替えない
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.9), (1, 0.85), (2, 0.6)];
+        assert_eq!(rerank(&ans_vec, 0.5, 2, sim), ans_vec);
+        assert!(rerank(&[], 0.5, 10, sim).is_empty());
+    }
+}
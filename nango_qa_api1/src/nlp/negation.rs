@@ -0,0 +1,79 @@
+use crate::config;
+
+const DEFAULT_WORDS: [&str; 6] = ["ない", "ません", "不可", "無理", "禁止", "ダメ"];
+const DEFAULT_PENALTY: f64 = 0.7;
+
+/// 否定表現の食い違いによる減点の設定
+/// 環境変数 NEGATION_WORDS (カンマ区切り) で否定語、NEGATION_PENALTY で減点時の係数を指定
+#[derive(Debug)]
+pub struct NegationConfig {
+    pub words: Vec<String>,
+    pub penalty: f64,
+}
+
+impl Default for NegationConfig {
+    fn default() -> Self {
+        Self { words: DEFAULT_WORDS.iter().map(|s| s.to_string()).collect(), penalty: DEFAULT_PENALTY }
+    }
+}
+
+impl NegationConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let words: Vec<String> = match std::env::var("NEGATION_WORDS") {
+            Ok(words) => words.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect(),
+            Err(_) => default.words,
+        };
+        Self { words, penalty: config::env_or("NEGATION_PENALTY", default.penalty) }
+    }
+
+    pub fn has_negation(&self, sentence: &str) -> bool {
+        self.words.iter().any(|w| sentence.contains(w.as_str()))
+    }
+}
+
+/// クエリと候補質問で否定の有無が食い違う候補を減点し、スコア順に並べ直す
+pub fn adjust_scores(que_sentence: &str, ans_vec: Vec<(usize, f64)>, que_vec: &[String], config: &NegationConfig) -> Vec<(usize, f64)> {
+    let que_negation: bool = config.has_negation(que_sentence);
+    let mut adjusted: Vec<(usize, f64)> = ans_vec.into_iter()
+        .map(|(id, cos_val)| {
+            if que_vec.get(id).is_some_and(|que| config.has_negation(que) != que_negation) {
+                (id, cos_val * config.penalty)
+            } else {
+                (id, cos_val)
+            }
+        })
+        .collect();
+    adjusted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap()); // 同点は元の順序を保つ
+    adjusted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn has_negation_test1() {
+        let config = NegationConfig::default();
+        assert!(config.has_negation("駐車場は使えません"));
+        assert!(config.has_negation("持ち込みは不可ですか？"));
+        assert!(!config.has_negation("駐車場は使えますか？"));
+    }
+
+    #[test]
+    fn adjust_scores_test1() {
+        let que_vec: Vec<String> = ["駐車場は使えません", "駐車場は使えますか？"].iter().map(|s| s.to_string()).collect();
+        let ans_vec: Vec<(usize, f64)> = vec![(0, 0.8), (1, 0.7)];
+        // 否定の有無が一致する候補が上位になる
+        let res = adjust_scores("駐車場は使えますか", ans_vec.to_owned(), &que_vec, &NegationConfig::default());
+        assert_eq!(res[0].0, 1);
+        assert!((res[1].1 - 0.8 * 0.7).abs() < 1e-9);
+
+        let res = adjust_scores("駐車場は使えないですか", ans_vec.to_owned(), &que_vec, &NegationConfig::default());
+        assert_eq!(res, vec![(0, 0.8), (1, 0.7 * 0.7)]);
+
+        // 係数1.0なら減点しない
+        let config = NegationConfig { penalty: 1.0, ..Default::default() };
+        assert_eq!(adjust_scores("駐車場は使えますか", ans_vec.to_owned(), &que_vec, &config), ans_vec);
+    }
+}
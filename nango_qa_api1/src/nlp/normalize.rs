@@ -0,0 +1,140 @@
+/// HTMLタグと基本的なMarkdown記法を除去してプレーンテキスト化
+/// 対象: `<...>`タグ、`[text](url)`リンク・画像、`**` `__` `~~` `` ` ``の強調、行頭の`#` `>` `-` `*`
+pub fn strip_markup(s: String) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for line in strip_tags(&s).lines() {
+        let line = strip_line_prefix(line);
+        let line = strip_links(line);
+        let line = ["**", "__", "~~", "`"].iter().fold(line, |acc, mark| acc.replace(mark, ""));
+        lines.push(line);
+    }
+    unescape_entities(&lines.join("\n"))
+}
+
+/// `<`から`>`までを除去 (閉じていない`<`はそのまま残す)
+fn strip_tags(s: &str) -> String {
+    let mut res = String::new();
+    let mut rest: &str = s;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) => {
+                res.push_str(&rest[..start]);
+                rest = &rest[start + end + 1..];
+            },
+            None => break,
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+/// 見出し・引用・箇条書きの行頭記号を除去
+fn strip_line_prefix(line: &str) -> &str {
+    let trimmed: &str = line.trim_start();
+    if trimmed.starts_with('#') {
+        return trimmed.trim_start_matches('#').trim_start();
+    }
+    if let Some(rest) = trimmed.strip_prefix('>') {
+        return rest.trim_start();
+    }
+    for marker in ["- ", "* ", "+ "] {
+        if let Some(rest) = trimmed.strip_prefix(marker) {
+            return rest;
+        }
+    }
+    line
+}
+
+/// `[text](url)`・`![alt](url)`をテキスト部分のみにする
+fn strip_links(line: &str) -> String {
+    let mut res = String::new();
+    let mut rest: &str = line;
+    while let Some(start) = rest.find('[') {
+        let Some(mid) = rest[start..].find("](") else { break };
+        let Some(end) = rest[start + mid..].find(')') else { break };
+        let text: &str = &rest[start + 1..start + mid];
+        res.push_str(rest[..start].strip_suffix('!').unwrap_or(&rest[..start]));
+        res.push_str(text);
+        rest = &rest[start + mid + end + 1..];
+    }
+    res.push_str(rest);
+    res
+}
+
+/// 全角英数字・記号を半角に、表記の揺れやすい記号を1つに揃え、数字の桁区切りのカンマを除く
+/// 対象: 全角の！〜～ (U+FF01〜U+FF5E)・全角スペース、ハイフン・マイナス類、波ダッシュ、引用符、円記号
+/// 長音記号 (ー) や句読点 (、。) は変換しない
+pub fn normalize_width(s: String) -> String {
+    let folded: String = s.chars().map(fold_char).collect();
+    remove_digit_separators(&folded)
+}
+
+fn fold_char(c: char) -> char {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '\u{3000}' => ' ',
+        '\u{2010}' | '\u{2011}' | '\u{2012}' | '\u{2013}' | '\u{2014}' | '\u{2212}' => '-',
+        '\u{301C}' => '~',
+        '\u{2018}' | '\u{2019}' => '\'',
+        '\u{201C}' | '\u{201D}' => '"',
+        '\u{FFE5}' => '\u{A5}',
+        _ => c,
+    }
+}
+
+/// 「3,000円」の桁区切り (前が数字、後ろがちょうど3桁の数字) のカンマを除く
+fn remove_digit_separators(s: &str) -> String {
+    let chars: Vec<char> = s.chars().collect();
+    let mut res = String::with_capacity(s.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let is_separator: bool = c == ','
+            && i > 0 && chars[i - 1].is_ascii_digit()
+            && chars.get(i + 1..i + 4).is_some_and(|group| group.iter().all(char::is_ascii_digit))
+            && !chars.get(i + 4).is_some_and(char::is_ascii_digit);
+        if !is_separator {
+            res.push(c);
+        }
+    }
+    res
+}
+
+fn unescape_entities(s: &str) -> String {
+    [("&lt;", "<"), ("&gt;", ">"), ("&quot;", "\""), ("&#39;", "'"), ("&nbsp;", " "), ("&amp;", "&")]
+        .iter()
+        .fold(s.to_string(), |acc, (entity, c)| acc.replace(entity, c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_markup_test1() {
+        let s = "<p>営業時間は<b>18時</b>からです。</p>".to_string();
+        assert_eq!(strip_markup(s), "営業時間は18時からです。");
+    }
+
+    #[test]
+    fn strip_markup_test2() {
+        let s = "# 料金\n**チャージ料金**は[こちら](https://example.com/fee)をご覧ください。\n- `1500円`".to_string();
+        assert_eq!(strip_markup(s), "料金\nチャージ料金はこちらをご覧ください。\n1500円");
+    }
+
+    #[test]
+    fn strip_markup_test3() {
+        // 記法を含まない文・閉じていない記号はそのまま
+        assert_eq!(strip_markup("お店で楽器は演奏できますか？".to_string()), "お店で楽器は演奏できますか？");
+        assert_eq!(strip_markup("1 < 2 [メモ".to_string()), "1 < 2 [メモ");
+        assert_eq!(strip_markup("![店内](img.png) &amp; 駐車場".to_string()), "店内 & 駐車場");
+    }
+
+    #[test]
+    fn normalize_width_test1() {
+        assert_eq!(normalize_width("３０００円（税込）＆ドリンク代".to_string()), "3000円(税込)&ドリンク代");
+        assert_eq!(normalize_width("ＡＢＣ　ｐｌａｎ：１〜３名".to_string()), "ABC plan:1~3名");
+        assert_eq!(normalize_width("“Ｂｌｕｅ”は −５％、￥１，５００".to_string()), "\"Blue\"は -5%、\u{A5}1500");
+        // 桁区切りは3桁ごとのみ、長音記号・句読点はそのまま
+        assert_eq!(normalize_width("1,234,567円、1,23個、12,3456".to_string()), "1234567円、1,23個、12,3456");
+        assert_eq!(normalize_width("コーヒー、ケーキ。".to_string()), "コーヒー、ケーキ。");
+    }
+}
@@ -0,0 +1,97 @@
+/// 文末表現の置き換え (敬体 → 常体、上から順に最初に一致したもの)
+/// 誤変換を避けるため、活用を推定せずに済む定型の文末のみ対象とする
+/// (「払います」のように語幹と区別できない「います」「ますか」などは対象外)
+/// 「できませんか」「ありませんか」は依頼・確認の意味のため、否定にせず疑問のまま常体にする
+const ENDING_RULES: [(&str, &str); 16] = [
+    ("できませんか", "できるか"),
+    ("できますでしょうか", "できる"),
+    ("できますか", "できる"),
+    ("できません", "できない"),
+    ("できます", "できる"),
+    ("できるか", "できる"),
+    ("ありませんか", "あるか"),
+    ("ありますでしょうか", "ある"),
+    ("ありますか", "ある"),
+    ("ありません", "ない"),
+    ("あります", "ある"),
+    ("あるか", "ある"),
+    ("でしょうか", ""),
+    ("ですか", ""),
+    ("てください", "て"),
+    ("でください", "で"),
+];
+
+/// 文末の敬体を常体へ寄せる簡易的な正規化 (「演奏できますか？」→「演奏できる？」)
+/// 文末は句点・感嘆符・疑問符・空白・改行の直前とし、文末以外は変換しない
+/// 学習・予測の両方で同じ処理を通すこと
+pub fn normalize_politeness(s: String) -> String {
+    let mut normalized: String = String::with_capacity(s.len());
+    let mut sentence: String = String::new();
+    for c in s.chars() {
+        if is_sentence_end(c) {
+            normalized.push_str(&normalize_ending(&sentence));
+            sentence.clear();
+            normalized.push(c);
+        } else {
+            sentence.push(c);
+        }
+    }
+    normalized.push_str(&normalize_ending(&sentence));
+    normalized
+}
+
+/// 文末表現だけの文 (「ですか」など) は空になるため変換しない
+fn normalize_ending(sentence: &str) -> String {
+    for (ending, replacement) in ENDING_RULES {
+        if let Some(stem) = sentence.strip_suffix(ending) {
+            if !stem.is_empty() {
+                return format!("{}{}", stem, replacement);
+            }
+        }
+    }
+    sentence.to_string()
+}
+
+fn is_sentence_end(c: char) -> bool {
+    matches!(c, '。' | '．' | '！' | '!' | '？' | '?') || c.is_whitespace()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nlp::{tf_idf::TfIdf, Retriever};
+    use crate::pipeline::Pipeline;
+
+    #[test]
+    fn normalize_politeness_test1() {
+        assert_eq!(normalize_politeness("楽器は演奏できますか？".to_string()), "楽器は演奏できる？");
+        assert_eq!(normalize_politeness("駐車場はありませんか".to_string()), "駐車場はあるか");
+        assert_eq!(normalize_politeness("予約できませんか？".to_string()), "予約できるか？");
+        assert_eq!(normalize_politeness("予約できません。".to_string()), "予約できない。");
+        assert_eq!(normalize_politeness("料金はいくらですか？ 予約方法を教えてください。".to_string()), "料金はいくら？ 予約方法を教えて。");
+        assert_eq!(normalize_politeness("楽器は演奏できる？".to_string()), "楽器は演奏できる？");
+        // 対象外の文末・文中・文末表現だけの文はそのまま
+        assert_eq!(normalize_politeness("カードで払いますか？".to_string()), "カードで払いますか？");
+        assert_eq!(normalize_politeness("できますかと聞かれた".to_string()), "できますかと聞かれた");
+        assert_eq!(normalize_politeness("ですか？".to_string()), "ですか？");
+        assert_eq!(normalize_politeness(String::new()), "");
+    }
+
+    #[test]
+    fn normalize_politeness_search_test1() {
+        // 文体違いの質問が同じQAにヒットするか確認 (学習・予測で同じパイプラインを通す)
+        let questions: [&str; 3] = ["お店で楽器は演奏できますか？", "駐車場はありますか？", "ライブの予定を教えてください。"];
+        let search = |pipeline: &Pipeline, query: &str| -> Vec<(usize, f64)> {
+            let docs: Vec<Vec<String>> = questions.iter().map(|que| pipeline.run(que.to_string())).collect();
+            TfIdf::get_tf_idf(&docs).unwrap().search(&docs, &pipeline.run(query.to_string()))
+        };
+        let politeness: Pipeline = Pipeline::from_names(&["politeness"]).unwrap();
+        for (query, id) in [("お店で楽器は演奏できる？", 0), ("駐車場はある？", 1), ("ライブの予定を教えて", 2)] {
+            let res: Vec<(usize, f64)> = search(&politeness, query);
+            assert_eq!(res[0].0, id);
+            // 正規化しない場合より類似度が上がる
+            let raw: Vec<(usize, f64)> = search(&Pipeline::default(), query);
+            assert!(res[0].1 > raw.iter().find(|(doc_id, _)| *doc_id == id).unwrap().1);
+        }
+    }
+}
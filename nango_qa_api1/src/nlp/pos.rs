@@ -0,0 +1,39 @@
+/// 内容語として残す品詞
+const CONTENT_POS: [&str; 3] = ["名詞", "動詞", "形容詞"];
+
+/// 名詞・動詞・形容詞のトークンのみ残す
+/// 品詞が推定されていないトークン(品詞モデルを持たない場合など)は除外せず残す
+pub fn filter_by_pos(tokens: Vec<(String, Option<String>)>) -> Vec<String> {
+    tokens.into_iter()
+        .filter(|(_, pos)| match pos {
+            Some(pos) => CONTENT_POS.iter().any(|p| pos.starts_with(p)),
+            None => true,
+        })
+        .map(|(surface, _)| surface)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_by_pos_test1() {
+        let tokens: Vec<(String, Option<String>)> = [
+            ("お店", "名詞"), ("で", "助詞"), ("楽器", "名詞"), ("は", "助詞"),
+            ("演奏", "名詞"), ("でき", "動詞"), ("ます", "助動詞"), ("か", "助詞"), ("？", "補助記号"),
+        ].iter().map(|(s, p)| (s.to_string(), Some(p.to_string()))).collect();
+        assert_eq!(filter_by_pos(tokens), vec!["お店", "楽器", "演奏", "でき"]);
+    }
+
+    #[test]
+    fn filter_by_pos_test2() {
+        // 細分類付きの品詞・品詞なしのトークン
+        let tokens: Vec<(String, Option<String>)> = vec![
+            ("安い".to_string(), Some("形容詞-一般".to_string())),
+            ("よ".to_string(), Some("助詞-終助詞".to_string())),
+            ("料金".to_string(), None),
+        ];
+        assert_eq!(filter_by_pos(tokens), vec!["安い", "料金"]);
+    }
+}
@@ -0,0 +1,44 @@
+/// 句点・疑問符・感嘆符・改行で文に分割する (区切り文字は直前の文に含める)
+/// 連続する区切り文字 ("？！" など) は1つの区切りとして扱い、空白・区切り文字のみの文は除く
+pub fn split_sentences(s: &str) -> Vec<String> {
+    let mut sentences: Vec<String> = Vec::new();
+    let mut current: String = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\n' && c != '\r' {
+            current.push(c);
+        }
+        let next_is_delimiter: bool = chars.peek().is_some_and(|&n| is_delimiter(n) && n != '\n' && n != '\r');
+        if is_delimiter(c) && !next_is_delimiter {
+            push_trimmed(&mut sentences, &current);
+            current.clear();
+        }
+    }
+    push_trimmed(&mut sentences, &current);
+    sentences
+}
+
+fn is_delimiter(c: char) -> bool {
+    matches!(c, '。' | '．' | '？' | '！' | '?' | '!' | '\n' | '\r')
+}
+
+fn push_trimmed(sentences: &mut Vec<String>, sentence: &str) {
+    let sentence: &str = sentence.trim();
+    if sentence.chars().any(|c| !is_delimiter(c)) {
+        sentences.push(sentence.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_sentences_test1() {
+        assert_eq!(split_sentences("営業時間は？あと駐車場はありますか？"), vec!["営業時間は？", "あと駐車場はありますか？"]);
+        assert_eq!(split_sentences("予約したいです。料金は？！\n 駐車場は"), vec!["予約したいです。", "料金は？！", "駐車場は"]);
+        // 区切りが無ければ1文、空白・区切り文字のみは除外
+        assert_eq!(split_sentences("料金は"), vec!["料金は"]);
+        assert!(split_sentences(" 。\n").is_empty());
+    }
+}
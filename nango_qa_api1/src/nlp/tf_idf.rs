@@ -1,15 +1,83 @@
 use std::collections::{ HashSet, HashMap };
+use std::fmt;
+use std::sync::OnceLock;
+
+use super::Retriever;
+
+/// モデルに保持するTF-IDF値の型
+/// 精度が足りない場合は feature "f64_weight" で f64 に切り替える (計算途中は常に f64)
+/// f32 で小さくなるのは密ベクトルとモデルファイルのみで、疎ベクトルの要素は (usize, f32) のアライメントにより16バイトのまま
+#[cfg(not(feature = "f64_weight"))]
+pub type Weight = f32;
+#[cfg(feature = "f64_weight")]
+pub type Weight = f64;
+
+/// 計算用に f64 へ変換 (f64_weight ではそのまま)
+#[cfg(not(feature = "f64_weight"))]
+pub fn to_f64(weight: Weight) -> f64 {
+    f64::from(weight)
+}
+
+#[cfg(feature = "f64_weight")]
+pub fn to_f64(weight: Weight) -> f64 {
+    weight
+}
+
+/// 文書数がこれ以上の場合は、文書を範囲に分けて並列に内積を計算する
+const PARALLEL_MIN_DOCS: usize = 20_000;
+
+/// TF-IDFの入力不正
+#[derive(Debug, PartialEq, Eq)]
+pub enum TfIdfError {
+    EmptyDocs,
+    /// 全文書が空で語彙が作れない
+    EmptyVocabulary,
+}
+
+impl fmt::Display for TfIdfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TfIdfError::EmptyDocs => write!(f, "学習データが0件です"),
+            TfIdfError::EmptyVocabulary => write!(f, "全ての文書が空のため語彙を作成できません"),
+        }
+    }
+}
+
+impl std::error::Error for TfIdfError {}
 
 /// TF-IDFの計算
 /// https://www.sejuku.net/blog/26420
 #[derive(Debug)]
 pub struct TfIdf {
     pub word_vec: Vec<String>,
-    pub tf_idf_vec: Vec<Vec<f64>>,
+    pub tf_idf_vec: Vec<HashMap<usize, Weight>>, // 文書ごとに非ゼロの語(語彙index → TF-IDF値)のみ保持
+    matrix: OnceLock<TfIdfMatrix>, // 検索用の転置行列 (初回の検索時に作る)
+    idf: OnceLock<Vec<f64>>, // 語彙順の学習済みIDF (初回の参照時に集計する)
 }
 
 impl TfIdf {
-    pub fn get_tf_idf(docs: &Vec<Vec<String>>) -> Self {
+    pub fn new(word_vec: Vec<String>, tf_idf_vec: Vec<HashMap<usize, Weight>>) -> Self {
+        Self { word_vec, tf_idf_vec, matrix: OnceLock::new(), idf: OnceLock::new() }
+    }
+
+    /// 検索用の転置行列 (モデルごとに一度だけ作る)
+    pub fn matrix(&self) -> &TfIdfMatrix {
+        self.matrix.get_or_init(|| TfIdfMatrix::new(self))
+    }
+
+    /// 空文書は行を空のまま残し、IDFの文書数からは除外する
+    pub fn get_tf_idf(docs: &[Vec<String>]) -> Result<Self, TfIdfError> {
+        if docs.is_empty() {
+            return Err(TfIdfError::EmptyDocs);
+        }
+        let empty_count: usize = docs.iter().filter(|d| d.is_empty()).count();
+        if empty_count == docs.len() {
+            return Err(TfIdfError::EmptyVocabulary);
+        }
+        if empty_count > 0 {
+            println!("warning: 空の文書が{}件あります", empty_count);
+        }
+
         let mut tmp_words: Vec<String> = Vec::new();
         for doc in docs {
             for w in doc {
@@ -18,33 +86,245 @@ impl TfIdf {
         }
         let words: HashSet<String> = tmp_words.into_iter().collect();
         let mut word_vec: Vec<String> = words.iter().map(|s| s.to_string()).collect();
-        word_vec.sort();
+        word_vec.sort(); // HashSetの順序に依存しないよう辞書順で列順を固定
     
-        let mut tf_idf_vec: Vec<Vec<f64>> = Vec::new();
-        let n: usize = docs.len();
-        for i in 0..n {
-            tf_idf_vec.push(vec![]);
-            let d = &docs[i];
-            for word in &word_vec {
-                tf_idf_vec[i].push(cal_tf_idf(word.to_string(), &d, &docs));
+        let mut tf_idf_vec: Vec<HashMap<usize, Weight>> = Vec::new();
+        for d in docs {
+            // 文書に出現しない語はTF=0なので、出現語のみ計算する
+            let doc_words: HashSet<&String> = d.iter().collect();
+            let mut tf_idf_map: HashMap<usize, Weight> = HashMap::new();
+            for word in doc_words {
+                let index: usize = word_vec.binary_search(word).unwrap();
+                tf_idf_map.insert(index, cal_tf_idf(word.to_string(), d, docs) as Weight);
+            }
+            tf_idf_vec.push(tf_idf_map);
+        }
+        Ok(Self::new(word_vec, tf_idf_vec))
+    }
+
+    /// 文書を1件追加する (docs は追加前の全文書)
+    /// 新語彙は辞書順の位置に挿入し、既存行は文書数・文書頻度の変化に合わせてIDFを再計算する
+    pub fn add_document(&mut self, docs: &[Vec<String>], doc: &[String]) {
+        let old_n: f64 = doc_count(docs);
+        let new_n: f64 = old_n + if doc.is_empty() { 0.0 } else { 1.0 };
+        let mut old_df: HashMap<&str, f64> = HashMap::new();
+        for d in docs {
+            let doc_words: HashSet<&String> = d.iter().collect();
+            for word in doc_words {
+                *old_df.entry(word.as_str()).or_insert(0.0) += 1.0;
+            }
+        }
+        let new_words: HashSet<&String> = doc.iter().collect();
+        let mut new_df: HashMap<&str, f64> = old_df.clone();
+        for word in &new_words {
+            *new_df.entry(word.as_str()).or_insert(0.0) += 1.0;
+        }
+
+        let mut word_vec: Vec<String> = self.word_vec.to_owned();
+        word_vec.extend(new_words.iter().map(|w| w.to_string()));
+        word_vec.sort();
+        word_vec.dedup();
+
+        // 既存行: TFは変わらないのでIDFのみ差し替え、列は新語彙での位置に移す
+        let mut tf_idf_vec: Vec<HashMap<usize, Weight>> = Vec::new();
+        for row in &self.tf_idf_vec {
+            let mut tf_idf_map: HashMap<usize, Weight> = HashMap::new();
+            for (&index, &val) in row {
+                let word: &str = &self.word_vec[index];
+                let new_val: f64 = to_f64(val) / idf_from_df(old_n, old_df[word]) * idf_from_df(new_n, new_df[word]);
+                tf_idf_map.insert(word_vec.binary_search_by(|w| w.as_str().cmp(word)).unwrap(), new_val as Weight);
+            }
+            tf_idf_vec.push(tf_idf_map);
+        }
+
+        let mut tf_idf_map: HashMap<usize, Weight> = HashMap::new();
+        for word in new_words {
+            let val: f64 = tf(word, doc) * idf_from_df(new_n, new_df[word.as_str()]);
+            tf_idf_map.insert(word_vec.binary_search(word).unwrap(), val as Weight);
+        }
+        tf_idf_vec.push(tf_idf_map);
+
+        self.word_vec = word_vec;
+        self.tf_idf_vec = tf_idf_vec;
+        self.matrix = OnceLock::new();
+        self.idf = OnceLock::new();
+    }
+
+    /// 語彙順の各語の学習済みIDF
+    /// TF-IDF値を持つ文書 (語を含む文書) の数を文書頻度とするため、学習時の全文書は不要
+    pub fn idf_vec(&self) -> Vec<f64> {
+        let mut df: Vec<f64> = vec![0.0; self.word_vec.len()];
+        for row in &self.tf_idf_vec {
+            for &index in row.keys() {
+                df[index] += 1.0;
             }
         }
-        Self { word_vec, tf_idf_vec }
+        let n: f64 = self.tf_idf_vec.iter().filter(|row| !row.is_empty()).count() as f64;
+        df.into_iter().map(|df| idf_from_df(n, df)).collect()
     }
 
-    pub fn predict(tfidf: TfIdf, docs: &Vec<Vec<String>>, trg: &Vec<String>) -> Vec<(usize, f64)> {
-        let trg_val = get_sentence_tf_idf(&tfidf.word_vec, docs, trg);
-        get_cos_sort_list(&trg_val, &tfidf.tf_idf_vec)
+    /// idf_vec をモデルごとに一度だけ集計したもの
+    pub fn idf(&self) -> &[f64] {
+        self.idf.get_or_init(|| self.idf_vec())
+    }
+
+    /// 学習済みの語のIDF (語彙に無い未知語はNone)
+    pub fn word_idf(&self, word: &str) -> Option<f64> {
+        self.word_vec.binary_search_by(|w| w.as_str().cmp(word)).ok().map(|index| self.idf()[index])
+    }
+
+    /// 学習済み文書どうしのcos類似度
+    pub fn doc_cos(&self, a: usize, b: usize) -> f64 {
+        match (self.tf_idf_vec.get(a), self.tf_idf_vec.get(b)) {
+            (Some(a_map), Some(b_map)) => calc_sparse_cos(a_map, b_map),
+            _ => 0.0,
+        }
+    }
+
+    /// 指定文書の行を密ベクトル(語彙順)に復元
+    pub fn dense_row(&self, index: usize) -> Vec<Weight> {
+        let mut row: Vec<Weight> = vec![0.0; self.word_vec.len()];
+        for (&word_index, &val) in &self.tf_idf_vec[index] {
+            row[word_index] = val;
+        }
+        row
+    }
+}
+
+/// TF-IDFを語彙(列)ごとに転置した疎行列と各文書のノルム
+/// クエリの非ゼロ列だけを走査して全文書との内積を一括計算する
+#[derive(Debug)]
+pub struct TfIdfMatrix {
+    columns: Vec<Vec<(usize, f64)>>, // 語彙index → (文書index, TF-IDF値)
+    norms: Vec<f64>,
+}
+
+impl TfIdfMatrix {
+    pub fn new(tf_idf: &TfIdf) -> Self {
+        let mut columns: Vec<Vec<(usize, f64)>> = vec![Vec::new(); tf_idf.word_vec.len()];
+        for (doc_id, row) in tf_idf.tf_idf_vec.iter().enumerate() {
+            for (&index, &val) in row {
+                columns[index].push((doc_id, to_f64(val)));
+            }
+        }
+        let norms: Vec<f64> = tf_idf.tf_idf_vec.iter().map(sparse_norm).collect();
+        Self { columns, norms }
+    }
+
+    /// 全文書とのcos類似度 (文書index順)
+    pub fn cos_all(&self, query_vec: &HashMap<usize, f64>) -> Vec<f64> {
+        let threads: usize = if self.norms.len() >= PARALLEL_MIN_DOCS {
+            std::thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            1
+        };
+        self.cos_all_with_threads(query_vec, threads)
+    }
+
+    /// 文書を threads 個の範囲に分けて内積を計算する
+    /// 各文書への加算順はクエリの語順のみで決まるため、分割数によらず結果は一致する
+    fn cos_all_with_threads(&self, query_vec: &HashMap<usize, f64>, threads: usize) -> Vec<f64> {
+        let mut dots: Vec<f64> = vec![0.0; self.norms.len()];
+        let query_norm: f64 = sparse_norm(query_vec);
+        if query_norm == 0.0 {
+            return dots;
+        }
+        let terms: Vec<(&[(usize, f64)], f64)> = query_vec.iter()
+            .filter_map(|(&index, &q_val)| self.columns.get(index).map(|column| (column.as_slice(), q_val)))
+            .collect();
+        let chunk_len: usize = dots.len().div_ceil(threads.max(1)).max(1);
+        if threads <= 1 {
+            accumulate_dots(&mut dots, 0, &terms);
+        } else {
+            std::thread::scope(|scope| {
+                for (chunk_index, chunk) in dots.chunks_mut(chunk_len).enumerate() {
+                    let terms: &[(&[(usize, f64)], f64)] = &terms;
+                    scope.spawn(move || accumulate_dots(chunk, chunk_index * chunk_len, terms));
+                }
+            });
+        }
+        for (dot, &norm) in dots.iter_mut().zip(&self.norms) {
+            *dot = if norm == 0.0 { 0.0 } else { *dot / (query_norm * norm) };
+        }
+        dots
+    }
+
+    /// cos類似度の大きい順 (同スコアは文書順)
+    pub fn search(&self, query_vec: &HashMap<usize, f64>) -> Vec<(usize, f64)> {
+        let mut cos_vec: Vec<(usize, f64)> = self.cos_all(query_vec).into_iter().enumerate().collect();
+        cos_vec.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        cos_vec
+    }
+}
+
+/// 文書index start から dots.len() 件分の内積を加算する (列は文書index順)
+fn accumulate_dots(dots: &mut [f64], start: usize, terms: &[(&[(usize, f64)], f64)]) {
+    let end: usize = start + dots.len();
+    for &(column, q_val) in terms {
+        let from: usize = column.partition_point(|&(doc_id, _)| doc_id < start);
+        for &(doc_id, val) in column[from..].iter().take_while(|(doc_id, _)| *doc_id < end) {
+            dots[doc_id - start] += q_val * val;
+        }
+    }
+}
+
+/// 学習済みの値のみで計算するため docs は使わない
+impl Retriever for TfIdf {
+    fn search(&self, _docs: &[Vec<String>], query: &[String]) -> Vec<(usize, f64)> {
+        self.search_vector(&self.query_vector(query))
+    }
+}
+
+impl TfIdf {
+    /// クエリのTF-IDFベクトル (非ゼロ要素のみ)
+    /// TFはクエリから、IDFは学習済みの値 (idf_vec) をそのまま使う
+    pub fn query_vector(&self, query: &[String]) -> HashMap<usize, f64> {
+        self.query_vector_with_idf(query, self.idf())
+    }
+
+    /// 学習済みIDF (語彙順) でクエリをTF-IDFベクトルにする
+    /// TFは学習時と同じく出現回数をクエリのトークン数 (未知語を含む) で割った値、未知語は次元を持たない
+    pub fn query_vector_with_idf(&self, query: &[String], idf: &[f64]) -> HashMap<usize, f64> {
+        let mut query_vec: HashMap<usize, f64> = HashMap::new();
+        for word in query.iter().collect::<HashSet<&String>>() {
+            if let Ok(index) = self.word_vec.binary_search(word) {
+                query_vec.insert(index, tf(word, query) * idf[index]);
+            }
+        }
+        query_vec
+    }
+
+    /// クエリベクトルとのcos類似度の大きい順に返す
+    pub fn search_vector(&self, query_vec: &HashMap<usize, f64>) -> Vec<(usize, f64)> {
+        self.matrix().search(query_vec)
+    }
+
+    /// クエリベクトルと文書のcos類似度を、共通の語ごとの寄与 q_i * d_i / (|q| * |d|) に分解する (合計はcos類似度)
+    /// 寄与の大きい順 (同値は語順)
+    pub fn term_contributions(&self, query_vec: &HashMap<usize, f64>, doc_id: usize) -> Vec<(String, f64)> {
+        let Some(row) = self.tf_idf_vec.get(doc_id) else {
+            return Vec::new();
+        };
+        let norms: f64 = sparse_norm(query_vec) * sparse_norm(row);
+        if norms == 0.0 {
+            return Vec::new();
+        }
+        let mut contributions: Vec<(String, f64)> = query_vec.iter()
+            .filter_map(|(index, q_val)| row.get(index).map(|&d_val| (*index, q_val * to_f64(d_val) / norms)))
+            .map(|(index, weight)| (self.word_vec[index].to_owned(), weight))
+            .collect();
+        contributions.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        contributions
     }
 }
 
 #[allow(dead_code)]
-fn get_cos_max_id(trg: &Vec<f64>, docs: &Vec<Vec<f64>>) -> (usize, f64) {
+fn get_cos_max_id(trg: &[f64], docs: &Vec<Vec<f64>>) -> (usize, f64) {
     let mut max_id: usize = 0;
     let mut max_cos: f64 = 0.0;
     for doc in docs {
         let now_id: usize = doc[0] as usize;
-        let tf_idf_vec: Vec<f64> = (&doc[1..]).to_vec();
+        let tf_idf_vec: Vec<f64> = doc[1..].to_vec();
         // println!("{:?} : {:?}", now_id, tf_idf_vec);
         let now_cos: f64 = calc_cos(trg, &tf_idf_vec);
         if max_cos < now_cos {
@@ -56,30 +336,10 @@ fn get_cos_max_id(trg: &Vec<f64>, docs: &Vec<Vec<f64>>) -> (usize, f64) {
     (max_id, max_cos)
 }
 
-fn get_cos_sort_list(trg: &Vec<f64>, docs: &Vec<Vec<f64>>) -> Vec<(usize, f64)> {
-    let mut cos_vec: Vec<(usize, f64)> = Vec::new();
-    for doc in docs {
-        let now_id: usize = doc[0] as usize;
-        let tf_idf_vec: Vec<f64> = (&doc[1..]).to_vec();
-        let now_cos: f64 = calc_cos(trg, &tf_idf_vec);
-        cos_vec.push((now_id, now_cos));
-    }
-    cos_vec.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap()); // cos類似度の大きい順に並び替え
-    cos_vec
-}
-
-fn get_sentence_tf_idf(word_vec: &Vec<String>, docs: &Vec<Vec<String>>, trg: &Vec<String>) -> Vec<f64> {
-    let mut tf_idf_vec: Vec<f64> = Vec::new();
-    for word in word_vec {
-        tf_idf_vec.push(cal_tf_idf(word.to_string(), trg, &docs));
-    }
-    tf_idf_vec
-}
-
 /// cos類似度
 /// https://qiita.com/yonedaco/items/ef6fd0db2773f62b0f72
 /// https://w3e.kanazawa-it.ac.jp/math/category/vector/henkan-tex.cgi?target=/math/category/vector/naiseki-wo-fukumu-kihonsiki.html
-fn calc_cos(a_vec: &Vec<f64>, b_vec: &Vec<f64>) -> f64 {
+fn calc_cos(a_vec: &[f64], b_vec: &[f64]) -> f64 {
     // 文章aのベクトル長
     let a_len: f64 = a_vec.iter().fold(0_f64, |acc, cur| acc + cur.powf(2.0)).sqrt();
     // 文書bのベクトル長
@@ -99,43 +359,111 @@ fn calc_cos(a_vec: &Vec<f64>, b_vec: &Vec<f64>) -> f64 {
     dot_product / (a_len * b_len)
 }
 
+/// sparse表現どうしのcos類似度
+/// 非ゼロ要素のみで計算する (加算順による誤差を避けるため語彙index順に集計)
+fn calc_sparse_cos<A: Copy + Into<f64>, B: Copy + Into<f64>>(a_map: &HashMap<usize, A>, b_map: &HashMap<usize, B>) -> f64 {
+    let a_len: f64 = sparse_norm(a_map);
+    let b_len: f64 = sparse_norm(b_map);
+
+    if a_len == 0_f64 || b_len == 0_f64 {
+        return 0_f64
+    }
+
+    // 要素数の少ない方を走査して内積計算
+    let mut keys: Vec<&usize> = if a_map.len() <= b_map.len() { a_map.keys().collect() } else { b_map.keys().collect() };
+    keys.sort();
+    let mut dot_product: f64 = 0.0;
+    for key in keys {
+        if let (Some(&a_val), Some(&b_val)) = (a_map.get(key), b_map.get(key)) {
+            dot_product += a_val.into() * b_val.into();
+        }
+    }
+    dot_product / (a_len * b_len)
+}
+
+pub fn sparse_norm<T: Copy + Into<f64>>(v_map: &HashMap<usize, T>) -> f64 {
+    let mut keys: Vec<&usize> = v_map.keys().collect();
+    keys.sort();
+    keys.iter().fold(0_f64, |acc, key| acc + v_map[*key].into().powf(2.0)).sqrt()
+}
 
-fn tf(trg: &String, d: &Vec<String>) -> f64 {
+fn tf(trg: &str, d: &[String]) -> f64 {
+    if d.is_empty() {
+        return 0.0;
+    }
     str_count(trg, d) as f64 / d.len() as f64
 }
 
-fn idf(t: &String, docs: &Vec<Vec<String>>) -> f64 {
+fn idf(t: &str, docs: &[Vec<String>]) -> f64 {
     let mut df: f64 = 0.0;
     for doc in docs {
-        if doc.contains(&t) {
+        if doc.iter().any(|w| w == t) {
             df += 1.0_f64;
         }
     }
-    ((docs.len() as f64) / df).ln() + 1.0_f64
+    idf_from_df(doc_count(docs), df)
+}
+
+/// IDFの計算に使う文書数 (空文書は除く)
+fn doc_count(docs: &[Vec<String>]) -> f64 {
+    docs.iter().filter(|d| !d.is_empty()).count() as f64
 }
 
-fn cal_tf_idf(t: String, d: &Vec<String>, docs: &Vec<Vec<String>>) -> f64 {
+fn idf_from_df(n: f64, df: f64) -> f64 {
+    (n / df).ln() + 1.0_f64
+}
+
+fn cal_tf_idf(t: String, d: &[String], docs: &[Vec<String>]) -> f64 {
     tf(&t, d) * idf(&t, docs)
 }
 
-fn str_count(trg: &String, d: &Vec<String>) -> usize {
+fn str_count(trg: &str, d: &[String]) -> usize {
     let mut letters: HashMap<&str, usize> = HashMap::new();
     for s in d {
         let cnt = letters.entry(s).or_insert(0);
         *cnt += 1;
     }
-    *letters.get(trg.as_str()).unwrap_or(&0)
+    *letters.get(trg).unwrap_or(&0)
 }
 
 #[cfg(test)]
+#[allow(clippy::excessive_precision, clippy::unnecessary_to_owned, clippy::useless_vec)] // 既存テストの書き方をそのまま残す
 mod tests {
     use super::*; // モジュールの外側で定義したリソース使用
 
     const ADD_EPSILON: f64 = 2.2204460492503131E-5_f64;
 
+    /// 密ベクトルを非ゼロ要素のみのsparse表現に変換
+    fn to_sparse(dense: &[f64]) -> HashMap<usize, f64> {
+        dense.iter().enumerate()
+            .filter(|(_, &val)| val != 0.0)
+            .map(|(index, &val)| (index, val))
+            .collect()
+    }
+
+    /// 文書ごとにcos類似度を計算する従来の検索 (TfIdfMatrix との比較用)
+    fn get_cos_sort_list(trg: &HashMap<usize, f64>, docs: &[HashMap<usize, Weight>]) -> Vec<(usize, f64)> {
+        let mut cos_vec: Vec<(usize, f64)> = Vec::new();
+        for (now_id, doc) in docs.iter().enumerate() {
+            let now_cos: f64 = calc_sparse_cos(trg, doc);
+            cos_vec.push((now_id, now_cos));
+        }
+        cos_vec.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap()); // cos類似度の大きい順に並び替え
+        cos_vec
+    }
+
+    /// 語彙順の密なTF-IDFベクトル (query_vector との比較用)
+    fn get_sentence_tf_idf(word_vec: &[String], docs: &[Vec<String>], trg: &[String]) -> Vec<f64> {
+        let mut tf_idf_vec: Vec<f64> = Vec::new();
+        for word in word_vec {
+            tf_idf_vec.push(cal_tf_idf(word.to_string(), trg, docs));
+        }
+        tf_idf_vec
+    }
+
     #[test]
     fn str_count_test1() {
-        let d = vec!["猫", "小さい", "犬", "犬", "可愛い", "可愛い", "犬", "大きい"].iter().map(|s| s.to_string()).collect();
+        let d: Vec<String> = vec!["猫", "小さい", "犬", "犬", "可愛い", "可愛い", "犬", "大きい"].iter().map(|s| s.to_string()).collect();
         assert_eq!(str_count(&"犬".to_string(), &d), 3);
         assert_eq!(str_count(&"猫".to_string(), &d), 1);
         assert_eq!(str_count(&"ギター".to_string(), &d), 0);
@@ -144,7 +472,7 @@ mod tests {
 
     #[test]
     fn tf_test1() {
-        let d = vec!["犬", "可愛い", "犬", "大きい"].iter().map(|s| s.to_string()).collect();
+        let d: Vec<String> = vec!["犬", "可愛い", "犬", "大きい"].iter().map(|s| s.to_string()).collect();
         assert_eq!(tf(&"可愛い".to_string(), &d), 0.25);
         assert_eq!(tf(&"可愛くない".to_string(), &d), 0.0);
         assert_eq!(tf(&"大きい".to_string(), &d), 0.25);
@@ -163,6 +491,9 @@ mod tests {
         ];
 
         assert!(judge_diff(idf(&"可愛い".to_string(), &docs), 1.405465));
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        assert!(judge_diff(tfidf.word_idf("可愛い").unwrap(), idf("可愛い", &docs)));
+        assert_eq!(tfidf.word_idf("鳥"), None);
         // println!("{}", idf("可愛くない", &docs));
         assert!(judge_diff(idf(&"可愛くない".to_string(), &docs), 2.098612));
         assert!(judge_diff(idf(&"大きい".to_string(), &docs), 2.098612));
@@ -199,7 +530,8 @@ mod tests {
             vec![0.562186, 0.000000, 0.000000, 0.281093, 0.000000, 0.839445, 0.000000],
             vec![0.000000, 0.699537, 0.000000, 0.468488, 0.000000, 0.000000, 0.69953]
         ];
-        assert!(judge_vec_diff(TfIdf::get_tf_idf(&docs).tf_idf_vec, exp_v_v));
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        assert!(judge_vec_diff(dense_rows(&tfidf), exp_v_v));
     }
 
     #[test]
@@ -263,6 +595,368 @@ mod tests {
         assert_eq!(get_cos_max_id(&trg, &docs), (1, 0.9973736484404528));
     }
 
+    #[test]
+    fn calc_sparse_cos_test1() {
+        let a_vec: Vec<f64> = vec![0.351366, 0.00000, 0.524653, 0.000000, 1.049306, 0.000000, 0.000000];
+        let b_vec: Vec<f64> = vec![0.482186, 0.000000, 0.000000, 0.301093, 0.000000, 0.839445, 0.000000];
+        // 密ベクトルでの計算結果と一致するか確認
+        let res = calc_sparse_cos(&to_sparse(&a_vec), &to_sparse(&b_vec));
+        assert!(judge_diff(res, calc_cos(&a_vec, &b_vec)));
+        assert!(judge_diff(calc_sparse_cos(&to_sparse(&a_vec), &to_sparse(&a_vec)), 1.0));
+        assert_eq!(calc_sparse_cos(&to_sparse(&a_vec), &HashMap::<usize, Weight>::new()), 0.0);
+    }
+
+    #[test]
+    fn doc_cos_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            vec!["犬".to_string(), "可愛い".to_string()],
+            vec!["犬".to_string(), "可愛い".to_string()],
+            vec!["猫".to_string()],
+        ];
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        assert!((tfidf.doc_cos(0, 1) - 1.0).abs() < 1e-6);
+        assert_eq!(tfidf.doc_cos(0, 2), 0.0);
+        assert_eq!(tfidf.doc_cos(0, 9), 0.0);
+    }
+
+    #[test]
+    fn get_cos_sort_list_test1() {
+        let trg: Vec<f64> = vec![0.482186, 0.000000, 0.000000, 0.301093, 0.000000, 0.839445, 0.000000];
+        let docs: Vec<HashMap<usize, Weight>> = [
+            vec![0.351366, 0.00000, 0.524653, 0.000000, 1.049306, 0.000000, 0.000000],
+            vec![0.562186, 0.000000, 0.000000, 0.281093, 0.000000, 0.839445, 0.000000],
+            vec![0.000000, 0.699537, 0.000000, 0.468488, 0.000000, 0.000000, 0.69953]
+        ].iter().map(|v| to_sparse(v).into_iter().map(|(index, val)| (index, val as Weight)).collect()).collect();
+        let res = get_cos_sort_list(&to_sparse(&trg), &docs);
+        let ids: Vec<usize> = res.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 0, 2]);
+        assert!(judge_diff(res[0].1, 0.9973736484404528));
+    }
+
+    #[test]
+    fn query_vector_test1() {
+        // 学習時の全文書から求めたIDFで、文単位のTF-IDFと同じ値になる
+        let docs: Vec<Vec<String>> = vec![
+            ["犬", "可愛い", "犬", "大きい"].iter().map(|s| s.to_string()).collect(),
+            ["猫", "小さい", "猫", "可愛い", "可愛い"].iter().map(|s| s.to_string()).collect(),
+            ["虫", "小さい", "可愛くない"].iter().map(|s| s.to_string()).collect()
+        ];
+        let trg: Vec<String> = ["猫", "大さい","ギター", "猫", "可愛い"].iter().map(|s| s.to_string()).collect();
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        assert_eq!(tfidf.query_vector(&trg), to_sparse(&get_sentence_tf_idf(&tfidf.word_vec, &docs, &trg)));
+    }
+
+    #[test]
+    fn term_contributions_test1() {
+        let docs: Vec<Vec<String>> = random_docs(30, 20, 6);
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        let query_vec: HashMap<usize, f64> = tfidf.query_vector(&docs[4][..3]);
+        let cos_vals: HashMap<usize, f64> = tfidf.search_vector(&query_vec).into_iter().collect();
+        for doc_id in 0..docs.len() {
+            let contributions: Vec<(String, f64)> = tfidf.term_contributions(&query_vec, doc_id);
+            // 寄与の合計は内積をノルムで割ったcos類似度と一致する
+            let sum: f64 = contributions.iter().map(|(_, weight)| weight).sum();
+            assert!(judge_diff(sum, cos_vals[&doc_id]));
+            // 各寄与はクエリ側と文書側のTF-IDF値の積
+            let norms: f64 = sparse_norm(&query_vec) * sparse_norm(&tfidf.tf_idf_vec[doc_id]);
+            for (term, weight) in &contributions {
+                let index: usize = tfidf.word_vec.binary_search(term).unwrap();
+                assert!(judge_diff(*weight, query_vec[&index] * to_f64(tfidf.tf_idf_vec[doc_id][&index]) / norms));
+            }
+            assert!(contributions.windows(2).all(|w| w[0].1 >= w[1].1));
+        }
+        assert!(tfidf.term_contributions(&query_vec, docs.len()).is_empty());
+        assert!(tfidf.term_contributions(&HashMap::new(), 0).is_empty());
+    }
+
+    #[test]
+    fn idf_vec_test1() {
+        // 学習済みの行から求めたIDFが、全文書から求めたIDFと完全に一致する (空文書・文書追加後も含む)
+        let mut docs: Vec<Vec<String>> = random_docs(60, 40, 6);
+        docs[3].clear();
+        let mut tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        let from_docs = |tfidf: &TfIdf, docs: &[Vec<String>]| -> Vec<f64> { tfidf.word_vec.iter().map(|word| idf(word, docs)).collect() };
+        assert_eq!(tfidf.idf_vec(), from_docs(&tfidf, &docs));
+
+        let doc: Vec<String> = ["w1", "新語", "w1"].iter().map(|s| s.to_string()).collect();
+        tfidf.add_document(&docs, &doc);
+        docs.push(doc);
+        assert_eq!(tfidf.idf_vec(), from_docs(&tfidf, &docs));
+        // クエリベクトルはリファクタ前 (全文書から求めたIDF) と完全に一致し、検索結果は加算順の誤差のみ
+        for query in docs.iter().take(10) {
+            let before: HashMap<usize, f64> = tfidf.query_vector_with_idf(query, &from_docs(&tfidf, &docs));
+            assert_eq!(tfidf.query_vector(query), before);
+            let res_by_id: HashMap<usize, f64> = tfidf.search(&docs, query).into_iter().collect();
+            for (id, cos) in tfidf.search_vector(&before) {
+                assert!((res_by_id[&id] - cos).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn query_vector_cos_test1() {
+        // 手計算: idf(a) = ln(2/2)+1 = 1、idf(b) = idf(c) = ln(2/1)+1 = k
+        // 文書0 = (a: 1/2, b: k/2)、クエリ "b a a" = (a: 2/3, b: k/3)
+        // cos = (1/3 + k^2/6) / (sqrt(4/9 + k^2/9) * sqrt(1/4 + k^2/4)) = (2 + k^2) / (sqrt(4 + k^2) * sqrt(1 + k^2))
+        let docs: Vec<Vec<String>> = vec![
+            vec!["a".to_string(), "b".to_string()],
+            vec!["a".to_string(), "c".to_string()],
+        ];
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        let k: f64 = 2_f64.ln() + 1.0;
+        assert_eq!(tfidf.idf_vec(), vec![1.0, k, k]);
+
+        let query: Vec<String> = ["b", "a", "a"].iter().map(|s| s.to_string()).collect();
+        let query_vec: HashMap<usize, f64> = tfidf.query_vector_with_idf(&query, &tfidf.idf_vec());
+        assert_eq!(query_vec, HashMap::from([(0, 2.0 / 3.0), (1, k / 3.0)]));
+        let res: Vec<(usize, f64)> = tfidf.search_vector(&query_vec);
+        let expected_0: f64 = (2.0 + k * k) / ((4.0 + k * k).sqrt() * (1.0 + k * k).sqrt());
+        let expected_1: f64 = 2.0 / ((4.0 + k * k).sqrt() * (1.0 + k * k).sqrt()); // aのみ共通
+        assert_eq!(res[0].0, 0);
+        assert!((res[0].1 - expected_0).abs() < 1e-6);
+        assert!((res[1].1 - expected_1).abs() < 1e-6);
+        // 未知語はTFの分母にのみ効き、cos類似度は変わらない
+        let query: Vec<String> = ["b", "a", "a", "ギター"].iter().map(|s| s.to_string()).collect();
+        assert!((tfidf.search_vector(&tfidf.query_vector(&query))[0].1 - expected_0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn search_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            ["犬", "可愛い", "犬", "大きい"].iter().map(|s| s.to_string()).collect(),
+            ["猫", "小さい", "猫", "可愛い", "可愛い"].iter().map(|s| s.to_string()).collect(),
+            ["虫", "小さい", "可愛くない"].iter().map(|s| s.to_string()).collect()
+        ];
+        let trg: Vec<String> = ["猫", "大さい","ギター", "猫", "可愛い"].iter().map(|s| s.to_string()).collect();
+        // トレイトオブジェクト越しでも同じ結果となるか確認
+        let retriever: Box<dyn Retriever> = Box::new(TfIdf::get_tf_idf(&docs).unwrap());
+        let res = retriever.search(&docs, &trg);
+        let ids: Vec<usize> = res.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn add_document_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            ["犬", "可愛い", "犬", "大きい"].iter().map(|s| s.to_string()).collect(),
+            ["猫", "小さい", "猫", "可愛い", "可愛い"].iter().map(|s| s.to_string()).collect(),
+            ["虫", "小さい", "可愛くない"].iter().map(|s| s.to_string()).collect()
+        ];
+        let new_doc: Vec<String> = ["猫", "鳥", "可愛い", "青い"].iter().map(|s| s.to_string()).collect();
+
+        // 差分追加とフル再学習の結果がほぼ一致するか確認
+        let mut tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        tfidf.add_document(&docs, &new_doc);
+        let mut all_docs: Vec<Vec<String>> = docs.to_owned();
+        all_docs.push(new_doc);
+        let exp = TfIdf::get_tf_idf(&all_docs).unwrap();
+
+        assert_eq!(tfidf.word_vec, exp.word_vec);
+        assert!(judge_vec_diff(dense_rows(&tfidf), dense_rows(&exp)));
+    }
+
+    #[test]
+    fn add_document_test2() {
+        // 空のモデルへの追加
+        let mut tfidf = TfIdf::new(vec![], vec![]);
+        let new_doc: Vec<String> = ["料金", "は", "？"].iter().map(|s| s.to_string()).collect();
+        tfidf.add_document(&[], &new_doc);
+        assert_eq!(tfidf.word_vec, vec!["は", "料金", "？"]);
+        assert_eq!(tfidf.dense_row(0), vec![1.0 / 3.0; 3]);
+    }
+
+    #[test]
+    fn get_tf_idf_empty_test1() {
+        assert_eq!(TfIdf::get_tf_idf(&[]).unwrap_err(), TfIdfError::EmptyDocs);
+        let docs: Vec<Vec<String>> = vec![vec![], vec![]];
+        assert_eq!(TfIdf::get_tf_idf(&docs).unwrap_err(), TfIdfError::EmptyVocabulary);
+    }
+
+    #[test]
+    fn get_tf_idf_empty_test2() {
+        // 空文書は行を空で残し、IDFの文書数に含めない
+        let docs: Vec<Vec<String>> = vec![
+            ["犬", "可愛い"].iter().map(|s| s.to_string()).collect(),
+            vec![],
+            ["猫", "可愛い"].iter().map(|s| s.to_string()).collect(),
+        ];
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        assert!(tfidf.tf_idf_vec[1].is_empty());
+        let without_empty: Vec<Vec<String>> = vec![docs[0].to_owned(), docs[2].to_owned()];
+        let exp = TfIdf::get_tf_idf(&without_empty).unwrap();
+        assert_eq!(tfidf.tf_idf_vec[0], exp.tf_idf_vec[0]);
+        assert_eq!(tfidf.tf_idf_vec[2], exp.tf_idf_vec[1]);
+        // 空のクエリでもNaNにならない
+        assert!(tfidf.search(&docs, &[]).iter().all(|(_, cos_val)| *cos_val == 0.0));
+    }
+
+    #[test]
+    fn weight_precision_test1() {
+        let docs: Vec<Vec<String>> = vec![
+            ["犬", "可愛い", "犬", "大きい"].iter().map(|s| s.to_string()).collect(),
+            ["猫", "小さい", "猫", "可愛い", "可愛い"].iter().map(|s| s.to_string()).collect(),
+            ["虫", "小さい", "可愛くない"].iter().map(|s| s.to_string()).collect()
+        ];
+        let trg: Vec<String> = ["猫", "大さい","ギター", "猫", "可愛い"].iter().map(|s| s.to_string()).collect();
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        let res = tfidf.search(&docs, &trg);
+
+        // 全てf64で計算したcos類似度と許容誤差内で一致するか確認
+        let trg_vec: Vec<f64> = get_sentence_tf_idf(&tfidf.word_vec, &docs, &trg);
+        for (id, cos_val) in res {
+            let doc_vec: Vec<f64> = get_sentence_tf_idf(&tfidf.word_vec, &docs, &docs[id]);
+            assert!((cos_val - calc_cos(&trg_vec, &doc_vec)).abs() < 1e-6);
+        }
+    }
+
+    /// 大語彙でのメモリ使用量比較 (cargo test -- --ignored --nocapture)
+    /// f32化で密ベクトルは半減するが、sparseの要素は(usize, f32)のアライメントにより16バイトのまま
+    #[test]
+    #[ignore]
+    fn sparse_memory_bench() {
+        let (n_docs, n_vocab, doc_len) = (300, 5000, 20);
+        let mut seed: u64 = 1;
+        let docs: Vec<Vec<String>> = (0..n_docs).map(|_| {
+            (0..doc_len).map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                format!("w{}", (seed >> 33) % n_vocab)
+            }).collect()
+        }).collect();
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        let entry_size = std::mem::size_of::<(usize, Weight)>();
+        let dense_bytes = tfidf.tf_idf_vec.len() * tfidf.word_vec.len() * std::mem::size_of::<Weight>();
+        let dense_f64_bytes = tfidf.tf_idf_vec.len() * tfidf.word_vec.len() * std::mem::size_of::<f64>();
+        let sparse_bytes: usize = tfidf.tf_idf_vec.iter().map(|m| m.capacity() * (entry_size + 1)).sum();
+        println!("vocab: {}, dense: {} bytes (f64: {} bytes), sparse: {} bytes (entry: {} bytes)",
+            tfidf.word_vec.len(), dense_bytes, dense_f64_bytes, sparse_bytes, entry_size);
+        assert!(sparse_bytes < dense_bytes);
+    }
+
+    fn random_docs(n_docs: usize, n_vocab: u64, doc_len: usize) -> Vec<Vec<String>> {
+        let mut seed: u64 = 1;
+        (0..n_docs).map(|_| {
+            (0..doc_len).map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                format!("w{}", (seed >> 33) % n_vocab)
+            }).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn tf_idf_matrix_test1() {
+        let docs: Vec<Vec<String>> = random_docs(50, 80, 8);
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        let matrix = TfIdfMatrix::new(&tfidf);
+        // 既存の文書ごとの計算と許容誤差内で一致するか確認
+        for query in docs.iter().take(10) {
+            let query_vec: HashMap<usize, f64> = tfidf.query_vector(query);
+            let res: Vec<(usize, f64)> = matrix.search(&query_vec);
+            let exp: Vec<(usize, f64)> = get_cos_sort_list(&query_vec, &tfidf.tf_idf_vec);
+            let res_by_id: HashMap<usize, f64> = res.into_iter().collect();
+            for (id, cos) in exp {
+                assert!((res_by_id[&id] - cos).abs() < 1e-9);
+            }
+        }
+        // 語彙に無いクエリ・空文書
+        assert!(matrix.cos_all(&HashMap::new()).iter().all(|cos| *cos == 0.0));
+    }
+
+    #[test]
+    fn matrix_cache_test1() {
+        let mut docs: Vec<Vec<String>> = random_docs(20, 30, 5);
+        let mut tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        // 検索のたびに作り直さない
+        assert!(std::ptr::eq(tfidf.matrix(), tfidf.matrix()));
+        // 文書を追加した場合は追加後のモデルで作り直す
+        let new_doc: Vec<String> = ["未知", "語"].iter().map(|s| s.to_string()).collect();
+        tfidf.add_document(&docs, &new_doc);
+        docs.push(new_doc.to_owned());
+        assert_eq!(tfidf.search(&docs, &new_doc)[0].0, 20);
+    }
+
+    #[test]
+    fn cos_all_parallel_test1() {
+        let docs: Vec<Vec<String>> = random_docs(103, 80, 8);
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        let matrix = TfIdfMatrix::new(&tfidf);
+        // 分割数 (割り切れない場合・文書数より多い場合も含む) によらずシングルスレッドと完全に一致する
+        for query in docs.iter().take(10) {
+            let query_vec: HashMap<usize, f64> = tfidf.query_vector(query);
+            let exp: Vec<f64> = matrix.cos_all_with_threads(&query_vec, 1);
+            for threads in [2, 4, 200] {
+                assert_eq!(matrix.cos_all_with_threads(&query_vec, threads), exp);
+            }
+        }
+    }
+
+    /// cargo test --release cos_all_parallel_bench -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn cos_all_parallel_bench() {
+        // get_tf_idf は文書数の2乗に比例するため、行は乱数で直接作る
+        let n_vocab: usize = 5000;
+        let mut seed: u64 = 1;
+        let tf_idf_vec: Vec<HashMap<usize, Weight>> = (0..200_000).map(|_| {
+            (0..20).map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                ((seed >> 33) as usize % n_vocab, ((seed >> 20) % 1000) as Weight / 1000.0)
+            }).collect()
+        }).collect();
+        let tfidf = TfIdf::new((0..n_vocab).map(|i| format!("w{}", i)).collect(), tf_idf_vec);
+        let matrix = TfIdfMatrix::new(&tfidf);
+        let queries: Vec<HashMap<usize, f64>> = tfidf.tf_idf_vec.iter().take(50)
+            .map(|row| row.iter().map(|(&index, &val)| (index, to_f64(val))).collect())
+            .collect();
+        let threads: usize = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+        let start = std::time::Instant::now();
+        for query_vec in &queries {
+            matrix.cos_all_with_threads(query_vec, 1);
+        }
+        let single_elapsed = start.elapsed();
+        let start = std::time::Instant::now();
+        for query_vec in &queries {
+            matrix.cos_all_with_threads(query_vec, threads);
+        }
+        let parallel_elapsed = start.elapsed();
+        println!("docs: {}, queries: {}, single: {:?}, parallel ({} threads): {:?}",
+            tfidf.tf_idf_vec.len(), queries.len(), single_elapsed, threads, parallel_elapsed);
+    }
+
+    /// cargo test --release tf_idf_matrix_bench -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn tf_idf_matrix_bench() {
+        let docs: Vec<Vec<String>> = random_docs(2000, 10000, 20);
+        let tfidf = TfIdf::get_tf_idf(&docs).unwrap();
+        // 文書の行をそのままクエリベクトルとして使う
+        let queries: Vec<HashMap<usize, f64>> = tfidf.tf_idf_vec.iter().take(200)
+            .map(|row| row.iter().map(|(&index, &val)| (index, to_f64(val))).collect())
+            .collect();
+
+        let start = std::time::Instant::now();
+        for query_vec in &queries {
+            get_cos_sort_list(query_vec, &tfidf.tf_idf_vec);
+        }
+        let loop_elapsed = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let matrix = TfIdfMatrix::new(&tfidf);
+        let build_elapsed = start.elapsed();
+        for query_vec in &queries {
+            matrix.search(query_vec);
+        }
+        let matrix_elapsed = start.elapsed();
+        println!("docs: {}, queries: {}, per-doc loop: {:?}, matrix: {:?} (build: {:?})",
+            docs.len(), queries.len(), loop_elapsed, matrix_elapsed, build_elapsed);
+    }
+
+    fn dense_rows(tfidf: &TfIdf) -> Vec<Vec<f64>> {
+        (0..tfidf.tf_idf_vec.len())
+            .map(|i| tfidf.dense_row(i).into_iter().map(to_f64).collect())
+            .collect()
+    }
+
     fn judge_diff(res: f64, exp: f64) -> bool {
         let abs_diff = (exp - res).abs();
         abs_diff <= f64::EPSILON + ADD_EPSILON // 許容範囲を超えたらfalse
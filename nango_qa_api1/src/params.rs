@@ -0,0 +1,79 @@
+use serde_json::Value;
+
+use crate::error::InitError;
+
+/// 数値パラメータを取得 (数値・文字列のどちらでも受け付ける)
+/// 数値として読めない文字列と、範囲の検証をすり抜ける NaN・無限大はパラメータ名を示して400
+pub fn get_f64(params: &Value, key: &str) -> Result<Option<f64>, InitError> {
+    let invalid = || InitError::from(format!("{} は数値で指定してください。", key));
+    match &params[key] {
+        Value::Number(n) => Ok(n.as_f64()),
+        Value::String(s) => s.trim().parse::<f64>().ok().filter(|v| v.is_finite()).map(Some).ok_or_else(invalid),
+        _ => Ok(None),
+    }
+}
+
+/// 非負整数パラメータを取得 (数値・文字列のどちらでも受け付ける)
+pub fn get_usize(params: &Value, key: &str) -> Option<usize> {
+    match &params[key] {
+        Value::Number(n) => n.as_u64().map(|n| n as usize),
+        Value::String(s) => s.trim().parse::<usize>().ok(),
+        _ => None,
+    }
+}
+
+/// 真偽値パラメータを取得 (true / "true" のどちらでも受け付ける)
+pub fn get_bool(params: &Value, key: &str) -> Option<bool> {
+    match &params[key] {
+        Value::Bool(b) => Some(*b),
+        Value::String(s) => match s.trim().to_lowercase().as_str() {
+            "true" | "1" => Some(true),
+            "false" | "0" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn get_f64_test1() {
+        let params: Value = json!({ "a": 0.3, "b": "0.3", "c": " 1 ", "d": "abc", "e": true, "f": "NaN", "g": "inf", "h": "-inf" });
+        assert_eq!(get_f64(&params, "a").unwrap(), Some(0.3));
+        assert_eq!(get_f64(&params, "b").unwrap(), Some(0.3));
+        assert_eq!(get_f64(&params, "c").unwrap(), Some(1.0));
+        assert_eq!(get_f64(&params, "e").unwrap(), None);
+        assert_eq!(get_f64(&params, "x").unwrap(), None);
+        // 読めない文字列・NaN・無限大は未指定扱いにせず400
+        for key in ["d", "f", "g", "h"] {
+            let err: InitError = get_f64(&params, key).unwrap_err();
+            assert_eq!((err.code(), err.to_string()), (400, format!("{} は数値で指定してください。", key)));
+        }
+    }
+
+    #[test]
+    fn get_usize_test1() {
+        let params: Value = json!({ "a": 20, "b": "20", "c": -1, "d": 1.5, "e": "abc" });
+        assert_eq!(get_usize(&params, "a"), Some(20));
+        assert_eq!(get_usize(&params, "b"), Some(20));
+        assert_eq!(get_usize(&params, "c"), None);
+        assert_eq!(get_usize(&params, "d"), None);
+        assert_eq!(get_usize(&params, "e"), None);
+        assert_eq!(get_usize(&params, "x"), None);
+    }
+
+    #[test]
+    fn get_bool_test1() {
+        let params: Value = json!({ "a": true, "b": "true", "c": "False", "d": "yes", "e": 1 });
+        assert_eq!(get_bool(&params, "a"), Some(true));
+        assert_eq!(get_bool(&params, "b"), Some(true));
+        assert_eq!(get_bool(&params, "c"), Some(false));
+        assert_eq!(get_bool(&params, "d"), None);
+        assert_eq!(get_bool(&params, "e"), None);
+        assert_eq!(get_bool(&params, "x"), None);
+    }
+}
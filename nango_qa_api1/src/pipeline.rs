@@ -0,0 +1,394 @@
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::Duration;
+use vaporetto::{Predictor, Sentence};
+use vaporetto_rules::{
+    string_filters::KyteaFullwidthFilter, StringFilter,
+};
+
+use crate::atomic;
+use crate::config;
+use crate::error::AppError;
+use crate::nlp::{lemmatize, normalize, politeness, pos};
+use crate::token_cache::TokenCache;
+
+/// 分かち書き前のフィルタの既定 (環境変数 TOKENIZER_FILTERS で変更)
+const DEFAULT_FILTERS: [&str; 1] = ["kytea_fullwidth"];
+
+static PREDICTOR: OnceLock<Predictor> = OnceLock::new();
+
+/// 分かち書き器 (トークナイズモデルの読み込みはプロセス内で初回のみ)
+pub fn predictor() -> Result<&'static Predictor, AppError> {
+    if let Some(predictor) = PREDICTOR.get() {
+        return Ok(predictor);
+    }
+    let predictor = Predictor::new(crate::load_tokenizer_model()?, true)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+    Ok(PREDICTOR.get_or_init(|| predictor))
+}
+
+/// 表層形と品詞 (品詞を推定していない場合はNone)
+pub type Token = (String, Option<String>);
+
+/// 前処理の1ステップ
+/// 分かち書き前の文字列と分かち書き後のトークン列のどちらか (または両方) を変換する
+pub trait TextStep: fmt::Debug + Send {
+    /// モデルメタに保存する名前
+    fn name(&self) -> &'static str;
+
+    fn apply_text(&self, text: String) -> String {
+        text
+    }
+
+    fn apply_tokens(&self, tokens: Vec<Token>) -> Vec<Token> {
+        tokens
+    }
+
+    /// 品詞の推定が必要か
+    fn needs_tags(&self) -> bool {
+        false
+    }
+}
+
+/// HTMLタグ・Markdown記法の除去 (除去後に空になる場合は元の文のまま)
+#[derive(Debug)]
+struct StripMarkup;
+
+impl TextStep for StripMarkup {
+    fn name(&self) -> &'static str {
+        "strip_markup"
+    }
+
+    fn apply_text(&self, text: String) -> String {
+        let stripped: String = normalize::strip_markup(text.to_owned());
+        if stripped.trim().is_empty() { text } else { stripped }
+    }
+}
+
+/// 全角英数字・記号を半角に揃え、数字の桁区切りを除く
+#[derive(Debug)]
+struct Width;
+
+impl TextStep for Width {
+    fn name(&self) -> &'static str {
+        "width"
+    }
+
+    fn apply_text(&self, text: String) -> String {
+        normalize::normalize_width(text)
+    }
+}
+
+/// 文末の敬体を常体へ寄せる
+#[derive(Debug)]
+struct Politeness;
+
+impl TextStep for Politeness {
+    fn name(&self) -> &'static str {
+        "politeness"
+    }
+
+    fn apply_text(&self, text: String) -> String {
+        politeness::normalize_politeness(text)
+    }
+}
+
+/// 内容語のみ残す
+#[derive(Debug)]
+struct PosFilter;
+
+impl TextStep for PosFilter {
+    fn name(&self) -> &'static str {
+        "pos_filter"
+    }
+
+    fn apply_tokens(&self, tokens: Vec<Token>) -> Vec<Token> {
+        pos::filter_by_pos(tokens).into_iter().map(|surface| (surface, None)).collect()
+    }
+
+    fn needs_tags(&self) -> bool {
+        true
+    }
+}
+
+/// 活用語尾を原形へ寄せる
+#[derive(Debug)]
+struct Lemmatize;
+
+impl TextStep for Lemmatize {
+    fn name(&self) -> &'static str {
+        "lemmatize"
+    }
+
+    fn apply_tokens(&self, tokens: Vec<Token>) -> Vec<Token> {
+        let surfaces: Vec<String> = tokens.into_iter().map(|(surface, _)| surface).collect();
+        lemmatize::lemmatize(surfaces).into_iter().map(|surface| (surface, None)).collect()
+    }
+}
+
+/// 英字を小文字に揃える (全角英字も含む)
+#[derive(Debug)]
+struct LowercaseFilter;
+
+impl<S: AsRef<str>> StringFilter<S> for LowercaseFilter {
+    fn filter(&self, string: S) -> String {
+        string.as_ref().to_lowercase()
+    }
+}
+
+/// 名前の順に分かち書き前のフィルタを並べる
+/// kytea_fullwidth: 半角を全角に変換 (vaporetto_rules)、lowercase: 英字を小文字に揃える
+pub fn build_filters<S: AsRef<str>>(names: &[S]) -> Result<Vec<Box<dyn StringFilter<String>>>, AppError> {
+    names.iter()
+        .map(|name| -> Result<Box<dyn StringFilter<String>>, AppError> {
+            match name.as_ref() {
+                "kytea_fullwidth" => Ok(Box::new(KyteaFullwidthFilter)),
+                "lowercase" => Ok(Box::new(LowercaseFilter)),
+                other => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unknown tokenizer filter: {}", other)).into()),
+            }
+        })
+        .collect()
+}
+
+fn step_from_name(name: &str) -> Option<Box<dyn TextStep>> {
+    match name {
+        "strip_markup" => Some(Box::new(StripMarkup)),
+        "width" => Some(Box::new(Width)),
+        "politeness" => Some(Box::new(Politeness)),
+        "pos_filter" => Some(Box::new(PosFilter)),
+        "lemmatize" => Some(Box::new(Lemmatize)),
+        _ => None,
+    }
+}
+
+/// 学習・予測で共通の前処理
+/// 学習時の構成 (ステップ・分かち書き前のフィルタ) をモデルメタに保存し、予測時はそれを読み戻して同じ処理を通す
+#[derive(Debug)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn TextStep>>,
+    filters: Vec<String>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self { steps: vec![], filters: DEFAULT_FILTERS.iter().map(|name| name.to_string()).collect() }
+    }
+}
+
+impl Clone for Pipeline {
+    fn clone(&self) -> Self {
+        Self { steps: self.names().into_iter().filter_map(step_from_name).collect(), filters: self.filters.clone() }
+    }
+}
+
+impl Pipeline {
+    /// 環境変数 STRIP_MARKUP / NORMALIZE_WIDTH / NORMALIZE_POLITENESS / POS_FILTER / LEMMATIZE で有効にしたステップと、TOKENIZER_FILTERS (カンマ区切り) のフィルタから構成
+    pub fn from_env() -> Self {
+        let names: Vec<&str> = [("STRIP_MARKUP", "strip_markup"), ("NORMALIZE_WIDTH", "width"), ("NORMALIZE_POLITENESS", "politeness"), ("POS_FILTER", "pos_filter"), ("LEMMATIZE", "lemmatize")]
+            .into_iter()
+            .filter(|(key, _)| config::env_or(key, false))
+            .map(|(_, name)| name)
+            .collect();
+        let filters: String = config::env_or("TOKENIZER_FILTERS", DEFAULT_FILTERS.join(","));
+        let filters: Vec<&str> = filters.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        Self::from_names(&names).unwrap().with_filters(&filters).unwrap()
+    }
+
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Result<Self, AppError> {
+        let steps: Vec<Box<dyn TextStep>> = names.iter()
+            .map(|name| step_from_name(name.as_ref()).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unknown pipeline step: {}", name.as_ref()))
+            }))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { steps, ..Self::default() })
+    }
+
+    /// 分かち書き前のフィルタを差し替える (未知のフィルタはエラー)
+    pub fn with_filters<S: AsRef<str>>(self, names: &[S]) -> Result<Self, AppError> {
+        build_filters(names)?;
+        Ok(Self { filters: names.iter().map(|name| name.as_ref().to_string()).collect(), ..self })
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.steps.iter().map(|step| step.name()).collect()
+    }
+
+    pub fn filter_names(&self) -> &[String] {
+        &self.filters
+    }
+
+    /// 文を前処理してトークン列にする
+    pub fn run(&self, doc: String) -> Vec<String> {
+        self.try_run(doc).unwrap()
+    }
+
+    /// 分かち書き直前の文字列 (ステップの文字列変換・フィルタを適用)
+    pub fn normalize(&self, doc: String) -> String {
+        let text: String = self.steps.iter().fold(doc, |text, step| step.apply_text(text));
+        let pre_filters: Vec<Box<dyn StringFilter<String>>> = build_filters(&self.filters).unwrap(); // 構築時に検証済み
+        pre_filters.iter().fold(text, |s, filter| filter.filter(s))
+    }
+
+    /// 前処理の結果が空文字列の場合など、解析できない文は AppError::Tokenize を返す
+    pub fn try_run(&self, doc: String) -> Result<Vec<String>, AppError> {
+        self.tokenize_normalized(self.normalize(doc))
+    }
+
+    /// try_run と同じ結果で、正規化後の文がキャッシュにあれば分かち書きしない
+    pub fn try_run_cached(&self, doc: String, cache: &mut TokenCache) -> Result<Vec<String>, AppError> {
+        let normalized: String = self.normalize(doc);
+        if let Some(tokens) = cache.get(&normalized) {
+            return Ok(tokens);
+        }
+        let tokens: Vec<String> = self.tokenize_normalized(normalized.to_owned())?;
+        cache.insert(&normalized, tokens.to_owned());
+        Ok(tokens)
+    }
+
+    /// 正規化済みの文を分かち書きし、トークン列のステップを適用する
+    fn tokenize_normalized(&self, preproc_input: String) -> Result<Vec<String>, AppError> {
+        let predictor: &Predictor = predictor().unwrap();
+        let mut sentence = Sentence::from_raw(preproc_input).map_err(|err| AppError::Tokenize(err.to_string()))?;
+        predictor.predict(&mut sentence);
+        if self.steps.iter().any(|step| step.needs_tags()) {
+            sentence.fill_tags();
+        }
+
+        Ok(self.steps.iter()
+            .fold(sentence_tokens(&sentence), |tokens, step| step.apply_tokens(tokens))
+            .into_iter()
+            .map(|(surface, _)| surface)
+            .collect())
+    }
+
+    /// 別スレッドで前処理し、上限時間を超えたら AppError::Timeout を返す
+    /// タイムアウトしたスレッドは結果を捨てて最後まで実行される
+    pub fn run_with_timeout(self, doc: String, timeout: Duration) -> Result<Vec<String>, AppError> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(self.try_run(doc)); // 受信側がタイムアウトで破棄済みの場合は送信失敗を無視
+        });
+        rx.recv_timeout(timeout).map_err(|_| AppError::Timeout { limit_ms: timeout.as_millis() as u64 })?
+    }
+
+    /// モデルメタとして構成を書き出す
+    pub fn write_meta(&self, path: &str) -> Result<(), AppError> {
+        let meta: Value = json!({ "pipeline": self.names(), "filters": self.filters });
+        atomic::write(path, meta.to_string().as_bytes())
+    }
+
+    /// モデルメタから構成を読み戻す (メタが無い旧形式のモデルはNone、フィルタの記録が無い場合は既定のフィルタ)
+    pub fn read_meta(path: &str) -> Result<Option<Self>, AppError> {
+        let text: String = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let meta: Value = serde_json::from_str(&text).map_err(std::io::Error::from)?;
+        let names: Vec<&str> = meta["pipeline"].as_array().into_iter().flatten().filter_map(|v| v.as_str()).collect();
+        let pipeline: Self = Self::from_names(&names)?;
+        match meta["filters"].as_array() {
+            Some(filters) => {
+                let filters: Vec<&str> = filters.iter().filter_map(|v| v.as_str()).collect();
+                Ok(Some(pipeline.with_filters(&filters)?))
+            },
+            None => Ok(Some(pipeline)),
+        }
+    }
+}
+
+/// 分かち書き結果のトークン境界からトークン列を作る (空白のみのトークンは除外)
+fn sentence_tokens(sentence: &Sentence) -> Vec<Token> {
+    sentence.iter_tokens()
+        .filter(|t| !t.surface().trim().is_empty())
+        .map(|t| (t.surface().to_string(), t.tags().first().cloned().flatten().map(|p| p.to_string())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentence_tokens_test1() {
+        // スペースを含むトークンも1トークンのまま、空白のみのトークンは除外
+        let sentence = Sentence::from_tokenized("Blue\\ Note で \\  演奏").unwrap();
+        let surfaces: Vec<String> = sentence_tokens(&sentence).into_iter().map(|(surface, _)| surface).collect();
+        assert_eq!(surfaces, vec!["Blue Note", "で", "演奏"]);
+    }
+
+    #[test]
+    fn pipeline_meta_test1() {
+        let path = std::env::temp_dir().join("nango_pipeline_meta_test1.json");
+        let path: &str = path.to_str().unwrap();
+        let learn_pipeline = Pipeline::from_names(&["strip_markup", "lemmatize"]).unwrap().with_filters(&["lowercase", "kytea_fullwidth"]).unwrap();
+        learn_pipeline.write_meta(path).unwrap();
+
+        // 予測時はメタから読み戻した構成で学習時と同じトークン列になる
+        let predict_pipeline: Pipeline = Pipeline::read_meta(path).unwrap().unwrap();
+        assert_eq!(predict_pipeline.names(), learn_pipeline.names());
+        assert_eq!(predict_pipeline.filter_names(), learn_pipeline.filter_names());
+        let text: &str = "<b>予約し</b>たいです";
+        assert_eq!(predict_pipeline.run(text.to_string()), learn_pipeline.run(text.to_string()));
+        assert!(learn_pipeline.run(text.to_string()).iter().all(|token| !token.contains('<')));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn run_with_timeout_test1() {
+        // 上限内なら通常の前処理と同じ結果
+        let tokens: Vec<String> = Pipeline::default().run_with_timeout("料金は？".to_string(), Duration::from_secs(60)).unwrap();
+        assert_eq!(tokens, Pipeline::default().run("料金は？".to_string()));
+        // 長い入力を分かち書きし終わらない短い上限ではタイムアウト
+        let res = Pipeline::default().run_with_timeout("料金は？".repeat(10000), Duration::from_millis(1));
+        assert!(matches!(res, Err(AppError::Timeout { limit_ms: 1 })));
+        assert_eq!(res.unwrap_err().code(), 500);
+    }
+
+    #[test]
+    fn filters_test1() {
+        // フィルタ構成によって分かち書き結果が変わる
+        let text: &str = "ABCの料金";
+        let fullwidth: Vec<String> = Pipeline::default().run(text.to_string());
+        let lowercase: Vec<String> = Pipeline::default().with_filters(&["lowercase", "kytea_fullwidth"]).unwrap().run(text.to_string());
+        let none: Vec<String> = Pipeline::default().with_filters::<&str>(&[]).unwrap().run(text.to_string());
+        assert!(fullwidth.concat().starts_with("ＡＢＣ"));
+        assert!(lowercase.concat().starts_with("ａｂｃ"));
+        assert!(none.concat().starts_with("ABC"));
+        // 複製しても同じ構成
+        assert_eq!(Pipeline::default().with_filters(&["lowercase"]).unwrap().clone().filter_names(), ["lowercase"]);
+        assert!(build_filters(&["nfkc"]).is_err());
+    }
+
+    #[test]
+    fn width_test1() {
+        // 数値・記号の表記違いの質問が同じQAにヒットする (半角→全角のフィルタを使わない構成でも揃う)
+        let pipeline = Pipeline::from_names(&["width"]).unwrap().with_filters(&["lowercase"]).unwrap();
+        let questions: [&str; 3] = ["駐車場は3000円ですか？", "ドリンク&フードはありますか？", "営業時間は何時からですか？"];
+        let docs: Vec<Vec<String>> = questions.iter().map(|que| pipeline.run(que.to_string())).collect();
+        let tfidf = crate::nlp::tf_idf::TfIdf::get_tf_idf(&docs).unwrap();
+        for (query, doc_id) in [("駐車場は３，０００円ですか？", 0), ("ドリンク＆フードはありますか？", 1)] {
+            let tokens: Vec<String> = pipeline.run(query.to_string());
+            assert_eq!(tokens, docs[doc_id]);
+            assert_eq!(crate::nlp::Retriever::search(&tfidf, &docs, &tokens)[0].0, doc_id);
+        }
+        // ステップ無しでは全角数字と半角数字は別のトークン
+        let plain = Pipeline::default().with_filters(&["lowercase"]).unwrap();
+        assert_ne!(plain.run("３０００円".to_string()), plain.run("3000円".to_string()));
+    }
+
+    #[test]
+    fn predictor_test1() {
+        // 2回目以降は読み込み済みの分かち書き器を使う
+        assert!(std::ptr::eq(predictor().unwrap(), predictor().unwrap()));
+    }
+
+    #[test]
+    fn pipeline_meta_test2() {
+        // メタが無い場合・未知のステップ
+        assert!(Pipeline::read_meta("not_found_meta.json").unwrap().is_none());
+        assert!(Pipeline::from_names(&["stemming"]).is_err());
+    }
+}
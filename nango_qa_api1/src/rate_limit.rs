@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::Instant;
+
+use crate::config;
+
+const DEFAULT_CAPACITY: f64 = 10.0;
+const DEFAULT_REFILL_PER_SEC: f64 = 10.0;
+// バケット数がこれを超えたら、満タンまで補充済みのバケットを捨てる
+const EVICT_THRESHOLD: usize = 64;
+
+static RATE_LIMITER: OnceLock<Mutex<RateLimiter>> = OnceLock::new();
+
+/// 簡易トークンバケット
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// pkey単位のレート制限 (ウォームインスタンス内のみ有効)
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: f64, refill_per_sec: f64) -> Self {
+        Self { capacity, refill_per_sec, buckets: HashMap::new() }
+    }
+
+    /// 1回分のトークンを消費できれば true
+    pub fn try_acquire(&mut self, key: &str, now: Instant) -> bool {
+        if self.buckets.len() > EVICT_THRESHOLD {
+            self.evict_idle(now);
+        }
+        let bucket = self.buckets.entry(key.to_string())
+            .or_insert(TokenBucket { tokens: self.capacity, last: now });
+        // 経過時間分を補充 (上限は capacity)
+        let elapsed: f64 = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.last = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// 満タンまで補充される時間アクセスの無いバケットは、新しく作るのと同じなので捨てる
+    fn evict_idle(&mut self, now: Instant) {
+        let refill_secs: f64 = self.capacity / self.refill_per_sec;
+        self.buckets.retain(|_, bucket| now.saturating_duration_since(bucket.last).as_secs_f64() < refill_secs);
+    }
+}
+
+/// 上限は環境変数 RATE_LIMIT_CAPACITY、毎秒の補充数は RATE_LIMIT_REFILL_PER_SEC で指定
+pub fn global() -> &'static Mutex<RateLimiter> {
+    RATE_LIMITER.get_or_init(|| {
+        let capacity: f64 = config::env_or("RATE_LIMIT_CAPACITY", DEFAULT_CAPACITY);
+        let refill_per_sec: f64 = config::env_or("RATE_LIMIT_REFILL_PER_SEC", DEFAULT_REFILL_PER_SEC);
+        Mutex::new(RateLimiter::new(capacity, refill_per_sec))
+    })
+}
+
+/// レート制限のロックを取る (パニックで毒化していても使い続ける)
+pub fn lock() -> MutexGuard<'static, RateLimiter> {
+    global().lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn try_acquire_test1() {
+        let mut limiter = RateLimiter::new(2.0, 1.0);
+        let now = Instant::now();
+        assert!(limiter.try_acquire("a", now));
+        assert!(limiter.try_acquire("a", now));
+        // 上限超過
+        assert!(!limiter.try_acquire("a", now));
+        // pkeyごとに独立
+        assert!(limiter.try_acquire("b", now));
+        // 1秒後には1回分補充される
+        assert!(limiter.try_acquire("a", now + Duration::from_secs(1)));
+        assert!(!limiter.try_acquire("a", now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn evict_idle_test1() {
+        let mut limiter = RateLimiter::new(2.0, 1.0);
+        let now = Instant::now();
+        for i in 0..=EVICT_THRESHOLD {
+            limiter.try_acquire(&i.to_string(), now);
+        }
+        assert_eq!(limiter.buckets.len(), EVICT_THRESHOLD + 1);
+        // 補充に必要な2秒が過ぎたバケットは捨て、使用中のバケットは残す
+        assert!(limiter.try_acquire("0", now + Duration::from_secs(1)));
+        assert!(limiter.try_acquire("a", now + Duration::from_secs(2)));
+        assert_eq!(limiter.buckets.len(), 2);
+        assert!(limiter.buckets.contains_key("0"));
+    }
+}
@@ -0,0 +1,88 @@
+use serde_json::Value;
+use std::sync::OnceLock;
+
+/// リクエストのスキーマ (パラメータごとの型と、mode ごとの必須パラメータ)
+/// 数値・真偽値のパラメータは params と同じく文字列でも受け付ける
+const SCHEMA: &str = include_str!("request_schema.json");
+
+static PARSED_SCHEMA: OnceLock<Value> = OnceLock::new();
+
+fn schema() -> &'static Value {
+    PARSED_SCHEMA.get_or_init(|| serde_json::from_str(SCHEMA).expect("request_schema.json が不正です"))
+}
+
+/// JSON Schema の型名での一致判定 (integer は非負の整数のみ)
+fn matches_type(value: &Value, type_name: &str) -> bool {
+    match type_name {
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "number" => value.is_number(),
+        "integer" => value.is_u64(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    }
+}
+
+/// スキーマで配列のパラメータ (フォーム入力では値が1つでも配列にする)
+pub fn is_array(key: &str) -> bool {
+    schema()["properties"][key]["type"].as_array().is_some_and(|types| types.iter().any(|t| t == "array"))
+}
+
+/// 認証後、ExecMode::new の前にリクエストの型・必須パラメータを検証し、違反したパラメータ名をエラーに含める
+/// スキーマに無いパラメータ・null は検証しない (未指定と同じ扱い)
+pub fn validate(event: &Value) -> Result<(), String> {
+    let Some(params) = event.as_object() else {
+        return Err("リクエストはJSONオブジェクトで指定してください。".to_string());
+    };
+    let schema: &Value = schema();
+    for (key, value) in params.iter().filter(|(_, value)| !value.is_null()) {
+        let Some(types) = schema["properties"][key.as_str()]["type"].as_array() else {
+            continue;
+        };
+        let types: Vec<&str> = types.iter().filter_map(Value::as_str).collect();
+        if !types.iter().any(|type_name| matches_type(value, type_name)) {
+            return Err(format!("{} の型が不正です (期待する型: {})", key, types.join(" または ")));
+        }
+    }
+    let mode: String = event["mode"].as_str().unwrap_or("").trim().to_lowercase();
+    let required = schema["required_by_mode"][mode.as_str()].as_array().into_iter().flatten().filter_map(Value::as_str);
+    for key in required {
+        if event[key].is_null() {
+            return Err(format!("{} は mode {} では必須です。", key, mode));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn validate_test1() {
+        assert_eq!(validate(&json!({ "mode": "l", "pkey": "key", "self_eval": "true" })), Ok(()));
+        assert_eq!(validate(&json!({ "mode": " P ", "pkey": "key", "que_sentence": "料金は？", "threshold": "0.5", "max_results": 3, "category": null, "unknown": 1 })), Ok(()));
+        // mode ごとの必須パラメータ
+        assert_eq!(validate(&json!({ "mode": "p", "pkey": "key" })), Err("que_sentence は mode p では必須です。".to_string()));
+        assert!(validate(&json!({ "mode": "a", "pkey": "key", "que_sentence": "料金は？" })).unwrap_err().starts_with("ans_sentence "));
+        assert!(validate(&json!({ "mode": "t", "pkey": "key" })).unwrap_err().starts_with("sentence "));
+        assert!(validate(&json!({ "mode": "m", "pkey": "key" })).unwrap_err().starts_with("action "));
+        // 型の違反
+        assert_eq!(validate(&json!({ "mode": "p", "que_sentence": 1 })), Err("que_sentence の型が不正です (期待する型: string)".to_string()));
+        assert!(validate(&json!({ "mode": "p", "que_sentence": "料金は？", "threshold": true })).unwrap_err().starts_with("threshold "));
+        assert!(validate(&json!({ "mode": "p", "que_sentence": "料金は？", "max_results": 1.5 })).unwrap_err().starts_with("max_results "));
+        assert!(validate(&json!({ "mode": "l", "persist_word_list": 0 })).unwrap_err().starts_with("persist_word_list "));
+        assert!(validate(&json!({ "mode": ["p"] })).unwrap_err().starts_with("mode "));
+        assert!(validate(&json!({ "mode": "l", "column_map": [3, 2] })).unwrap_err().starts_with("column_map "));
+        assert!(validate(&json!(null)).is_err());
+    }
+
+    #[test]
+    fn is_array_test1() {
+        assert!(is_array("datasets"));
+        assert!(!is_array("que_sentence"));
+        assert!(!is_array("unknown"));
+    }
+}
@@ -0,0 +1,586 @@
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// 出力JSONのキー命名 (環境変数 JSON_CASE: snake / camel)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonCase {
+    Snake,
+    Camel,
+}
+
+impl FromStr for JsonCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "snake" => Ok(JsonCase::Snake),
+            "camel" => Ok(JsonCase::Camel),
+            _ => Err(format!("未知のJSON_CASEです: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ErrorResponse {
+    pub code: u16,
+    pub success: bool,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct LearnStats {
+    pub unknown_rate: Option<f64>,
+    /// 自己評価 (self_eval) 時のみ出力
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub self_match_rate: Option<f64>,
+    pub phases: Vec<PhaseStat>,
+}
+
+/// 学習フェーズごとの所要時間
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PhaseStat {
+    pub phase: &'static str,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct LearnResponse {
+    pub code: u16,
+    pub success: bool,
+    pub mode: &'static str,
+    pub stats: LearnStats,
+    pub warnings: LearnWarnings,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct LearnWarnings {
+    pub messages: Vec<String>,
+    pub duplicate_questions: Vec<DuplicateQuestion>,
+    pub failed_docs: Vec<FailedDoc>,
+}
+
+/// トークナイズに失敗し、学習から除いた質問 (行番号は学習データCSV上の1始まり)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FailedDoc {
+    pub row: usize,
+    pub question: String,
+    pub error: String,
+}
+
+/// 正規化後に同じ文になる質問 (行番号は学習データCSV上の1始まり)
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DuplicateQuestion {
+    pub question: String,
+    pub rows: Vec<usize>,
+    pub same_answer: bool, // 全て同じ回答か
+}
+
+/// 管理用APIの結果
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct AdminResponse {
+    pub code: u16,
+    pub success: bool,
+    pub mode: &'static str,
+    pub datasets: Vec<DatasetInfo>,
+}
+
+/// 管理用APIのロールバック結果 (version は戻した世代番号)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct RollbackResponse {
+    pub code: u16,
+    pub success: bool,
+    pub mode: &'static str,
+    pub version: u64,
+}
+
+/// 2世代のモデルの検索結果の比較 (from・to は世代番号)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DiffModelsResponse {
+    pub code: u16,
+    pub success: bool,
+    pub mode: &'static str,
+    pub from: u64,
+    pub to: u64,
+    pub queries: Vec<QueryDiff>,
+}
+
+/// クエリごとの上位候補の変化
+/// kept は両方の上位に残った候補、added は to のみ、removed は from のみの候補
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct QueryDiff {
+    pub query: String,
+    pub kept: Vec<RankChange>,
+    pub added: Vec<DiffCandidate>,
+    pub removed: Vec<DiffCandidate>,
+}
+
+/// 比較するモデルでの上位候補 (similar_que はバンドルに学習データが無い場合は出力しない)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DiffCandidate {
+    pub doc_id: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similar_que: Option<String>,
+    pub rank: usize,
+    pub cos_val: f64,
+}
+
+/// 両方の上位に残った候補の順位・スコア (rank_change は正なら順位が上がった)
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct RankChange {
+    pub doc_id: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similar_que: Option<String>,
+    pub from_rank: usize,
+    pub to_rank: usize,
+    pub rank_change: i64,
+    pub from_cos_val: f64,
+    pub to_cos_val: f64,
+}
+
+/// 保存先のテナントごとのデータセット (tenant はサニタイズ済みの pkey、updated_at はUNIX時間、秒)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DatasetInfo {
+    pub tenant: String,
+    pub dataset: String,
+    pub doc_count: usize,
+    pub updated_at: u64,
+}
+
+/// トークナイズ結果の確認 (steps・filters は使った前処理の構成)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TokenizeResponse {
+    pub code: u16,
+    pub success: bool,
+    pub mode: &'static str,
+    pub payload: TokenizePayload,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TokenizePayload {
+    pub sentence: String,
+    pub normalized: String,
+    pub tokens: Vec<String>,
+    pub stop_words: Vec<String>,
+    pub steps: Vec<&'static str>,
+    pub filters: Vec<String>,
+}
+
+/// ドライランの結果 (列番号は0始まり、行番号はCSV上の1始まり)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DryRunResponse {
+    pub code: u16,
+    pub success: bool,
+    pub mode: &'static str,
+    pub payload: DryRunPayload,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DryRunPayload {
+    pub valid: bool, // 学習時と同じく読み込めるか
+    pub error: Option<String>,
+    pub doc_count: usize,
+    pub delimiter: Option<String>, // タブは "tab"
+    pub columns: DryRunColumns,
+    pub skipped_rows: Vec<usize>, // 列不足で読み飛ばす行
+    pub preview: Vec<PreviewRow>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DryRunColumns {
+    pub answer: usize,
+    pub question: usize,
+    pub category: Option<usize>,
+    pub langs: Vec<String>, // カテゴリ列の後ろに並ぶ他言語の回答
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PreviewRow {
+    pub row: usize,
+    pub question: String,
+    pub answer: String,
+    pub category: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct AddResponse {
+    pub code: u16,
+    pub success: bool,
+    pub mode: &'static str,
+    pub doc_id: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct QaInfo {
+    pub que: String,
+    pub ans: String,
+    pub cos_val: f64,
+    pub similar_que: String,
+    pub band: &'static str,
+    pub doc_id: usize,
+    /// クエリと similar_que の正規化編集距離 (0〜1)
+    pub edit_distance: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formatted_answer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+    /// 学習データの質問と完全一致した場合のみ出力
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub exact_match: bool,
+    /// 返す候補群の中での順位とパーセンタイル
+    pub rank: usize,
+    pub percentile: f64,
+    /// softmax 指定時のみ出力 (返す候補群の中で合計1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probability: Option<f64>,
+    /// calibrate 指定時のみ出力 (学習時の自己検索スコアの分布での位置、0〜1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calibrated_score: Option<f64>,
+    /// データセット横断検索時のみ出力 (候補のデータセット名)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dataset: Option<String>,
+    /// ensemble 指定時のみ出力 (候補の並び順に使った合成スコア、cos_val は合成前のcos類似度)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ensemble_score: Option<f64>,
+}
+
+/// 候補ゼロ時の問い合わせ先
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FallbackContact {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl FallbackContact {
+    /// 環境変数 FALLBACK_CONTACT_MESSAGE, FALLBACK_CONTACT_URL から設定 (どちらも無ければNone)
+    pub fn from_env() -> Option<Self> {
+        let get = |key: &str| std::env::var(key).ok().filter(|s| !s.trim().is_empty());
+        let contact = Self { message: get("FALLBACK_CONTACT_MESSAGE"), url: get("FALLBACK_CONTACT_URL") };
+        if contact.message.is_none() && contact.url.is_none() {
+            return None;
+        }
+        Some(contact)
+    }
+}
+
+/// qa_infos は候補の配列 (QaInfoSeq の場合は書き出しながら1件ずつ生成する)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PredictPayload<Q = Vec<QaInfo>> {
+    pub qa_infos: Q,
+    pub applied_threshold: f64, // 候補に採用したスコアのしきい値
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_contact: Option<FallbackContact>,
+}
+
+/// 学習済みモデルの版・学習日時 (UNIX時間、秒)・文書数
+/// version は全再学習・QA追加のたびに1ずつ増える
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ModelInfo {
+    pub version: u64,
+    pub trained_at: u64,
+    pub num_docs: usize,
+}
+
+/// クエリのトークンのうちモデルの語彙にある割合 (低い場合は言い換えを案内する)
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct QueryCoverage {
+    pub total: usize,
+    pub matched: usize,
+    pub ratio: f64, // トークンが無い場合は0
+}
+
+/// 候補に関連する他のQA (候補質問とのTF-IDFベクトルのcos類似度順)
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct RelatedQa {
+    pub que: String,
+    pub ans: String,
+}
+
+/// クエリと候補に共通の語と、そのTF-IDFのcos類似度への寄与
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct MatchedTerm {
+    pub term: String,
+    pub weight: f64,
+}
+
+/// 候補ゼロ時に返すよくある質問
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Suggestion {
+    pub doc_id: usize,
+    pub que: String,
+    pub ans: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PredictResponse<Q = Vec<QaInfo>> {
+    pub code: u16,
+    pub success: bool,
+    pub mode: &'static str,
+    pub payload: PredictPayload<Q>,
+}
+
+/// len 件の候補を qa_info(i) で生成しながら配列として書き出す
+/// 大量の候補を返す場合も、全候補の QaInfo をまとめて保持しない
+pub struct QaInfoSeq<F> {
+    pub len: usize,
+    pub qa_info: F,
+}
+
+impl<F: Fn(usize) -> QaInfo> Serialize for QaInfoSeq<F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for i in 0..self.len {
+            seq.serialize_element(&(self.qa_info)(i))?;
+        }
+        seq.end()
+    }
+}
+
+/// 候補以外を組み立てた予測結果 (head、payload.qa_infos は空) の qa_infos に、書き出しながら生成する候補を差し込む
+/// API Gateway の body に直接書き出し、全候補の Value をまとめて作らない
+pub struct StreamedPredict<'a, Q> {
+    pub head: &'a Map<String, Value>,
+    pub qa_infos: Q,
+}
+
+impl<Q: Serialize> Serialize for StreamedPredict<'_, Q> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.head.len()))?;
+        for (key, value) in self.head {
+            match value.as_object().filter(|_| key == "payload") {
+                Some(payload) => map.serialize_entry(key, &StreamedPayload { payload, qa_infos: &self.qa_infos })?,
+                None => map.serialize_entry(key, value)?,
+            }
+        }
+        map.end()
+    }
+}
+
+struct StreamedPayload<'a, Q> {
+    payload: &'a Map<String, Value>,
+    qa_infos: &'a Q,
+}
+
+impl<Q: Serialize> Serialize for StreamedPayload<'_, Q> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.payload.len()))?;
+        for (key, value) in self.payload {
+            match key.as_str() {
+                "qa_infos" => map.serialize_entry(key, self.qa_infos)?,
+                _ => map.serialize_entry(key, value)?,
+            }
+        }
+        map.end()
+    }
+}
+
+/// 意図ごとにまとめた候補 (members は representative 以外の候補)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct CandidateGroup {
+    pub representative: Value,
+    pub members: Vec<Value>,
+}
+
+/// 分割した文ごとの予測結果
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct QueryGroup {
+    pub que_sentence: String,
+    pub payload: Value, // 1文で予測した場合と同じ payload
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SplitPredictPayload {
+    pub groups: Vec<QueryGroup>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SplitPredictResponse {
+    pub code: u16,
+    pub success: bool,
+    pub mode: &'static str,
+    pub payload: SplitPredictPayload,
+}
+
+/// 開発用の中間データ (DEBUG_MODE 有効時のみ)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct DebugInfo {
+    pub query_tokens: Vec<String>,
+    /// 語 → クエリのTF-IDF値 (非ゼロのみ)
+    pub query_tfidf_nonzero: BTreeMap<String, f64>,
+}
+
+/// レスポンス構造体をJSONに変換 (キーはsnake_case)
+pub fn to_value<T: Serialize>(body: &T) -> Value {
+    serde_json::to_value(body).unwrap()
+}
+
+/// 指定の命名規則にキーを変換する (値は変換しない)
+pub fn convert_case(body: Value, case: JsonCase) -> Value {
+    match (body, case) {
+        (body, JsonCase::Snake) => body,
+        (Value::Object(obj), JsonCase::Camel) => {
+            let converted: Map<String, Value> = obj.into_iter()
+                .map(|(key, val)| (to_camel_case(&key), convert_case(val, case)))
+                .collect();
+            Value::Object(converted)
+        },
+        (Value::Array(arr), JsonCase::Camel) => {
+            Value::Array(arr.into_iter().map(|val| convert_case(val, case)).collect())
+        },
+        (body, JsonCase::Camel) => body,
+    }
+}
+
+fn to_camel_case(key: &str) -> String {
+    let mut words = key.split('_');
+    let mut camel: String = words.next().unwrap_or("").to_string();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_predict_test1() {
+        // head の payload.qa_infos のみ差し替えて書き出す
+        let head: Value = serde_json::json!({ "code": 200, "payload": { "qa_infos": [], "applied_threshold": 0.3 }, "api_version": "1" });
+        let qa_infos: Vec<u32> = vec![1, 2];
+        let body: String = serde_json::to_string(&StreamedPredict { head: head.as_object().unwrap(), qa_infos: &qa_infos }).unwrap();
+        let exp: Value = serde_json::json!({ "code": 200, "payload": { "qa_infos": [1, 2], "applied_threshold": 0.3 }, "api_version": "1" });
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), exp);
+    }
+    use serde_json::json;
+
+    fn qa_info_sample() -> Value {
+        to_value(&PredictResponse {
+            code: 200,
+            success: true,
+            mode: "predict",
+            payload: PredictPayload {
+                qa_infos: vec![QaInfo {
+                    que: "料金は？".to_string(),
+                    ans: "1500円です".to_string(),
+                    cos_val: 0.8,
+                    similar_que: "料金はいくら？".to_string(),
+                    band: "high",
+                    doc_id: 0,
+                    edit_distance: 0.25,
+                    formatted_answer: None,
+                    snippet: None,
+                    exact_match: false,
+                    rank: 1,
+                    percentile: 100.0,
+                    probability: None,
+                    calibrated_score: None,
+                    dataset: None,
+                    ensemble_score: None,
+                }],
+                applied_threshold: 0.3,
+                fallback_contact: None,
+            },
+        })
+    }
+
+    #[test]
+    fn convert_case_test1() {
+        let exp: Value = json!({
+            "code": 200,
+            "success": true,
+            "mode": "predict",
+            "payload": {
+                "qa_infos": [{
+                    "que": "料金は？",
+                    "ans": "1500円です",
+                    "cos_val": 0.8,
+                    "similar_que": "料金はいくら？",
+                    "band": "high",
+                    "doc_id": 0,
+                    "edit_distance": 0.25,
+                    "rank": 1,
+                    "percentile": 100.0
+                }],
+                "applied_threshold": 0.3
+            }
+        });
+        assert_eq!(convert_case(qa_info_sample(), JsonCase::Snake), exp);
+    }
+
+    #[test]
+    fn convert_case_test2() {
+        let exp: Value = json!({
+            "code": 200,
+            "success": true,
+            "mode": "predict",
+            "payload": {
+                "qaInfos": [{
+                    "que": "料金は？",
+                    "ans": "1500円です",
+                    "cosVal": 0.8,
+                    "similarQue": "料金はいくら？",
+                    "band": "high",
+                    "docId": 0,
+                    "editDistance": 0.25,
+                    "rank": 1,
+                    "percentile": 100.0
+                }],
+                "appliedThreshold": 0.3
+            }
+        });
+        assert_eq!(convert_case(qa_info_sample(), JsonCase::Camel), exp);
+    }
+
+    #[test]
+    fn json_case_test1() {
+        assert_eq!("camel".parse::<JsonCase>(), Ok(JsonCase::Camel));
+        assert_eq!("Snake".parse::<JsonCase>(), Ok(JsonCase::Snake));
+        assert!("kebab".parse::<JsonCase>().is_err());
+        assert_eq!(to_camel_case("api_version"), "apiVersion");
+    }
+}
@@ -0,0 +1,186 @@
+use csv::StringRecord;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::AppError;
+
+/// 区切り文字の判定に使う先頭の行数
+const SNIFF_LINES: usize = 5;
+
+/// 学習リクエストで指定する列位置 (0始まり、カテゴリ列は任意)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ColumnMap {
+    pub question: usize,
+    pub answer: usize,
+    pub category: Option<usize>,
+}
+
+/// 学習データCSVの列構成 (列番号は0始まり)
+/// 既定は id, 種別, 回答, 質問, カテゴリ(任意), 他言語の回答(任意、ANSWER_LANGS の順)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CsvSchema {
+    pub answer_col: usize,
+    pub question_col: usize,
+    pub category_col: Option<usize>,
+    /// 列位置を指定した場合はカテゴリ列も必須とし、1行目に列が無ければ範囲外の指定とする
+    pub explicit: bool,
+}
+
+impl Default for CsvSchema {
+    fn default() -> Self {
+        Self { answer_col: 2, question_col: 3, category_col: Some(4), explicit: false }
+    }
+}
+
+impl From<ColumnMap> for CsvSchema {
+    fn from(map: ColumnMap) -> Self {
+        Self { answer_col: map.answer, question_col: map.question, category_col: map.category, explicit: true }
+    }
+}
+
+impl CsvSchema {
+    /// 必須列(回答・質問、列位置指定時はカテゴリも)を含むのに必要な列数
+    pub fn min_columns(&self) -> usize {
+        let category_col: usize = self.category_col.filter(|_| self.explicit).unwrap_or(0);
+        self.answer_col.max(self.question_col).max(category_col) + 1
+    }
+
+    /// 列数が足りているか検証 (row は1始まり)
+    pub fn validate(&self, record: &StringRecord, row: usize) -> Result<(), AppError> {
+        if record.len() < self.min_columns() {
+            return Err(AppError::CsvColumns { row, expected: self.min_columns(), actual: record.len() });
+        }
+        Ok(())
+    }
+
+    pub fn answer<'a>(&self, record: &'a StringRecord) -> &'a str {
+        &record[self.answer_col]
+    }
+
+    pub fn question<'a>(&self, record: &'a StringRecord) -> &'a str {
+        &record[self.question_col]
+    }
+
+    pub fn category<'a>(&self, record: &'a StringRecord) -> &'a str {
+        self.category_col.and_then(|col| record.get(col)).unwrap_or("")
+    }
+
+    /// 回答・質問・カテゴリのうち最も後ろの列の後ろに並ぶ他言語の回答 (言語 → 回答、空欄は含めない)
+    pub fn answers(&self, record: &StringRecord, langs: &[String]) -> HashMap<String, String> {
+        let last_col: usize = self.answer_col.max(self.question_col).max(self.category_col.unwrap_or(0));
+        langs.iter().enumerate()
+            .filter_map(|(i, lang)| {
+                record.get(last_col + 1 + i)
+                    .filter(|ans| !ans.is_empty())
+                    .map(|ans| (lang.to_owned(), ans.to_string()))
+            })
+            .collect()
+    }
+}
+
+/// 先頭数行を走査し、カンマとタブのどちらが区切り文字かを推定する (引用符内は数えない)
+pub fn detect_delimiter(sample: &[u8]) -> u8 {
+    let (mut commas, mut tabs, mut lines) = (0, 0, 0);
+    let mut in_quotes: bool = false;
+    for &b in sample {
+        match b {
+            b'"' => in_quotes = !in_quotes,
+            b',' if !in_quotes => commas += 1,
+            b'\t' if !in_quotes => tabs += 1,
+            b'\n' if !in_quotes => {
+                lines += 1;
+                if lines >= SNIFF_LINES {
+                    break;
+                }
+            },
+            _ => {},
+        }
+    }
+    if tabs > commas { b'\t' } else { b',' }
+}
+
+/// 環境変数 CSV_DELIMITER で明示された区切り文字 ("tab" または1文字)
+pub fn delimiter_from_env() -> Option<u8> {
+    let value: String = std::env::var("CSV_DELIMITER").ok()?;
+    match value.as_str() {
+        "tab" | "\\t" | "\t" => Some(b'\t'),
+        v if v.len() == 1 => Some(v.as_bytes()[0]),
+        _ => None,
+    }
+}
+
+/// 環境変数 ANSWER_LANGS (カンマ区切り、例: "en,zh") で指定した他言語の回答列の言語
+pub fn answer_langs_from_env() -> Vec<String> {
+    std::env::var("ANSWER_LANGS").unwrap_or_default()
+        .split(',')
+        .map(|lang| lang.trim().to_string())
+        .filter(|lang| !lang.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_test1() {
+        let schema = CsvSchema::default();
+        let record = StringRecord::from(vec!["1", "T", "1500円です", "料金は？"]);
+        assert!(schema.validate(&record, 1).is_ok());
+        assert_eq!(schema.question(&record), "料金は？");
+        assert_eq!(schema.answer(&record), "1500円です");
+        // カテゴリ列は任意
+        assert_eq!(schema.category(&record), "");
+
+        let record = StringRecord::from(vec!["2", "T", "1500円です"]);
+        match schema.validate(&record, 2) {
+            Err(AppError::CsvColumns { row, expected, actual }) => {
+                assert_eq!((row, expected, actual), (2, 4, 3));
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn answers_test1() {
+        let schema = CsvSchema::default();
+        let langs: Vec<String> = vec!["en".to_string(), "zh".to_string()];
+        let record = StringRecord::from(vec!["1", "T", "1500円です", "料金は？", "料金", "It costs 1500 yen.", ""]);
+        let answers: HashMap<String, String> = schema.answers(&record, &langs);
+        assert_eq!(answers.get("en").map(|s| s.as_str()), Some("It costs 1500 yen."));
+        // 空欄・列が無い言語は含めない
+        assert_eq!(answers.get("zh"), None);
+        assert!(schema.answers(&StringRecord::from(vec!["1", "T", "a", "q"]), &langs).is_empty());
+    }
+
+    #[test]
+    fn column_map_test1() {
+        // 質問, 回答, カテゴリ の並び
+        let schema = CsvSchema::from(ColumnMap { question: 0, answer: 1, category: Some(2) });
+        let record = StringRecord::from(vec!["料金は？", "1500円です", "料金", "It costs 1500 yen."]);
+        assert!(schema.validate(&record, 1).is_ok());
+        assert_eq!((schema.question(&record), schema.answer(&record), schema.category(&record)), ("料金は？", "1500円です", "料金"));
+        assert_eq!(schema.answers(&record, &["en".to_string()]).get("en").map(|s| s.as_str()), Some("It costs 1500 yen."));
+        // 指定したカテゴリ列は必須
+        assert!(schema.validate(&StringRecord::from(vec!["料金は？", "1500円です"]), 1).is_err());
+        // カテゴリ列を指定しない場合は空文字、他言語の回答は回答・質問の後ろ
+        let schema = CsvSchema::from(ColumnMap { question: 1, answer: 0, category: None });
+        let record = StringRecord::from(vec!["1500円です", "料金は？", "It costs 1500 yen."]);
+        assert_eq!(schema.min_columns(), 2);
+        assert_eq!(schema.category(&record), "");
+        assert_eq!(schema.answers(&record, &["en".to_string()]).get("en").map(|s| s.as_str()), Some("It costs 1500 yen."));
+        // カテゴリ列が回答・質問より前にある場合も、他言語の回答は最も後ろの列の後ろ
+        let schema = CsvSchema::from(ColumnMap { question: 2, answer: 1, category: Some(0) });
+        let record = StringRecord::from(vec!["料金", "1500円です", "料金は？", "It costs 1500 yen."]);
+        assert_eq!(schema.answers(&record, &["en".to_string()]).get("en").map(|s| s.as_str()), Some("It costs 1500 yen."));
+    }
+
+    #[test]
+    fn detect_delimiter_test1() {
+        assert_eq!(detect_delimiter(b"1,T,1500\xe5\x86\x86,q\n2,T,a,q\n"), b',');
+        assert_eq!(detect_delimiter(b"1\tT\ta\tq\n2\tT\ta\tq\n"), b'\t');
+        // 引用符内のカンマは数えない
+        assert_eq!(detect_delimiter(b"1\tT\t\"a,b,c,d\"\tq\n"), b'\t');
+        assert_eq!(detect_delimiter(b""), b',');
+    }
+}
@@ -0,0 +1,176 @@
+use std::path::PathBuf;
+
+use crate::atomic;
+use crate::error::AppError;
+
+/// テナントごとのモデルを置くキーの接頭辞
+pub const MODELS_PREFIX: &str = "models/";
+const MODEL_FILE_NAME: &str = "model_bundle.json";
+/// テナントのデータセットを置くディレクトリ (手元・保存先のキーで共通)
+pub const DATASETS_DIR: &str = "datasets";
+
+/// 保存先のオブジェクト (更新日時はUNIX時間、秒)
+#[derive(Debug, PartialEq)]
+pub struct StorageObject {
+    pub key: String,
+    pub modified: u64,
+}
+
+/// 学習済みモデルの保存先 (キーは "/" 区切り)
+pub trait Storage {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), AppError>;
+    /// キーが無い場合はNone
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, AppError>;
+    /// 内容を書き換えると変わる値 (S3のETag相当、キーが無い場合はNone)
+    fn etag(&self, key: &str) -> Result<Option<String>, AppError>;
+    /// 接頭辞 ("/" 終わり) 以下のオブジェクト (キーの辞書順)
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>, AppError>;
+}
+
+/// ディレクトリをバケットに見立てた保存先 (S3をマウント・同期したディレクトリなど)
+#[derive(Debug)]
+pub struct LocalStorage {
+    pub root: PathBuf,
+}
+
+impl LocalStorage {
+    /// 環境変数 MODEL_STORAGE_DIR が設定されている場合のみ有効
+    pub fn from_env() -> Option<Self> {
+        std::env::var("MODEL_STORAGE_DIR").ok()
+            .filter(|dir| !dir.trim().is_empty())
+            .map(|dir| Self { root: PathBuf::from(dir) })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        key.split('/').fold(self.root.to_owned(), |path, part| path.join(part))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), AppError> {
+        let path: PathBuf = self.path(key);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        atomic::write(&path.to_string_lossy(), bytes)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, AppError> {
+        match std::fs::read(self.path(key)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// 更新日時 (ナノ秒) とサイズ
+    fn etag(&self, key: &str) -> Result<Option<String>, AppError> {
+        match std::fs::metadata(self.path(key)) {
+            Ok(metadata) => {
+                let modified: u128 = metadata.modified()?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_nanos())
+                    .unwrap_or(0);
+                Ok(Some(format!("{}-{}", modified, metadata.len())))
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<StorageObject>, AppError> {
+        let mut objects: Vec<StorageObject> = Vec::new();
+        let mut dirs: Vec<String> = vec![prefix.trim_end_matches('/').to_string()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match std::fs::read_dir(self.path(&dir)) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let key: String = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    dirs.push(key);
+                } else if !key.ends_with(".tmp") { // 書き込み途中の一時ファイルは除く
+                    let modified: u64 = metadata.modified()?
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+                    objects.push(StorageObject { key, modified });
+                }
+            }
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+}
+
+/// テナントのファイルのキー (models/{テナントID}/{ファイル名}、テナントIDはサニタイズ済みの pkey)
+pub fn tenant_key(tenant_id: &str, file_name: &str) -> String {
+    format!("{}{}/{}", MODELS_PREFIX, tenant_id, file_name)
+}
+
+/// データセットのモデルのキー (models/{テナントID}/datasets/{データセット名}/model_bundle.json) からテナントIDとデータセット名を取り出す
+/// (データセット以外のキーはNone)
+pub fn dataset_of(key: &str) -> Option<(&str, &str)> {
+    let path: &str = key.strip_prefix(MODELS_PREFIX)?.strip_suffix(MODEL_FILE_NAME)?.strip_suffix('/')?;
+    let (tenant_id, dataset) = path.split_once('/')?;
+    let dataset: &str = dataset.strip_prefix(DATASETS_DIR)?.strip_prefix('/')?;
+    (!tenant_id.is_empty() && !dataset.is_empty() && !dataset.contains('/')).then_some((tenant_id, dataset))
+}
+
+/// 英数字・"-"・"_" 以外はUTF-8のバイトごとに %XX とする
+/// パス区切りや ".." を含まず、異なる pkey が同じキーにならない
+pub fn sanitize_key(pkey: &str) -> String {
+    pkey.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_key_test1() {
+        let model_key = |pkey: &str| tenant_key(&sanitize_key(pkey), MODEL_FILE_NAME);
+        assert_eq!(model_key("nango7_ai_nango_kun"), "models/nango7_ai_nango_kun/model_bundle.json");
+        // パス区切り・親ディレクトリ参照は含まれない
+        assert_eq!(model_key("../a/b"), "models/%2E%2E%2Fa%2Fb/model_bundle.json");
+        assert_eq!(sanitize_key("キー"), "%E3%82%AD%E3%83%BC");
+        // 置換後の文字と同じ文字を含む pkey とも区別される
+        assert_ne!(sanitize_key("a/b"), sanitize_key("a%2Fb"));
+        assert_eq!(dataset_of("models/tenant%2F1/datasets/front/model_bundle.json"), Some(("tenant%2F1", "front")));
+        // テナントのモデル・データセット以外のキーは含めない
+        assert_eq!(dataset_of(&model_key("tenant/1")), None);
+        assert_eq!(dataset_of("models/a/datasets/front/other.json"), None);
+        assert_eq!(dataset_of("models/a/b/model_bundle.json"), None);
+        assert_eq!(dataset_of("models/a/datasets/front/x/model_bundle.json"), None);
+    }
+
+    #[test]
+    fn local_storage_test1() {
+        let storage = LocalStorage { root: std::env::temp_dir().join("nango_local_storage_test1") };
+        let key: String = tenant_key(&sanitize_key("tenant/1"), MODEL_FILE_NAME);
+        assert_eq!(storage.get(&key).unwrap(), None);
+        assert_eq!(storage.etag(&key).unwrap(), None);
+        storage.put(&key, b"{}").unwrap();
+        assert_eq!(storage.get(&key).unwrap(), Some(b"{}".to_vec()));
+        // 書き換えると変わる
+        let etag: Option<String> = storage.etag(&key).unwrap();
+        assert!(etag.is_some());
+        storage.put(&key, b"{\"a\":1}").unwrap();
+        assert_ne!(storage.etag(&key).unwrap(), etag);
+        storage.put(&key, b"{}").unwrap();
+        assert!(storage.root.join("models").join("tenant%2F1").join("model_bundle.json").exists());
+        let keys: Vec<String> = storage.list(MODELS_PREFIX).unwrap().into_iter().map(|o| o.key).collect();
+        assert_eq!(keys, vec![key]);
+        assert!(storage.list("not_found/").unwrap().is_empty());
+        std::fs::remove_dir_all(&storage.root).unwrap();
+    }
+}
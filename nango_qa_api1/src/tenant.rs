@@ -0,0 +1,95 @@
+use crate::storage;
+
+/// 既定以外のテナントの学習データ・学習結果を置くディレクトリ
+const TENANTS_DIR: &str = "tenants";
+
+/// 学習できる pkey (テナント) ごとの学習データ・学習結果の置き場所
+/// 既定のテナントは従来どおり input/・output/ 直下、他のテナントは tenants/{テナントID}/ 以下の同じ構成を使う
+/// テナントIDは保存先のキーと同じくサニタイズした pkey で、保持中の学習結果・予測結果のキャッシュもこのIDで分ける
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    id: String,
+    root: Option<String>,
+}
+
+impl Default for Tenant {
+    fn default() -> Self {
+        Self::new(crate::STR_PKEY, crate::STR_PKEY)
+    }
+}
+
+impl Tenant {
+    /// default_pkey のテナントのみ input/・output/ 直下を使う
+    pub fn new(pkey: &str, default_pkey: &str) -> Self {
+        let id: String = storage::sanitize_key(pkey);
+        let root: Option<String> = (pkey != default_pkey).then(|| format!("{}/{}", TENANTS_DIR, id));
+        Self { id, root }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 既定のテナントでのパス (output/model_bundle.json など) を、このテナントのパスにする
+    pub fn path(&self, path: &str) -> String {
+        match &self.root {
+            Some(root) => format!("{}/{}", root, path),
+            None => path.to_string(),
+        }
+    }
+
+    /// 保存先でのキー (models/{テナントID}/{ファイル名})
+    pub fn storage_key(&self, path: &str) -> String {
+        let file_name: &str = path.rsplit('/').next().unwrap_or(path);
+        storage::tenant_key(&self.id, file_name)
+    }
+
+    /// テナントのデータセット (学習時に dataset を指定して作る、同じ構成を datasets/{データセット名}/ 以下に置く)
+    /// 保存先のキーは models/{テナントID}/datasets/{データセット名}/ で、他のテナントのデータセットにはならない
+    pub fn dataset(&self, name: &str) -> Self {
+        let path: String = format!("{}/{}", storage::DATASETS_DIR, name);
+        Self { id: format!("{}/{}", self.id, path), root: Some(self.path(&path)) }
+    }
+
+    /// 学習データ・学習結果のディレクトリを作る (学習・保存先からの取得の前)
+    pub fn create_dirs(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(self.path("input"))?;
+        std::fs::create_dir_all(self.path("output"))
+    }
+}
+
+/// データセット名は英数字・"-"・"_" のみ (パス区切り・親ディレクトリ参照を含まない)
+pub fn is_dataset_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenant_test1() {
+        let tenant = Tenant::new("nango7_ai_nango_kun", "nango7_ai_nango_kun");
+        assert_eq!(tenant, Tenant::default());
+        assert_eq!(tenant.path("output/model_bundle.json"), "output/model_bundle.json");
+        assert_eq!(tenant.storage_key("output/model_bundle.json"), "models/nango7_ai_nango_kun/model_bundle.json");
+
+        // 既定以外のテナントはサニタイズしたIDのディレクトリ以下 (パス区切り・親ディレクトリ参照を含まない)
+        let tenant = Tenant::new("../a/b", "nango7_ai_nango_kun");
+        assert_eq!(tenant.id(), "%2E%2E%2Fa%2Fb");
+        assert_eq!(tenant.path("input/study_qa1.csv"), "tenants/%2E%2E%2Fa%2Fb/input/study_qa1.csv");
+        assert_eq!(tenant.storage_key("input/study_qa1.csv"), "models/%2E%2E%2Fa%2Fb/study_qa1.csv");
+        assert_ne!(Tenant::new("a/b", "").path("output"), Tenant::new("a%2Fb", "").path("output"));
+
+        // データセットはテナントごとの置き場所・キー
+        let dataset = Tenant::default().dataset("front");
+        assert_eq!(dataset.path("input/study_qa1.csv"), "datasets/front/input/study_qa1.csv");
+        assert_eq!(dataset.storage_key("output/model_bundle.json"), "models/nango7_ai_nango_kun/datasets/front/model_bundle.json");
+        let dataset = Tenant::new("a", "nango7_ai_nango_kun").dataset("front");
+        assert_eq!(dataset.path("output"), "tenants/a/datasets/front/output");
+        assert_eq!(dataset.storage_key("output/model_bundle.json"), "models/a/datasets/front/model_bundle.json");
+        assert_ne!(dataset.id(), Tenant::default().dataset("front").id());
+        assert!(is_dataset_name("front-1_a"));
+        assert!(!is_dataset_name("") && !is_dataset_name("../a") && !is_dataset_name("a%2F"));
+    }
+}
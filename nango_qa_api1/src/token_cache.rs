@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::atomic;
+use crate::error::AppError;
+use crate::pipeline::Pipeline;
+
+const VERSION_KEY: &str = "version";
+
+/// 正規化後の文 → トークン列のキャッシュ (再学習時に既知の文のトークナイズを省く)
+/// キーは正規化後の文そのもの、version はトークナイズモデルと前処理の構成のハッシュで、一致しない場合は全て破棄する
+/// 書き出すのは今回の学習で使った文のみ (削除したQAの分は残さない)
+#[derive(Debug, Default)]
+pub struct TokenCache {
+    version: u64,
+    entries: HashMap<String, Vec<String>>,
+    used: HashMap<String, Vec<String>>,
+    hits: usize,
+}
+
+impl TokenCache {
+    pub fn new(version: u64) -> Self {
+        Self { version, ..Default::default() }
+    }
+
+    /// 形式は 1行目が version,{version}、以降は {文},{トークン}... (ヘッダー無し)
+    /// ファイルが無い・version が異なる・壊れている場合は空のキャッシュとする
+    pub fn read(csv_file_path: &str, version: u64) -> Result<Self, AppError> {
+        let bytes: Vec<u8> = match std::fs::read(csv_file_path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new(version)),
+            Err(err) => return Err(err.into()),
+        };
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false) // ヘッダーが無い事を明示的に設定
+            .flexible(true)
+            .from_reader(bytes.as_slice());
+        let mut records = rdr.records();
+        let cached_version: Option<u64> = match records.next() {
+            Some(Ok(record)) if record.get(0) == Some(VERSION_KEY) => record.get(1).and_then(|v| v.parse().ok()),
+            _ => None,
+        };
+        if cached_version != Some(version) {
+            return Ok(Self::new(version));
+        }
+        let mut entries: HashMap<String, Vec<String>> = HashMap::new();
+        for record in records {
+            let Ok(record) = record else {
+                println!("warning: トークナイズキャッシュが壊れているため破棄します");
+                return Ok(Self::new(version));
+            };
+            if let Some(text) = record.get(0) {
+                entries.insert(text.to_string(), record.iter().skip(1).map(|s| s.to_string()).collect());
+            }
+        }
+        Ok(Self { version, entries, ..Default::default() })
+    }
+
+    pub fn get(&mut self, text: &str) -> Option<Vec<String>> {
+        let tokens: Vec<String> = self.entries.get(text).or_else(|| self.used.get(text))?.to_owned();
+        self.used.insert(text.to_string(), tokens.to_owned());
+        self.hits += 1;
+        Some(tokens)
+    }
+
+    pub fn insert(&mut self, text: &str, tokens: Vec<String>) {
+        self.used.insert(text.to_string(), tokens);
+    }
+
+    #[cfg(test)]
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    pub fn write(&self, csv_file_out_path: &str) -> Result<(), AppError> {
+        let mut keys: Vec<&String> = self.used.keys().collect();
+        keys.sort();
+        let mut builder = csv::WriterBuilder::new();
+        builder.flexible(true);
+        atomic::write_csv(csv_file_out_path, &builder, |wtr| {
+            wtr.write_record([VERSION_KEY, &self.version.to_string()])?;
+            for key in keys {
+                let mut record: Vec<String> = vec![key.to_owned()];
+                record.extend(self.used[key].iter().cloned());
+                wtr.write_record(&record)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// ファイルに残す値のためのハッシュ (FNV-1a)
+/// DefaultHasher はRustのバージョンで値が変わり得るため使わない
+struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// キャッシュの version (トークナイズモデルの内容と前処理のステップ・フィルタから算出)
+pub fn fingerprint(pipeline: &Pipeline, tokenizer_model_path: &str) -> Result<u64, AppError> {
+    let mut hasher = StableHasher::default();
+    hasher.write(&std::fs::read(tokenizer_model_path)?);
+    pipeline.names().hash(&mut hasher);
+    pipeline.filter_names().hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_cache_test1() {
+        let path = std::env::temp_dir().join("nango_token_cache_test1.csv");
+        let path: &str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let mut cache = TokenCache::read(path, 1).unwrap();
+        assert_eq!(cache.get("料金は？"), None);
+        cache.insert("料金は？", vec!["料金".to_string(), "は".to_string(), "？".to_string()]);
+        cache.insert("", vec![]);
+        cache.write(path).unwrap();
+
+        // 同じ version なら読み戻せる (トークンが無い文も含む)
+        let mut cache = TokenCache::read(path, 1).unwrap();
+        assert_eq!(cache.get("料金は？"), Some(vec!["料金".to_string(), "は".to_string(), "？".to_string()]));
+        assert_eq!(cache.get(""), Some(vec![]));
+        assert_eq!(cache.hits(), 2);
+        // version が変わったら破棄する
+        assert_eq!(TokenCache::read(path, 2).unwrap().get("料金は？"), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fingerprint_test1() {
+        let model_path: &str = crate::DEFAULT_TOKENIZER_MODEL_PATH;
+        let a: u64 = fingerprint(&Pipeline::default(), model_path).unwrap();
+        assert_eq!(a, fingerprint(&Pipeline::default(), model_path).unwrap());
+        // 前処理の構成が変わると別の version
+        let lemmatize: Pipeline = Pipeline::from_names(&["lemmatize"]).unwrap();
+        assert_ne!(a, fingerprint(&lemmatize, model_path).unwrap());
+    }
+
+    #[test]
+    fn stable_hasher_test1() {
+        // FNV-1a の既知の値と一致する (実行環境で変わらない)
+        let mut hasher = StableHasher::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn token_cache_key_test1() {
+        // キャッシュの文が一致しなければヒットしない (同じハッシュ値の別の文を取り違えない)
+        let mut cache = TokenCache::new(1);
+        cache.insert("料金は？", vec!["料金".to_string()]);
+        assert_eq!(cache.get("料金は?"), None);
+        assert_eq!(cache.get("料金は？"), Some(vec!["料金".to_string()]));
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs::OpenOptions;
+
+use crate::atomic;
+use crate::cache;
+use crate::config;
+use crate::error::AppError;
+
+const HEADER: [&str; 4] = ["timestamp", "query", "max_score", "count"];
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+
+/// 回答候補が見つからなかったクエリ (timestamp は最後に記録したUNIX時間、秒)
+#[derive(Debug, PartialEq)]
+pub struct Unanswered {
+    pub timestamp: u64,
+    pub query: String,
+    pub max_score: f64,
+    pub count: u64,
+}
+
+/// 記録済みのクエリを読み込む (ファイルが無い初期状態は空)
+/// 形式は timestamp,query,max_score,count (ヘッダー有り)、記録のたびに追記した行を同じクエリごとに集計する
+/// (初めて記録した順、日時と最高スコアは最後の行の値)
+pub fn read_unanswered(csv_file_path: &str) -> Result<Vec<Unanswered>, AppError> {
+    let bytes: Vec<u8> = match std::fs::read(csv_file_path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut rdr = csv::Reader::from_reader(bytes.as_slice());
+
+    let mut entries: Vec<Unanswered> = Vec::new();
+    for (index, result) in rdr.records().enumerate() {
+        let record = result?;
+        let get = |col: usize| record.get(col).unwrap_or("").trim();
+        match (get(0).parse::<u64>(), get(2).parse::<f64>(), get(3).parse::<u64>()) {
+            (Ok(timestamp), Ok(max_score), Ok(count)) => {
+                match entries.iter_mut().find(|entry| entry.query == get(1)) {
+                    Some(entry) => {
+                        entry.timestamp = timestamp;
+                        entry.max_score = max_score;
+                        entry.count += count;
+                    },
+                    None => entries.push(Unanswered { timestamp, query: get(1).to_string(), max_score, count }),
+                }
+            },
+            _ => {
+                let value: String = record.iter().collect::<Vec<&str>>().join(",");
+                return Err(AppError::ParseFloat { row: index + 2, col: 1, value });
+            },
+        }
+    }
+    Ok(entries)
+}
+
+/// 初めて記録した順に、クエリごとに1行で書き出す
+pub fn write_unanswered(csv_file_out_path: &str, entries: &[Unanswered]) -> Result<(), AppError> {
+    atomic::write_csv(csv_file_out_path, &csv::WriterBuilder::new(), |wtr| {
+        wtr.write_record(HEADER)?;
+        for entry in entries {
+            wtr.write_record([entry.timestamp.to_string(), entry.query.to_owned(), entry.max_score.to_string(), entry.count.to_string()])?;
+        }
+        Ok(())
+    })
+}
+
+/// クエリを1行追記する (空白を正規化、件数は読み込み時に集計)
+/// ファイルが上限 (環境変数 UNANSWERED_MAX_BYTES) 以上であれば、集計して {path}.1 へ退避し、新しいファイルに書く (退避は1世代のみ)
+pub fn record(csv_file_path: &str, query: &str, max_score: f64, timestamp: u64) -> Result<(), AppError> {
+    record_with_limit(csv_file_path, query, max_score, timestamp, config::env_or("UNANSWERED_MAX_BYTES", DEFAULT_MAX_BYTES))
+}
+
+fn record_with_limit(csv_file_path: &str, query: &str, max_score: f64, timestamp: u64, max_bytes: u64) -> Result<(), AppError> {
+    let mut size: u64 = match std::fs::metadata(csv_file_path) {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err.into()),
+    };
+    if size >= max_bytes {
+        write_unanswered(&format!("{}.1", csv_file_path), &read_unanswered(csv_file_path)?)?;
+        std::fs::remove_file(csv_file_path)?;
+        size = 0;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(csv_file_path)?;
+    let mut wtr = csv::WriterBuilder::new().from_writer(file);
+    if size == 0 {
+        wtr.write_record(HEADER)?;
+    }
+    wtr.write_record([timestamp.to_string(), cache::normalize_query(query), max_score.to_string(), 1.to_string()])?;
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_test1() {
+        let path = std::env::temp_dir().join("nango_unanswered_record_test1.csv");
+        let path: &str = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        // ファイルが無い初期状態は記録無し
+        assert!(read_unanswered(path).unwrap().is_empty());
+        record(path, "駐車場は？", 0.1, 100).unwrap();
+        record(path, "Wi-Fi, ありますか", 0.0, 200).unwrap();
+        record(path, " 駐車場は？ ", 0.2, 300).unwrap();
+        assert_eq!(read_unanswered(path).unwrap(), vec![
+            Unanswered { timestamp: 300, query: "駐車場は？".to_string(), max_score: 0.2, count: 2 },
+            Unanswered { timestamp: 200, query: "Wi-Fi, ありますか".to_string(), max_score: 0.0, count: 1 },
+        ]);
+        // 既存の行は書き換えずに追記する
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "timestamp,query,max_score,count\n100,駐車場は？,0.1,1\n200,\"Wi-Fi, ありますか\",0,1\n300,駐車場は？,0.2,1\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn record_rotate_test1() {
+        let path = std::env::temp_dir().join("nango_unanswered_record_rotate_test1.csv");
+        let path: &str = path.to_str().unwrap();
+        let rotated: String = format!("{}.1", path);
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(&rotated);
+        // 上限を超えたら退避し、新しいファイルはヘッダーから始める
+        record_with_limit(path, "駐車場は？", 0.1, 100, 64).unwrap();
+        record_with_limit(path, "駐車場は？", 0.2, 200, 64).unwrap();
+        record_with_limit(path, "予約は？", 0.1, 300, 64).unwrap();
+        // 退避したファイルはクエリごとに集計済み
+        assert_eq!(std::fs::read_to_string(&rotated).unwrap(), "timestamp,query,max_score,count\n200,駐車場は？,0.2,2\n");
+        assert_eq!(read_unanswered(path).unwrap(), vec![
+            Unanswered { timestamp: 300, query: "予約は？".to_string(), max_score: 0.1, count: 1 },
+        ]);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::atomic;
+use crate::error::AppError;
+
+const CURRENT_FILE_NAME: &str = "current";
+
+/// 学習済みモデルの世代管理
+/// 学習のたびにモデルファイルを {dir}/{名前}.v{n}.{拡張子} に複製し、現行の世代番号を {dir}/current に記録する
+#[derive(Debug)]
+pub struct ModelVersions {
+    pub dir: String,
+    pub paths: Vec<String>, // 世代ごとに保存するファイル
+    pub keep: usize, // 保持する世代数 (古い順に削除、現行は常に残す)
+}
+
+impl ModelVersions {
+    /// 現在のモデルファイルを新しい世代として保存し、現行とする (世代番号を返す)
+    /// 存在しないファイルは保存しない
+    pub fn save(&self) -> Result<u64, AppError> {
+        std::fs::create_dir_all(&self.dir)?;
+        let version: u64 = self.list()?.last().map_or(1, |v| v + 1);
+        for path in &self.paths {
+            match std::fs::read(path) {
+                Ok(bytes) => atomic::write(&self.versioned_path(path, version), &bytes)?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.set_current(version)?;
+        self.prune(version)?;
+        Ok(version)
+    }
+
+    /// 現行より1つ前の世代のファイルを書き戻し、現行とする (戻した世代番号を返す)
+    pub fn rollback(&self) -> Result<u64, AppError> {
+        let current: Option<u64> = self.current()?;
+        let target: u64 = self.list()?.into_iter()
+            .rev()
+            .find(|v| current.is_some_and(|current| *v < current))
+            .ok_or(AppError::NoPreviousVersion)?;
+        for path in &self.paths {
+            let versioned: String = self.versioned_path(path, target);
+            if Path::new(&versioned).exists() {
+                atomic::write(path, &std::fs::read(&versioned)?)?;
+            }
+        }
+        self.set_current(target)?;
+        Ok(target)
+    }
+
+    /// 現行の世代番号 (保存したことが無ければNone)
+    pub fn current(&self) -> Result<Option<u64>, AppError> {
+        match std::fs::read_to_string(self.current_path()) {
+            Ok(text) => Ok(text.trim().parse::<u64>().ok()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// 指定の世代で保存したファイルの内容 (その世代で保存していなければNone)
+    pub fn read(&self, path: &str, version: u64) -> Result<Option<Vec<u8>>, AppError> {
+        match std::fs::read(self.versioned_path(path, version)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// 保存済みの世代番号 (昇順)
+    pub fn list(&self) -> Result<Vec<u64>, AppError> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut versions: BTreeSet<u64> = BTreeSet::new();
+        for entry in entries {
+            let file_name: String = entry?.file_name().to_string_lossy().to_string();
+            versions.extend(self.paths.iter().filter_map(|path| parse_version(&file_name, path)));
+        }
+        Ok(versions.into_iter().collect())
+    }
+
+    fn set_current(&self, version: u64) -> Result<(), AppError> {
+        atomic::write(&self.current_path(), version.to_string().as_bytes())
+    }
+
+    /// keep 世代より古いものを削除する
+    fn prune(&self, current: u64) -> Result<(), AppError> {
+        let versions: Vec<u64> = self.list()?;
+        let remove_count: usize = versions.len().saturating_sub(self.keep.max(1));
+        for version in versions.into_iter().take(remove_count).filter(|v| *v != current) {
+            for path in &self.paths {
+                let _ = std::fs::remove_file(self.versioned_path(path, version));
+            }
+        }
+        Ok(())
+    }
+
+    fn current_path(&self) -> String {
+        Path::new(&self.dir).join(CURRENT_FILE_NAME).to_string_lossy().to_string()
+    }
+
+    fn versioned_path(&self, path: &str, version: u64) -> String {
+        let (stem, ext) = stem_ext(path);
+        let path: PathBuf = Path::new(&self.dir).join(format!("{}.v{}.{}", stem, version, ext));
+        path.to_string_lossy().to_string()
+    }
+}
+
+/// ファイル名の拡張子より前と拡張子 (output/model_bundle.json → model_bundle, json)
+fn stem_ext(path: &str) -> (String, String) {
+    let path: &Path = Path::new(path);
+    let stem: String = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let ext: String = path.extension().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    (stem, ext)
+}
+
+/// {名前}.v{n}.{拡張子} の n
+fn parse_version(file_name: &str, path: &str) -> Option<u64> {
+    let (stem, ext) = stem_ext(path);
+    file_name.strip_prefix(&format!("{}.v", stem))?
+        .strip_suffix(&format!(".{}", ext))?
+        .parse::<u64>()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_versions_test1() {
+        let root = std::env::temp_dir().join("nango_model_versions_test1");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let model_path: String = root.join("model.json").to_string_lossy().to_string();
+        let dir: String = root.join("versions").to_string_lossy().to_string();
+        let versions = ModelVersions { dir, paths: vec![model_path.to_owned()], keep: 2 };
+
+        // 保存前は戻せない
+        assert!(matches!(versions.rollback(), Err(AppError::NoPreviousVersion)));
+        for content in ["v1", "v2", "v3"] {
+            std::fs::write(&model_path, content).unwrap();
+            versions.save().unwrap();
+        }
+        // 古い世代は keep 件まで削除する
+        assert_eq!(versions.list().unwrap(), vec![2, 3]);
+        assert_eq!(versions.current().unwrap(), Some(3));
+        assert!(root.join("versions").join("model.v3.json").exists());
+        assert_eq!(versions.read(&model_path, 2).unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(versions.read(&model_path, 1).unwrap(), None);
+
+        assert_eq!(versions.rollback().unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&model_path).unwrap(), "v2");
+        assert_eq!(versions.current().unwrap(), Some(2));
+        // それより前は削除済み
+        assert!(matches!(versions.rollback(), Err(AppError::NoPreviousVersion)));
+        // 戻した後の学習は新しい番号で保存する
+        std::fs::write(&model_path, "v4").unwrap();
+        assert_eq!(versions.save().unwrap(), 4);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn parse_version_test1() {
+        assert_eq!(parse_version("model_bundle.v12.json", "output/model_bundle.json"), Some(12));
+        assert_eq!(parse_version("model_bundle.json", "output/model_bundle.json"), None);
+        assert_eq!(parse_version("model_info.v1.json", "output/model_bundle.json"), None);
+        assert_eq!(parse_version("model_bundle.v1.json.tmp", "output/model_bundle.json"), None);
+    }
+}